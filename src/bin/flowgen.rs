@@ -0,0 +1,369 @@
+//! Traffic generator sending synthetic NetFlow v5 or IPFIX flows to a collector, for load tests and integration tests.
+//! With --metrics, the rate sent is compared with the counters exposed by the prometheus exporter of the collector.
+//!
+//! cargo run --release --bin flowgen -- --threads 4 --duration 10 --metrics 127.0.0.1:9100
+
+use ipfix::flow::ipfix::{self as ipfix_msg, DataSetTemplate, FieldType, FieldValue, SetHeader, TemplateField};
+use ipfix::flow::netflow5;
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+
+const TEMPLATE_ID: u16 = 256;
+
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    Netflow5,
+    Ipfix,
+    IpfixTcp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "netflow5" => Ok(Protocol::Netflow5),
+            "ipfix" => Ok(Protocol::Ipfix),
+            "ipfix-tcp" => Ok(Protocol::IpfixTcp),
+            _ => Err(format!("Unknown protocol {}, expected netflow5, ipfix or ipfix-tcp", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct Opts {
+    /// IP:port of the collector
+    #[structopt(short = "-t", long = "--target", default_value = "127.0.0.1:4739")]
+    target: SocketAddr,
+
+    /// netflow5, ipfix (over UDP) or ipfix-tcp
+    #[structopt(short = "-p", long = "--protocol", default_value = "ipfix")]
+    protocol: Protocol,
+
+    /// Number of sending threads, each one using its own source port
+    #[structopt(long = "--threads", default_value = "1")]
+    threads: usize,
+
+    /// Duration of the test in seconds
+    #[structopt(long = "--duration", default_value = "10")]
+    duration: u64,
+
+    /// Number of messages sent by each thread, stops before the end of the duration when reached
+    #[structopt(long = "--count")]
+    count: Option<u64>,
+
+    /// Maximum number of messages per second and per thread, no limit if not set
+    #[structopt(long = "--rate")]
+    rate: Option<u64>,
+
+    /// Number of flows per message, at most 30 for netflow v5 and 1000 for IPFIX
+    #[structopt(long = "--flows", default_value = "10")]
+    flows: u16,
+
+    /// Number of messages sent between two refresh of the IPFIX template over UDP
+    #[structopt(long = "--template-refresh", default_value = "10000")]
+    template_refresh: u64,
+
+    /// Observation domain id of the IPFIX messages, incremented for each thread
+    #[structopt(long = "--domain-id", default_value = "0")]
+    domain_id: u32,
+
+    /// IP:port of the prometheus exporter of the collector, to read the number of datagrams received
+    #[structopt(long = "--metrics")]
+    metrics: Option<SocketAddr>,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    let max_flows = if matches!(opts.protocol, Protocol::Netflow5) { 30 } else { 1000 };
+    if opts.flows == 0 || opts.flows > max_flows {
+        eprintln!("The number of flows per message must be between 1 and {}", max_flows);
+        std::process::exit(2);
+    }
+    let duration = Duration::from_secs(opts.duration);
+
+    let before = opts.metrics.map(scrape);
+    let start = Instant::now();
+
+    let senders: Vec<_> = (0..opts.threads.max(1))
+        .map(|i| {
+            let opts = opts.clone();
+            thread::spawn(move || Generator::new(&opts, i as u32).send(&opts, duration))
+        })
+        .collect();
+
+    let mut sent = 0;
+    for sender in senders {
+        match sender.join().unwrap() {
+            Ok(count) => sent += count,
+            Err(e) => eprintln!("Failed to send to {} : {}", opts.target, e),
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!(
+        "sent {} messages ({} flows) in {:.1}s: {:.0} messages/s",
+        sent,
+        sent * opts.flows as u64,
+        elapsed,
+        sent as f64 / elapsed
+    );
+
+    if let (Some(addr), Some((rcv_before, flows_before))) = (opts.metrics, before) {
+        // let the collector empty its socket buffers
+        thread::sleep(Duration::from_secs(1));
+        let (rcv_after, flows_after) = scrape(addr);
+        let received = rcv_after - rcv_before;
+
+        println!(
+            "received {} messages ({:.0} messages/s, {:.2}% lost), decoded {} flows",
+            received,
+            received as f64 / elapsed,
+            100.0 * sent.saturating_sub(received) as f64 / sent.max(1) as f64,
+            flows_after - flows_before
+        );
+    }
+}
+
+/******************************** GENERATOR ********************************/
+
+/// Builds the messages of one sending thread, with pseudo-random flows
+struct Generator {
+    protocol: Protocol,
+    flows: u16,
+    domain_id: u32,
+    template: DataSetTemplate,
+    /// Number of flows sent so far, the sequence number of both protocols
+    seq_number: u32,
+    start: Instant,
+    rng: u64,
+}
+
+impl Generator {
+    fn new(opts: &Opts, thread: u32) -> Self {
+        let field = |id, length| TemplateField { id, length };
+
+        Generator {
+            protocol: opts.protocol,
+            flows: opts.flows,
+            domain_id: opts.domain_id.wrapping_add(thread),
+            template: DataSetTemplate::new(
+                TEMPLATE_ID,
+                vec![
+                    field(FieldType::SourceIPv4Address, 4),
+                    field(FieldType::DestinationIPv4Address, 4),
+                    field(FieldType::SourceTransportPort, 2),
+                    field(FieldType::DestinationTransportPort, 2),
+                    field(FieldType::ProtocolIdentifier, 1),
+                    field(FieldType::TcpControlBits, 1),
+                    field(FieldType::OctetDeltaCount, 8),
+                    field(FieldType::PacketDeltaCount, 8),
+                    field(FieldType::IngressInterface, 4),
+                    field(FieldType::EgressInterface, 4),
+                    field(FieldType::FlowStartMilliseconds, 8),
+                    field(FieldType::FlowEndMilliseconds, 8),
+                ],
+            ),
+            seq_number: 0,
+            start: Instant::now(),
+            rng: 0x9e37_79b9_7f4a_7c15 ^ (thread as u64 + 1),
+        }
+    }
+
+    /// Send messages until the end of the duration or until count messages are sent, returning the number of messages sent
+    fn send(&mut self, opts: &Opts, duration: Duration) -> io::Result<u64> {
+        let mut output = match self.protocol {
+            Protocol::IpfixTcp => Output::Tcp(TcpStream::connect(opts.target)?),
+            _ => {
+                let socket = UdpSocket::bind(if opts.target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+                socket.connect(opts.target)?;
+                Output::Udp(socket)
+            }
+        };
+
+        let mut sent = 0;
+        let mut msg = vec![];
+
+        while self.start.elapsed() < duration && opts.count.is_none_or(|count| sent < count) {
+            // the template goes with the first message, then periodically over UDP
+            let with_template = match self.protocol {
+                Protocol::Netflow5 => false,
+                Protocol::Ipfix => sent % opts.template_refresh.max(1) == 0,
+                Protocol::IpfixTcp => sent == 0,
+            };
+
+            msg.clear();
+            match self.protocol {
+                Protocol::Netflow5 => self.netflow5_msg(&mut msg),
+                _ => self.ipfix_msg(with_template, &mut msg),
+            }
+
+            // a datagram refused by the collector is not fatal over UDP
+            match output.send(&msg) {
+                Ok(()) => sent += 1,
+                Err(e) if matches!(self.protocol, Protocol::IpfixTcp) => return Err(e),
+                Err(_) => (),
+            }
+
+            if let Some(rate) = opts.rate {
+                let expected = Duration::from_secs_f64(sent as f64 / rate as f64);
+                if let Some(wait) = expected.checked_sub(self.start.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+        }
+
+        Ok(sent)
+    }
+
+    fn netflow5_msg(&mut self, buf: &mut Vec<u8>) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        // the exporter booted one hour before the start of the generator
+        let uptime = 3_600_000 + self.start.elapsed().as_millis() as u32;
+
+        netflow5::Header {
+            version: netflow5::VERSION,
+            count: self.flows,
+            uptime,
+            unix_secs: now.as_secs() as u32,
+            unix_nsecs: now.subsec_nanos(),
+            seq_number: self.seq_number,
+            engine_type: 0,
+            engine_id: 0,
+            sampl: 0,
+        }
+        .write(buf);
+
+        for _ in 0..self.flows {
+            let duration = self.random(60_000) as u32;
+            let packets = 1 + self.random(1000) as u32;
+
+            netflow5::DataSet {
+                src_addr: 0x0a00_0000 | self.random(1 << 16) as u32,
+                dst_addr: 0xc633_6400 | self.random(256) as u32,
+                next_hop: 0x0a00_0001,
+                input_int: 1 + self.random(4) as u16,
+                output_int: 1 + self.random(4) as u16,
+                packets,
+                octets: packets * (64 + self.random(1400) as u32),
+                start_time: uptime - duration,
+                end_time: uptime,
+                src_port: 1024 + self.random(60_000) as u16,
+                dst_port: [53, 80, 443][self.random(3) as usize],
+                tcp_flag: 0x1b,
+                protocol: 6,
+                src_as: 64512,
+                dst_as: 64513,
+                src_mask: 16,
+                dst_mask: 24,
+                ..Default::default()
+            }
+            .write(buf);
+        }
+
+        self.seq_number = self.seq_number.wrapping_add(self.flows as u32);
+    }
+
+    fn ipfix_msg(&mut self, with_template: bool, buf: &mut Vec<u8>) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        ipfix_msg::Header {
+            version: ipfix_msg::VERSION,
+            length: 0,
+            export_time: now.as_secs() as u32,
+            seq_number: self.seq_number,
+            domain_id: self.domain_id,
+        }
+        .write(buf);
+
+        if with_template {
+            let mut record = vec![];
+            self.template.write(&mut record);
+            SetHeader {
+                id: DataSetTemplate::SET_ID,
+                length: (SetHeader::SIZE + record.len()) as u16,
+            }
+            .write(buf);
+            buf.extend_from_slice(&record);
+        }
+
+        let set_offset = buf.len();
+        SetHeader {
+            id: TEMPLATE_ID,
+            length: (SetHeader::SIZE + self.template.length * self.flows as usize) as u16,
+        }
+        .write(buf);
+
+        for _ in 0..self.flows {
+            let end = now.as_millis() as u64;
+            let packets = 1 + self.random(1000);
+            let mut fields = HashMap::new();
+            fields.insert(FieldType::SourceIPv4Address, FieldValue::U32(0x0a00_0000 | self.random(1 << 16) as u32));
+            fields.insert(FieldType::DestinationIPv4Address, FieldValue::U32(0xc633_6400 | self.random(256) as u32));
+            fields.insert(FieldType::SourceTransportPort, FieldValue::U16(1024 + self.random(60_000) as u16));
+            fields.insert(FieldType::DestinationTransportPort, FieldValue::U16([53, 80, 443][self.random(3) as usize]));
+            fields.insert(FieldType::ProtocolIdentifier, FieldValue::U8(6));
+            fields.insert(FieldType::TcpControlBits, FieldValue::U8(0x1b));
+            fields.insert(FieldType::OctetDeltaCount, FieldValue::U64(packets * (64 + self.random(1400))));
+            fields.insert(FieldType::PacketDeltaCount, FieldValue::U64(packets));
+            fields.insert(FieldType::IngressInterface, FieldValue::U32(1 + self.random(4) as u32));
+            fields.insert(FieldType::EgressInterface, FieldValue::U32(1 + self.random(4) as u32));
+            fields.insert(FieldType::FlowStartMilliseconds, FieldValue::U64(end - self.random(60_000)));
+            fields.insert(FieldType::FlowEndMilliseconds, FieldValue::U64(end));
+
+            // every value fits the length of its template field
+            ipfix_msg::DataSet { fields }.write(&self.template.fields, buf).unwrap();
+        }
+        debug_assert_eq!(buf.len() - set_offset, SetHeader::SIZE + self.template.length * self.flows as usize);
+
+        let length = buf.len() as u16;
+        buf[2..4].copy_from_slice(&length.to_be_bytes());
+        self.seq_number = self.seq_number.wrapping_add(self.flows as u32);
+    }
+
+    /// xorshift64, enough to spread the flows without a dependency
+    fn random(&mut self, max: u64) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng % max
+    }
+}
+
+enum Output {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Output {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Udp(socket) => socket.send(msg).map(|_| ()),
+            Output::Tcp(stream) => stream.write_all(msg),
+        }
+    }
+}
+
+/// Read the received datagrams and decoded flows counters of the collector, summed over all their labels
+fn scrape(addr: SocketAddr) -> (u64, u64) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let counter = |name: &str| -> u64 {
+        response
+            .lines()
+            .filter(|l| l.strip_prefix(name).is_some_and(|rest| rest.starts_with(' ') || rest.starts_with('{')))
+            .filter_map(|l| l.rsplit(' ').next()?.parse::<u64>().ok())
+            .sum()
+    };
+
+    (counter("ipfix_received_datagrams_total"), counter("ipfix_decoded_flows_total"))
+}
@@ -0,0 +1,706 @@
+use log::{info, warn, LevelFilter};
+use serde::{Deserialize, Deserializer};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::sinks::SinkConfig;
+use crate::threads::capture::DebugCapture;
+use crate::threads::channel::OverflowPolicy;
+use crate::threads::clock::Clock;
+use crate::threads::listener::{ListenerOptions, ListenerSpec, Transport};
+
+/******************************** CONFIGURATION FILE ********************************/
+
+/// Content of the TOML file given with --config, or built from the command line flags
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_log_level", deserialize_with = "from_str")]
+    pub log_level: LevelFilter,
+    /// IP:port for the prometheus exporter
+    pub prometheus: Option<SocketAddr>,
+    /// Maximum number of flow batches waiting for the exporter thread
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    #[serde(default = "default_overflow_policy", deserialize_with = "from_str")]
+    pub overflow_policy: OverflowPolicy,
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub templates: TemplatesConfig,
+    #[serde(default)]
+    pub exporters: ExportersConfig,
+    /// Template cache persisted across restarts
+    pub state: Option<StateConfig>,
+    #[serde(default, rename = "sink")]
+    pub sinks: Vec<SinkConfig>,
+}
+
+/// One [[listener]] entry
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    #[serde(default = "default_transport")]
+    pub transport: Transport,
+    /// Accepted versions, every version supported by the transport if not set
+    pub versions: Option<Vec<u16>>,
+    pub tag: Option<String>,
+    /// Number of threads sharing the UDP port with SO_REUSEPORT
+    #[serde(default = "default_one")]
+    pub workers: usize,
+    /// Maximum number of datagrams read per syscall
+    #[serde(default = "default_one")]
+    pub batch_size: usize,
+    /// Size in bytes of the kernel receive buffer
+    pub recv_buffer_size: Option<usize>,
+    /// Downstream collectors receiving a copy of the datagrams, the messages of a TCP listener are sent as UDP datagrams
+    #[serde(default)]
+    pub forward: Vec<ForwardConfig>,
+    /// pcap file receiving a copy of the datagrams, for debugging
+    pub capture: Option<CaptureConfig>,
+}
+
+/// One [[listener.forward]] entry
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardConfig {
+    pub target: SocketAddr,
+    /// Prefixes of the exporters forwarded, every exporter if empty
+    #[serde(default)]
+    pub exporters: Vec<Prefix>,
+    /// Versions forwarded, every version if not set
+    pub versions: Option<Vec<u16>>,
+    /// Send the datagrams with the address of the exporter as source, needs CAP_NET_RAW, an IPv4 target and a UDP listener.
+    /// The packets aren't fragmented, the datagrams bigger than the MTU toward the target are counted as forward errors.
+    #[serde(default)]
+    pub spoof: bool,
+}
+
+impl ForwardConfig {
+    pub fn matches(&self, exporter: IpAddr, version: u16) -> bool {
+        (self.exporters.is_empty() || self.exporters.iter().any(|p| p.contains(exporter))) && self.versions.as_ref().is_none_or(|v| v.contains(&version))
+    }
+}
+
+/// The [listener.capture] section
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// pcap file, rotated to path.1, path.2, ... when it reaches max_size
+    pub path: PathBuf,
+    /// Only capture the datagrams that couldn't be decoded
+    #[serde(default)]
+    pub errors_only: bool,
+    /// Prefixes of the exporters captured, every exporter if empty
+    #[serde(default)]
+    pub exporters: Vec<Prefix>,
+    /// Size in bytes of the file before its rotation
+    #[serde(default = "default_capture_max_size")]
+    pub max_size: u64,
+    /// Number of rotated files kept
+    #[serde(default = "default_capture_max_files")]
+    pub max_files: usize,
+}
+
+impl CaptureConfig {
+    pub fn matches(&self, exporter: IpAddr, failed: bool) -> bool {
+        (failed || !self.errors_only) && (self.exporters.is_empty() || self.exporters.iter().any(|p| p.contains(exporter)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplatesConfig {
+    /// Number of seconds a template stays valid without being refreshed by its exporter, 0 to keep them forever
+    #[serde(default = "default_template_timeout")]
+    pub timeout: u64,
+    /// Maximum number of templates kept for one exporter, 0 for no limit
+    #[serde(default)]
+    pub max_per_exporter: usize,
+}
+
+impl Default for TemplatesConfig {
+    fn default() -> Self {
+        TemplatesConfig {
+            timeout: default_template_timeout(),
+            max_per_exporter: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportersConfig {
+    /// Prefixes of the exporters allowed to send flows, every exporter is accepted if empty
+    #[serde(default)]
+    pub allow: Vec<Prefix>,
+    /// Prefixes always rejected, even when they are in the allow list
+    #[serde(default)]
+    pub deny: Vec<Prefix>,
+    /// IPFIX observation domains accepted, every domain if empty
+    #[serde(default)]
+    pub domain_ids: Vec<u32>,
+    /// Maximum number of exporters (address and observation domain) kept by each listener worker, 0 for no limit
+    #[serde(default)]
+    pub max: usize,
+    /// Identify the exporters with the ExporterIPv4Address/ExporterIPv6Address of their option data instead of the source address
+    #[serde(default)]
+    pub use_reported_address: bool,
+    /// Static identities of the exporters, the first matching entry is used
+    #[serde(default, rename = "mapping")]
+    pub mappings: Vec<ExporterMapping>,
+}
+
+/// One [[exporters.mapping]] entry
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExporterMapping {
+    /// Address of the exporter, after the reported address is applied
+    pub source: IpAddr,
+    /// Observation domain of the exporter, any domain if not set
+    pub domain_id: Option<u32>,
+    /// Address attached to the flows instead of the source
+    pub address: Option<IpAddr>,
+    /// Name attached to the flows
+    pub name: Option<Arc<str>>,
+}
+
+impl ExportersConfig {
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        !self.deny.iter().any(|p| p.contains(addr)) && (self.allow.is_empty() || self.allow.iter().any(|p| p.contains(addr)))
+    }
+
+    pub fn is_domain_allowed(&self, domain_id: u32) -> bool {
+        self.domain_ids.is_empty() || self.domain_ids.contains(&domain_id)
+    }
+
+    /// Address and name of the exporter after the static mappings
+    pub fn resolve(&self, addr: IpAddr, domain_id: Option<u32>) -> (IpAddr, Option<Arc<str>>) {
+        let mapping = self.mappings.iter().find(|m| m.source == addr && (m.domain_id.is_none() || m.domain_id == domain_id));
+
+        match mapping {
+            Some(m) => (m.address.unwrap_or(addr), m.name.clone()),
+            None => (addr, None),
+        }
+    }
+}
+
+/// CIDR prefix as 10.0.0.0/8 or 2001:db8::/32, a single address if the length is not given
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Prefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Prefix {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // a dual stack socket gives the IPv4 sources as IPv4-mapped IPv6 addresses
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => self.len == 0 || (u32::from(net) ^ u32::from(addr)) >> (32 - self.len) == 0,
+            (IpAddr::V6(net), IpAddr::V6(addr)) => self.len == 0 || (u128::from(net) ^ u128::from(addr)) >> (128 - self.len) == 0,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("Invalid prefix '{}' : {}", s, e))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let len = match len {
+            None => max_len,
+            Some(len) => len.parse().ok().filter(|&len| len <= max_len).ok_or_else(|| format!("Invalid prefix length in '{}'", s))?,
+        };
+
+        Ok(Prefix { addr, len })
+    }
+}
+
+impl<'de> Deserialize<'de> for Prefix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_str(deserializer)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    /// File holding the templates of every exporter
+    pub path: PathBuf,
+    /// Number of seconds between two writes of the file, 0 to only write it on shutdown
+    #[serde(default = "default_state_interval")]
+    pub interval: u64,
+}
+
+fn default_log_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+fn default_queue_size() -> usize {
+    1024
+}
+
+fn default_overflow_policy() -> OverflowPolicy {
+    OverflowPolicy::Block
+}
+
+fn default_transport() -> Transport {
+    Transport::Udp
+}
+
+fn default_one() -> usize {
+    1
+}
+
+fn default_template_timeout() -> u64 {
+    3600
+}
+
+fn default_state_interval() -> u64 {
+    300
+}
+
+fn default_capture_max_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_capture_max_files() -> usize {
+    4
+}
+
+/// Deserialize a string with the FromStr implementation of the type
+pub(crate) fn from_str<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D) -> Result<T, D::Error>
+where
+    T::Err: std::fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Failed to read the configuration file {} : {}", path.display(), e))?;
+        Self::parse(&content).map_err(|e| format!("Invalid configuration file {} : {}", path.display(), e))
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(content).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that can't be expressed with the types of the fields
+    pub fn validate(&self) -> Result<(), String> {
+        if self.listeners.is_empty() {
+            return Err("At least one [[listener]] is required".to_string());
+        }
+
+        let mut addresses = vec![];
+        for listener in &self.listeners {
+            listener.spec()?;
+
+            if addresses.contains(&(listener.address, listener.transport)) {
+                return Err(format!("The address {} is used by several listeners", listener.address));
+            }
+            addresses.push((listener.address, listener.transport));
+
+            // the exporter has no UDP port to spoof, its messages come from a TCP connection
+            if listener.transport == Transport::Tcp && listener.forward.iter().any(|forward| forward.spoof) {
+                return Err(format!("The TCP listener {} can't forward its messages with the exporter address as source", listener.address));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Options of each listener thread to start
+    pub fn listener_options(&self) -> Vec<ListenerOptions> {
+        let mut list = vec![];
+
+        for listener in &self.listeners {
+            // a TCP listener starts one thread per connection on its own
+            let workers = match listener.transport {
+                Transport::Udp => listener.workers.max(1),
+                Transport::Tcp => 1,
+            };
+            let spec = listener.spec().unwrap();
+            // the workers of a listener share its capture file
+            let capture = listener.capture.clone().map(|config| Arc::new(DebugCapture::new(config, &spec)));
+            let opts = ListenerOptions {
+                spec,
+                recv_buffer_size: listener.recv_buffer_size,
+                batch_size: listener.batch_size.max(1),
+                reuse_port: workers > 1,
+                forward: listener.forward.clone(),
+                capture,
+                clock: Clock::default(),
+            };
+
+            list.extend(std::iter::repeat_n(opts, workers));
+        }
+
+        list
+    }
+}
+
+impl ListenerConfig {
+    pub fn spec(&self) -> Result<ListenerSpec, String> {
+        ListenerSpec::new(self.address, self.transport, self.versions.clone(), self.tag.as_deref())
+    }
+}
+
+/******************************** SHARED CONFIGURATION ********************************/
+
+/// Configuration shared by the threads, replaced on SIGHUP.
+/// The threads keep their own copy and only refresh it when the generation changes.
+pub struct SharedConfig {
+    config: RwLock<Arc<Config>>,
+    generation: AtomicU64,
+}
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Arc<Self> {
+        Arc::new(SharedConfig {
+            config: RwLock::new(Arc::new(config)),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Apply the parts of the new configuration that can change at runtime, keeping the others
+    pub fn reload(&self, mut new: Config) {
+        let mut config = self.config.write().unwrap();
+
+        if new.listeners != config.listeners || new.prometheus != config.prometheus || new.queue_size != config.queue_size || new.overflow_policy != config.overflow_policy || new.state != config.state
+        {
+            warn!("Changes to the listeners, prometheus exporter, queue and state file need a restart to be applied");
+            new.listeners = config.listeners.clone();
+            new.prometheus = config.prometheus;
+            new.queue_size = config.queue_size;
+            new.overflow_policy = config.overflow_policy;
+            new.state = config.state.clone();
+        }
+
+        log::set_max_level(new.log_level);
+        *config = Arc::new(new);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        info!("Configuration reloaded");
+    }
+}
+
+/// Cached copy of the shared configuration, cheap to check on every message
+pub struct ConfigView {
+    shared: Arc<SharedConfig>,
+    generation: u64,
+    config: Arc<Config>,
+}
+
+impl ConfigView {
+    pub fn new(shared: Arc<SharedConfig>) -> Self {
+        ConfigView {
+            generation: shared.generation(),
+            config: shared.current(),
+            shared,
+        }
+    }
+
+    /// Refresh the copy if the configuration has been reloaded, returning true if it changed
+    pub fn refresh(&mut self) -> bool {
+        let generation = self.shared.generation();
+        if generation == self.generation {
+            return false;
+        }
+
+        self.generation = generation;
+        self.config = self.shared.current();
+        true
+    }
+
+    #[inline]
+    pub fn get(&self) -> &Config {
+        &self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        log_level = "debug"
+        prometheus = "127.0.0.1:9100"
+        overflow_policy = "drop-oldest"
+
+        [[listener]]
+        address = "0.0.0.0:4739"
+        tag = "core"
+        workers = 4
+        batch_size = 64
+
+        [[listener.forward]]
+        target = "192.0.2.10:4739"
+        exporters = ["10.0.0.0/8"]
+        versions = [10]
+
+        [listener.capture]
+        path = "/tmp/ipfix-core.pcap"
+        errors_only = true
+        exporters = ["10.0.0.1"]
+
+        [[listener]]
+        address = "0.0.0.0:4739"
+        transport = "tcp"
+
+        [templates]
+        timeout = 1800
+
+        [exporters]
+        allow = ["10.0.0.0/8", "2001:db8::1"]
+        deny = ["10.0.0.128/25"]
+        domain_ids = [1, 2]
+        use_reported_address = true
+
+        [[exporters.mapping]]
+        source = "10.0.0.1"
+        domain_id = 2
+        name = "edge-1"
+
+        [[exporters.mapping]]
+        source = "10.0.0.1"
+        address = "192.0.2.1"
+
+        [state]
+        path = "/var/lib/ipfix/templates.state"
+
+        [[sink]]
+        type = "stdout"
+
+        [[sink]]
+        type = "ipfix"
+        target = "192.0.2.10:4739"
+        transport = "tcp"
+        domain_id = 7
+
+        [[sink]]
+        type = "csv"
+        directory = "/var/lib/ipfix/csv"
+        columns = ["exporter", "start_time", "sourceIPv4Address", "octetDeltaCount"]
+
+        [[sink]]
+        type = "parquet"
+        directory = "/var/lib/ipfix/parquet"
+        max_rows = 500000
+
+        [[sink]]
+        type = "clickhouse"
+        target = "clickhouse:8123"
+        format = "JSONEachRow"
+        spool = "/var/spool/ipfix"
+
+        [[sink]]
+        type = "elasticsearch"
+        target = "opensearch:9200"
+        index_prefix = "netflow"
+
+        [[sink]]
+        type = "kafka"
+        brokers = ["kafka-1:9092", "kafka-2:9092"]
+        topic = "flows"
+        format = "protobuf"
+        key = "source_address"
+        compression = "zstd"
+
+        [[sink]]
+        type = "goflow2"
+        output = { tcp = "127.0.0.1:6343" }
+
+        [[sink]]
+        type = "influxdb"
+        target = "influxdb:8086"
+        tags = ["exporter_name", "interface", "direction"]
+        fields = ["bytes", "packets", "flows"]
+        interval = 300
+    "#;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(CONFIG).unwrap();
+
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(config.queue_size, 1024);
+        assert_eq!(config.overflow_policy, OverflowPolicy::DropOldest);
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[1].spec().unwrap().versions, vec![10]);
+        assert_eq!(config.templates.timeout, 1800);
+        assert!(config.exporters.is_allowed("10.0.0.1".parse().unwrap()));
+        assert!(config.exporters.is_allowed("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!config.exporters.is_allowed("10.0.0.200".parse().unwrap()));
+        assert!(!config.exporters.is_allowed("11.0.0.1".parse().unwrap()));
+        assert!(config.exporters.is_allowed("2001:db8::1".parse().unwrap()));
+        assert!(!config.exporters.is_allowed("2001:db8::2".parse().unwrap()));
+        assert!(config.exporters.is_domain_allowed(2));
+        assert!(!config.exporters.is_domain_allowed(3));
+        assert!(config.exporters.use_reported_address);
+
+        let exporter = "10.0.0.1".parse().unwrap();
+        assert_eq!(config.exporters.resolve(exporter, Some(2)), (exporter, Some(Arc::from("edge-1"))));
+        assert_eq!(config.exporters.resolve(exporter, None), ("192.0.2.1".parse().unwrap(), None));
+        assert_eq!(config.exporters.resolve("10.0.0.2".parse().unwrap(), Some(2)), ("10.0.0.2".parse().unwrap(), None));
+        assert_eq!(config.state.as_ref().unwrap().interval, 300);
+        assert_eq!(config.sinks.len(), 9);
+        match &config.sinks[1] {
+            SinkConfig::Ipfix(sink) => {
+                assert_eq!(sink.transport, Transport::Tcp);
+                assert_eq!(sink.domain_id, 7);
+                assert_eq!(sink.template_refresh, 600);
+            }
+            _ => panic!("Wrong sink type"),
+        }
+        match &config.sinks[2] {
+            SinkConfig::Csv(sink) => {
+                assert_eq!(sink.columns.len(), 4);
+                assert_eq!(sink.prefix, "flows");
+                assert_eq!(sink.rotate, 3600);
+            }
+            _ => panic!("Wrong sink type"),
+        }
+        match &config.sinks[3] {
+            SinkConfig::Parquet(sink) => {
+                assert_eq!(sink.max_rows, 500_000);
+                assert_eq!(sink.max_age, 300);
+                assert_eq!(sink.compression_level, 3);
+            }
+            _ => panic!("Wrong sink type"),
+        }
+        match &config.sinks[4] {
+            SinkConfig::ClickHouse(sink) => {
+                assert_eq!(sink.format, crate::sinks::clickhouse::InsertFormat::JSONEachRow);
+                assert_eq!(sink.database, "default");
+                assert_eq!(sink.columns.len(), 17);
+                assert_eq!(sink.batch.or(crate::sinks::clickhouse::BATCH_DEFAULTS).retries, 3);
+            }
+            _ => panic!("Wrong sink type"),
+        }
+        match &config.sinks[5] {
+            SinkConfig::OpenSearch(sink) => {
+                assert_eq!(sink.index_prefix, "netflow");
+                assert_eq!(sink.batch.or(crate::sinks::opensearch::BATCH_DEFAULTS).size, 5000);
+            }
+            _ => panic!("Wrong sink type"),
+        }
+        match &config.sinks[6] {
+            SinkConfig::Kafka(sink) => {
+                assert_eq!(sink.brokers.len(), 2);
+                assert_eq!(sink.format, crate::sinks::kafka::RecordFormat::Protobuf);
+                assert_eq!(sink.key, crate::sinks::kafka::RecordKey::SourceAddress);
+                assert_eq!(sink.compression, crate::sinks::kafka::Compression::Zstd);
+                assert_eq!(sink.acks, -1);
+            }
+            _ => panic!("Wrong sink type"),
+        }
+        match &config.sinks[7] {
+            SinkConfig::Goflow2(sink) => assert_eq!(sink.output, crate::sinks::goflow2::Output::Tcp("127.0.0.1:6343".parse().unwrap())),
+            _ => panic!("Wrong sink type"),
+        }
+        match &config.sinks[8] {
+            SinkConfig::InfluxDb(sink) => {
+                assert_eq!(sink.tags.len(), 3);
+                assert_eq!(sink.fields.len(), 3);
+                assert_eq!(sink.interval, 300);
+                assert_eq!(sink.transport, crate::sinks::influxdb::InfluxTransport::Http);
+            }
+            _ => panic!("Wrong sink type"),
+        }
+
+        let opts = config.listener_options();
+        assert_eq!(opts.len(), 5);
+        assert!(opts[0].reuse_port);
+        assert_eq!(opts[0].spec.name(), "core");
+        assert_eq!(opts[4].spec.transport, Transport::Tcp);
+        assert!(opts[0].forward[0].matches("10.1.1.1".parse().unwrap(), 10));
+        assert!(!opts[0].forward[0].matches("10.1.1.1".parse().unwrap(), 5));
+        assert!(!opts[0].forward[0].matches("11.1.1.1".parse().unwrap(), 10));
+        assert!(opts[4].forward.is_empty());
+        let capture = config.listeners[0].capture.as_ref().unwrap();
+        assert_eq!(capture.max_size, 64 * 1024 * 1024);
+        assert!(capture.matches("10.0.0.1".parse().unwrap(), true));
+        assert!(!capture.matches("10.0.0.1".parse().unwrap(), false));
+        assert!(!capture.matches("10.0.0.2".parse().unwrap(), true));
+        // the workers write to the same file
+        assert!(Arc::ptr_eq(opts[0].capture.as_ref().unwrap(), opts[3].capture.as_ref().unwrap()));
+        assert!(opts[4].capture.is_none());
+    }
+
+    #[test]
+    fn parse_invalid_config() {
+        // unknown field
+        assert!(Config::parse("[[listener]]\naddress = \"0.0.0.0:4739\"\nport = 1").is_err());
+        // no listener
+        assert!(Config::parse("log_level = \"info\"").is_err());
+        // invalid version for a TCP listener
+        assert!(Config::parse("[[listener]]\naddress = \"0.0.0.0:4739\"\ntransport = \"tcp\"\nversions = [5]").is_err());
+        // spoofing on a TCP listener
+        assert!(Config::parse("[[listener]]\naddress = \"0.0.0.0:4739\"\ntransport = \"tcp\"\n[[listener.forward]]\ntarget = \"192.0.2.10:4739\"\nspoof = true").is_err());
+        // same address twice
+        assert!(Config::parse("[[listener]]\naddress = \"0.0.0.0:4739\"\n[[listener]]\naddress = \"0.0.0.0:4739\"").is_err());
+        // invalid prefix
+        assert!(Config::parse("[[listener]]\naddress = \"0.0.0.0:4739\"\n[exporters]\nallow = [\"10.0.0.0/33\"]").is_err());
+        // unknown sink
+        assert!(Config::parse("[[listener]]\naddress = \"0.0.0.0:4739\"\n[[sink]]\ntype = \"foo\"").is_err());
+        // unknown column of a csv sink
+        assert!(Config::parse("[[listener]]\naddress = \"0.0.0.0:4739\"\n[[sink]]\ntype = \"csv\"\ndirectory = \"/tmp\"\ncolumns = [\"foo\"]").is_err());
+        // unknown field of a sink
+        assert!(Config::parse("[[listener]]\naddress = \"0.0.0.0:4739\"\n[[sink]]\ntype = \"ipfix\"\ntarget = \"192.0.2.10:4739\"\nfoo = 1").is_err());
+    }
+
+    #[test]
+    fn match_prefix() {
+        let any: Prefix = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.0.2.1".parse().unwrap()));
+        assert!(!any.contains("2001:db8::1".parse().unwrap()));
+
+        let net: Prefix = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+        assert!("10.0.0.1/".parse::<Prefix>().is_err());
+        assert!("2001:db8::/129".parse::<Prefix>().is_err());
+    }
+
+    #[test]
+    fn reload_config() {
+        let shared = SharedConfig::new(Config::parse(CONFIG).unwrap());
+        let mut view = ConfigView::new(shared.clone());
+        assert!(!view.refresh());
+
+        let mut new = Config::parse(CONFIG).unwrap();
+        new.templates.timeout = 60;
+        new.listeners.pop();
+        shared.reload(new);
+
+        assert!(view.refresh());
+        assert_eq!(view.get().templates.timeout, 60);
+        // the listeners can't be changed without a restart
+        assert_eq!(view.get().listeners.len(), 2);
+    }
+}
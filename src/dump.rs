@@ -0,0 +1,442 @@
+//! Human readable tree of NetFlow v5 and IPFIX messages, printed by the decode subcommand to diagnose the exporters.
+
+use core::convert::TryInto;
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::ops::Range;
+
+use crate::flow::ipfix::{self, DataSetTemplate, FieldType, OptionDataSetTemplate, SetHeader, TemplateField, TemplateHeader};
+use crate::flow::{netflow5, Template};
+use crate::pcap::{self, Capture};
+
+/******************************** INPUT ********************************/
+
+/// Message read from the input file, with the addresses of its datagram when it comes from a capture
+pub struct Message {
+    pub from: Option<SocketAddr>,
+    pub to: Option<SocketAddr>,
+    pub data: Vec<u8>,
+}
+
+/// Split a pcap/pcapng capture, a hex dump or a raw file of consecutive messages into messages
+pub fn read_messages(content: &[u8]) -> Result<Vec<Message>, String> {
+    if content.len() >= 4 && is_capture(&content[0..4]) {
+        return read_capture(content);
+    }
+
+    let raw = match std::str::from_utf8(content).ok().and_then(parse_hex) {
+        Some(raw) => raw,
+        None => content.to_vec(),
+    };
+    split_messages(&raw)
+}
+
+fn is_capture(magic: &[u8]) -> bool {
+    [0xa1b2_c3d4u32, 0xa1b2_3c4d, 0x0a0d_0d0a].iter().any(|m| magic == m.to_le_bytes() || magic == m.to_be_bytes())
+}
+
+/// The NetFlow/IPFIX datagrams of the capture, recognized by their version whatever their port
+fn read_capture(content: &[u8]) -> Result<Vec<Message>, String> {
+    let mut capture = Capture::new(content)?;
+    let mut messages = vec![];
+
+    while let Some(packet) = capture.next_packet()? {
+        if let Some(datagram) = pcap::decode_udp(packet.link_type, &packet.data) {
+            let version = datagram.payload.get(0..2).map(|v| u16::from_be_bytes(v.try_into().unwrap()));
+            if version == Some(netflow5::VERSION) || version == Some(ipfix::VERSION) {
+                messages.push(Message {
+                    from: Some(datagram.from),
+                    to: Some(datagram.to),
+                    data: datagram.payload.to_vec(),
+                });
+            }
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Bytes of a hex dump, the whitespaces, the 0x prefixes, the commas and the comments after # are ignored
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let mut digits = String::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        for token in line.split(|c: char| c.is_whitespace() || c == ',') {
+            digits.push_str(token.strip_prefix("0x").unwrap_or(token));
+        }
+    }
+
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Consecutive messages delimited with the length of their header
+fn split_messages(raw: &[u8]) -> Result<Vec<Message>, String> {
+    let mut messages = vec![];
+    let mut offset = 0;
+
+    while offset < raw.len() {
+        let rest = &raw[offset..];
+        let version = rest.get(0..2).map(|v| u16::from_be_bytes(v.try_into().unwrap()));
+        let length = match version {
+            Some(ipfix::VERSION) if rest.len() >= 4 => u16::from_be_bytes(rest[2..4].try_into().unwrap()) as usize,
+            Some(netflow5::VERSION) if rest.len() >= 4 => netflow5::Header::SIZE + u16::from_be_bytes(rest[2..4].try_into().unwrap()) as usize * netflow5::DataSet::SIZE,
+            _ => return Err(format!("No NetFlow v5 or IPFIX message at offset {}", offset)),
+        };
+        if length < 4 {
+            return Err(format!("Invalid message length {} at offset {}", length, offset));
+        }
+
+        // a cut message is still shown, its decoding stops where the data ends
+        let end = (offset + length).min(raw.len());
+        messages.push(Message {
+            from: None,
+            to: None,
+            data: raw[offset..end].to_vec(),
+        });
+        offset = end;
+    }
+
+    Ok(messages)
+}
+
+/******************************** TREE ********************************/
+
+/// Print the messages one after the other, keeping the templates of each exporter to decode its data sets
+#[derive(Default)]
+pub struct Dumper {
+    /// Templates by source address (when known), observation domain and template id
+    templates: HashMap<(Option<SocketAddr>, u32, u16), Template>,
+}
+
+impl Dumper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tree of the message, with the offset of every element from the start of the message
+    pub fn dump(&mut self, index: usize, msg: &Message, out: &mut String) {
+        write!(out, "message #{}", index).unwrap();
+        if let (Some(from), Some(to)) = (msg.from, msg.to) {
+            write!(out, " from {} to {}", from, to).unwrap();
+        }
+        writeln!(out, ", {} bytes", msg.data.len()).unwrap();
+
+        let version = msg.data.get(0..2).map(|v| u16::from_be_bytes(v.try_into().unwrap()));
+        let result = match version {
+            Some(netflow5::VERSION) => self.dump_netflow5(&msg.data, out),
+            Some(ipfix::VERSION) => self.dump_ipfix(msg.from, &msg.data, out),
+            _ => Err(format!("unknown version {}", version.unwrap_or_default())),
+        };
+
+        if let Err(e) = result {
+            writeln!(out, "  error: {}", e).unwrap();
+        }
+        out.push('\n');
+    }
+
+    fn dump_netflow5(&mut self, buf: &[u8], out: &mut String) -> Result<(), String> {
+        let header = netflow5::Header::read(buf)?;
+        writeln!(out, "  {:04x}  netflow v5 header: {}", 0, header).unwrap();
+
+        let mut offset = netflow5::Header::SIZE;
+        for i in 0..header.count as usize {
+            let record = match buf.get(offset..offset + netflow5::DataSet::SIZE) {
+                Some(record) => record,
+                None => return Err(format!("record {} cut at the end of the message", i + 1)),
+            };
+            match netflow5::DataSet::read(record) {
+                Ok(pdu) => writeln!(out, "  {:04x}  record {}: {}", offset, i + 1, pdu).unwrap(),
+                Err(e) => return Err(e),
+            }
+            writeln!(out, "          {}", hex(record)).unwrap();
+            offset += netflow5::DataSet::SIZE;
+        }
+
+        Ok(())
+    }
+
+    fn dump_ipfix(&mut self, from: Option<SocketAddr>, buf: &[u8], out: &mut String) -> Result<(), String> {
+        let header = ipfix::Header::read(buf)?;
+        writeln!(
+            out,
+            "  {:04x}  IPFIX header: version {}, length {}, export time {}, sequence {}, domain {}",
+            0, header.version, header.length, header.export_time, header.seq_number, header.domain_id
+        )
+        .unwrap();
+
+        let end = (header.length as usize).min(buf.len());
+        let mut offset = ipfix::Header::SIZE;
+        while offset + SetHeader::SIZE <= end {
+            let set = SetHeader::read(&buf[offset..end]).unwrap();
+            let kind = match set.id {
+                DataSetTemplate::SET_ID => "template set".to_string(),
+                OptionDataSetTemplate::SET_ID => "option template set".to_string(),
+                id if id >= ipfix::DataSet::MIN_SET_ID => format!("data set of template {}", id),
+                _ => "reserved set id".to_string(),
+            };
+            writeln!(out, "  {:04x}  set {} ({}), length {}", offset, set.id, kind, set.length).unwrap();
+
+            if (set.length as usize) < SetHeader::SIZE || offset + set.length as usize > end {
+                return Err(format!("invalid set length {}, {} bytes left in the message", set.length, end - offset));
+            }
+
+            let content = offset + SetHeader::SIZE..offset + set.length as usize;
+            match set.id {
+                DataSetTemplate::SET_ID | OptionDataSetTemplate::SET_ID => self.dump_templates(from, header.domain_id, set.id, buf, content, out),
+                id if id >= ipfix::DataSet::MIN_SET_ID => self.dump_records(from, header.domain_id, id, buf, content, out),
+                _ => {}
+            }
+            offset += set.length as usize;
+        }
+
+        if offset < end {
+            writeln!(out, "  {:04x}  {} trailing bytes: {}", offset, end - offset, hex(&buf[offset..end])).unwrap();
+        }
+        if end < header.length as usize {
+            return Err(format!("message cut, {} bytes announced but {} available", header.length, buf.len()));
+        }
+        Ok(())
+    }
+
+    fn dump_templates(&mut self, from: Option<SocketAddr>, domain_id: u32, set_id: u16, buf: &[u8], content: Range<usize>, out: &mut String) {
+        let header_size = if set_id == DataSetTemplate::SET_ID {
+            TemplateHeader::SIZE
+        } else {
+            ipfix::OptionTemplateHeader::SIZE
+        };
+        let mut offset = content.start;
+
+        while offset + header_size <= content.end {
+            let record = &buf[offset..content.end];
+            let template_id = u16::from_be_bytes(record[0..2].try_into().unwrap());
+            let field_count = u16::from_be_bytes(record[2..4].try_into().unwrap());
+            writeln!(out, "  {:04x}    template {}, {} fields", offset, template_id, field_count).unwrap();
+
+            // the raw fields are listed first, a template with an unknown field type can't be decoded
+            let mut field_offset = header_size;
+            for _ in 0..field_count {
+                let field = match record.get(field_offset..field_offset + TemplateField::SIZE) {
+                    Some(field) => field,
+                    None => {
+                        writeln!(out, "  {:04x}      error: template cut at the end of the set", offset + field_offset).unwrap();
+                        return;
+                    }
+                };
+                let id = u16::from_be_bytes(field[0..2].try_into().unwrap());
+                let length = u16::from_be_bytes(field[2..4].try_into().unwrap());
+                let name = match FieldType::from_u16(id) {
+                    Some(field_type) => format!("{:?}", field_type),
+                    None => "unknown".to_string(),
+                };
+                write!(out, "  {:04x}      {}  id {} ({}), length {}", offset + field_offset, hex(field), id & 0x7fff, name, length).unwrap();
+                field_offset += TemplateField::SIZE;
+
+                // enterprise-specific field, followed by its enterprise number
+                if id & 0x8000 != 0 {
+                    match record.get(field_offset..field_offset + 4) {
+                        Some(pen) => write!(out, ", enterprise {}", u32::from_be_bytes(pen.try_into().unwrap())).unwrap(),
+                        None => {
+                            writeln!(out, ", enterprise number cut").unwrap();
+                            writeln!(out, "  {:04x}      error: template cut at the end of the set", offset + field_offset).unwrap();
+                            return;
+                        }
+                    }
+                    field_offset += 4;
+                }
+                writeln!(out).unwrap();
+            }
+
+            match Template::read(set_id, record) {
+                Ok((template, _)) => {
+                    let text = match &template {
+                        Template::IpfixDataSet(t) => t.to_string(),
+                        Template::IpfixOptionDataSet(t) => t.to_string(),
+                    };
+                    for line in text.lines() {
+                        writeln!(out, "            {}", line).unwrap();
+                    }
+                    self.templates.insert((from, domain_id, template_id), template);
+                }
+                Err(e) => writeln!(out, "            error: {}", e).unwrap(),
+            }
+            offset += field_offset;
+        }
+
+        if offset < content.end {
+            writeln!(out, "  {:04x}    padding: {}", offset, hex(&buf[offset..content.end])).unwrap();
+        }
+    }
+
+    fn dump_records(&self, from: Option<SocketAddr>, domain_id: u32, set_id: u16, buf: &[u8], content: Range<usize>, out: &mut String) {
+        let plan = match self.templates.get(&(from, domain_id, set_id)) {
+            Some(Template::IpfixDataSet(t)) => &t.plan,
+            Some(Template::IpfixOptionDataSet(t)) => &t.plan,
+            None => {
+                writeln!(out, "  {:04x}    error: no template {} received before, the records can't be decoded", content.start, set_id).unwrap();
+                writeln!(out, "            {}", hex(&buf[content])).unwrap();
+                return;
+            }
+        };
+
+        let set = &buf[content.clone()];
+        let mut offset = 0;
+        let mut index = 1;
+        while set.len() - offset >= plan.min_length.max(1) {
+            let start = offset;
+            let mut lines = String::new();
+
+            for field in &plan.fields {
+                let field_start = offset;
+                match field.locate(set, &mut offset) {
+                    Ok(value) => writeln!(
+                        lines,
+                        "  {:04x}      {:?}  {}  = {}",
+                        content.start + field_start,
+                        field.id,
+                        hex(&set[field_start..value.end]),
+                        field.decode(&set[value])
+                    )
+                    .unwrap(),
+                    Err(e) => {
+                        writeln!(out, "  {:04x}    record {}", content.start + start, index).unwrap();
+                        out.push_str(&lines);
+                        writeln!(out, "  {:04x}      error: {}", content.start + field_start, e).unwrap();
+                        return;
+                    }
+                }
+            }
+
+            writeln!(out, "  {:04x}    record {}, {} bytes", content.start + start, index, offset - start).unwrap();
+            out.push_str(&lines);
+            index += 1;
+            if offset == start {
+                break;
+            }
+        }
+
+        if offset < set.len() {
+            writeln!(out, "  {:04x}    padding: {}", content.start + offset, hex(&set[offset..])).unwrap();
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE_AND_DATA: &str = "
+        # template 256 with an unknown field type, then template 257 and one record
+        00 0a 00 40 60 6c 55 89 00 00 00 01 00 00 00 08
+        00 02 00 1c 01 00 00 02 00 08 00 04 7f ff 00 04
+        01 01 00 02 00 08 00 04 00 07 00 02
+        01 01 00 0c c0 00 02 01 12 83 00 00
+        01 00 00 08 00 00 00 00
+        # announced with 24 bytes but cut after 20
+        00 0a 00 18 60 6c 55 8a 00 00 00 02 00 00 00 08
+        01 01 00 04
+    ";
+
+    #[test]
+    fn parse_hex_dumps() {
+        assert_eq!(parse_hex("00 0a\n0x01, 0x02 # comment ff"), Some(vec![0, 10, 1, 2]));
+        assert_eq!(parse_hex("00 0"), None);
+        assert_eq!(parse_hex("zz"), None);
+    }
+
+    #[test]
+    fn dump_messages() {
+        let messages = read_messages(TEMPLATE_AND_DATA.as_bytes()).unwrap();
+        assert_eq!(messages.len(), 2);
+
+        let mut dumper = Dumper::new();
+        let mut out = String::new();
+        for (i, msg) in messages.iter().enumerate() {
+            dumper.dump(i + 1, msg, &mut out);
+        }
+
+        // the first template is listed field by field with the error, the second one is decoded
+        assert!(out.contains("  0014    template 256, 2 fields\n  0018      00 08 00 04  id 8 (SourceIPv4Address), length 4\n  001c      7f ff 00 04  id 32767 (unknown), length 4\n            error: No FieldType found for value : 32767\n"));
+        assert!(out.contains("  0020    template 257, 2 fields\n"));
+        assert!(out.contains("  0030    record 1, 6 bytes\n  0030      SourceIPv4Address  c0 00 02 01  = 3221225985\n  0034      SourceTransportPort  12 83  = 4739\n  0036    padding: 00 00\n"));
+        assert!(out.contains("  003c    error: no template 256 received before, the records can't be decoded\n"));
+        assert!(out.contains("  0010  set 257 (data set of template 257), length 4\n  error: message cut, 24 bytes announced but 20 available\n"));
+    }
+
+    #[test]
+    fn dump_template_with_empty_records() {
+        let messages = read_messages(
+            "00 0a 00 24 60 6c 55 89 00 00 00 01 00 00 00 08
+             00 02 00 0c 01 00 00 01 00 01 00 00
+             01 00 00 08 00 00 00 00"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut out = String::new();
+        Dumper::new().dump(1, &messages[0], &mut out);
+
+        assert!(out.contains("            error: Template 256 has no field with a length, its records would be empty\n"));
+        assert!(out.contains("error: no template 256 received before, the records can't be decoded\n"));
+    }
+
+    #[test]
+    fn read_capture_cut_in_udp_header() {
+        let (from, to) = ("192.0.2.1:54321".parse().unwrap(), "0.0.0.0:4739".parse().unwrap());
+        let mut file = pcap::file_header();
+        file.extend(pcap::udp_record(std::time::Duration::ZERO, &[0, 10, 0, 4], from, to).unwrap());
+
+        // ethernet, IPv4 and 6 bytes of the UDP header
+        let mut cut = pcap::udp_record(std::time::Duration::ZERO, &[0, 10, 0, 4], from, to).unwrap();
+        cut.truncate(16 + 14 + 20 + 6);
+        cut[8..12].copy_from_slice(&(14u32 + 20 + 6).to_le_bytes());
+        file.extend(cut);
+
+        let messages = read_messages(&file).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].data, [0, 10, 0, 4]);
+    }
+
+    #[test]
+    fn dump_template_with_enterprise_number_cut() {
+        let messages = read_messages(
+            "00 0a 00 1e 60 6c 55 89 00 00 00 01 00 00 00 08
+             00 02 00 0e 01 00 00 01 80 01 00 04 00 00"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut out = String::new();
+        Dumper::new().dump(1, &messages[0], &mut out);
+
+        assert!(
+            out.contains(
+                "  0018      80 01 00 04  id 1 (unknown), length 4, enterprise number cut
+  001c      error: template cut at the end of the set
+"
+            ),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn dump_netflow5_message() {
+        let mut raw = vec![0, 5, 0, 1];
+        raw.extend_from_slice(&[0; 20]);
+        raw.extend_from_slice(&[0; 48]);
+
+        let messages = read_messages(&raw).unwrap();
+        let mut out = String::new();
+        Dumper::new().dump(1, &messages[0], &mut out);
+
+        assert!(out.starts_with("message #1, 72 bytes\n  0000  netflow v5 header: version: 5, count: 1,"));
+        assert!(out.contains("  0018  record 1: from: 0.0.0.0/0:0"));
+    }
+}
@@ -1,8 +1,9 @@
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
 use num_traits::FromPrimitive;
 use std::collections::HashMap;
 use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
 
 use super::*;
 
@@ -41,6 +42,14 @@ impl Header {
             domain_id: u32::from_be_bytes(buf[12..16].try_into().unwrap()),
         })
     }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+        buf.extend_from_slice(&self.export_time.to_be_bytes());
+        buf.extend_from_slice(&self.seq_number.to_be_bytes());
+        buf.extend_from_slice(&self.domain_id.to_be_bytes());
+    }
 }
 
 /******************************** SET HEADER ********************************/
@@ -71,6 +80,11 @@ impl SetHeader {
     pub fn content_size(&self) -> usize {
         self.length as usize - Self::SIZE
     }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+    }
 }
 
 /******************************** TEMPLATE HEADER ********************************/
@@ -96,11 +110,16 @@ impl TemplateHeader {
             field_count: u16::from_be_bytes(buf[2..4].try_into().unwrap()),
         })
     }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.field_count.to_be_bytes());
+    }
 }
 
 /********************************  TEMPLATE RECORD FIELD ********************************/
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TemplateField {
     /// A numeric value that represents the Information Element
     pub id: FieldType,
@@ -126,6 +145,133 @@ impl TemplateField {
             length: u16::from_be_bytes(buf[2..4].try_into().unwrap()),
         })
     }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.id as u16).to_be_bytes());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+    }
+}
+
+/******************************** DECODE PLAN ********************************/
+
+/// Length announced by a template for a variable-length Information Element (RFC 7011 section 7)
+pub const VARIABLE_LENGTH: u16 = 65535;
+
+/// Decoding instruction for one field of a data record, resolved when the template is received
+#[derive(Debug, Clone, Copy)]
+pub struct FieldDecoder {
+    pub id: FieldType,
+    /// Offset of the field from the start of the record, only meaningful when the plan has no variable-length field
+    pub offset: usize,
+    /// Length of the field in the record, VARIABLE_LENGTH if the length is carried in the record itself
+    pub length: u16,
+    decode: fn(&[u8]) -> FieldValue,
+}
+
+impl FieldDecoder {
+    fn new(field: &TemplateField, offset: usize) -> Self {
+        FieldDecoder {
+            id: field.id,
+            offset,
+            length: field.length,
+            decode: match field.length {
+                1 => |b| FieldValue::U8(b[0]),
+                2 => |b| FieldValue::U16(u16::from_be_bytes(b.try_into().unwrap())),
+                4 => |b| FieldValue::U32(u32::from_be_bytes(b.try_into().unwrap())),
+                8 => |b| FieldValue::U64(u64::from_be_bytes(b.try_into().unwrap())),
+                16 => |b| FieldValue::U128(u128::from_be_bytes(b.try_into().unwrap())),
+                _ => |b| FieldValue::Dyn(b.to_vec()),
+            },
+        }
+    }
+
+    #[inline]
+    pub fn decode(&self, buf: &[u8]) -> FieldValue {
+        (self.decode)(buf)
+    }
+
+    /// Position of the value of the field starting at offset in the record, offset is moved after it.
+    /// A variable-length field starts with its length, on 1 byte or on the 2 bytes following 255.
+    pub fn locate(&self, buf: &[u8], offset: &mut usize) -> Result<Range<usize>, String> {
+        let mut length = self.length as usize;
+
+        if self.length == VARIABLE_LENGTH {
+            length = *buf
+                .get(*offset)
+                .ok_or_else(|| format!("Not enough space in buffer to read the length of the variable-length field {:?}", self.id))? as usize;
+            *offset += 1;
+
+            if length == 255 {
+                let ext = buf
+                    .get(*offset..*offset + 2)
+                    .ok_or_else(|| format!("Not enough space in buffer to read the length of the variable-length field {:?}", self.id))?;
+                length = u16::from_be_bytes(ext.try_into().unwrap()) as usize;
+                *offset += 2;
+            }
+        }
+
+        if *offset + length > buf.len() {
+            return Err(format!(
+                "Not enough space in buffer to read the field {:?}, required {} but received {}",
+                self.id,
+                *offset + length,
+                buf.len()
+            ));
+        }
+
+        let value = *offset..*offset + length;
+        *offset += length;
+        Ok(value)
+    }
+}
+
+/// Template compiled into the list of operations needed to decode each of its data records
+#[derive(Debug)]
+pub struct DecodePlan {
+    pub fields: Vec<FieldDecoder>,
+    /// Size of a record, or its minimum size if it contains variable-length fields (1 byte each for the length prefix)
+    pub min_length: usize,
+    /// At least one field has its length encoded in the record
+    pub has_variable_length: bool,
+    /// At least one delta counter must be corrected with the sampling interval of the exporter
+    pub has_counters: bool,
+    /// At least one flow timestamp must be converted to absolute milliseconds
+    pub has_timestamps: bool,
+}
+
+impl DecodePlan {
+    pub fn compile(fields: &[TemplateField]) -> Self {
+        let mut decoders = Vec::with_capacity(fields.len());
+        let mut offset = 0;
+        let mut has_variable_length = false;
+
+        for field in fields {
+            decoders.push(FieldDecoder::new(field, offset));
+
+            if field.length == VARIABLE_LENGTH {
+                has_variable_length = true;
+                offset += 1;
+            } else {
+                offset += field.length as usize;
+            }
+        }
+
+        DecodePlan {
+            min_length: offset,
+            has_variable_length,
+            has_counters: fields.iter().any(|f| f.id.is_sampled_counter()),
+            has_timestamps: fields.iter().any(|f| f.id.is_flow_timestamp()),
+            fields: decoders,
+        }
+    }
+
+    /// A record must take at least one byte, else the records of a set can't be delimited and reading them never ends
+    pub fn check(&self, template_id: u16) -> Result<(), String> {
+        if self.min_length == 0 {
+            return Err(format!("Template {} has no field with a length, its records would be empty", template_id));
+        }
+        Ok(())
+    }
 }
 
 /******************************** DATA SET ********************************/
@@ -138,46 +284,143 @@ pub struct DataSet {
 impl DataSet {
     pub const MIN_SET_ID: u16 = 256;
 
-    pub fn read(buf: &[u8], field_list: &Vec<TemplateField>, min_size: usize) -> Result<Self, String> {
-        if buf.len() < min_size {
-            return Err(format!("Not enough space in buffer to read IPFIX DataSet, required {} but received {}", min_size, buf.len()));
+    /// Decode one data record with the plan of its template, returning the record and the number of bytes read
+    pub fn read(buf: &[u8], plan: &DecodePlan) -> Result<(Self, usize), String> {
+        if buf.len() < plan.min_length {
+            return Err(format!("Not enough space in buffer to read IPFIX DataSet, required {} but received {}", plan.min_length, buf.len()));
+        }
+
+        let mut fields = HashMap::with_capacity(plan.fields.len());
+
+        if !plan.has_variable_length {
+            for field in &plan.fields {
+                fields.insert(field.id, field.decode(&buf[field.offset..field.offset + field.length as usize]));
+            }
+
+            return Ok((DataSet { fields }, plan.min_length));
         }
 
-        let mut fields = HashMap::with_capacity(field_list.len());
         let mut offset = 0;
+        for field in &plan.fields {
+            let value = field.locate(buf, &mut offset)?;
+            fields.insert(field.id, field.decode(&buf[value]));
+        }
 
-        for field in field_list {
-            fields.insert(
-                field.id,
-                match field.length {
-                    1 => FieldValue::U8(buf[offset]),
-                    2 => FieldValue::U16(u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())),
-                    4 => FieldValue::U32(u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())),
-                    8 => FieldValue::U64(u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())),
-                    16 => FieldValue::U128(u128::from_be_bytes(buf[offset..offset + 16].try_into().unwrap())),
-                    _ => FieldValue::Dyn(buf[offset..offset + field.length as usize].to_vec()),
-                },
-            );
-            offset += field.length as usize;
+        Ok((DataSet { fields }, offset))
+    }
+
+    /// Encode the record with the fields of its template, in their order and with their length
+    pub fn write(&self, template: &[TemplateField], buf: &mut Vec<u8>) -> Result<(), String> {
+        for field in template {
+            match self.fields.get(&field.id) {
+                Some(value) => value.write(field.length, buf)?,
+                None => return Err(format!("The field {:?} of the template is missing from the record", field.id)),
+            }
         }
 
-        Ok(DataSet { fields })
+        Ok(())
+    }
+
+    /// Packets represented by each sampled packet, when the record carries its own sampling fields
+    pub fn sampling(&self) -> Option<u64> {
+        let get = |id: FieldType| self.fields.get(&id).and_then(FieldValue::as_u128).map(|v| v as u64);
+
+        if let Some(rate) = get(FieldType::SamplingInterval)
+            .filter(|&rate| rate > 0)
+            .or_else(|| get(FieldType::SamplerRandomInterval).filter(|&rate| rate > 0))
+        {
+            return Some(rate);
+        }
+        // 1 packet selected then space packets skipped (RFC 5476)
+        match (get(FieldType::SamplingPacketInterval), get(FieldType::SamplingPacketSpace)) {
+            (Some(interval), Some(space)) if interval > 0 => Some(interval.saturating_add(space) / interval),
+            _ => None,
+        }
     }
 
     pub fn add_sampling(&mut self, sampling: u64) {
         if sampling > 0 {
-            if let Some(FieldValue::U64(v)) = self.fields.get_mut(&FieldType::OctetDeltaCount) {
-                *v *= sampling
+            for (ftype, fvalue) in self.fields.iter_mut() {
+                if !ftype.is_sampled_counter() {
+                    continue;
+                }
+
+                match fvalue {
+                    FieldValue::U32(v) => *v = v.saturating_mul(sampling as u32),
+                    FieldValue::U64(v) => *v = v.saturating_mul(sampling),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Add FlowStartMilliseconds and FlowEndMilliseconds to the record when the exporter used another time representation
+    pub fn normalize_timestamps(&mut self, export_time: u32, system_init_time: Option<u64>) {
+        for (target, sources) in [
+            (
+                FieldType::FlowStartMilliseconds,
+                [
+                    FieldType::FlowStartSeconds,
+                    FieldType::FlowStartMicroseconds,
+                    FieldType::FlowStartNanoseconds,
+                    FieldType::FlowStartDeltaMicroseconds,
+                    FieldType::FlowStartSysUpTime,
+                ],
+            ),
+            (
+                FieldType::FlowEndMilliseconds,
+                [
+                    FieldType::FlowEndSeconds,
+                    FieldType::FlowEndMicroseconds,
+                    FieldType::FlowEndNanoseconds,
+                    FieldType::FlowEndDeltaMicroseconds,
+                    FieldType::FlowEndSysUpTime,
+                ],
+            ),
+        ] {
+            if self.fields.contains_key(&target) {
+                continue;
             }
 
-            if let Some(FieldValue::U64(v)) = self.fields.get_mut(&FieldType::PacketDeltaCount) {
-                *v *= sampling
+            let millis = sources.iter().find_map(|source| match (source, self.fields.get(source)?) {
+                (FieldType::FlowStartSeconds, FieldValue::U32(v)) | (FieldType::FlowEndSeconds, FieldValue::U32(v)) => Some(*v as u64 * 1000),
+                (FieldType::FlowStartMicroseconds, FieldValue::U64(v))
+                | (FieldType::FlowEndMicroseconds, FieldValue::U64(v))
+                | (FieldType::FlowStartNanoseconds, FieldValue::U64(v))
+                | (FieldType::FlowEndNanoseconds, FieldValue::U64(v)) => ntp_to_millis(*v),
+                (FieldType::FlowStartDeltaMicroseconds, FieldValue::U32(v)) | (FieldType::FlowEndDeltaMicroseconds, FieldValue::U32(v)) => (export_time as u64 * 1000).checked_sub(*v as u64 / 1000),
+                (FieldType::FlowStartSysUpTime, FieldValue::U32(v)) | (FieldType::FlowEndSysUpTime, FieldValue::U32(v)) => system_init_time.map(|init| init + *v as u64),
+                _ => None,
+            });
+
+            if let Some(millis) = millis {
+                self.fields.insert(target, FieldValue::U64(millis));
             }
         }
     }
 }
 
-impl Flow for DataSet {}
+/// Convert a dateTimeMicroseconds/dateTimeNanoseconds value (NTP timestamp format) to milliseconds since the UNIX epoch
+pub fn ntp_to_millis(ntp: u64) -> Option<u64> {
+    const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+    let secs = (ntp >> 32).checked_sub(NTP_UNIX_OFFSET)?;
+    let frac = ntp & 0xffff_ffff;
+
+    Some(secs * 1000 + ((frac * 1000) >> 32))
+}
+
+impl Flow for DataSet {
+    fn fields(&self) -> Vec<(FieldType, FieldValue)> {
+        let mut fields: Vec<_> = self.fields.iter().map(|(&ftype, fvalue)| (ftype, fvalue.clone())).collect();
+        fields.sort_by_key(|&(ftype, _)| ftype);
+        fields
+    }
+
+    fn version(&self) -> u16 {
+        VERSION
+    }
+}
 
 impl fmt::Display for DataSet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -189,6 +432,10 @@ impl fmt::Display for DataSet {
                 (FieldType::SourceIPv6Address, FieldValue::U128(v)) | (FieldType::DestinationIPv6Prefix, FieldValue::U128(v)) | (FieldType::ExporterIPv6Address, FieldValue::U128(v)) => {
                     write!(f, "{:?}: {}, ", ftype, Ipv6Addr::from(*v))?
                 }
+                (FieldType::FlowEndReason, FieldValue::U8(v)) => match EndReason::from_u8(*v) {
+                    Some(reason) => write!(f, "{:?}: {:?}, ", ftype, reason)?,
+                    None => write!(f, "{:?}: {}, ", ftype, fvalue)?,
+                },
                 _ => write!(f, "{:?}: {}, ", ftype, fvalue)?,
             }
         }
@@ -227,6 +474,12 @@ impl OptionTemplateHeader {
             scope_field_count: u16::from_be_bytes(buf[4..6].try_into().unwrap()),
         })
     }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.field_count.to_be_bytes());
+        buf.extend_from_slice(&self.scope_field_count.to_be_bytes());
+    }
 }
 
 /******************************** DATA SET TEMPLATE ********************************/
@@ -235,25 +488,47 @@ pub struct DataSetTemplate {
     pub header: TemplateHeader,
     pub fields: Vec<TemplateField>,
     pub length: usize,
+    pub plan: DecodePlan,
 }
 
 impl DataSetTemplate {
     pub const SET_ID: u16 = 2;
 
+    /// Template with the id and the fields given, to export our own data records
+    pub fn new(id: u16, fields: Vec<TemplateField>) -> Self {
+        let plan = DecodePlan::compile(&fields);
+
+        DataSetTemplate {
+            header: TemplateHeader { id, field_count: fields.len() as u16 },
+            length: plan.min_length,
+            fields,
+            plan,
+        }
+    }
+
     pub fn read(buf: &[u8]) -> Result<(Self, usize), String> {
-        let header = TemplateHeader::read(&buf)?;
+        let header = TemplateHeader::read(buf)?;
         let mut fields: Vec<TemplateField> = vec![];
         let mut offset = TemplateHeader::SIZE;
-        let mut length = 0;
 
         for _ in 0..header.field_count {
-            let field = TemplateField::read(&buf[offset..])?;
-            length += field.length as usize;
-            fields.push(field);
+            fields.push(TemplateField::read(&buf[offset..])?);
             offset += TemplateField::SIZE;
         }
 
-        Ok((DataSetTemplate { header, fields, length }, offset))
+        let plan = DecodePlan::compile(&fields);
+        plan.check(header.id)?;
+        let length = plan.min_length;
+
+        Ok((DataSetTemplate { header, fields, length, plan }, offset))
+    }
+
+    /// Encode the template record as it is sent in a template set
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        self.header.write(buf);
+        for field in &self.fields {
+            field.write(buf);
+        }
     }
 }
 
@@ -276,25 +551,51 @@ pub struct OptionDataSetTemplate {
     pub header: OptionTemplateHeader,
     pub fields: Vec<TemplateField>,
     pub length: usize,
+    pub plan: DecodePlan,
 }
 
 impl OptionDataSetTemplate {
     pub const SET_ID: u16 = 3;
 
+    /// Option template with the id and the fields given, the first scope_field_count fields being the scope
+    pub fn new(id: u16, scope_field_count: u16, fields: Vec<TemplateField>) -> Self {
+        let plan = DecodePlan::compile(&fields);
+
+        OptionDataSetTemplate {
+            header: OptionTemplateHeader {
+                id,
+                field_count: fields.len() as u16,
+                scope_field_count,
+            },
+            length: plan.min_length,
+            fields,
+            plan,
+        }
+    }
+
     pub fn read(buf: &[u8]) -> Result<(Self, usize), String> {
-        let header = OptionTemplateHeader::read(&buf)?;
+        let header = OptionTemplateHeader::read(buf)?;
         let mut fields: Vec<TemplateField> = vec![];
         let mut offset = OptionTemplateHeader::SIZE;
-        let mut length = 0;
 
         for _ in 0..header.field_count {
-            let field = TemplateField::read(&buf[offset..])?;
-            length += field.length as usize;
-            fields.push(field);
+            fields.push(TemplateField::read(&buf[offset..])?);
             offset += TemplateField::SIZE;
         }
 
-        Ok((OptionDataSetTemplate { header, fields, length }, offset))
+        let plan = DecodePlan::compile(&fields);
+        plan.check(header.id)?;
+        let length = plan.min_length;
+
+        Ok((OptionDataSetTemplate { header, fields, length, plan }, offset))
+    }
+
+    /// Encode the option template record as it is sent in an option template set
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        self.header.write(buf);
+        for field in &self.fields {
+            field.write(buf);
+        }
     }
 }
 
@@ -785,10 +1086,207 @@ pub enum FieldType {
     // 492-32767	Unassigned
 }
 
+impl FieldType {
+    /// Delta counters that have to be multiplied by the sampling interval of the exporter
+    pub fn is_sampled_counter(&self) -> bool {
+        matches!(
+            self,
+            FieldType::OctetDeltaCount | FieldType::PacketDeltaCount | FieldType::PostOctetDeltaCount | FieldType::PostPacketDeltaCount | FieldType::Layer2OctetDeltaCount
+        )
+    }
+
+    /// Field type from its IANA name (sourceIPv4Address) or its name in this enum (SourceIPv4Address), ignoring the case
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..=u16::MAX).filter_map(FieldType::from_u16).find(|t| format!("{:?}", t).eq_ignore_ascii_case(name))
+    }
+
+    /// Flow start/end timestamps that can be normalized to FlowStartMilliseconds/FlowEndMilliseconds
+    pub fn is_flow_timestamp(&self) -> bool {
+        matches!(
+            self,
+            FieldType::FlowStartSeconds
+                | FieldType::FlowEndSeconds
+                | FieldType::FlowStartMicroseconds
+                | FieldType::FlowEndMicroseconds
+                | FieldType::FlowStartNanoseconds
+                | FieldType::FlowEndNanoseconds
+                | FieldType::FlowStartDeltaMicroseconds
+                | FieldType::FlowEndDeltaMicroseconds
+                | FieldType::FlowStartSysUpTime
+                | FieldType::FlowEndSysUpTime
+        )
+    }
+
+    /// Abstract data type of the information element (RFC 7012 section 3.1), the unsigned integers of any size are Unsigned
+    pub fn data_type(&self) -> DataType {
+        use FieldType::*;
+
+        match self {
+            SourceIPv4Address
+            | DestinationIPv4Address
+            | IpNextHopIPv4Address
+            | BgpNextHopIPv4Address
+            | SourceIPv4Prefix
+            | DestinationIPv4Prefix
+            | MplsTopLabelIPv4Address
+            | ExporterIPv4Address
+            | CollectorIPv4Address
+            | PostNATSourceIPv4Address
+            | PostNATDestinationIPv4Address
+            | StaIPv4Address
+            | OriginalExporterIPv4Address
+            | PseudoWireDestinationIPv4Address => DataType::Ipv4Address,
+            SourceIPv6Address
+            | DestinationIPv6Address
+            | IpNextHopIPv6Address
+            | BgpNextHopIPv6Address
+            | ExporterIPv6Address
+            | MplsTopLabelIPv6Address
+            | DestinationIPv6Prefix
+            | SourceIPv6Prefix
+            | CollectorIPv6Address
+            | PostNATSourceIPv6Address
+            | PostNATDestinationIPv6Address
+            | OriginalExporterIPv6Address => DataType::Ipv6Address,
+            SourceMacAddress
+            | PostDestinationMacAddress
+            | DestinationMacAddress
+            | PostSourceMacAddress
+            | StaMacAddress
+            | WtpMacAddress
+            | Dot1qCustomerSourceMacAddress
+            | Dot1qCustomerDestinationMacAddress => DataType::MacAddress,
+            InterfaceName
+            | InterfaceDescription
+            | SamplerName
+            | ApplicationDescription
+            | ApplicationName
+            | ClassName
+            | WlanSSID
+            | VRFname
+            | NatPoolName
+            | P2PTechnology
+            | TunnelTechnology
+            | EncryptedTechnology
+            | ObservationDomainName
+            | SelectorName
+            | InformationElementDescription
+            | InformationElementName
+            | InformationElementUnits
+            | VirtualStationInterfaceName
+            | VirtualStationName
+            | UserName
+            | ApplicationCategoryName
+            | ApplicationSubCategoryName
+            | ApplicationGroupName
+            | MibContextName
+            | MibObjectName
+            | MibObjectDescription
+            | MibModuleName
+            | MobileIMSI
+            | MobileMSISDN
+            | HttpRequestMethod
+            | HttpRequestHost
+            | HttpRequestTarget
+            | HttpMessageVersion
+            | HttpUserAgent
+            | HttpContentType
+            | HttpReasonPhrase => DataType::String,
+            MplsTopLabelStackSection
+            | MplsLabelStackSection2
+            | MplsLabelStackSection3
+            | MplsLabelStackSection4
+            | MplsLabelStackSection5
+            | MplsLabelStackSection6
+            | MplsLabelStackSection7
+            | MplsLabelStackSection8
+            | MplsLabelStackSection9
+            | MplsLabelStackSection10
+            | MplsVpnRouteDistinguisher
+            | ApplicationId
+            | Layer2packetSectionData
+            | PaddingOctets
+            | MessageMD5Checksum
+            | OpaqueOctets
+            | CollectorCertificate
+            | ExporterCertificate
+            | IpHeaderPacketSection
+            | IpPayloadPacketSection
+            | DataLinkFrameSection
+            | MplsLabelStackSection
+            | MplsPayloadPacketSection
+            | VirtualStationInterfaceId
+            | VirtualStationUUID
+            | MibObjectValueOctetString
+            | MibObjectValueOID
+            | MibObjectValueBits
+            | MibObjectIdentifier
+            | MibContextEngineID
+            | VpnIdentifier
+            | BgpExtendedCommunity
+            | BgpLargeCommunity => DataType::OctetArray,
+            FlowStartSeconds | FlowEndSeconds | MaxExportSeconds | MaxFlowEndSeconds | MinExportSeconds | MinFlowStartSeconds | ObservationTimeSeconds => DataType::DateTimeSeconds,
+            FlowStartMilliseconds
+            | FlowEndMilliseconds
+            | SystemInitTimeMilliseconds
+            | CollectionTimeMilliseconds
+            | MaxFlowEndMilliseconds
+            | MinFlowStartMilliseconds
+            | ObservationTimeMilliseconds
+            | MonitoringIntervalStartMilliSeconds
+            | MonitoringIntervalEndMilliSeconds => DataType::DateTimeMilliseconds,
+            FlowStartMicroseconds | FlowEndMicroseconds | MaxFlowEndMicroseconds | MinFlowStartMicroseconds | ObservationTimeMicroseconds => DataType::DateTimeMicroseconds,
+            FlowStartNanoseconds | FlowEndNanoseconds | MaxFlowEndNanoseconds | MinFlowStartNanoseconds | ObservationTimeNanoseconds => DataType::DateTimeNanoseconds,
+            SamplingProbability | AbsoluteError | RelativeError | UpperCILimit | LowerCILimit | ConfidenceLevel => DataType::Float64,
+            DataRecordsReliability | Dot1qDEI | Dot1qCustomerDEI => DataType::Boolean,
+            MibObjectValueInteger => DataType::Signed,
+            BasicList
+            | SubTemplateList
+            | SubTemplateMultiList
+            | MibObjectValueTable
+            | MibObjectValueRow
+            | BgpSourceCommunityList
+            | BgpDestinationCommunityList
+            | BgpSourceExtendedCommunityList
+            | BgpDestinationExtendedCommunityList
+            | BgpSourceLargeCommunityList
+            | BgpDestinationLargeCommunityList => DataType::List,
+            _ => DataType::Unsigned,
+        }
+    }
+}
+
+/// Abstract data types of the information elements (RFC 7012 section 3.1), grouped by their representation once decoded
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataType {
+    /// unsigned8 to unsigned64
+    Unsigned,
+    /// signed8 to signed64
+    Signed,
+    /// float32 and float64
+    Float64,
+    Boolean,
+    MacAddress,
+    OctetArray,
+    String,
+    /// Seconds since the UNIX epoch
+    DateTimeSeconds,
+    /// Milliseconds since the UNIX epoch
+    DateTimeMilliseconds,
+    /// NTP timestamp
+    DateTimeMicroseconds,
+    /// NTP timestamp
+    DateTimeNanoseconds,
+    Ipv4Address,
+    Ipv6Address,
+    /// basicList, subTemplateList and subTemplateMultiList (RFC 6313), kept encoded
+    List,
+}
+
 /******************************** IPFIX FIELD VALUE ********************************/
 
 /// from http://www.iana.org/assignments/ipfix/ipfix.xml
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum FieldValue {
     U8(u8),
     U16(u16),
@@ -798,6 +1296,60 @@ pub enum FieldValue {
     Dyn(Vec<u8>),
 }
 
+impl FieldValue {
+    /// Length announced in a template for this value, VARIABLE_LENGTH for the values without a fixed size
+    pub fn length(&self) -> u16 {
+        match self {
+            FieldValue::U8(_) => 1,
+            FieldValue::U16(_) => 2,
+            FieldValue::U32(_) => 4,
+            FieldValue::U64(_) => 8,
+            FieldValue::U128(_) => 16,
+            FieldValue::Dyn(_) => VARIABLE_LENGTH,
+        }
+    }
+
+    /// Value as an unsigned integer, None for the values without a fixed size
+    pub fn as_u128(&self) -> Option<u128> {
+        match *self {
+            FieldValue::U8(v) => Some(v as u128),
+            FieldValue::U16(v) => Some(v as u128),
+            FieldValue::U32(v) => Some(v as u128),
+            FieldValue::U64(v) => Some(v as u128),
+            FieldValue::U128(v) => Some(v),
+            FieldValue::Dyn(_) => None,
+        }
+    }
+
+    /// Encode the value on length bytes, an integer can use a reduced size encoding (RFC 7011 section 6.2)
+    pub fn write(&self, length: u16, buf: &mut Vec<u8>) -> Result<(), String> {
+        match (self, length) {
+            (FieldValue::Dyn(v), VARIABLE_LENGTH) => {
+                // the length is encoded on 1 byte, or on the 2 following bytes after 255
+                if v.len() < 255 {
+                    buf.push(v.len() as u8);
+                } else {
+                    let length = u16::try_from(v.len()).map_err(|_| format!("Variable-length value of {} bytes too big", v.len()))?;
+                    buf.push(255);
+                    buf.extend_from_slice(&length.to_be_bytes());
+                }
+                buf.extend_from_slice(v);
+            }
+            (FieldValue::Dyn(v), length) if v.len() == length as usize => buf.extend_from_slice(v),
+            (value, length @ 1..=16) => {
+                let v = value.as_u128().ok_or_else(|| format!("Can't encode {:?} on {} bytes", value, length))?;
+                if length < 16 && v >> (length * 8) != 0 {
+                    return Err(format!("The value {} doesn't fit in {} bytes", v, length));
+                }
+                buf.extend_from_slice(&v.to_be_bytes()[16 - length as usize..]);
+            }
+            (value, length) => return Err(format!("Can't encode {:?} on {} bytes", value, length)),
+        }
+
+        Ok(())
+    }
+}
+
 impl fmt::Display for FieldValue {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -816,17 +1368,19 @@ impl fmt::Display for FieldValue {
 #[derive(FromPrimitive, PartialEq, Debug)]
 #[repr(u8)]
 pub enum EndReason {
-    IDLETIMEOUT = 1,
-    ACTIVETIMEOUT = 2,
-    ENDOFFLOWDETECTED = 3,
-    FORCEDEND = 4,
-    LACKOFRESOURCES = 5,
+    IdleTimeout = 1,
+    ActiveTimeout = 2,
+    EndOfFlowDetected = 3,
+    ForcedEnd = 4,
+    LackOfResources = 5,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use hex_literal::hex;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
 
     const HEADER_PAYLOD: [u8; Header::SIZE] = hex!("00 0a 00 84 60 6c 55 89 df b2 ba d2 00 08 00 00");
 
@@ -897,6 +1451,7 @@ mod tests {
     }
 
     #[test]
+    #[rustfmt::skip]
     fn read_template() {
         let (template, size_read) = DataSetTemplate::read(&TEMPLATE_PAYLOAD).unwrap();
 
@@ -905,8 +1460,6 @@ mod tests {
         assert_eq!(size_read, TEMPLATE_PAYLOAD.len());
         assert_eq!(template.fields.len(), template.header.field_count as usize);
 
-        #[cfg_attr(rustfmt, rustfmt::skip)]
-        {
         assert_eq!(template.fields[0], TemplateField {id: FieldType::SourceIPv4Address, length: 4});
         assert_eq!(template.fields[1], TemplateField {id: FieldType::DestinationIPv4Address, length: 4});
         assert_eq!(template.fields[2], TemplateField {id: FieldType::IPClassOfService, length: 1});
@@ -934,7 +1487,6 @@ mod tests {
         assert_eq!(template.fields[24], TemplateField {id: FieldType::Dot1qVlanId, length: 2});
         assert_eq!(template.fields[25], TemplateField {id: FieldType::Dot1qCustomerVlanId, length: 2});
         assert_eq!(template.fields[26], TemplateField {id: FieldType::FragmentIdentification, length: 4});
-        }
     }
 
     #[test]
@@ -944,6 +1496,7 @@ mod tests {
     }
 
     #[test]
+    #[rustfmt::skip]
     fn read_option_template() {
         let (template, size_read) = OptionDataSetTemplate::read(&OPTION_TEMPLATE_PAYLOAD).unwrap();
 
@@ -954,8 +1507,6 @@ mod tests {
         assert_eq!(size_read, OPTION_TEMPLATE_PAYLOAD.len());
         assert_eq!(template.fields.len(), template.header.field_count as usize);
 
-        #[cfg_attr(rustfmt, rustfmt::skip)]
-        {
         assert_eq!(template.fields[0], TemplateField {id: FieldType::ExportingProcessId, length: 4});
         assert_eq!(template.fields[1], TemplateField {id: FieldType::ExportedMessageTotalCount, length: 8});
         assert_eq!(template.fields[2], TemplateField {id: FieldType::ExportedFlowRecordTotalCount, length: 8});
@@ -967,7 +1518,6 @@ mod tests {
         assert_eq!(template.fields[8], TemplateField {id: FieldType::FlowIdleTimeout, length: 2});
         assert_eq!(template.fields[9], TemplateField {id: FieldType::ExportProtocolVersion, length: 1});
         assert_eq!(template.fields[10], TemplateField {id: FieldType::ExportTransportProtocol, length: 1});   
-        }
     }
 
     #[test]
@@ -976,10 +1526,167 @@ mod tests {
         OptionDataSetTemplate::read(&OPTION_TEMPLATE_PAYLOAD[0..OPTION_TEMPLATE_PAYLOAD.len() - 1]).unwrap();
     }
 
+    #[test]
+    fn read_template_with_empty_records() {
+        // no field, then only a zero-length octetDeltaCount
+        assert!(DataSetTemplate::read(&hex!("01 00 00 00")).is_err());
+        assert!(DataSetTemplate::read(&hex!("01 00 00 01 00 01 00 00")).is_err());
+        assert!(OptionDataSetTemplate::read(&hex!("01 01 00 01 00 01 00 01 00 00")).is_err());
+
+        // a variable-length field takes at least its length byte
+        assert!(DataSetTemplate::read(&hex!("01 00 00 01 00 52 ff ff")).is_ok());
+    }
+
+    #[test]
+    fn write_dataset() {
+        let (template, _) = DataSetTemplate::read(&TEMPLATE_PAYLOAD).unwrap();
+        let (msg, _) = DataSet::read(&DATASET, &template.plan).unwrap();
+
+        let mut buf = vec![];
+        msg.write(&template.fields, &mut buf).unwrap();
+        assert_eq!(buf, DATASET);
+    }
+
+    #[test]
+    fn write_field_values() {
+        let mut buf = vec![];
+        FieldValue::U64(0x0102).write(2, &mut buf).unwrap();
+        FieldValue::U8(7).write(4, &mut buf).unwrap();
+        FieldValue::Dyn(vec![1, 2, 3]).write(VARIABLE_LENGTH, &mut buf).unwrap();
+        FieldValue::Dyn(vec![9; 300]).write(VARIABLE_LENGTH, &mut buf).unwrap();
+
+        assert_eq!(&buf[0..10], &hex!("01 02 00 00 00 07 03 01 02 03"));
+        assert_eq!(&buf[10..13], &hex!("ff 01 2c"));
+        assert_eq!(buf.len(), 13 + 300);
+
+        assert!(FieldValue::U32(0x10000).write(2, &mut buf).is_err());
+        assert!(FieldValue::Dyn(vec![1]).write(4, &mut buf).is_err());
+    }
+
+    #[test]
+    fn write_headers() {
+        let mut buf = vec![];
+        Header::read(&HEADER_PAYLOD).unwrap().write(&mut buf);
+        SetHeader::read(&SET_HEADER_PAYLOAD).unwrap().write(&mut buf);
+
+        assert_eq!(&buf[0..Header::SIZE], &HEADER_PAYLOD);
+        assert_eq!(&buf[Header::SIZE..], &SET_HEADER_PAYLOAD);
+    }
+
+    #[test]
+    fn write_templates() {
+        let mut buf = vec![];
+        DataSetTemplate::read(&TEMPLATE_PAYLOAD).unwrap().0.write(&mut buf);
+        assert_eq!(buf, TEMPLATE_PAYLOAD);
+
+        let mut buf = vec![];
+        OptionDataSetTemplate::read(&OPTION_TEMPLATE_PAYLOAD).unwrap().0.write(&mut buf);
+        assert_eq!(buf, OPTION_TEMPLATE_PAYLOAD);
+
+        let (template, _) = DataSetTemplate::read(&TEMPLATE_PAYLOAD).unwrap();
+        let length = template.length;
+        let template = DataSetTemplate::new(256, template.fields);
+        assert_eq!(template.length, length);
+        let mut buf = vec![];
+        template.write(&mut buf);
+        assert_eq!(buf, TEMPLATE_PAYLOAD);
+    }
+
+    #[test]
+    fn compile_template_plan() {
+        let (template, _) = DataSetTemplate::read(&TEMPLATE_PAYLOAD).unwrap();
+        let plan = &template.plan;
+
+        assert_eq!(plan.fields.len(), template.fields.len());
+        assert_eq!(plan.min_length, DATASET.len());
+        assert_eq!(plan.fields[1].offset, 4);
+        assert_eq!(plan.fields[16].id, FieldType::OctetDeltaCount);
+        assert_eq!(plan.fields[16].offset, 41);
+        assert!(!plan.has_variable_length);
+        assert!(plan.has_counters);
+        assert!(!plan.has_timestamps); // timestamps are already in milliseconds
+    }
+
+    #[test]
+    fn read_dataset_with_variable_length_fields() {
+        let fields = vec![
+            TemplateField {
+                id: FieldType::FlowStartSeconds,
+                length: 4,
+            },
+            TemplateField {
+                id: FieldType::InterfaceName,
+                length: VARIABLE_LENGTH,
+            },
+            TemplateField {
+                id: FieldType::OctetDeltaCount,
+                length: 8,
+            },
+        ];
+        let plan = DecodePlan::compile(&fields);
+
+        assert!(plan.has_variable_length);
+        assert!(plan.has_timestamps);
+        assert_eq!(plan.min_length, 13);
+
+        let buf = hex!("60 6c 55 89 03 65 74 30 00 00 00 00 00 00 00 2a ff");
+        let (msg, size_read) = DataSet::read(&buf, &plan).unwrap();
+
+        assert_eq!(size_read, buf.len() - 1);
+        assert_eq!(msg.fields.get(&FieldType::InterfaceName), Some(&FieldValue::Dyn(b"et0".to_vec())));
+        assert_eq!(msg.fields.get(&FieldType::OctetDeltaCount), Some(&FieldValue::U64(42)));
+
+        // the length announced for the variable field overflows the record
+        assert!(DataSet::read(&hex!("60 6c 55 89 20 65 74 30 00 00 00 00 00 00 00 2a"), &plan).is_err());
+    }
+
+    #[test]
+    fn sampling_of_the_record() {
+        let mut fields = HashMap::new();
+        fields.insert(FieldType::SamplingPacketInterval, FieldValue::U32(1));
+        fields.insert(FieldType::SamplingPacketSpace, FieldValue::U32(99));
+        let mut msg = DataSet { fields };
+        assert_eq!(msg.sampling(), Some(100));
+
+        msg.fields.insert(FieldType::SamplerRandomInterval, FieldValue::U16(1000));
+        assert_eq!(msg.sampling(), Some(1000));
+        msg.fields.insert(FieldType::SamplingInterval, FieldValue::U32(0));
+        assert_eq!(msg.sampling(), Some(1000));
+        assert_eq!(DataSet { fields: HashMap::new() }.sampling(), None);
+    }
+
+    #[test]
+    fn normalize_timestamps() {
+        let mut fields = HashMap::new();
+        fields.insert(FieldType::FlowStartSeconds, FieldValue::U32(1617712433));
+        fields.insert(FieldType::FlowEndSysUpTime, FieldValue::U32(90_000));
+        let mut msg = DataSet { fields };
+
+        msg.normalize_timestamps(1617712521, Some(1617712433000));
+
+        assert_eq!(msg.fields.get(&FieldType::FlowStartMilliseconds), Some(&FieldValue::U64(1617712433000)));
+        assert_eq!(msg.fields.get(&FieldType::FlowEndMilliseconds), Some(&FieldValue::U64(1617712523000)));
+    }
+
+    #[test]
+    fn normalize_timestamps_without_system_init_time() {
+        let mut fields = HashMap::new();
+        fields.insert(FieldType::FlowStartSysUpTime, FieldValue::U32(1000));
+        fields.insert(FieldType::FlowEndDeltaMicroseconds, FieldValue::U32(2_000_000));
+        let mut msg = DataSet { fields };
+
+        msg.normalize_timestamps(1617712521, None);
+
+        assert_eq!(msg.fields.get(&FieldType::FlowStartMilliseconds), None);
+        assert_eq!(msg.fields.get(&FieldType::FlowEndMilliseconds), Some(&FieldValue::U64(1617712519000)));
+    }
+
     #[test]
     fn readd_dataset() {
         let (template, _) = DataSetTemplate::read(&TEMPLATE_PAYLOAD).unwrap();
-        let msg = DataSet::read(&DATASET, &template.fields, template.length).unwrap();
+        let (msg, size_read) = DataSet::read(&DATASET, &template.plan).unwrap();
+
+        assert_eq!(size_read, DATASET.len());
 
         assert_eq!(msg.fields.len(), template.fields.len());
         assert_eq!(msg.fields.get(&FieldType::SourceIPv4Address), Some(&FieldValue::U32(u32::from(Ipv4Addr::new(195, 5, 237, 90)))));
@@ -1015,13 +1722,13 @@ mod tests {
     #[should_panic]
     fn read_invalid_dataset() {
         let (template, _) = DataSetTemplate::read(&TEMPLATE_PAYLOAD).unwrap();
-        DataSet::read(&DATASET[0..DATASET.len() - 1], &template.fields, template.length).unwrap();
+        DataSet::read(&DATASET[0..DATASET.len() - 1], &template.plan).unwrap();
     }
 
     #[test]
     fn read_option_dataset() {
         let (template, _) = OptionDataSetTemplate::read(&OPTION_TEMPLATE_PAYLOAD).unwrap();
-        let msg = DataSet::read(&OPTION_DATASET, &template.fields, template.length).unwrap();
+        let (msg, _) = DataSet::read(&OPTION_DATASET, &template.plan).unwrap();
 
         assert_eq!(msg.fields.len(), template.fields.len());
 
@@ -1042,6 +1749,121 @@ mod tests {
     #[should_panic]
     fn read_invalid_option_dataset() {
         let (template, _) = OptionDataSetTemplate::read(&TEMPLATE_PAYLOAD).unwrap();
-        DataSet::read(&OPTION_DATASET[0..OPTION_DATASET.len() - 1], &template.fields, template.length).unwrap();
+        DataSet::read(&OPTION_DATASET[0..OPTION_DATASET.len() - 1], &template.plan).unwrap();
+    }
+
+    /******************************** PROPERTY TESTS ********************************/
+
+    /// Any field type, with the length of every FieldValue width, an odd fixed length or a variable length
+    fn template_field() -> impl Strategy<Value = TemplateField> {
+        (
+            (1u16..500).prop_filter_map("unassigned field id", FieldType::from_u16),
+            prop_oneof![Just(1u16), Just(2), Just(4), Just(8), Just(16), 3u16..32, Just(VARIABLE_LENGTH)],
+        )
+            .prop_map(|(id, length)| TemplateField { id, length })
+    }
+
+    /// Fields with distinct ids, a record keeps its values by field id
+    fn template_fields() -> impl Strategy<Value = Vec<TemplateField>> {
+        prop::collection::vec(template_field(), 1..32).prop_map(|mut fields| {
+            let mut ids = HashSet::new();
+            fields.retain(|field| ids.insert(field.id));
+            fields
+        })
+    }
+
+    /// Value decoded from a field of this length
+    fn field_value(length: u16) -> BoxedStrategy<FieldValue> {
+        match length {
+            1 => any::<u8>().prop_map(FieldValue::U8).boxed(),
+            2 => any::<u16>().prop_map(FieldValue::U16).boxed(),
+            4 => any::<u32>().prop_map(FieldValue::U32).boxed(),
+            8 => any::<u64>().prop_map(FieldValue::U64).boxed(),
+            16 => any::<u128>().prop_map(FieldValue::U128).boxed(),
+            VARIABLE_LENGTH => prop::collection::vec(any::<u8>(), 0..300).prop_map(FieldValue::Dyn).boxed(),
+            length => prop::collection::vec(any::<u8>(), length as usize).prop_map(FieldValue::Dyn).boxed(),
+        }
+    }
+
+    fn record(fields: &[TemplateField]) -> impl Strategy<Value = DataSet> {
+        fields
+            .iter()
+            .map(|field| {
+                let id = field.id;
+                field_value(field.length).prop_map(move |value| (id, value))
+            })
+            .collect::<Vec<_>>()
+            .prop_map(|values| DataSet { fields: values.into_iter().collect() })
+    }
+
+    fn template_and_records() -> impl Strategy<Value = (Vec<TemplateField>, Vec<DataSet>)> {
+        template_fields().prop_flat_map(|fields| {
+            let records = prop::collection::vec(record(&fields), 1..10);
+            (Just(fields), records)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn headers_round_trip(length: u16, export_time: u32, seq_number: u32, domain_id: u32, set_id: u16, set_length: u16) {
+            let mut buf = vec![];
+            Header { version: VERSION, length, export_time, seq_number, domain_id }.write(&mut buf);
+            SetHeader { id: set_id, length: set_length }.write(&mut buf);
+
+            let header = Header::read(&buf).unwrap();
+            prop_assert_eq!((header.length, header.export_time, header.seq_number, header.domain_id), (length, export_time, seq_number, domain_id));
+            let set = SetHeader::read(&buf[Header::SIZE..]).unwrap();
+            prop_assert_eq!((set.id, set.length), (set_id, set_length));
+        }
+
+        #[test]
+        fn template_round_trip(id in 256u16.., fields in template_fields()) {
+            let mut buf = vec![];
+            DataSetTemplate::new(id, fields.clone()).write(&mut buf);
+
+            let (template, size_read) = DataSetTemplate::read(&buf).unwrap();
+            prop_assert_eq!(size_read, buf.len());
+            prop_assert_eq!(template.header.id, id);
+            prop_assert_eq!(template.fields, fields);
+        }
+
+        #[test]
+        fn option_template_round_trip(id in 256u16.., fields in template_fields(), scope in 1usize..32) {
+            let template = OptionDataSetTemplate::new(id, scope.min(fields.len()) as u16, fields);
+            let mut buf = vec![];
+            template.write(&mut buf);
+
+            let (read, size_read) = OptionDataSetTemplate::read(&buf).unwrap();
+            prop_assert_eq!(size_read, buf.len());
+            prop_assert_eq!(read.header.scope_field_count, template.header.scope_field_count);
+            prop_assert_eq!(read.fields, template.fields);
+        }
+
+        #[test]
+        fn records_round_trip((fields, records) in template_and_records()) {
+            let template = DataSetTemplate::new(256, fields);
+            let mut buf = vec![];
+            for record in &records {
+                record.write(&template.fields, &mut buf).unwrap();
+            }
+
+            let mut offset = 0;
+            for record in &records {
+                let (read, size_read) = DataSet::read(&buf[offset..], &template.plan).unwrap();
+                prop_assert_eq!(&read.fields, &record.fields);
+                offset += size_read;
+            }
+            prop_assert_eq!(offset, buf.len());
+        }
+
+        #[test]
+        fn reduced_size_encoding(value: u64, length in 1u16..=8) {
+            let value = value >> (64 - 8 * length as u32);
+            let mut buf = vec![];
+            FieldValue::U64(value).write(length, &mut buf).unwrap();
+
+            prop_assert_eq!(buf.len(), length as usize);
+            prop_assert_eq!(buf.iter().fold(0u64, |acc, &b| acc << 8 | b as u64), value);
+        }
     }
 }
@@ -1,12 +1,66 @@
 use std::fmt::Display;
+use std::net::IpAddr;
+use std::sync::Arc;
 
 pub mod ipfix;
 pub mod netflow5;
 
 // common structure for each netflow data message
-pub trait Flow: Send + Display {}
+pub trait Flow: Send + Display {
+    /// Every information element of the record, sorted by field id. The netflow v5 records are mapped to their IPFIX equivalent
+    fn fields(&self) -> Vec<(ipfix::FieldType, ipfix::FieldValue)>;
+
+    /// Version of the export protocol the record was received with, 5 for NetFlow v5 and 10 for IPFIX
+    fn version(&self) -> u16;
+}
 
 pub enum Template {
     IpfixDataSet(ipfix::DataSetTemplate),
     IpfixOptionDataSet(ipfix::OptionDataSetTemplate),
 }
+
+impl Template {
+    /// Id of the set carrying this kind of template
+    pub fn set_id(&self) -> u16 {
+        match self {
+            Template::IpfixDataSet(_) => ipfix::DataSetTemplate::SET_ID,
+            Template::IpfixOptionDataSet(_) => ipfix::OptionDataSetTemplate::SET_ID,
+        }
+    }
+
+    /// Template id, referenced by the data sets
+    pub fn id(&self) -> u16 {
+        match self {
+            Template::IpfixDataSet(t) => t.header.id,
+            Template::IpfixOptionDataSet(t) => t.header.id,
+        }
+    }
+
+    /// Read a template record of the set set_id, returning the template and the number of bytes read
+    pub fn read(set_id: u16, buf: &[u8]) -> Result<(Self, usize), String> {
+        match set_id {
+            ipfix::DataSetTemplate::SET_ID => ipfix::DataSetTemplate::read(buf).map(|(t, size)| (Template::IpfixDataSet(t), size)),
+            ipfix::OptionDataSetTemplate::SET_ID => ipfix::OptionDataSetTemplate::read(buf).map(|(t, size)| (Template::IpfixOptionDataSet(t), size)),
+            _ => Err(format!("No template carried by the set {}", set_id)),
+        }
+    }
+
+    /// Encode the template record, without its set header
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        match self {
+            Template::IpfixDataSet(t) => t.write(buf),
+            Template::IpfixOptionDataSet(t) => t.write(buf),
+        }
+    }
+}
+
+/// Flows decoded from one message, with the context in which they were received
+pub struct FlowBatch {
+    /// Tag of the listener that received the message
+    pub tag: Option<Arc<str>>,
+    /// Address of the exporter that sent the message, or the one it reported or configured for it
+    pub exporter: IpAddr,
+    /// Name given to the exporter in the configuration
+    pub exporter_name: Option<Arc<str>>,
+    pub flows: Vec<Box<dyn Flow>>,
+}
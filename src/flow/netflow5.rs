@@ -2,6 +2,7 @@ use core::convert::TryInto;
 use std::fmt;
 use std::net::Ipv4Addr;
 
+use super::ipfix::{FieldType, FieldValue};
 use super::*;
 
 pub const VERSION: u16 = 5;
@@ -28,7 +29,7 @@ pub struct Header {
     /// Slot number of the flow-switching engine
     pub engine_id: u8,
     /// First two bits hold the sampling mode; remaining 14 bits hold value of sampling interval
-    sampl: u16,
+    pub sampl: u16,
 }
 
 impl Header {
@@ -52,6 +53,18 @@ impl Header {
         })
     }
 
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.count.to_be_bytes());
+        buf.extend_from_slice(&self.uptime.to_be_bytes());
+        buf.extend_from_slice(&self.unix_secs.to_be_bytes());
+        buf.extend_from_slice(&self.unix_nsecs.to_be_bytes());
+        buf.extend_from_slice(&self.seq_number.to_be_bytes());
+        buf.push(self.engine_type);
+        buf.push(self.engine_id);
+        buf.extend_from_slice(&self.sampl.to_be_bytes());
+    }
+
     #[inline]
     pub fn sampl_mode(&self) -> u16 {
         self.sampl >> 14
@@ -85,7 +98,7 @@ impl fmt::Display for Header {
 /******************************** DATA ********************************/
 
 // from https://www.cisco.com/c/en/us/td/docs/net_mgmt/netflow_collection_engine/3-6/user/guide/format.html#wp1006186
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct DataSet {
     /// Source IP address
     pub src_addr: u32,
@@ -110,7 +123,7 @@ pub struct DataSet {
     /// TCP/UDP destination port number or equivalent
     pub dst_port: u16,
     /// Unused (zero) bytes
-    pad1: u8,
+    pub pad1: u8,
     /// Cumulative OR of TCP flags        
     pub tcp_flag: u8,
     /// IP protocol type (for example, TCP = 6; UDP = 17)
@@ -126,10 +139,52 @@ pub struct DataSet {
     /// Destination address prefix mask bits
     pub dst_mask: u8,
     /// Unused (zero) bytes
-    pad2: u16,
+    pub pad2: u16,
+    /// Boot time of the exporter in ms since the UNIX epoch, from the header of the message
+    pub system_init_time: Option<u64>,
 }
 
-impl Flow for DataSet {}
+impl Flow for DataSet {
+    fn fields(&self) -> Vec<(FieldType, FieldValue)> {
+        let mut fields = vec![
+            (FieldType::OctetDeltaCount, FieldValue::U64(self.octets as u64)),
+            (FieldType::PacketDeltaCount, FieldValue::U64(self.packets as u64)),
+            (FieldType::ProtocolIdentifier, FieldValue::U8(self.protocol)),
+            (FieldType::IPClassOfService, FieldValue::U8(self.tos)),
+            (FieldType::TcpControlBits, FieldValue::U8(self.tcp_flag)),
+            (FieldType::SourceTransportPort, FieldValue::U16(self.src_port)),
+            (FieldType::SourceIPv4Address, FieldValue::U32(self.src_addr)),
+            (FieldType::SourceIPv4PrefixLength, FieldValue::U8(self.src_mask)),
+            (FieldType::IngressInterface, FieldValue::U32(self.input_int as u32)),
+            (FieldType::DestinationTransportPort, FieldValue::U16(self.dst_port)),
+            (FieldType::DestinationIPv4Address, FieldValue::U32(self.dst_addr)),
+            (FieldType::DestinationIPv4PrefixLength, FieldValue::U8(self.dst_mask)),
+            (FieldType::EgressInterface, FieldValue::U32(self.output_int as u32)),
+            (FieldType::IpNextHopIPv4Address, FieldValue::U32(self.next_hop)),
+            (FieldType::BgpSourceAsNumber, FieldValue::U32(self.src_as as u32)),
+            (FieldType::BgpDestinationAsNumber, FieldValue::U32(self.dst_as as u32)),
+        ];
+
+        // absolute timestamps when the boot time of the exporter is known
+        match self.system_init_time {
+            Some(init) => fields.extend([
+                (FieldType::FlowStartMilliseconds, FieldValue::U64(init + self.start_time as u64)),
+                (FieldType::FlowEndMilliseconds, FieldValue::U64(init + self.end_time as u64)),
+            ]),
+            None => fields.extend([
+                (FieldType::FlowEndSysUpTime, FieldValue::U32(self.end_time)),
+                (FieldType::FlowStartSysUpTime, FieldValue::U32(self.start_time)),
+            ]),
+        }
+
+        fields.sort_by_key(|&(ftype, _)| ftype);
+        fields
+    }
+
+    fn version(&self) -> u16 {
+        VERSION
+    }
+}
 
 impl fmt::Display for DataSet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -182,14 +237,44 @@ impl DataSet {
             src_mask: buf[44],
             dst_mask: buf[45],
             pad2: u16::from_be_bytes(buf[46..48].try_into().unwrap()),
+            system_init_time: None,
         })
     }
 
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.src_addr.to_be_bytes());
+        buf.extend_from_slice(&self.dst_addr.to_be_bytes());
+        buf.extend_from_slice(&self.next_hop.to_be_bytes());
+        buf.extend_from_slice(&self.input_int.to_be_bytes());
+        buf.extend_from_slice(&self.output_int.to_be_bytes());
+        buf.extend_from_slice(&self.packets.to_be_bytes());
+        buf.extend_from_slice(&self.octets.to_be_bytes());
+        buf.extend_from_slice(&self.start_time.to_be_bytes());
+        buf.extend_from_slice(&self.end_time.to_be_bytes());
+        buf.extend_from_slice(&self.src_port.to_be_bytes());
+        buf.extend_from_slice(&self.dst_port.to_be_bytes());
+        buf.push(self.pad1);
+        buf.push(self.tcp_flag);
+        buf.push(self.protocol);
+        buf.push(self.tos);
+        buf.extend_from_slice(&self.src_as.to_be_bytes());
+        buf.extend_from_slice(&self.dst_as.to_be_bytes());
+        buf.push(self.src_mask);
+        buf.push(self.dst_mask);
+        buf.extend_from_slice(&self.pad2.to_be_bytes());
+    }
+
     #[inline]
     pub fn duration(&self) -> u32 {
         self.end_time - self.start_time
     }
 
+    /// Keep the boot time of the exporter, to convert the SysUptime of the flow to absolute timestamps
+    pub fn set_system_init_time(&mut self, header: &Header) {
+        let now = header.unix_secs as u64 * 1000 + header.unix_nsecs as u64 / 1_000_000;
+        self.system_init_time = now.checked_sub(header.uptime as u64);
+    }
+
     pub fn add_sampling(&mut self, sampling: u32) {
         if sampling > 0 {
             self.octets *= sampling;
@@ -202,6 +287,7 @@ impl DataSet {
 mod tests {
     use super::*;
     use hex_literal::hex;
+    use proptest::prelude::*;
 
     const HEADER_PAYLOD: [u8; Header::SIZE] = hex!(
         "00 05 00 10 00 00 04 b2 60 80 b8 9c 1a 47 ff 30
@@ -268,6 +354,36 @@ mod tests {
         DataSet::read(&DATA_SET_PAYLOD[0..DataSet::SIZE - 1]).unwrap();
     }
 
+    #[test]
+    fn write_header_and_dataset() {
+        let mut buf = vec![];
+        Header::read(&HEADER_PAYLOD).unwrap().write(&mut buf);
+        DataSet::read(&DATA_SET_PAYLOD).unwrap().write(&mut buf);
+
+        assert_eq!(&buf[0..Header::SIZE], &HEADER_PAYLOD);
+        assert_eq!(&buf[Header::SIZE..], &DATA_SET_PAYLOD);
+    }
+
+    fn get(msg: &DataSet, field: FieldType) -> Option<FieldValue> {
+        msg.fields().into_iter().find(|&(ftype, _)| ftype == field).map(|(_, fvalue)| fvalue)
+    }
+
+    #[test]
+    fn map_to_ipfix_fields() {
+        let mut msg = DataSet::read(&DATA_SET_PAYLOD).unwrap();
+        assert_eq!(get(&msg, FieldType::FlowStartSysUpTime), Some(FieldValue::U32(566)));
+        assert_eq!(get(&msg, FieldType::FlowStartMilliseconds), None);
+
+        msg.set_system_init_time(&Header::read(&HEADER_PAYLOD).unwrap());
+        assert_eq!(get(&msg, FieldType::FlowStartMilliseconds), Some(FieldValue::U64(1619048604440 - 1202 + 566)));
+        assert_eq!(get(&msg, FieldType::SourceIPv4Address), Some(FieldValue::U32(u32::from(Ipv4Addr::new(112, 10, 20, 10)))));
+        assert_eq!(get(&msg, FieldType::BgpSourceAsNumber), Some(FieldValue::U32(49933)));
+
+        let fields = msg.fields();
+        assert_eq!(fields.len(), 18);
+        assert!(fields.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
     #[test]
     fn check_invalid_sampling() {
         let mut msg = DataSet::read(&DATA_SET_PAYLOD).unwrap();
@@ -287,4 +403,23 @@ mod tests {
         assert_eq!(msg.packets, 795 * sampling);
         assert_eq!(msg.octets, 259 * sampling);
     }
+
+    proptest! {
+        #[test]
+        fn header_and_dataset_round_trip(header in prop::array::uniform24(any::<u8>()), records in prop::collection::vec(prop::array::uniform32(any::<u8>()), 1..30), tails in prop::collection::vec(prop::array::uniform16(any::<u8>()), 30)) {
+            // every byte of both structures is kept, the pad bytes included
+            let mut msg = header.to_vec();
+            for (record, tail) in records.iter().zip(&tails) {
+                msg.extend_from_slice(record);
+                msg.extend_from_slice(tail);
+            }
+
+            let mut buf = vec![];
+            Header::read(&msg).unwrap().write(&mut buf);
+            for offset in (Header::SIZE..msg.len()).step_by(DataSet::SIZE) {
+                DataSet::read(&msg[offset..]).unwrap().write(&mut buf);
+            }
+            prop_assert_eq!(buf, msg);
+        }
+    }
 }
@@ -0,0 +1,17 @@
+//! NetFlow v5 and IPFIX collector: the wire format of the messages, the listener threads decoding them and the sinks receiving the flows.
+//! Shared by the collector, the flowgen traffic generator and the integration tests.
+
+#[cfg(test)]
+#[macro_use]
+extern crate pretty_assertions;
+
+#[macro_use]
+extern crate num_derive;
+
+pub mod config;
+pub mod dump;
+pub mod flow;
+pub mod metrics;
+pub mod pcap;
+pub mod sinks;
+pub mod threads;
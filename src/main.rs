@@ -1,65 +1,409 @@
-use log::info;
+use config::{Config, ForwardConfig, ListenerConfig, SharedConfig, StateConfig, TemplatesConfig};
+use ipfix::dump::{self, Dumper};
+use ipfix::sinks::SinkConfig;
+use ipfix::{config, threads};
 use log::LevelFilter;
+use log::{error, info, warn};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
 use std::net::SocketAddr;
-use std::sync::mpsc::channel;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
-
-#[cfg(test)]
-#[macro_use]
-extern crate pretty_assertions;
-
-#[macro_use]
-extern crate num_derive;
-
-mod flow;
-mod threads;
+use threads::channel::{self, OverflowPolicy};
+use threads::listener::ListenerSpec;
+use threads::replay::ReplayOptions;
+use threads::shutdown::{Shutdown, POLL_INTERVAL};
+use threads::state::StateStore;
 
 #[derive(Debug, StructOpt)]
 struct Opts {
+    /// TOML configuration file, reloaded on SIGHUP. The other options are ignored when it is set, except the --pcap ones.
+    #[structopt(short = "-c", long = "--config")]
+    config: Option<PathBuf>,
+
     /// Log level to use
     #[structopt(long = "-log", default_value = "Info")]
     log_level: LevelFilter,
 
-    /// IP:port for the UDP listener
-    #[structopt(short = "-l", long = "--listener", default_value = "127.0.0.1:4739")]
-    listener: SocketAddr,
+    /// Listener definition as [udp|tcp]://IP:port[?versions=5,10&tag=name], can be repeated
+    #[structopt(short = "-l", long = "--listener", default_value = "127.0.0.1:4739", number_of_values = 1)]
+    listeners: Vec<ListenerSpec>,
+
+    /// Size in bytes of the kernel receive buffer of the UDP listener, the system default is kept if not set
+    #[structopt(long = "--rcvbuf")]
+    recv_buffer_size: Option<usize>,
+
+    /// Number of threads sharing each UDP listener port with SO_REUSEPORT
+    #[structopt(long = "--workers", default_value = "1")]
+    workers: usize,
+
+    /// Maximum number of datagrams read per syscall (recvmmsg, linux only)
+    #[structopt(long = "--batch-size", default_value = "1")]
+    batch_size: usize,
+
+    /// Maximum number of flow batches waiting for the exporter thread
+    #[structopt(long = "--queue-size", default_value = "1024")]
+    queue_size: usize,
+
+    /// What to do when the exporter queue is full: block, drop-newest or drop-oldest
+    #[structopt(long = "--overflow-policy", default_value = "block")]
+    overflow_policy: OverflowPolicy,
 
     /// IP:port for the prometheus exporter
     #[structopt(short = "-e", long = "--exporter")]
     exporter: Option<SocketAddr>,
+
+    /// IP:port of a downstream collector receiving a copy of every datagram, can be repeated
+    #[structopt(long = "--forward", number_of_values = 1)]
+    forward: Vec<SocketAddr>,
+
+    /// File where the templates are saved on shutdown and every 5 minutes, to decode the flows right after a restart
+    #[structopt(long = "--state")]
+    state: Option<PathBuf>,
+
+    /// Decode the NetFlow/IPFIX datagrams of a pcap or pcapng file instead of listening, and stop at its end
+    #[structopt(long = "--pcap")]
+    pcap: Option<PathBuf>,
+
+    /// UDP destination ports of the NetFlow/IPFIX traffic in the --pcap file
+    #[structopt(long = "--pcap-ports", default_value = "2055,4739,9995,9996", use_delimiter = true)]
+    pcap_ports: Vec<u16>,
+
+    /// Replay the --pcap file with the timing of the capture instead of as fast as possible
+    #[structopt(long = "--pcap-timing")]
+    pcap_timing: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Print every message of a pcap/pcapng capture, a raw file of messages or a hex dump as a tree, without starting the collector
+    Decode {
+        /// File to decode
+        file: PathBuf,
+    },
+    /// Print the CREATE TABLE statement of each ClickHouse sink of the --config file
+    CreateTable,
+}
+
+impl Opts {
+    /// Configuration equivalent to the command line flags, used when no file is given
+    fn to_config(&self) -> Config {
+        Config {
+            log_level: self.log_level,
+            prometheus: self.exporter,
+            queue_size: self.queue_size,
+            overflow_policy: self.overflow_policy,
+            listeners: self
+                .listeners
+                .iter()
+                .map(|spec| ListenerConfig {
+                    address: spec.addr,
+                    transport: spec.transport,
+                    versions: Some(spec.versions.clone()),
+                    tag: spec.tag.as_deref().map(str::to_string),
+                    workers: self.workers,
+                    batch_size: self.batch_size,
+                    recv_buffer_size: self.recv_buffer_size,
+                    forward: self
+                        .forward
+                        .iter()
+                        .map(|&target| ForwardConfig {
+                            target,
+                            exporters: vec![],
+                            versions: None,
+                            spoof: false,
+                        })
+                        .collect(),
+                    capture: None,
+                })
+                .collect(),
+            templates: TemplatesConfig::default(),
+            exporters: Default::default(),
+            state: self.state.clone().map(|path| StateConfig { path, interval: 300 }),
+            sinks: vec![],
+        }
+    }
+}
+
+/// Stopped by a signal or at the end of the capture file, after draining the queue
+const EXIT_SUCCESS: i32 = 0;
+/// A thread failed, at startup or while running
+const EXIT_FAILURE: i32 = 1;
+/// The configuration file can't be loaded
+const EXIT_CONFIG_ERROR: i32 = 2;
+
 fn main() {
+    process::exit(run());
+}
+
+fn run() -> i32 {
     let opts = Opts::from_args();
 
-    // init the app logger
-    env_logger::Builder::new().format_timestamp_millis().filter(None, opts.log_level).init();
+    match &opts.command {
+        Some(Command::Decode { file }) => return decode(file),
+        Some(Command::CreateTable) => return create_table(opts.config.as_deref()),
+        None => {}
+    }
+
+    let config = match &opts.config {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                return EXIT_CONFIG_ERROR;
+            }
+        },
+        None => opts.to_config(),
+    };
+
+    // init the app logger, the level is then driven by the configuration so it can change on reload
+    env_logger::Builder::new().format_timestamp_millis().filter(None, LevelFilter::Trace).init();
+    log::set_max_level(config.log_level);
 
     info!("Starting App");
 
-    let mut thread_list = vec![];
-    let (sender, receiver) = channel();
+    let (sender, receiver) = channel::bounded(config.queue_size, config.overflow_policy);
+    let shared = SharedConfig::new(config);
+    let config = shared.current();
+    let shutdown = Shutdown::new();
+
+    if let Err(e) = handle_signals(opts.config.clone(), shared.clone(), shutdown.clone()) {
+        error!("Failed to register the signal handlers : {}", e);
+        return EXIT_FAILURE;
+    }
+
+    let replay = opts.pcap.clone().map(|path| ReplayOptions {
+        path,
+        ports: opts.pcap_ports.clone(),
+        timing: opts.pcap_timing,
+    });
 
-    let listener_url = opts.listener.clone();
-    thread_list.push(thread::Builder::new().name("Listener".to_string()).spawn(move || {
-        threads::listener::listen(listener_url, sender);
-    }));
+    // the templates of a capture file are not saved, they would replace the ones of the live exporters
+    let state = config.state.as_ref().filter(|_| replay.is_none()).map(|state| Arc::new(StateStore::load(&state.path, state.interval)));
 
-    thread_list.push(thread::Builder::new().name("Exporter".to_string()).spawn(move || {
-        threads::exporter::exporte(receiver);
-    }));
+    let mut status = EXIT_SUCCESS;
+    let mut listeners = vec![];
+    let mut worker_ids = HashMap::new();
 
-    if opts.exporter != None {
-        let prometheus_listener = opts.exporter.unwrap().clone();
-        thread_list.push(thread::Builder::new().name("Prometheus".to_string()).spawn(move || {
-            threads::prometheus::listen(prometheus_listener);
+    if let Some(replay) = replay {
+        let sender = sender.clone();
+        let shared = shared.clone();
+        let replay_shutdown = shutdown.clone();
+        listeners.push(spawn("Replay".to_string(), &shutdown, move || {
+            let result = threads::replay::replay(replay, sender, shared, replay_shutdown.clone());
+            // the other threads stop once the file is read
+            replay_shutdown.trigger();
+            result
         }));
     }
 
-    for t in thread_list {
-        t.unwrap().join().unwrap();
+    for listener_opts in config.listener_options().into_iter().filter(|_| opts.pcap.is_none()) {
+        let id = worker_ids.entry(listener_opts.spec.name()).or_insert(0);
+        let name = format!("Listener-{}-{}", listener_opts.spec.name(), id);
+        *id += 1;
+
+        let sender = sender.clone();
+        let shared = shared.clone();
+        let listener_shutdown = shutdown.clone();
+        let state = state.clone();
+        listeners.push(spawn(name, &shutdown, move || threads::listener::listen(listener_opts, sender, shared, listener_shutdown, state)));
+    }
+    // the exporter thread stops once every listener has dropped its sender and the queue is empty
+    drop(sender);
+
+    let exporter_config = shared.clone();
+    let exporter = spawn("Exporter".to_string(), &shutdown, move || {
+        threads::exporter::exporte(receiver, exporter_config);
+        Ok(())
+    });
+
+    let prometheus = config.prometheus.map(|addr| {
+        let prometheus_shutdown = shutdown.clone();
+        spawn("Prometheus".to_string(), &shutdown, move || threads::prometheus::listen(addr, prometheus_shutdown))
+    });
+
+    let state_writer = state.clone().filter(|state| state.interval > 0).map(|state| {
+        let writer_shutdown = shutdown.clone();
+        spawn("State".to_string(), &shutdown, move || {
+            write_state_periodically(&state, &writer_shutdown);
+            Ok(())
+        })
+    });
+
+    for t in listeners {
+        if !join(t) {
+            status = EXIT_FAILURE;
+        }
+    }
+
+    // every listener has published its last snapshot before stopping
+    if let Some(state) = &state {
+        match state.save() {
+            Ok(()) => info!("Templates saved to the state file"),
+            Err(e) => {
+                error!("{}", e);
+                status = EXIT_FAILURE;
+            }
+        }
+    }
+
+    for t in std::iter::once(exporter).chain(prometheus).chain(state_writer) {
+        if !join(t) {
+            status = EXIT_FAILURE;
+        }
     }
 
     info!("Closing App");
+    status
+}
+
+/// Print the CREATE TABLE statements of the ClickHouse sinks of the configuration
+fn create_table(path: Option<&Path>) -> i32 {
+    let config = match path.map(Config::load) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return EXIT_CONFIG_ERROR;
+        }
+        None => {
+            eprintln!("The ClickHouse sinks are read from the --config file");
+            return EXIT_CONFIG_ERROR;
+        }
+    };
+
+    let statements: Vec<String> = config
+        .sinks
+        .iter()
+        .filter_map(|sink| match sink {
+            SinkConfig::ClickHouse(clickhouse) => Some(clickhouse.create_table()),
+            _ => None,
+        })
+        .collect();
+    if statements.is_empty() {
+        eprintln!("No ClickHouse sink in the configuration");
+        return EXIT_CONFIG_ERROR;
+    }
+
+    print!("{}", statements.join("\n"));
+    EXIT_SUCCESS
+}
+
+/// Print the tree of every message of the file
+fn decode(path: &Path) -> i32 {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Failed to read {} : {}", path.display(), e);
+            return EXIT_FAILURE;
+        }
+    };
+    let messages = match dump::read_messages(&content) {
+        Ok(messages) => messages,
+        Err(e) => {
+            eprintln!("{} : {}", path.display(), e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    let mut dumper = Dumper::new();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (i, msg) in messages.iter().enumerate() {
+        let mut text = String::new();
+        dumper.dump(i + 1, msg, &mut text);
+        // the output is often cut with head
+        if out.write_all(text.as_bytes()).is_err() {
+            break;
+        }
+    }
+
+    EXIT_SUCCESS
+}
+
+/// Write the state file every interval until the shutdown, the final write is done once the listeners are stopped
+fn write_state_periodically(state: &StateStore, shutdown: &Shutdown) {
+    let mut elapsed = Duration::ZERO;
+
+    while !shutdown.is_triggered() {
+        thread::sleep(POLL_INTERVAL);
+        elapsed += POLL_INTERVAL;
+
+        if elapsed >= Duration::from_secs(state.interval) {
+            elapsed = Duration::ZERO;
+            if let Err(e) = state.save() {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+/// Start a thread that stops the whole collector if it fails
+fn spawn<F>(name: String, shutdown: &Shutdown, f: F) -> Option<thread::JoinHandle<Result<(), String>>>
+where
+    F: FnOnce() -> Result<(), String> + Send + 'static,
+{
+    let thread_shutdown = shutdown.clone();
+    let spawned = thread::Builder::new().name(name.clone()).spawn(move || {
+        let _guard = thread_shutdown.on_panic();
+        let result = f();
+        if let Err(e) = &result {
+            error!("{}", e);
+            thread_shutdown.trigger();
+        }
+        result
+    });
+
+    match spawned {
+        Ok(t) => Some(t),
+        Err(e) => {
+            error!("Failed to start the thread {} : {}", name, e);
+            shutdown.trigger();
+            None
+        }
+    }
+}
+
+/// Wait for the thread, returning false if it failed or could not be started
+fn join(t: Option<thread::JoinHandle<Result<(), String>>>) -> bool {
+    matches!(t.map(|t| t.join()), Some(Ok(Ok(()))))
+}
+
+/// SIGHUP reloads the configuration file, SIGINT/SIGTERM stop the collector and a second one forces the exit
+fn handle_signals(path: Option<PathBuf>, shared: Arc<SharedConfig>, shutdown: Shutdown) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM])?;
+
+    thread::Builder::new().name("Signals".to_string()).spawn(move || {
+        for signal in signals.forever() {
+            match (signal, &path) {
+                (SIGHUP, Some(path)) => {
+                    info!("SIGHUP received, reloading {}", path.display());
+                    match Config::load(path) {
+                        Ok(new) => shared.reload(new),
+                        Err(e) => error!("Configuration not reloaded: {}", e),
+                    }
+                }
+                (SIGHUP, None) => warn!("SIGHUP received but there is no configuration file to reload"),
+                _ if shutdown.is_triggered() => {
+                    warn!("Second stop signal received, exiting without draining the queue");
+                    process::exit(EXIT_FAILURE);
+                }
+                _ => {
+                    info!("Stop signal received, draining the queue before exiting");
+                    shutdown.trigger();
+                }
+            }
+        }
+    })?;
+
+    Ok(())
 }
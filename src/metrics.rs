@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// every metric exported by the prometheus thread
+static REGISTRY: &[&dyn Metric] = &[
+    &RECEIVED_DATAGRAMS,
+    &TRUNCATED_DATAGRAMS,
+    &REJECTED_VERSIONS,
+    &DENIED_MESSAGES,
+    &DENIED_SOURCES,
+    &REJECTED_TEMPLATES,
+    &PARSING_ERRORS,
+    &DECODED_FLOWS,
+    &FORWARDED_DATAGRAMS,
+    &FORWARD_ERRORS,
+    &CAPTURED_DATAGRAMS,
+    &DROPPED_BATCHES,
+    &DROPPED_RECORDS,
+    &KAFKA_DELIVERED_RECORDS,
+    &KAFKA_DELIVERY_ERRORS,
+    &INFLUXDB_LATE_FLOWS,
+];
+
+pub static RECEIVED_DATAGRAMS: LabeledCounter = LabeledCounter::new("ipfix_received_datagrams_total", "Number of datagrams or messages received, per listener");
+pub static TRUNCATED_DATAGRAMS: LabeledCounter = LabeledCounter::new("ipfix_truncated_datagrams_total", "Number of datagrams bigger than the receive buffer, per listener");
+pub static REJECTED_VERSIONS: LabeledCounter = LabeledCounter::new("ipfix_rejected_version_total", "Number of messages with a version not accepted by the listener");
+pub static DENIED_MESSAGES: LabeledCounter = LabeledCounter::new("ipfix_denied_messages_total", "Number of messages dropped by the exporter access rules, per listener and reason");
+pub static DENIED_SOURCES: LabeledCounter = LabeledCounter::new(
+    "ipfix_denied_sources_total",
+    "Number of messages dropped by the exporter access rules, per listener and source for the first sources then under other",
+);
+pub static REJECTED_TEMPLATES: Counter = Counter::new(
+    "ipfix_rejected_templates_total",
+    "Number of templates ignored because their exporter reached the maximum number of templates",
+);
+pub static PARSING_ERRORS: LabeledCounter = LabeledCounter::new("ipfix_parsing_errors_total", "Number of messages that couldn't be decoded, per listener");
+pub static DECODED_FLOWS: LabeledCounter = LabeledCounter::new("ipfix_decoded_flows_total", "Number of flow records decoded, per listener");
+pub static FORWARDED_DATAGRAMS: LabeledCounter = LabeledCounter::new("ipfix_forwarded_datagrams_total", "Number of datagrams copied to a downstream collector, per listener and target");
+pub static FORWARD_ERRORS: LabeledCounter = LabeledCounter::new(
+    "ipfix_forward_errors_total",
+    "Number of datagrams that couldn't be copied to a downstream collector, per listener and target",
+);
+pub static CAPTURED_DATAGRAMS: LabeledCounter = LabeledCounter::new("ipfix_captured_datagrams_total", "Number of datagrams written to the debug capture file, per listener");
+pub static DROPPED_BATCHES: Counter = Counter::new("ipfix_dropped_batches_total", "Number of batches dropped because the exporter queue was full");
+pub static DROPPED_RECORDS: Counter = Counter::new("ipfix_dropped_records_total", "Number of flow records dropped because the exporter queue was full");
+pub static KAFKA_DELIVERED_RECORDS: LabeledCounter = LabeledCounter::new("ipfix_kafka_delivered_records_total", "Number of records acknowledged by the Kafka brokers, per topic");
+pub static KAFKA_DELIVERY_ERRORS: LabeledCounter = LabeledCounter::new("ipfix_kafka_delivery_errors_total", "Number of records the Kafka sink couldn't deliver, per topic and error");
+pub static INFLUXDB_LATE_FLOWS: Counter = Counter::new("ipfix_influxdb_late_flows_total", "Number of flows ignored because their interval was already written to InfluxDB");
+
+pub trait Metric: Sync {
+    fn render(&self, out: &mut String);
+}
+
+/******************************** COUNTER ********************************/
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter { name, help, value: AtomicU64::new(0) }
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Counter {
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", self.name, self.help, self.name, self.name, self.get()).unwrap();
+    }
+}
+
+/******************************** LABELED COUNTER ********************************/
+
+/// Counter with one value per set of labels, for metrics split by listener, exporter, target...
+pub struct LabeledCounter {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<String, Arc<AtomicU64>>>,
+}
+
+/// Value of a labeled counter for one set of labels, resolved once to be incremented without lock
+#[derive(Debug, Clone)]
+pub struct CounterHandle(Arc<AtomicU64>);
+
+impl CounterHandle {
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+impl LabeledCounter {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        LabeledCounter {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn with(&self, labels: &[(&str, &str)]) -> CounterHandle {
+        let mut key = String::new();
+        for (i, (label, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                key.push(',');
+            }
+            write!(key, "{}=\"{}\"", label, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")).unwrap();
+        }
+
+        CounterHandle(self.values.lock().unwrap().entry(key).or_default().clone())
+    }
+}
+
+impl Metric for LabeledCounter {
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}\n# TYPE {} counter", self.name, self.help, self.name).unwrap();
+
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            writeln!(out, "{}{{{}}} {}", self.name, labels, value.load(Ordering::Relaxed)).unwrap();
+        }
+    }
+}
+
+/// Text exposition of every registered metric
+pub fn render() -> String {
+    let mut out = String::new();
+
+    for metric in REGISTRY {
+        metric.render(&mut out);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_counter() {
+        let counter = Counter::new("test_total", "Test counter");
+        counter.inc();
+        counter.add(2);
+
+        let mut out = String::new();
+        counter.render(&mut out);
+
+        assert_eq!(out, "# HELP test_total Test counter\n# TYPE test_total counter\ntest_total 3\n");
+    }
+
+    #[test]
+    fn render_labeled_counter() {
+        let counter = LabeledCounter::new("test_total", "Test counter");
+        counter.with(&[("listener", "core"), ("exporter", "10.0.0.1")]).inc();
+        counter.with(&[("listener", "core"), ("exporter", "10.0.0.1")]).add(4);
+        counter.with(&[("listener", "a\"b"), ("exporter", "10.0.0.2")]).inc();
+
+        let mut out = String::new();
+        counter.render(&mut out);
+
+        assert_eq!(
+            out,
+            "# HELP test_total Test counter\n# TYPE test_total counter\n\
+             test_total{listener=\"a\\\"b\",exporter=\"10.0.0.2\"} 1\n\
+             test_total{listener=\"core\",exporter=\"10.0.0.1\"} 5\n"
+        );
+    }
+}
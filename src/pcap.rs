@@ -0,0 +1,723 @@
+//! Reader of pcap and pcapng capture files, extracting the UDP datagrams of the NetFlow/IPFIX traffic they contain,
+//! and writer of the synthetic packets wrapping the datagrams received by the listeners.
+
+use core::convert::{TryFrom, TryInto};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+
+/******************************** CAPTURE FILE ********************************/
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+/// Upper bound of a record or block, a bigger length means the file is corrupted
+const MAX_RECORD_SIZE: usize = 16 * 1024 * 1024;
+
+/// One packet of the capture, as seen on the link
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    /// Capture time since the UNIX epoch, zero for the pcapng simple packets which have none
+    pub timestamp: Duration,
+    pub link_type: u16,
+    /// Captured bytes, possibly cut by the snapshot length
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    link_type: u16,
+    /// Timestamp units per second
+    resolution: u64,
+}
+
+#[derive(Debug)]
+enum Format {
+    Pcap { big_endian: bool, resolution: u64, link_type: u16 },
+    PcapNg { big_endian: bool, interfaces: Vec<Interface> },
+}
+
+/// Packets of a pcap or pcapng file, the format and byte order are detected from its first bytes
+pub struct Capture<R: Read> {
+    reader: R,
+    format: Format,
+}
+
+impl<R: Read> Capture<R> {
+    pub fn new(mut reader: R) -> Result<Self, String> {
+        let mut magic = [0; 4];
+        if !read_or_eof(&mut reader, &mut magic)? {
+            return Err("Empty capture file".to_string());
+        }
+
+        let format = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (PCAPNG_SECTION_HEADER, _) => {
+                let big_endian = read_section_header(&mut reader)?;
+                Format::PcapNg { big_endian, interfaces: vec![] }
+            }
+            (le, be) => {
+                let (big_endian, magic) = if le == PCAP_MAGIC_MICROS || le == PCAP_MAGIC_NANOS { (false, le) } else { (true, be) };
+                let resolution = match magic {
+                    PCAP_MAGIC_MICROS => 1_000_000,
+                    PCAP_MAGIC_NANOS => 1_000_000_000,
+                    _ => return Err(format!("Not a pcap or pcapng file, read magic {:08x}", be)),
+                };
+
+                // version, timezone, timestamp accuracy and snapshot length are not needed
+                let mut header = [0; 20];
+                read_exact(&mut reader, &mut header)?;
+                Format::Pcap {
+                    big_endian,
+                    resolution,
+                    link_type: read_u32(&header[16..20], big_endian) as u16,
+                }
+            }
+        };
+
+        Ok(Capture { reader, format })
+    }
+
+    /// Next packet of the file, None at its end
+    pub fn next_packet(&mut self) -> Result<Option<Packet>, String> {
+        match &mut self.format {
+            Format::Pcap { big_endian, resolution, link_type } => {
+                let mut header = [0; 16];
+                if !read_or_eof(&mut self.reader, &mut header)? {
+                    return Ok(None);
+                }
+
+                let secs = read_u32(&header[0..4], *big_endian) as u64;
+                let fraction = read_u32(&header[4..8], *big_endian) as u64;
+                let length = read_u32(&header[8..12], *big_endian) as usize;
+                if length > MAX_RECORD_SIZE {
+                    return Err(format!("Invalid pcap record length {}", length));
+                }
+
+                let mut data = vec![0; length];
+                read_exact(&mut self.reader, &mut data)?;
+                Ok(Some(Packet {
+                    timestamp: Duration::from_secs(secs) + to_duration(fraction, *resolution),
+                    link_type: *link_type,
+                    data,
+                }))
+            }
+            Format::PcapNg { big_endian, interfaces } => loop {
+                let mut header = [0; 8];
+                if !read_or_eof(&mut self.reader, &mut header)? {
+                    return Ok(None);
+                }
+
+                let block_type = read_u32(&header[0..4], *big_endian);
+                if block_type == PCAPNG_SECTION_HEADER {
+                    // a new section can change the byte order and restarts the interface numbering
+                    let mut rest = [0; 4];
+                    rest.copy_from_slice(&header[4..8]);
+                    let section_big_endian = read_section_header(&mut io::Cursor::new(rest).chain(&mut self.reader))?;
+                    *big_endian = section_big_endian;
+                    interfaces.clear();
+                    continue;
+                }
+
+                let length = read_u32(&header[4..8], *big_endian) as usize;
+                if length < 12 || !length.is_multiple_of(4) || length > MAX_RECORD_SIZE {
+                    return Err(format!("Invalid pcapng block length {}", length));
+                }
+                // the body is followed by a copy of the block length
+                let mut body = vec![0; length - 8];
+                read_exact(&mut self.reader, &mut body)?;
+                body.truncate(length - 12);
+
+                match block_type {
+                    PCAPNG_INTERFACE_DESCRIPTION => interfaces.push(read_interface(&body, *big_endian)?),
+                    PCAPNG_ENHANCED_PACKET => {
+                        if body.len() < 20 {
+                            return Err("Enhanced packet block too short".to_string());
+                        }
+                        let id = read_u32(&body[0..4], *big_endian) as usize;
+                        let interface = interfaces.get(id).ok_or(format!("Packet of the undeclared interface {}", id))?;
+                        let units = (read_u32(&body[4..8], *big_endian) as u64) << 32 | read_u32(&body[8..12], *big_endian) as u64;
+                        let length = read_u32(&body[12..16], *big_endian) as usize;
+                        let data = body.get(20..20 + length).ok_or("Enhanced packet block too short")?;
+
+                        return Ok(Some(Packet {
+                            timestamp: Duration::from_secs(units / interface.resolution) + to_duration(units % interface.resolution, interface.resolution),
+                            link_type: interface.link_type,
+                            data: data.to_vec(),
+                        }));
+                    }
+                    PCAPNG_SIMPLE_PACKET => {
+                        let interface = interfaces.first().ok_or("Simple packet block without interface")?;
+                        if body.len() < 4 {
+                            return Err("Simple packet block too short".to_string());
+                        }
+                        // the captured length is only given by the block length, the data may be padded
+                        let length = (read_u32(&body[0..4], *big_endian) as usize).min(body.len() - 4);
+                        return Ok(Some(Packet {
+                            timestamp: Duration::ZERO,
+                            link_type: interface.link_type,
+                            data: body[4..4 + length].to_vec(),
+                        }));
+                    }
+                    // statistics, name resolution, custom blocks, ...
+                    _ => continue,
+                }
+            },
+        }
+    }
+}
+
+/// Read the pcapng section header after its block type, returning its byte order
+fn read_section_header(reader: &mut impl Read) -> Result<bool, String> {
+    let mut header = [0; 8];
+    read_exact(reader, &mut header)?;
+
+    let big_endian = match (u32::from_le_bytes(header[4..8].try_into().unwrap()), u32::from_be_bytes(header[4..8].try_into().unwrap())) {
+        (PCAPNG_BYTE_ORDER_MAGIC, _) => false,
+        (_, PCAPNG_BYTE_ORDER_MAGIC) => true,
+        _ => return Err("Invalid pcapng byte order magic".to_string()),
+    };
+    let length = read_u32(&header[0..4], big_endian) as usize;
+    if length < 28 || !length.is_multiple_of(4) || length > MAX_RECORD_SIZE {
+        return Err(format!("Invalid pcapng section header length {}", length));
+    }
+
+    // versions, section length and options
+    let mut rest = vec![0; length - 12];
+    read_exact(reader, &mut rest)?;
+    Ok(big_endian)
+}
+
+fn read_interface(body: &[u8], big_endian: bool) -> Result<Interface, String> {
+    if body.len() < 8 {
+        return Err("Interface description block too short".to_string());
+    }
+
+    let mut interface = Interface {
+        link_type: read_u16(&body[0..2], big_endian),
+        resolution: 1_000_000,
+    };
+
+    let mut offset = 8;
+    while offset + 4 <= body.len() {
+        let code = read_u16(&body[offset..offset + 2], big_endian);
+        let length = read_u16(&body[offset + 2..offset + 4], big_endian) as usize;
+        let value = body.get(offset + 4..offset + 4 + length).ok_or("Interface option too long")?;
+
+        if code == PCAPNG_OPTION_TSRESOL && length == 1 {
+            // power of 10, or of 2 when the high bit is set
+            let exponent = (value[0] & 0x7f) as u32;
+            let base: u64 = if value[0] & 0x80 == 0 { 10 } else { 2 };
+            interface.resolution = base.checked_pow(exponent).filter(|&r| r > 0).ok_or("Invalid interface timestamp resolution")?;
+        }
+        offset += 4 + length.div_ceil(4) * 4;
+    }
+
+    Ok(interface)
+}
+
+fn to_duration(units: u64, resolution: u64) -> Duration {
+    Duration::from_nanos((units as u128 * 1_000_000_000 / resolution as u128) as u64)
+}
+
+fn read_u16(buf: &[u8], big_endian: bool) -> u16 {
+    let bytes = buf[0..2].try_into().unwrap();
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn read_u32(buf: &[u8], big_endian: bool) -> u32 {
+    let bytes = buf[0..4].try_into().unwrap();
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), String> {
+    match read_or_eof(reader, buf)? {
+        true => Ok(()),
+        false => Err("Capture file truncated".to_string()),
+    }
+}
+
+/// Fill buf, false if the file ends before its first byte
+fn read_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, String> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err("Capture file truncated".to_string()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(format!("Failed to read the capture file : {}", e)),
+        }
+    }
+    Ok(true)
+}
+
+/******************************** PACKET DECODING ********************************/
+
+pub const LINKTYPE_NULL: u16 = 0;
+pub const LINKTYPE_ETHERNET: u16 = 1;
+pub const LINKTYPE_RAW: u16 = 101;
+pub const LINKTYPE_LOOP: u16 = 108;
+pub const LINKTYPE_LINUX_SLL: u16 = 113;
+pub const LINKTYPE_IPV4: u16 = 228;
+pub const LINKTYPE_IPV6: u16 = 229;
+pub const LINKTYPE_LINUX_SLL2: u16 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: [u16; 3] = [0x8100, 0x88a8, 0x9100];
+
+const IP_PROTOCOL_UDP: u8 = 17;
+/// Hop-by-hop, routing and destination options, skipped to reach the UDP header
+const IPV6_EXTENSIONS: [u8; 3] = [0, 43, 60];
+
+/// UDP datagram found in a packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpDatagram<'a> {
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub payload: &'a [u8],
+    /// The payload was cut by the snapshot length of the capture
+    pub truncated: bool,
+}
+
+/// Extract the UDP datagram of a packet, None for the other protocols and for the IP fragments which are not reassembled
+pub fn decode_udp(link_type: u16, data: &[u8]) -> Option<UdpDatagram<'_>> {
+    let (ethertype, ip) = match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().unwrap());
+            while ETHERTYPE_VLAN.contains(&ethertype) {
+                offset += 4;
+                ethertype = u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().unwrap());
+            }
+            (Some(ethertype), data.get(offset + 2..)?)
+        }
+        LINKTYPE_LINUX_SLL => (Some(u16::from_be_bytes(data.get(14..16)?.try_into().unwrap())), data.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => (Some(u16::from_be_bytes(data.get(0..2)?.try_into().unwrap())), data.get(20..)?),
+        // the address family is in the byte order of the capturing host, or always big endian for LOOP
+        LINKTYPE_NULL | LINKTYPE_LOOP => {
+            let family = u32::from_le_bytes(data.get(0..4)?.try_into().unwrap());
+            let family = if family > 0xffff { family.swap_bytes() } else { family };
+            let ethertype = match family {
+                2 => ETHERTYPE_IPV4,
+                24 | 28 | 30 => ETHERTYPE_IPV6,
+                _ => return None,
+            };
+            (Some(ethertype), data.get(4..)?)
+        }
+        LINKTYPE_RAW => (None, data),
+        LINKTYPE_IPV4 => (Some(ETHERTYPE_IPV4), data),
+        LINKTYPE_IPV6 => (Some(ETHERTYPE_IPV6), data),
+        _ => return None,
+    };
+
+    // the raw link type has no header, the IP version is read from the packet
+    let ethertype = ethertype.unwrap_or(match ip.first()? >> 4 {
+        4 => ETHERTYPE_IPV4,
+        6 => ETHERTYPE_IPV6,
+        _ => return None,
+    });
+
+    let (src, dst, segment, truncated) = match ethertype {
+        ETHERTYPE_IPV4 => decode_ipv4(ip)?,
+        ETHERTYPE_IPV6 => decode_ipv6(ip)?,
+        _ => return None,
+    };
+
+    let length = u16::from_be_bytes(segment.get(4..6)?.try_into().unwrap()) as usize;
+    if length < 8 {
+        return None;
+    }
+
+    Some(UdpDatagram {
+        from: SocketAddr::new(src, u16::from_be_bytes(segment[0..2].try_into().unwrap())),
+        to: SocketAddr::new(dst, u16::from_be_bytes(segment[2..4].try_into().unwrap())),
+        // the snapshot length may cut the UDP header itself
+        payload: segment.get(8..length.min(segment.len())).unwrap_or_default(),
+        truncated: truncated || length > segment.len(),
+    })
+}
+
+/// Addresses and UDP segment of an IPv4 packet, and whether it is cut
+fn decode_ipv4(ip: &[u8]) -> Option<(IpAddr, IpAddr, &[u8], bool)> {
+    let header_length = (*ip.first()? & 0x0f) as usize * 4;
+    if header_length < 20 || ip.len() < header_length || ip[9] != IP_PROTOCOL_UDP {
+        return None;
+    }
+    // more fragments flag or fragment offset
+    if u16::from_be_bytes(ip[6..8].try_into().unwrap()) & 0x3fff != 0 {
+        return None;
+    }
+
+    let total_length = u16::from_be_bytes(ip[2..4].try_into().unwrap()) as usize;
+    let src = Ipv4Addr::from(<[u8; 4]>::try_from(&ip[12..16]).unwrap());
+    let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&ip[16..20]).unwrap());
+    let segment = ip.get(header_length..total_length.min(ip.len()))?;
+    Some((src.into(), dst.into(), segment, total_length > ip.len()))
+}
+
+/// Addresses and UDP segment of an IPv6 packet, and whether it is cut
+fn decode_ipv6(ip: &[u8]) -> Option<(IpAddr, IpAddr, &[u8], bool)> {
+    if ip.len() < 40 {
+        return None;
+    }
+
+    let payload_length = u16::from_be_bytes(ip[4..6].try_into().unwrap()) as usize;
+    let src = Ipv6Addr::from(<[u8; 16]>::try_from(&ip[8..24]).unwrap());
+    let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&ip[24..40]).unwrap());
+    let end = (40 + payload_length).min(ip.len());
+
+    let mut next_header = ip[6];
+    let mut offset = 40;
+    while IPV6_EXTENSIONS.contains(&next_header) {
+        let extension = ip.get(offset..offset + 2)?;
+        next_header = extension[0];
+        offset += (extension[1] as usize + 1) * 8;
+    }
+    // the fragments are not reassembled
+    if next_header != IP_PROTOCOL_UDP {
+        return None;
+    }
+
+    Some((src.into(), dst.into(), ip.get(offset..end)?, 40 + payload_length > ip.len()))
+}
+
+/******************************** CAPTURE WRITER ********************************/
+
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const ETHERNET_HEADER_SIZE: usize = 14;
+/// Locally administered addresses of the synthetic ethernet frames
+const ETHERNET_ADDRESSES: [u8; 12] = [2, 0, 0, 0, 0, 2, 2, 0, 0, 0, 0, 1];
+
+/// Header of a pcap file of ethernet frames with microsecond timestamps, in little endian
+pub fn file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&PCAP_MAGIC_MICROS.to_le_bytes());
+    // version 2.4, UTC, no accuracy, 64k snapshot length
+    header.extend_from_slice(&[2, 0, 4, 0]);
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&(u16::MAX as u32).to_le_bytes());
+    header.extend_from_slice(&(LINKTYPE_ETHERNET as u32).to_le_bytes());
+    header
+}
+
+/// pcap record of an ethernet frame carrying the datagram, None if it doesn't fit in an IP packet.
+/// IPv4 is used when both addresses are IPv4 or IPv4-mapped, the unspecified address of a dual stack socket matching both families.
+pub fn udp_record(timestamp: Duration, payload: &[u8], from: SocketAddr, to: SocketAddr) -> Option<Vec<u8>> {
+    let ipv4 = |ip: IpAddr| match ip {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(ip) if ip.is_unspecified() => Some(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+    };
+    let ipv6 = |ip: IpAddr| match ip {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv6Addr::UNSPECIFIED,
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+
+    let (ethertype, packet) = match (ipv4(from.ip()), ipv4(to.ip())) {
+        (Some(src), Some(dst)) => (ETHERTYPE_IPV4, ipv4_udp_packet(payload, SocketAddrV4::new(src, from.port()), SocketAddrV4::new(dst, to.port()))?),
+        _ => (
+            ETHERTYPE_IPV6,
+            ipv6_udp_packet(payload, SocketAddrV6::new(ipv6(from.ip()), from.port(), 0, 0), SocketAddrV6::new(ipv6(to.ip()), to.port(), 0, 0))?,
+        ),
+    };
+
+    let length = (ETHERNET_HEADER_SIZE + packet.len()) as u32;
+    let mut record = Vec::with_capacity(16 + length as usize);
+    record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&length.to_le_bytes());
+    record.extend_from_slice(&length.to_le_bytes());
+    record.extend_from_slice(&ETHERNET_ADDRESSES);
+    record.extend_from_slice(&ethertype.to_be_bytes());
+    record.extend_from_slice(&packet);
+    Some(record)
+}
+
+/// IPv4 and UDP headers followed by the payload, None if it doesn't fit in an IPv4 packet
+pub fn ipv4_udp_packet(payload: &[u8], from: SocketAddrV4, to: SocketAddrV4) -> Option<Vec<u8>> {
+    let udp_length = u16::try_from(UDP_HEADER_SIZE + payload.len()).ok()?;
+    let total_length = u16::try_from(IPV4_HEADER_SIZE + UDP_HEADER_SIZE + payload.len()).ok()?;
+    let mut packet = Vec::with_capacity(total_length as usize);
+
+    // version 4, header of 5 words, no TOS
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_length.to_be_bytes());
+    // identification, flags and fragment offset, filled by the kernel when left to 0
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    // TTL 64, UDP
+    packet.extend_from_slice(&[64, IP_PROTOCOL_UDP]);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&from.ip().octets());
+    packet.extend_from_slice(&to.ip().octets());
+    let checksum = internet_checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(&from.port().to_be_bytes());
+    packet.extend_from_slice(&to.port().to_be_bytes());
+    packet.extend_from_slice(&udp_length.to_be_bytes());
+    // the UDP checksum is optional over IPv4
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+
+    Some(packet)
+}
+
+/// IPv6 and UDP headers followed by the payload, None if it doesn't fit in an IPv6 packet without jumbogram
+pub fn ipv6_udp_packet(payload: &[u8], from: SocketAddrV6, to: SocketAddrV6) -> Option<Vec<u8>> {
+    let udp_length = u16::try_from(UDP_HEADER_SIZE + payload.len()).ok()?;
+    let mut packet = Vec::with_capacity(IPV6_HEADER_SIZE + udp_length as usize);
+
+    // version 6, no traffic class nor flow label
+    packet.extend_from_slice(&[0x60, 0, 0, 0]);
+    packet.extend_from_slice(&udp_length.to_be_bytes());
+    // UDP, hop limit 64
+    packet.extend_from_slice(&[IP_PROTOCOL_UDP, 64]);
+    packet.extend_from_slice(&from.ip().octets());
+    packet.extend_from_slice(&to.ip().octets());
+
+    let mut udp = Vec::with_capacity(UDP_HEADER_SIZE);
+    udp.extend_from_slice(&from.port().to_be_bytes());
+    udp.extend_from_slice(&to.port().to_be_bytes());
+    udp.extend_from_slice(&udp_length.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+
+    // the UDP checksum is mandatory over IPv6, computed with the addresses, the length and the protocol
+    let pseudo_header = [0, 0, udp_length.to_be_bytes()[0], udp_length.to_be_bytes()[1], 0, 0, 0, IP_PROTOCOL_UDP];
+    let checksum = match internet_checksum(&[&packet[8..40], &pseudo_header, &udp, payload]) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(&udp);
+    packet.extend_from_slice(payload);
+    Some(packet)
+}
+
+/// One's complement of the one's complement sum of the 16 bits words, the last part may have an odd length
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for word in part.chunks(2) {
+            sum += u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    /// IPv4 packet from 192.0.2.1:54321 to 198.51.100.7:2055 with a 4 bytes payload
+    const IPV4_UDP: [u8; 32] = hex!(
+        "45 00 00 20 00 00 40 00 40 11 00 00 c0 00 02 01
+         c6 33 64 07 d4 31 08 07 00 0c 00 00 00 05 00 00"
+    );
+
+    fn expected_datagram(payload: &[u8]) -> UdpDatagram<'_> {
+        UdpDatagram {
+            from: "192.0.2.1:54321".parse().unwrap(),
+            to: "198.51.100.7:2055".parse().unwrap(),
+            payload,
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn read_pcap_file() {
+        // little endian, microseconds, ethernet
+        let file = [
+            &hex!(
+                "d4 c3 b2 a1 02 00 04 00 00 00 00 00 00 00 00 00
+                 ff ff 00 00 01 00 00 00
+                 9c b8 80 60 20 a1 07 00 2e 00 00 00 2e 00 00 00
+                 00 11 22 33 44 55 66 77 88 99 aa bb 08 00"
+            )[..],
+            &IPV4_UDP,
+        ]
+        .concat();
+
+        let mut capture = Capture::new(&file[..]).unwrap();
+        let packet = capture.next_packet().unwrap().unwrap();
+        assert_eq!(packet.timestamp, Duration::from_millis(1_619_048_604_500));
+        assert_eq!(packet.link_type, LINKTYPE_ETHERNET);
+        assert_eq!(decode_udp(packet.link_type, &packet.data), Some(expected_datagram(&hex!("00 05 00 00"))));
+        assert_eq!(capture.next_packet(), Ok(None));
+    }
+
+    #[test]
+    fn read_pcapng_file() {
+        // big endian, raw IP interface with nanosecond timestamps
+        let file = [
+            &hex!(
+                "0a 0d 0d 0a 00 00 00 1c 1a 2b 3c 4d 00 01 00 00
+                 ff ff ff ff ff ff ff ff 00 00 00 1c
+                 00 00 00 01 00 00 00 20 00 65 00 00 00 00 ff ff
+                 00 09 00 01 09 00 00 00 00 00 00 00 00 00 00 20
+                 00 00 00 06 00 00 00 40 00 00 00 00 16 78 04 20
+                 b1 50 7d 00 00 00 00 20 00 00 00 20"
+            )[..],
+            &IPV4_UDP,
+            &hex!("00 00 00 40"),
+        ]
+        .concat();
+
+        let mut capture = Capture::new(&file[..]).unwrap();
+        let packet = capture.next_packet().unwrap().unwrap();
+        assert_eq!(packet.timestamp, Duration::from_millis(1_619_048_604_500));
+        assert_eq!(packet.link_type, LINKTYPE_RAW);
+        assert_eq!(decode_udp(packet.link_type, &packet.data), Some(expected_datagram(&hex!("00 05 00 00"))));
+        assert_eq!(capture.next_packet(), Ok(None));
+    }
+
+    #[test]
+    fn read_invalid_files() {
+        assert!(Capture::new(&hex!("00 01 02 03 04 05 06 07")[..]).is_err());
+        assert!(Capture::new(&[][..]).is_err());
+
+        // the record announces 46 bytes but the file ends after 4
+        let file = hex!(
+            "a1 b2 c3 d4 00 02 00 04 00 00 00 00 00 00 00 00
+             00 00 ff ff 00 00 00 01
+             60 80 b8 9c 00 07 a1 20 00 00 00 2e 00 00 00 2e
+             00 11 22 33"
+        );
+        let mut capture = Capture::new(&file[..]).unwrap();
+        assert_eq!(capture.next_packet(), Err("Capture file truncated".to_string()));
+    }
+
+    #[test]
+    fn decode_link_layers() {
+        let payload = hex!("00 05 00 00");
+
+        // ethernet with a VLAN tag
+        let vlan = [&hex!("00 11 22 33 44 55 66 77 88 99 aa bb 81 00 00 64 08 00")[..], &IPV4_UDP].concat();
+        assert_eq!(decode_udp(LINKTYPE_ETHERNET, &vlan), Some(expected_datagram(&payload)));
+
+        // linux cooked capture
+        let sll = [&hex!("00 00 00 01 00 06 00 11 22 33 44 55 00 00 08 00")[..], &IPV4_UDP].concat();
+        assert_eq!(decode_udp(LINKTYPE_LINUX_SLL, &sll), Some(expected_datagram(&payload)));
+
+        // BSD loopback, family in little endian
+        let null = [&hex!("02 00 00 00")[..], &IPV4_UDP].concat();
+        assert_eq!(decode_udp(LINKTYPE_NULL, &null), Some(expected_datagram(&payload)));
+
+        // unknown link type
+        assert_eq!(decode_udp(147, &IPV4_UDP), None);
+    }
+
+    #[test]
+    fn decode_ipv6_with_extension() {
+        // hop-by-hop options then UDP from [2001:db8::1]:4739 to [2001:db8::2]:4739
+        let packet = hex!(
+            "60 00 00 00 00 14 00 40 20 01 0d b8 00 00 00 00
+             00 00 00 00 00 00 00 01 20 01 0d b8 00 00 00 00
+             00 00 00 00 00 00 00 02 11 00 01 04 00 00 00 00
+             12 83 12 83 00 0c 00 00 00 0a 00 00"
+        );
+
+        let datagram = decode_udp(LINKTYPE_IPV6, &packet).unwrap();
+        assert_eq!(datagram.from, "[2001:db8::1]:4739".parse().unwrap());
+        assert_eq!(datagram.to, "[2001:db8::2]:4739".parse().unwrap());
+        assert_eq!(datagram.payload, hex!("00 0a 00 00"));
+    }
+
+    #[test]
+    fn skip_fragments_and_flag_truncated_datagrams() {
+        // more fragments flag set
+        let mut fragment = IPV4_UDP;
+        fragment[6] = 0x20;
+        assert_eq!(decode_udp(LINKTYPE_RAW, &fragment), None);
+
+        // not UDP
+        let mut tcp = IPV4_UDP;
+        tcp[9] = 6;
+        assert_eq!(decode_udp(LINKTYPE_RAW, &tcp), None);
+
+        // cut by the snapshot length in the middle of the payload
+        let datagram = decode_udp(LINKTYPE_RAW, &IPV4_UDP[..30]).unwrap();
+        assert_eq!(datagram.payload, hex!("00 05"));
+        assert!(datagram.truncated);
+
+        // cut in the middle of the UDP header
+        let datagram = decode_udp(LINKTYPE_RAW, &IPV4_UDP[..27]).unwrap();
+        assert!(datagram.payload.is_empty());
+        assert!(datagram.truncated);
+    }
+
+    #[test]
+    fn build_udp_packets() {
+        let packet = ipv4_udp_packet(&[1, 2, 3, 4], "192.0.2.1:4739".parse().unwrap(), "198.51.100.7:2055".parse().unwrap()).unwrap();
+        assert_eq!(
+            packet,
+            hex!(
+                "45 00 00 20 00 00 00 00 40 11 8e 91 c0 00 02 01
+                 c6 33 64 07 12 83 08 07 00 0c 00 00 01 02 03 04"
+            )
+        );
+        assert_eq!(internet_checksum(&[&packet[0..IPV4_HEADER_SIZE]]), 0);
+        assert!(ipv4_udp_packet(&vec![0; 65535 - 27], "192.0.2.1:1".parse().unwrap(), "192.0.2.2:1".parse().unwrap()).is_none());
+
+        let packet = ipv6_udp_packet(&[0, 10, 0], "[2001:db8::1]:4739".parse().unwrap(), "[2001:db8::2]:4739".parse().unwrap()).unwrap();
+        assert_eq!(
+            packet,
+            hex!(
+                "60 00 00 00 00 0b 11 40 20 01 0d b8 00 00 00 00
+                 00 00 00 00 00 00 00 01 20 01 0d b8 00 00 00 00
+                 00 00 00 00 00 00 00 02 12 83 12 83 00 0b 7f 53
+                 00 0a 00"
+            )
+        );
+    }
+
+    #[test]
+    fn write_and_read_capture() {
+        let timestamp = Duration::from_micros(1_619_048_604_500_001);
+        let mut file = file_header();
+        file.extend(udp_record(timestamp, &[0, 5], "192.0.2.1:54321".parse().unwrap(), "0.0.0.0:2055".parse().unwrap()).unwrap());
+        // received by a dual stack socket
+        file.extend(udp_record(timestamp, &[0, 10], "[::ffff:192.0.2.1]:54321".parse().unwrap(), "[::]:4739".parse().unwrap()).unwrap());
+        file.extend(udp_record(timestamp, &[0, 10], "[2001:db8::1]:4739".parse().unwrap(), "[::]:4739".parse().unwrap()).unwrap());
+
+        let mut capture = Capture::new(&file[..]).unwrap();
+        let mut datagrams = vec![];
+        while let Some(packet) = capture.next_packet().unwrap() {
+            assert_eq!(packet.timestamp, timestamp);
+            let datagram = decode_udp(packet.link_type, &packet.data).unwrap();
+            datagrams.push((datagram.from, datagram.to, datagram.payload.to_vec()));
+        }
+
+        assert_eq!(
+            datagrams,
+            vec![
+                ("192.0.2.1:54321".parse().unwrap(), "0.0.0.0:2055".parse().unwrap(), vec![0, 5]),
+                ("192.0.2.1:54321".parse().unwrap(), "0.0.0.0:4739".parse().unwrap(), vec![0, 10]),
+                ("[2001:db8::1]:4739".parse().unwrap(), "[::]:4739".parse().unwrap(), vec![0, 10]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,640 @@
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::columns::{self, Column, Value};
+use super::http;
+use super::json;
+use super::{BatchConfig, BatchOptions, Batcher, Sink};
+use crate::flow::ipfix::{DataType, FieldType};
+use crate::flow::FlowBatch;
+use crate::threads::clock::Clock;
+
+/******************************** CONFIGURATION ********************************/
+
+/// Batches of rows inserted through the HTTP interface of ClickHouse
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClickHouseSinkConfig {
+    /// host:port of the HTTP interface
+    pub target: String,
+    #[serde(default = "default_database")]
+    pub database: String,
+    #[serde(default = "default_table")]
+    pub table: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub format: InsertFormat,
+    /// Field type names or derived columns (exporter, exporter_name, tag, duration, start_time, end_time)
+    #[serde(default = "default_columns")]
+    pub columns: Vec<Column>,
+    /// Rows of an insert, 10000 rows or 10s by default, then 3 retries before the batch is spooled
+    #[serde(flatten)]
+    pub batch: BatchConfig,
+    /// Directory keeping the batches that couldn't be inserted, sent again once the server is back
+    #[serde(default)]
+    pub spool: Option<PathBuf>,
+    /// Spooled batches kept, the oldest ones are dropped
+    #[serde(default = "default_max_spool_files")]
+    pub max_spool_files: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum InsertFormat {
+    #[default]
+    RowBinary,
+    JSONEachRow,
+}
+
+fn default_database() -> String {
+    "default".to_string()
+}
+
+fn default_table() -> String {
+    "flows".to_string()
+}
+
+fn default_columns() -> Vec<Column> {
+    let mut columns = vec![Column::Exporter, Column::ExporterName, Column::Tag, Column::StartTime, Column::EndTime];
+    columns.extend(
+        [
+            FieldType::SourceIPv4Address,
+            FieldType::DestinationIPv4Address,
+            FieldType::SourceIPv6Address,
+            FieldType::DestinationIPv6Address,
+            FieldType::SourceTransportPort,
+            FieldType::DestinationTransportPort,
+            FieldType::ProtocolIdentifier,
+            FieldType::TcpControlBits,
+            FieldType::OctetDeltaCount,
+            FieldType::PacketDeltaCount,
+            FieldType::IngressInterface,
+            FieldType::EgressInterface,
+        ]
+        .iter()
+        .map(|&id| Column::Field(id)),
+    );
+    columns
+}
+
+pub const BATCH_DEFAULTS: BatchOptions = BatchOptions {
+    size: 10_000,
+    max_age: 10,
+    retries: 3,
+    backoff: 500,
+};
+
+fn default_max_spool_files() -> usize {
+    1000
+}
+
+impl ClickHouseSinkConfig {
+    /// Statement creating the table matching the columns of the sink
+    pub fn create_table(&self) -> String {
+        let mut sql = format!("CREATE TABLE IF NOT EXISTS `{}`.`{}`\n(\n", self.database, self.table);
+        for (i, column) in self.columns.iter().enumerate() {
+            let separator = if i + 1 < self.columns.len() { "," } else { "" };
+            writeln!(sql, "    `{}` {}{}", column.name(), ColumnType::of(column).sql(), separator).unwrap();
+        }
+        sql.push_str(")\nENGINE = MergeTree\n");
+        if self.columns.contains(&Column::Exporter) {
+            sql.push_str("ORDER BY exporter;\n");
+        } else {
+            sql.push_str("ORDER BY tuple();\n");
+        }
+        sql
+    }
+
+    /// INSERT statement of the batches, with the list of the columns
+    fn insert_query(&self) -> String {
+        let names: Vec<String> = self.columns.iter().map(|c| format!("`{}`", c.name())).collect();
+        format!("INSERT INTO `{}`.`{}` ({}) FORMAT {:?}", self.database, self.table, names.join(", "), self.format)
+    }
+}
+
+/******************************** COLUMN TYPES ********************************/
+
+/// ClickHouse type of a column, every column except the exporter is Nullable
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnType {
+    /// IPv6, the IPv4 exporters are mapped
+    Exporter,
+    LowCardinalityString,
+    UInt64,
+    Int64,
+    Float64,
+    Bool,
+    /// DateTime64(3, 'UTC')
+    DateTime64,
+    IPv4,
+    IPv6,
+    String,
+}
+
+impl ColumnType {
+    fn of(column: &Column) -> Self {
+        match column {
+            Column::Exporter => ColumnType::Exporter,
+            Column::ExporterName | Column::Tag => ColumnType::LowCardinalityString,
+            Column::Duration => ColumnType::UInt64,
+            Column::StartTime | Column::EndTime => ColumnType::DateTime64,
+            Column::Field(id) => match id.data_type() {
+                DataType::Unsigned => ColumnType::UInt64,
+                DataType::Signed => ColumnType::Int64,
+                DataType::Float64 => ColumnType::Float64,
+                DataType::Boolean => ColumnType::Bool,
+                DataType::DateTimeSeconds | DataType::DateTimeMilliseconds | DataType::DateTimeMicroseconds | DataType::DateTimeNanoseconds => ColumnType::DateTime64,
+                DataType::Ipv4Address => ColumnType::IPv4,
+                DataType::Ipv6Address => ColumnType::IPv6,
+                DataType::MacAddress | DataType::String | DataType::OctetArray | DataType::List => ColumnType::String,
+            },
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            ColumnType::Exporter => "IPv6",
+            ColumnType::LowCardinalityString => "LowCardinality(Nullable(String))",
+            ColumnType::UInt64 => "Nullable(UInt64)",
+            ColumnType::Int64 => "Nullable(Int64)",
+            ColumnType::Float64 => "Nullable(Float64)",
+            ColumnType::Bool => "Nullable(Bool)",
+            ColumnType::DateTime64 => "Nullable(DateTime64(3, 'UTC'))",
+            ColumnType::IPv4 => "Nullable(IPv4)",
+            ColumnType::IPv6 => "Nullable(IPv6)",
+            ColumnType::String => "Nullable(String)",
+        }
+    }
+
+    /// Value converted to the type of the column, None for a missing value or a value that doesn't fit the column
+    fn convert(&self, value: Option<Value>) -> Option<Cell> {
+        Some(match (self, value?) {
+            (ColumnType::Exporter, Value::Address(IpAddr::V4(addr))) => Cell::IPv6(addr.to_ipv6_mapped()),
+            (ColumnType::Exporter, Value::Address(IpAddr::V6(addr))) | (ColumnType::IPv6, Value::Address(IpAddr::V6(addr))) => Cell::IPv6(addr),
+            (ColumnType::IPv4, Value::Address(IpAddr::V4(addr))) => Cell::IPv4(addr.into()),
+            (ColumnType::UInt64, Value::Unsigned(v)) if v <= u64::MAX as u128 => Cell::UInt64(v as u64),
+            (ColumnType::Int64, Value::Signed(v)) => Cell::Int64(v),
+            (ColumnType::Float64, Value::Float(v)) => Cell::Float64(v),
+            (ColumnType::Bool, Value::Boolean(v)) => Cell::Bool(v),
+            (ColumnType::DateTime64, Value::Timestamp(millis)) => Cell::DateTime64(millis),
+            (ColumnType::String, value) | (ColumnType::LowCardinalityString, value) => Cell::String(value.to_string()),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Cell {
+    UInt64(u64),
+    Int64(i64),
+    Float64(f64),
+    Bool(bool),
+    DateTime64(u64),
+    IPv4(u32),
+    IPv6(Ipv6Addr),
+    String(String),
+}
+
+impl Cell {
+    /// Little-endian values, the IPv6 addresses in network order and the strings prefixed with their LEB128 length
+    fn write_binary(&self, buf: &mut Vec<u8>) {
+        match self {
+            Cell::UInt64(v) | Cell::DateTime64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+            Cell::Int64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+            Cell::Float64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+            Cell::Bool(v) => buf.push(*v as u8),
+            Cell::IPv4(v) => buf.extend_from_slice(&v.to_le_bytes()),
+            Cell::IPv6(addr) => buf.extend_from_slice(&addr.octets()),
+            Cell::String(s) => {
+                let mut length = s.len();
+                while length >= 0x80 {
+                    buf.push((length as u8) | 0x80);
+                    length >>= 7;
+                }
+                buf.push(length as u8);
+                buf.extend_from_slice(s.as_bytes());
+            }
+        }
+    }
+
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Cell::UInt64(v) => write!(out, "{}", v).unwrap(),
+            Cell::Int64(v) => write!(out, "{}", v).unwrap(),
+            Cell::Float64(v) if v.is_finite() => write!(out, "{}", v).unwrap(),
+            Cell::Float64(_) => out.push_str("null"),
+            Cell::Bool(v) => write!(out, "{}", v).unwrap(),
+            // 2021-04-21 23:43:24.500
+            Cell::DateTime64(millis) => {
+                let time = columns::rfc3339_millis(*millis).to_string();
+                json::write_string(out, &time.replace('T', " ").replace('Z', ""));
+            }
+            Cell::IPv4(v) => json::write_string(out, &std::net::Ipv4Addr::from(*v).to_string()),
+            Cell::IPv6(addr) => json::write_string(out, &addr.to_string()),
+            Cell::String(s) => json::write_string(out, s),
+        }
+    }
+}
+
+/******************************** SINK ********************************/
+
+pub struct ClickHouseSink {
+    config: ClickHouseSinkConfig,
+    types: Vec<ColumnType>,
+    query: String,
+    /// Encoded rows of the next insert
+    rows: Batcher<Vec<u8>>,
+    clock: Clock,
+}
+
+/// Why an insert failed
+enum InsertError {
+    /// The server is down or overloaded, the batch can be sent again later
+    Unavailable(String),
+    /// The server refused the batch
+    Rejected(String),
+}
+
+impl ClickHouseSink {
+    pub fn new(config: ClickHouseSinkConfig) -> Result<Self, String> {
+        if config.columns.is_empty() {
+            return Err("The ClickHouse sink needs at least one column".to_string());
+        }
+        let options = config.batch.or(BATCH_DEFAULTS);
+        if options.size == 0 {
+            return Err("The batches of the ClickHouse sink need at least 1 row".to_string());
+        }
+        if let Some(spool) = &config.spool {
+            fs::create_dir_all(spool).map_err(|e| format!("Failed to create the spool directory {} : {}", spool.display(), e))?;
+        }
+        info!(
+            "Inserting the flows into ClickHouse {} table {}.{} as {:?}",
+            config.target, config.database, config.table, config.format
+        );
+
+        Ok(ClickHouseSink {
+            types: config.columns.iter().map(ColumnType::of).collect(),
+            query: config.insert_query(),
+            config,
+            rows: Batcher::new(options),
+            clock: Clock::default(),
+        })
+    }
+
+    fn encode_row(&self, batch: &FlowBatch, fields: &[(FieldType, crate::flow::ipfix::FieldValue)]) -> Vec<u8> {
+        let mut row = vec![];
+        let cells = self
+            .config
+            .columns
+            .iter()
+            .zip(&self.types)
+            .map(|(column, column_type)| column_type.convert(column.value(batch, fields)));
+
+        match self.config.format {
+            InsertFormat::RowBinary => {
+                for (cell, column_type) in cells.zip(&self.types) {
+                    match (cell, column_type) {
+                        // never missing
+                        (Some(cell), ColumnType::Exporter) => cell.write_binary(&mut row),
+                        (Some(cell), _) => {
+                            row.push(0);
+                            cell.write_binary(&mut row);
+                        }
+                        (None, _) => row.push(1),
+                    }
+                }
+            }
+            InsertFormat::JSONEachRow => {
+                let mut line = String::from("{");
+                for (i, (cell, column)) in cells.zip(&self.config.columns).enumerate() {
+                    if i > 0 {
+                        line.push(',');
+                    }
+                    json::write_string(&mut line, &column.name());
+                    line.push(':');
+                    match cell {
+                        Some(cell) => cell.write_json(&mut line),
+                        None => line.push_str("null"),
+                    }
+                }
+                line.push_str("}\n");
+                row.extend_from_slice(line.as_bytes());
+            }
+        }
+        row
+    }
+
+    fn insert(&self, query: &str, body: &[u8]) -> Result<(), InsertError> {
+        let mut headers = vec![];
+        if let Some(user) = &self.config.user {
+            headers.push(("X-ClickHouse-User", user.as_str()));
+        }
+        if let Some(password) = &self.config.password {
+            headers.push(("X-ClickHouse-Key", password.as_str()));
+        }
+
+        let path = format!("/?query={}", http::url_encode(query));
+        match http::post(&self.config.target, &path, &headers, body) {
+            Ok(response) if response.is_success() => Ok(()),
+            Ok(response) if response.is_client_error() => Err(InsertError::Rejected(response.error())),
+            Ok(response) => Err(InsertError::Unavailable(response.error())),
+            Err(e) => Err(InsertError::Unavailable(e.to_string())),
+        }
+    }
+
+    /// Insert the rows after the spooled batches, with the retries and their exponential backoff
+    fn send(&self, rows: Vec<Vec<u8>>) -> Result<(), String> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        // the server is still down if the oldest spooled batch can't be sent, the new one joins it without waiting for the retries
+        let mut last_error = String::new();
+        let unsent = match self.send_spooled() {
+            Ok(()) => super::send_with_retries(rows, self.rows.options(), "ClickHouse rows", |rows| match self.insert(&self.query, &rows.concat()) {
+                Ok(()) => Ok(vec![]),
+                Err(InsertError::Rejected(e)) => Err(format!("ClickHouse rejected {} rows : {}", rows.len(), e)),
+                Err(InsertError::Unavailable(e)) => {
+                    warn!("ClickHouse insert failed : {}", e);
+                    last_error = e;
+                    Ok(rows)
+                }
+            })?,
+            Err(e) => {
+                last_error = e;
+                rows
+            }
+        };
+
+        if unsent.is_empty() {
+            return Ok(());
+        }
+        match &self.config.spool {
+            Some(spool) => {
+                self.spool(spool, &unsent.concat())?;
+                Err(format!("ClickHouse unavailable, {} rows spooled : {}", unsent.len(), last_error))
+            }
+            None => Err(format!("ClickHouse unavailable, {} rows dropped : {}", unsent.len(), last_error)),
+        }
+    }
+
+    /******************************** SPOOL ********************************/
+
+    /// Spooled batches, oldest first
+    fn spooled_files(spool: &Path) -> Result<Vec<PathBuf>, String> {
+        let entries = fs::read_dir(spool).map_err(|e| format!("Failed to read the spool directory {} : {}", spool.display(), e))?;
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "batch"))
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /// A spool file holds the insert query on its first line and the batch after it, so that it can be sent after a change of the format or columns
+    fn spool(&self, spool: &Path, body: &[u8]) -> Result<(), String> {
+        let mut files = Self::spooled_files(spool)?;
+        while files.len() >= self.config.max_spool_files.max(1) {
+            let oldest = files.remove(0);
+            warn!("ClickHouse spool full, dropping {}", oldest.display());
+            let _ = fs::remove_file(oldest);
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let mut path = spool.join(format!("{:013}.batch", now));
+        let mut i = 1;
+        while path.exists() {
+            path = spool.join(format!("{:013}-{:03}.batch", now, i));
+            i += 1;
+        }
+
+        let mut content = Vec::with_capacity(self.query.len() + 1 + body.len());
+        content.extend_from_slice(self.query.as_bytes());
+        content.push(b'\n');
+        content.extend_from_slice(body);
+
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, content).map_err(|e| format!("Failed to write the spool file {} : {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, &path).map_err(|e| format!("Failed to rename the spool file {} : {}", tmp_path.display(), e))
+    }
+
+    /// Insert the spooled batches in their order, stopping at the first that can't be sent
+    fn send_spooled(&self) -> Result<(), String> {
+        let spool = match &self.config.spool {
+            Some(spool) => spool,
+            None => return Ok(()),
+        };
+
+        for path in Self::spooled_files(spool)? {
+            let content = fs::read(&path).map_err(|e| format!("Failed to read the spool file {} : {}", path.display(), e))?;
+            let (query, body) = match content.iter().position(|&b| b == b'\n') {
+                Some(i) => (String::from_utf8_lossy(&content[..i]).to_string(), &content[i + 1..]),
+                None => {
+                    error!("Invalid spool file {}, removed", path.display());
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            };
+
+            match self.insert(&query, body) {
+                Ok(()) => info!("Spooled ClickHouse batch {} inserted", path.display()),
+                Err(InsertError::Rejected(e)) => error!("Spooled ClickHouse batch {} rejected, removed : {}", path.display(), e),
+                Err(InsertError::Unavailable(e)) => return Err(e),
+            }
+            let _ = fs::remove_file(&path);
+        }
+        Ok(())
+    }
+}
+
+impl Sink for ClickHouseSink {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String> {
+        let rows: Vec<_> = batch.flows.iter().map(|flow| self.encode_row(batch, &flow.fields())).collect();
+        let ready = self.rows.add(rows, self.clock.now_secs());
+        super::send_all(ready, |rows| self.send(rows))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        let rows = self.rows.take();
+        self.send(rows)
+    }
+
+    fn tick(&mut self) -> Result<(), String> {
+        if !self.rows.expired(self.clock.now_secs()) {
+            return Ok(());
+        }
+        let rows = self.rows.take();
+        self.send(rows)
+    }
+}
+
+impl Drop for ClickHouseSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::ipfix::FieldValue;
+    use crate::sinks::fixture;
+    use crate::sinks::http::mock;
+    use hex_literal::hex;
+
+    fn config(target: &str, format: InsertFormat) -> ClickHouseSinkConfig {
+        ClickHouseSinkConfig {
+            target: target.to_string(),
+            database: "netflow".to_string(),
+            table: "flows".to_string(),
+            user: Some("collector".to_string()),
+            password: None,
+            format,
+            columns: ["exporter", "tag", "end_time", "sourceIPv4Address", "destinationTransportPort", "octetDeltaCount", "interfaceName"]
+                .iter()
+                .map(|c| c.parse().unwrap())
+                .collect(),
+            batch: BatchConfig {
+                batch_size: Some(2),
+                max_age: Some(10),
+                retries: Some(1),
+                backoff: Some(1),
+            },
+            spool: None,
+            max_spool_files: 10,
+        }
+    }
+
+    fn batch(count: usize) -> FlowBatch {
+        fixture::batch((0..count).map(|_| fixture::flow()))
+    }
+
+    #[test]
+    fn insert_row_binary() {
+        let (target, server) = mock::serve(vec![(200, "")]);
+        let mut sink = ClickHouseSink::new(config(&target, InsertFormat::RowBinary)).unwrap();
+        sink.write(&batch(2)).unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests[0].line,
+            "POST /?query=INSERT%20INTO%20%60netflow%60.%60flows%60%20%28%60exporter%60%2C%20%60tag%60%2C%20%60end_time%60%2C%20%60sourceIPv4Address%60%2C%20\
+             %60destinationTransportPort%60%2C%20%60octetDeltaCount%60%2C%20%60interfaceName%60%29%20FORMAT%20RowBinary HTTP/1.1"
+        );
+        assert!(requests[0].headers.contains(&"X-ClickHouse-User: collector".to_string()));
+        let row = hex!(
+            "00 00 00 00 00 00 00 00 00 00 ff ff c0 00 02 01
+             00 04 63 6f 72 65
+             00 54 23 d1 f6 78 01 00 00
+             00 01 00 00 0a
+             00 bb 01 00 00 00 00 00 00
+             00 dc 05 00 00 00 00 00 00
+             01"
+        );
+        assert_eq!(requests[0].body, [row, row].concat());
+    }
+
+    #[test]
+    fn insert_json_each_row() {
+        let (target, server) = mock::serve(vec![(200, "")]);
+        let mut sink = ClickHouseSink::new(config(&target, InsertFormat::JSONEachRow)).unwrap();
+        let mut fields = std::collections::HashMap::new();
+        fields.insert(FieldType::InterfaceName, FieldValue::Dyn(b"eth\"0".to_vec()));
+        let flows = FlowBatch {
+            tag: None,
+            exporter: "2001:db8::1".parse().unwrap(),
+            exporter_name: None,
+            flows: vec![Box::new(crate::flow::ipfix::DataSet { fields })],
+        };
+        sink.write(&flows).unwrap();
+        sink.flush().unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].line.ends_with("FORMAT%20JSONEachRow HTTP/1.1"));
+        assert_eq!(
+            String::from_utf8_lossy(&requests[0].body),
+            "{\"exporter\":\"2001:db8::1\",\"tag\":null,\"end_time\":null,\"sourceIPv4Address\":null,\"destinationTransportPort\":null,\"octetDeltaCount\":null,\"interfaceName\":\"eth\\\"0\"}\n"
+        );
+    }
+
+    #[test]
+    fn spool_while_unavailable() {
+        let spool = std::env::temp_dir().join(format!("ipfix-clickhouse-{}", std::process::id()));
+        // the first batch fails twice, the second one is spooled without retry, then both are sent
+        let (target, server) = mock::serve(vec![(503, "down"), (503, "down"), (503, "down"), (200, ""), (200, ""), (200, "")]);
+        let mut config = config(&target, InsertFormat::RowBinary);
+        config.spool = Some(spool.clone());
+        let mut sink = ClickHouseSink::new(config).unwrap();
+
+        assert_eq!(sink.write(&batch(2)), Err("ClickHouse unavailable, 2 rows spooled : HTTP 503 : down".to_string()));
+        assert!(sink.write(&batch(2)).is_err());
+        assert_eq!(ClickHouseSink::spooled_files(&spool).unwrap().len(), 2);
+
+        sink.write(&batch(1)).unwrap();
+        sink.flush().unwrap();
+        assert!(ClickHouseSink::spooled_files(&spool).unwrap().is_empty());
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 6);
+        assert_eq!(requests[3].body, requests[0].body);
+        assert_eq!(requests[3].line, requests[0].line);
+        assert_eq!(requests[5].body.len(), requests[0].body.len() / 2);
+
+        fs::remove_dir_all(&spool).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_batches() {
+        let (target, server) = mock::serve(vec![(400, "Code: 62. DB::Exception: Syntax error")]);
+        let mut sink = ClickHouseSink::new(config(&target, InsertFormat::RowBinary)).unwrap();
+        assert_eq!(sink.write(&batch(2)), Err("ClickHouse rejected 2 rows : HTTP 400 : Code: 62. DB::Exception: Syntax error".to_string()));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn send_the_batches_after_a_failed_one() {
+        let (target, server) = mock::serve(vec![(400, "Code: 27. DB::Exception: Cannot parse input"), (200, ""), (200, "")]);
+        let mut sink = ClickHouseSink::new(config(&target, InsertFormat::RowBinary)).unwrap();
+
+        // the first insert of the 5 flows is rejected, the second one is still sent and the last flow waits for the next batch
+        assert_eq!(
+            sink.write(&batch(5)),
+            Err("ClickHouse rejected 2 rows : HTTP 400 : Code: 27. DB::Exception: Cannot parse input".to_string())
+        );
+        sink.flush().unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].body, requests[0].body);
+        assert_eq!(requests[2].body.len(), requests[0].body.len() / 2);
+    }
+
+    #[test]
+    fn create_table_statement() {
+        let config = config("localhost:8123", InsertFormat::RowBinary);
+        assert_eq!(
+            config.create_table(),
+            "CREATE TABLE IF NOT EXISTS `netflow`.`flows`\n\
+             (\n    \
+                 `exporter` IPv6,\n    \
+                 `tag` LowCardinality(Nullable(String)),\n    \
+                 `end_time` Nullable(DateTime64(3, 'UTC')),\n    \
+                 `sourceIPv4Address` Nullable(IPv4),\n    \
+                 `destinationTransportPort` Nullable(UInt64),\n    \
+                 `octetDeltaCount` Nullable(UInt64),\n    \
+                 `interfaceName` Nullable(String)\n\
+             )\n\
+             ENGINE = MergeTree\n\
+             ORDER BY exporter;\n"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::flow::ipfix::{ntp_to_millis, DataType, FieldType, FieldValue};
+use crate::flow::FlowBatch;
+
+/******************************** COLUMN ********************************/
+
+/// One column of a tabular sink: an information element of the flows, or a value derived from them and from their batch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Field(FieldType),
+    /// Address of the exporter, as reported or mapped in the configuration
+    Exporter,
+    ExporterName,
+    /// Tag of the listener
+    Tag,
+    /// Flow duration in milliseconds
+    Duration,
+    /// Absolute flow start time
+    StartTime,
+    /// Absolute flow end time
+    EndTime,
+}
+
+impl Column {
+    /// Value of the column for one flow of the batch, None if the flow doesn't have it.
+    /// fields are the information elements of the flow, sorted by field type.
+    pub fn value(&self, batch: &FlowBatch, fields: &[(FieldType, FieldValue)]) -> Option<Value> {
+        let get = |id: FieldType| fields.binary_search_by_key(&id, |&(t, _)| t).ok().map(|i| &fields[i].1);
+        let millis = |id: FieldType| get(id).and_then(FieldValue::as_u128).map(|v| v as u64);
+
+        match *self {
+            Column::Field(id) => get(id).map(|value| Value::from_field(id, value)),
+            Column::Exporter => Some(Value::Address(batch.exporter)),
+            Column::ExporterName => batch.exporter_name.as_deref().map(|name| Value::Text(name.to_string())),
+            Column::Tag => batch.tag.as_deref().map(|tag| Value::Text(tag.to_string())),
+            // the netflow v5 records without the boot time of their exporter only have relative timestamps
+            Column::Duration => match (millis(FieldType::FlowStartMilliseconds), millis(FieldType::FlowEndMilliseconds)) {
+                (Some(start), Some(end)) => Some(Value::Unsigned(end.saturating_sub(start) as u128)),
+                _ => Some(Value::Unsigned(millis(FieldType::FlowEndSysUpTime)?.saturating_sub(millis(FieldType::FlowStartSysUpTime)?) as u128)),
+            },
+            Column::StartTime => millis(FieldType::FlowStartMilliseconds).filter(|&millis| millis < TIMESTAMP_LIMIT).map(Value::Timestamp),
+            Column::EndTime => millis(FieldType::FlowEndMilliseconds).filter(|&millis| millis < TIMESTAMP_LIMIT).map(Value::Timestamp),
+        }
+    }
+}
+
+impl Column {
+    /// Name of the column in the files, the IANA name for the field types
+    pub fn name(&self) -> String {
+        match self {
+            Column::Field(id) => {
+                let name = format!("{:?}", id);
+                // the IANA names start with a lower case letter, except the acronyms (IPv4, ...)
+                match name.chars().nth(1) {
+                    Some(c) if c.is_ascii_uppercase() => name,
+                    _ => name[0..1].to_ascii_lowercase() + &name[1..],
+                }
+            }
+            Column::Exporter => "exporter".to_string(),
+            Column::ExporterName => "exporter_name".to_string(),
+            Column::Tag => "tag".to_string(),
+            Column::Duration => "duration".to_string(),
+            Column::StartTime => "start_time".to_string(),
+            Column::EndTime => "end_time".to_string(),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "exporter" => Column::Exporter,
+            "exporter_name" => Column::ExporterName,
+            "tag" => Column::Tag,
+            "duration" => Column::Duration,
+            "start_time" => Column::StartTime,
+            "end_time" => Column::EndTime,
+            _ => Column::Field(FieldType::from_name(s).ok_or_else(|| format!("Unknown column '{}', expected a field type name or a derived column", s))?),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Column {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::config::from_str(deserializer)
+    }
+}
+
+/******************************** VALUE ********************************/
+
+/// 10000-01-01T00:00:00Z in milliseconds since the UNIX epoch, the later times sent by the exporters are kept as integers
+pub const TIMESTAMP_LIMIT: u64 = 253_402_300_800_000;
+
+/// RFC 3339 date and time to the millisecond, the times past the year 9999 are clamped to its last millisecond
+pub fn rfc3339_millis(millis: u64) -> humantime::Rfc3339Timestamp {
+    humantime::format_rfc3339_millis(UNIX_EPOCH + Duration::from_millis(millis.min(TIMESTAMP_LIMIT - 1)))
+}
+
+/// RFC 3339 date and time to the second, clamped like rfc3339_millis
+pub fn rfc3339_seconds(secs: u64) -> humantime::Rfc3339Timestamp {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs.min(TIMESTAMP_LIMIT / 1000 - 1)))
+}
+
+/// Value of a column, typed for the sinks that keep the types
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Counters, identifiers, ports, ... and the durations in milliseconds
+    Unsigned(u128),
+    Signed(i64),
+    Float(f64),
+    Boolean(bool),
+    Address(IpAddr),
+    Mac([u8; 6]),
+    /// Milliseconds since the UNIX epoch
+    Timestamp(u64),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    /// Typed from the data type of the field, the values that don't match it (reduced to another size, ...) are kept as integers or bytes
+    pub fn from_field(id: FieldType, value: &FieldValue) -> Self {
+        match (id.data_type(), value) {
+            (DataType::Ipv4Address, FieldValue::U32(v)) => Value::Address(Ipv4Addr::from(*v).into()),
+            (DataType::Ipv6Address, FieldValue::U128(v)) => Value::Address(Ipv6Addr::from(*v).into()),
+            (DataType::MacAddress, FieldValue::Dyn(v)) if v.len() == 6 => Value::Mac([v[0], v[1], v[2], v[3], v[4], v[5]]),
+            (DataType::Signed, FieldValue::U8(v)) => Value::Signed(*v as i8 as i64),
+            (DataType::Signed, FieldValue::U16(v)) => Value::Signed(*v as i16 as i64),
+            (DataType::Signed, FieldValue::U32(v)) => Value::Signed(*v as i32 as i64),
+            (DataType::Signed, FieldValue::U64(v)) => Value::Signed(*v as i64),
+            (DataType::Float64, FieldValue::U32(v)) => Value::Float(f32::from_bits(*v) as f64),
+            (DataType::Float64, FieldValue::U64(v)) => Value::Float(f64::from_bits(*v)),
+            // true is 1 and false is 2 (RFC 7011 section 6.1.5)
+            (DataType::Boolean, FieldValue::U8(v)) => Value::Boolean(*v == 1),
+            (DataType::DateTimeSeconds, FieldValue::U32(v)) => Value::Timestamp(*v as u64 * 1000),
+            (DataType::DateTimeMilliseconds, FieldValue::U64(v)) if *v < TIMESTAMP_LIMIT => Value::Timestamp(*v),
+            (DataType::DateTimeMicroseconds, FieldValue::U64(v)) | (DataType::DateTimeNanoseconds, FieldValue::U64(v)) => match ntp_to_millis(*v) {
+                Some(millis) => Value::Timestamp(millis),
+                None => Value::Unsigned(*v as u128),
+            },
+            (DataType::OctetArray, FieldValue::Dyn(v)) | (DataType::List, FieldValue::Dyn(v)) => Value::Bytes(v.clone()),
+            (DataType::String, FieldValue::Dyn(v)) => Value::Text(String::from_utf8_lossy(v).trim_end_matches('\0').to_string()),
+            // the variable-length fields of the enterprise or unknown types holding printable text are kept as text
+            (_, FieldValue::Dyn(v)) => match std::str::from_utf8(v) {
+                Ok(text) if !text.is_empty() && text.chars().all(|c| !c.is_control() || c == '\0') => Value::Text(text.trim_end_matches('\0').to_string()),
+                _ => Value::Bytes(v.clone()),
+            },
+            _ => Value::Unsigned(value.as_u128().unwrap_or_default()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Unsigned(v) => v.fmt(f),
+            Value::Signed(v) => v.fmt(f),
+            Value::Float(v) => v.fmt(f),
+            Value::Boolean(v) => v.fmt(f),
+            Value::Address(addr) => addr.fmt(f),
+            Value::Mac(mac) => write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]),
+            Value::Timestamp(millis) => rfc3339_millis(*millis).fmt(f),
+            Value::Text(text) => text.fmt(f),
+            Value::Bytes(bytes) => bytes.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn parse_columns() {
+        assert_eq!("exporter".parse(), Ok(Column::Exporter));
+        assert_eq!("sourceIPv4Address".parse(), Ok(Column::Field(FieldType::SourceIPv4Address)));
+        assert_eq!("OctetDeltaCount".parse(), Ok(Column::Field(FieldType::OctetDeltaCount)));
+        assert!("foo".parse::<Column>().is_err());
+        assert_eq!(Column::Field(FieldType::SourceIPv4Address).name(), "sourceIPv4Address");
+        assert_eq!(Column::Field(FieldType::IPClassOfService).name(), "IPClassOfService");
+        assert_eq!(Column::Duration.name(), "duration");
+    }
+
+    #[test]
+    fn read_column_values() {
+        let batch = FlowBatch {
+            tag: Some(Arc::from("core")),
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: None,
+            flows: vec![],
+        };
+        let mut fields = vec![
+            (FieldType::SourceIPv4Address, FieldValue::U32(0x0a00_0001)),
+            (FieldType::SourceMacAddress, FieldValue::Dyn(vec![0, 0x11, 0x22, 0x33, 0x44, 0x55])),
+            (FieldType::FlowStartMilliseconds, FieldValue::U64(1_619_048_604_500)),
+            (FieldType::FlowEndMilliseconds, FieldValue::U64(1_619_048_605_750)),
+            (FieldType::InterfaceName, FieldValue::Dyn(b"eth0".to_vec())),
+        ];
+        fields.sort_by_key(|&(t, _)| t);
+
+        let value = |column: &str| column.parse::<Column>().unwrap().value(&batch, &fields).map(|v| v.to_string());
+        assert_eq!(value("sourceIPv4Address").as_deref(), Some("10.0.0.1"));
+        assert_eq!(value("sourceMacAddress").as_deref(), Some("00:11:22:33:44:55"));
+        assert_eq!(value("interfaceName").as_deref(), Some("eth0"));
+        assert_eq!(value("start_time").as_deref(), Some("2021-04-21T23:43:24.500Z"));
+        assert_eq!(value("duration").as_deref(), Some("1250"));
+        assert_eq!(value("exporter").as_deref(), Some("192.0.2.1"));
+        assert_eq!(value("tag").as_deref(), Some("core"));
+        assert_eq!(value("exporter_name"), None);
+        assert_eq!(value("destinationIPv4Address"), None);
+        assert_eq!(value("flowEndMilliseconds").as_deref(), Some("2021-04-21T23:43:25.750Z"));
+        assert_eq!(Value::from_field(FieldType::Dot1qDEI, &FieldValue::U8(2)), Value::Boolean(false));
+        assert_eq!(Value::from_field(FieldType::MibObjectValueInteger, &FieldValue::U32(0xffff_fffe)), Value::Signed(-2));
+        assert_eq!(Value::from_field(FieldType::SamplingProbability, &FieldValue::U64(0.25f64.to_bits())), Value::Float(0.25));
+    }
+
+    #[test]
+    fn read_timestamps_past_year_9999() {
+        let batch = FlowBatch {
+            tag: None,
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: None,
+            flows: vec![],
+        };
+        let fields = vec![
+            (FieldType::FlowStartMilliseconds, FieldValue::U64(TIMESTAMP_LIMIT - 1)),
+            (FieldType::FlowEndMilliseconds, FieldValue::U64(u64::MAX)),
+        ];
+
+        assert_eq!(Column::StartTime.value(&batch, &fields).map(|v| v.to_string()).as_deref(), Some("9999-12-31T23:59:59.999Z"));
+        assert_eq!(Column::EndTime.value(&batch, &fields), None);
+        assert_eq!(Column::Field(FieldType::FlowEndMilliseconds).value(&batch, &fields), Some(Value::Unsigned(u64::MAX as u128)));
+        assert_eq!(Value::Timestamp(u64::MAX).to_string(), "9999-12-31T23:59:59.999Z");
+        assert_eq!(rfc3339_seconds(u64::MAX).to_string(), "9999-12-31T23:59:59Z");
+    }
+}
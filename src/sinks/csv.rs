@@ -0,0 +1,246 @@
+use log::{error, info};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use super::columns::{self, Column};
+use super::Sink;
+use crate::flow::FlowBatch;
+use crate::threads::clock::Clock;
+
+/******************************** CONFIGURATION ********************************/
+
+/// Flat CSV files with one flow per line, completed and renamed at the end of each period
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CsvSinkConfig {
+    /// Directory of the files, named prefix-YYYYMMDDTHHMMSSZ.csv after the start of their period
+    pub directory: PathBuf,
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Field type names (sourceIPv4Address, ...) or derived columns: exporter, exporter_name, tag, duration, start_time, end_time
+    pub columns: Vec<Column>,
+    /// Seconds covered by a file, the periods are aligned on the UNIX epoch
+    #[serde(default = "default_rotate")]
+    pub rotate: u64,
+}
+
+fn default_prefix() -> String {
+    "flows".to_string()
+}
+
+fn default_rotate() -> u64 {
+    3600
+}
+
+/******************************** SINK ********************************/
+
+pub struct CsvSink {
+    config: CsvSinkConfig,
+    /// File being written, with the end of its period
+    current: Option<(CsvFile, u64)>,
+    clock: Clock,
+}
+
+struct CsvFile {
+    out: BufWriter<File>,
+    /// Name while the file is written, the loaders only pick the completed files
+    tmp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl CsvSink {
+    pub fn new(config: CsvSinkConfig) -> Result<Self, String> {
+        if config.columns.is_empty() {
+            return Err("The CSV sink needs at least one column".to_string());
+        }
+        if config.rotate == 0 {
+            return Err("The rotation period of the CSV sink must be at least 1 second".to_string());
+        }
+        fs::create_dir_all(&config.directory).map_err(|e| format!("Failed to create the directory {} : {}", config.directory.display(), e))?;
+
+        Ok(CsvSink {
+            config,
+            current: None,
+            clock: Clock::default(),
+        })
+    }
+
+    /// File of the current period, the previous one is completed when its period is over
+    fn file(&mut self) -> Result<&mut CsvFile, String> {
+        let now = self.clock.now_secs();
+        if self.current.as_ref().is_some_and(|(_, end)| now >= *end) {
+            self.complete()?;
+        }
+
+        if self.current.is_none() {
+            let start = now - now % self.config.rotate;
+            let file = self.create(start)?;
+            self.current = Some((file, start + self.config.rotate));
+        }
+
+        Ok(&mut self.current.as_mut().unwrap().0)
+    }
+
+    fn create(&self, start: u64) -> Result<CsvFile, String> {
+        let stamp = columns::rfc3339_seconds(start).to_string().replace(['-', ':'], "");
+
+        // a file of the same period may exist after a restart, it is kept
+        let mut path = self.config.directory.join(format!("{}-{}.csv", self.config.prefix, stamp));
+        let mut i = 1;
+        while path.exists() {
+            path = self.config.directory.join(format!("{}-{}-{}.csv", self.config.prefix, stamp, i));
+            i += 1;
+        }
+
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let file = File::create(&tmp_path).map_err(|e| format!("Failed to create {} : {}", tmp_path.display(), e))?;
+        let mut csv = CsvFile {
+            out: BufWriter::new(file),
+            tmp_path,
+            path,
+        };
+
+        let header: Vec<String> = self.config.columns.iter().map(Column::name).collect();
+        csv.write_line(&header)?;
+        Ok(csv)
+    }
+
+    /// Flush the current file and give it its final name
+    fn complete(&mut self) -> Result<(), String> {
+        if let Some((mut csv, _)) = self.current.take() {
+            csv.out.flush().map_err(|e| format!("Failed to write {} : {}", csv.tmp_path.display(), e))?;
+            fs::rename(&csv.tmp_path, &csv.path).map_err(|e| format!("Failed to rename {} : {}", csv.tmp_path.display(), e))?;
+            info!("CSV file {} completed", csv.path.display());
+        }
+        Ok(())
+    }
+}
+
+impl CsvFile {
+    fn write_line(&mut self, values: &[String]) -> Result<(), String> {
+        let line: Vec<String> = values.iter().map(|v| escape(v)).collect();
+        writeln!(self.out, "{}", line.join(",")).map_err(|e| format!("Failed to write {} : {}", self.tmp_path.display(), e))
+    }
+}
+
+impl Sink for CsvSink {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String> {
+        let columns = self.config.columns.clone();
+        let file = self.file()?;
+
+        for flow in &batch.flows {
+            let fields = flow.fields();
+            let values: Vec<String> = columns.iter().map(|c| c.value(batch, &fields).map(|v| v.to_string()).unwrap_or_default()).collect();
+            file.write_line(&values)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match &mut self.current {
+            Some((csv, _)) => csv.out.flush().map_err(|e| format!("Failed to write {} : {}", csv.tmp_path.display(), e)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CsvSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.complete() {
+            error!("{}", e);
+        }
+    }
+}
+
+/// Quote the values holding a comma, a quote or a line break (RFC 4180)
+fn escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::ipfix::{self, FieldType, FieldValue};
+    use crate::flow::netflow5;
+    use crate::sinks::fixture;
+    use std::sync::Arc;
+
+    fn batch(exporter_name: Option<&str>) -> FlowBatch {
+        let mut batch = fixture::batch(vec![netflow5::DataSet { end_time: 3500, ..fixture::flow() }]);
+        batch.tag = None;
+        batch.exporter_name = exporter_name.map(Arc::from);
+        batch.flows.push(Box::new(ipfix_flow()));
+        batch
+    }
+
+    /// IPFIX flow with absolute timestamps and without a destination address
+    fn ipfix_flow() -> ipfix::DataSet {
+        let mut fields = std::collections::HashMap::new();
+        fields.insert(FieldType::SourceIPv4Address, FieldValue::U32(0x0a00_0002));
+        fields.insert(FieldType::OctetDeltaCount, FieldValue::U64(64));
+        fields.insert(FieldType::FlowStartMilliseconds, FieldValue::U64(1_619_048_604_500));
+        fields.insert(FieldType::FlowEndMilliseconds, FieldValue::U64(1_619_048_605_000));
+        ipfix::DataSet { fields }
+    }
+
+    #[test]
+    fn write_and_rotate_files() {
+        let directory = std::env::temp_dir().join(format!("ipfix-csv-{}", std::process::id()));
+        let config = CsvSinkConfig {
+            directory: directory.clone(),
+            prefix: "flows".to_string(),
+            columns: [
+                "exporter",
+                "exporter_name",
+                "start_time",
+                "duration",
+                "sourceIPv4Address",
+                "destinationIPv4Address",
+                "destinationTransportPort",
+                "octetDeltaCount",
+            ]
+            .iter()
+            .map(|c| c.parse().unwrap())
+            .collect(),
+            rotate: 60,
+        };
+        let mut sink = CsvSink::new(config).unwrap();
+        sink.clock = Clock::manual(1_619_048_630);
+
+        sink.write(&batch(Some("edge, 1"))).unwrap();
+        sink.flush().unwrap();
+        // the file of the period is not visible before its completion
+        assert!(!directory.join("flows-20210421T234300Z.csv").exists());
+
+        sink.clock.advance(60);
+        sink.write(&batch(None)).unwrap();
+        drop(sink);
+
+        let first = fs::read_to_string(directory.join("flows-20210421T234300Z.csv")).unwrap();
+        assert_eq!(
+            first,
+            "exporter,exporter_name,start_time,duration,sourceIPv4Address,destinationIPv4Address,destinationTransportPort,octetDeltaCount\n\
+             192.0.2.1,\"edge, 1\",2021-04-21T23:43:21.000Z,2500,10.0.0.1,10.0.0.2,443,1500\n\
+             192.0.2.1,\"edge, 1\",2021-04-21T23:43:24.500Z,500,10.0.0.2,,,64\n"
+        );
+        let second = fs::read_to_string(directory.join("flows-20210421T234400Z.csv")).unwrap();
+        assert_eq!(second.lines().nth(1), Some("192.0.2.1,,2021-04-21T23:43:21.000Z,2500,10.0.0.1,10.0.0.2,443,1500"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn escape_values() {
+        assert_eq!(escape("a\"b"), "\"a\"\"b\"");
+        assert_eq!(escape("a\nb"), "\"a\nb\"");
+    }
+}
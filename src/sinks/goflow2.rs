@@ -0,0 +1,523 @@
+use log::info;
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::columns::{Column, Value};
+use super::protobuf::{self, Message};
+use super::Sink;
+use crate::flow::ipfix::{FieldType, FieldValue};
+use crate::flow::{netflow5, FlowBatch};
+use crate::threads::clock::Clock;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/******************************** CONFIGURATION ********************************/
+
+/// Flows encoded as the FlowMessage of goflow2, each message prefixed by its varint length like goflow2 -format=bin
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Goflow2SinkConfig {
+    /// "stdout", { file = "path" } or { tcp = "IP:port" }
+    #[serde(default)]
+    pub output: Output,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    #[default]
+    Stdout,
+    /// Appended to the file
+    File(PathBuf),
+    /// Streamed to a TCP server, reconnected after a failure
+    Tcp(SocketAddr),
+}
+
+/******************************** FLOW MESSAGE ********************************/
+
+// goflow2 FlowType values
+const FLOW_UNKNOWN: u32 = 0;
+const NETFLOW_V5: u32 = 2;
+const IPFIX: u32 = 4;
+
+const ETYPE_IPV4: u32 = 0x0800;
+const ETYPE_IPV6: u32 = 0x86dd;
+
+/// Fields of the FlowMessage of goflow2 v2 (pb/flow.proto) filled by this collector, with their protobuf field number.
+/// The records of both protocols go through their IPFIX fields, netflow v5 records are mapped to IPFIX by netflow5::DataSet.
+///
+/// | FlowMessage           | #     | IPFIX information elements |
+/// |-----------------------|-------|----------------------------|
+/// | type                  | 1     | NETFLOW_V5 or IPFIX, from the version of the record |
+/// | sampling_rate         | 3     | samplingInterval, samplerRandomInterval |
+/// | src_addr, dst_addr    | 6-7   | source/destinationIPv4Address, source/destinationIPv6Address |
+/// | bytes, packets        | 9-10  | octetDeltaCount, packetDeltaCount |
+/// | sampler_address       | 11    | address of the exporter |
+/// | next_hop              | 12    | ipNextHopIPv4Address, ipNextHopIPv6Address |
+/// | next_hop_as           | 13    | bgpNextAdjacentAsNumber |
+/// | src_as, dst_as        | 14-15 | bgpSourceAsNumber, bgpDestinationAsNumber |
+/// | src_net, dst_net      | 16-17 | source/destinationIPv4PrefixLength, source/destinationIPv6PrefixLength |
+/// | in_if, out_if         | 18-19 | ingressInterface, egressInterface |
+/// | proto                 | 20    | protocolIdentifier |
+/// | src_port, dst_port    | 21-22 | source/destinationTransportPort |
+/// | ip_tos                | 23    | ipClassOfService |
+/// | forwarding_status     | 24    | forwardingStatus |
+/// | ip_ttl                | 25    | ipTTL, minimumTTL |
+/// | tcp_flags             | 26    | tcpControlBits |
+/// | src_mac, dst_mac      | 27-28 | sourceMacAddress, postSourceMacAddress / destinationMacAddress, postDestinationMacAddress |
+/// | etype                 | 30    | ethernetType, ipVersion, else the family of the addresses |
+/// | icmp_type, icmp_code  | 31-32 | icmpTypeCodeIPv4/IPv6, icmpTypeIPv4/IPv6, icmpCodeIPv4/IPv6 |
+/// | src_vlan, dst_vlan    | 33-34 | vlanId, postVlanId |
+/// | fragment_id           | 35    | fragmentIdentification |
+/// | fragment_offset       | 36    | fragmentOffset |
+/// | ipv6_flow_label       | 37    | flowLabelIPv6 |
+/// | ip_flags              | 38    | fragmentFlags |
+/// | observation_domain_id | 70    | observationDomainId |
+/// | observation_point_id  | 71    | observationPointId |
+/// | mpls_label            | 81    | mplsTopLabelStackSection to mplsLabelStackSection10 |
+/// | bgp_next_hop          | 100   | bgpNextHopIPv4Address, bgpNextHopIPv6Address |
+/// | time_received_ns      | 110   | reception of the batch, to the second |
+/// | time_flow_start_ns    | 111   | flowStartMilliseconds, or the netflow v5 uptime plus the boot time |
+/// | time_flow_end_ns      | 112   | flowEndMilliseconds, likewise |
+///
+/// The listener already multiplies the counters of the netflow v5 records by the sampling interval of their header,
+/// their sampling_rate stays 0.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlowMessage {
+    pub flow_type: u32,
+    pub sampling_rate: u64,
+    pub src_addr: Vec<u8>,
+    pub dst_addr: Vec<u8>,
+    pub bytes: u64,
+    pub packets: u64,
+    pub sampler_address: Vec<u8>,
+    pub next_hop: Vec<u8>,
+    pub next_hop_as: u32,
+    pub src_as: u32,
+    pub dst_as: u32,
+    pub src_net: u32,
+    pub dst_net: u32,
+    pub in_if: u32,
+    pub out_if: u32,
+    pub proto: u32,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub ip_tos: u32,
+    pub forwarding_status: u32,
+    pub ip_ttl: u32,
+    pub tcp_flags: u32,
+    pub src_mac: u64,
+    pub dst_mac: u64,
+    pub etype: u32,
+    pub icmp_type: u32,
+    pub icmp_code: u32,
+    pub src_vlan: u32,
+    pub dst_vlan: u32,
+    pub fragment_id: u32,
+    pub fragment_offset: u32,
+    pub ipv6_flow_label: u32,
+    pub ip_flags: u32,
+    pub observation_domain_id: u32,
+    pub observation_point_id: u32,
+    pub mpls_label: Vec<u32>,
+    pub bgp_next_hop: Vec<u8>,
+    pub time_received_ns: u64,
+    pub time_flow_start_ns: u64,
+    pub time_flow_end_ns: u64,
+}
+
+impl FlowMessage {
+    /// Message of one flow of the batch, received is the reception time in seconds
+    pub fn new(batch: &FlowBatch, version: u16, fields: &[(FieldType, FieldValue)], received: u64) -> Self {
+        let mut message = FlowMessage {
+            flow_type: match version {
+                netflow5::VERSION => NETFLOW_V5,
+                crate::flow::ipfix::VERSION => IPFIX,
+                _ => FLOW_UNKNOWN,
+            },
+            sampler_address: protobuf::address_bytes(&batch.exporter),
+            time_received_ns: received.saturating_mul(1_000_000_000),
+            ..Default::default()
+        };
+        if let Some(Value::Timestamp(millis)) = Column::StartTime.value(batch, fields) {
+            message.time_flow_start_ns = millis.saturating_mul(1_000_000);
+        }
+        if let Some(Value::Timestamp(millis)) = Column::EndTime.value(batch, fields) {
+            message.time_flow_end_ns = millis.saturating_mul(1_000_000);
+        }
+
+        for (id, value) in fields {
+            let number = value.as_u128().unwrap_or_default() as u64;
+            let address = || match Value::from_field(*id, value) {
+                Value::Address(addr) => protobuf::address_bytes(&addr),
+                _ => vec![],
+            };
+            let mac = || match Value::from_field(*id, value) {
+                Value::Mac(mac) => mac.iter().fold(0, |mac, &b| mac << 8 | b as u64),
+                _ => 0,
+            };
+
+            match id {
+                FieldType::SamplingInterval | FieldType::SamplerRandomInterval => message.sampling_rate = number,
+                FieldType::SourceIPv4Address | FieldType::SourceIPv6Address => message.src_addr = address(),
+                FieldType::DestinationIPv4Address | FieldType::DestinationIPv6Address => message.dst_addr = address(),
+                FieldType::OctetDeltaCount => message.bytes = number,
+                FieldType::PacketDeltaCount => message.packets = number,
+                FieldType::IpNextHopIPv4Address | FieldType::IpNextHopIPv6Address => message.next_hop = address(),
+                FieldType::BgpNextAdjacentAsNumber => message.next_hop_as = number as u32,
+                FieldType::BgpSourceAsNumber => message.src_as = number as u32,
+                FieldType::BgpDestinationAsNumber => message.dst_as = number as u32,
+                FieldType::SourceIPv4PrefixLength | FieldType::SourceIPv6PrefixLength => message.src_net = number as u32,
+                FieldType::DestinationIPv4PrefixLength | FieldType::DestinationIPv6PrefixLength => message.dst_net = number as u32,
+                FieldType::IngressInterface => message.in_if = number as u32,
+                FieldType::EgressInterface => message.out_if = number as u32,
+                FieldType::ProtocolIdentifier => message.proto = number as u32,
+                FieldType::SourceTransportPort => message.src_port = number as u32,
+                FieldType::DestinationTransportPort => message.dst_port = number as u32,
+                FieldType::IPClassOfService => message.ip_tos = number as u32,
+                FieldType::ForwardingStatus => message.forwarding_status = number as u32,
+                FieldType::IpTTL | FieldType::MSinimumTTL => message.ip_ttl = number as u32,
+                FieldType::TcpControlBits => message.tcp_flags = number as u32,
+                FieldType::SourceMacAddress | FieldType::PostSourceMacAddress => message.src_mac = mac(),
+                FieldType::DestinationMacAddress | FieldType::PostDestinationMacAddress => message.dst_mac = mac(),
+                FieldType::EthernetType => message.etype = number as u32,
+                FieldType::IPVersion => {
+                    message.etype = match number {
+                        4 => ETYPE_IPV4,
+                        6 => ETYPE_IPV6,
+                        _ => message.etype,
+                    }
+                }
+                FieldType::IcmpTypeCodeIPv4 | FieldType::IcmpTypeCodeIPv6 => {
+                    message.icmp_type = (number >> 8) as u32 & 0xff;
+                    message.icmp_code = number as u32 & 0xff;
+                }
+                FieldType::IcmpTypeIPv4 | FieldType::IcmpTypeIPv6 => message.icmp_type = number as u32,
+                FieldType::IcmpCodeIPv4 | FieldType::IcmpCodeIPv6 => message.icmp_code = number as u32,
+                FieldType::VlanId => message.src_vlan = number as u32,
+                FieldType::PostVlanId => message.dst_vlan = number as u32,
+                FieldType::FragmentIdentification => message.fragment_id = number as u32,
+                FieldType::FragmentOffset => message.fragment_offset = number as u32,
+                FieldType::FlowLabelIPv6 => message.ipv6_flow_label = number as u32,
+                FieldType::FragmentFlags => message.ip_flags = number as u32,
+                FieldType::ObservationDomainId => message.observation_domain_id = number as u32,
+                FieldType::ObservationPointId => message.observation_point_id = number as u32,
+                // label (20 bits), traffic class (3 bits) and bottom of stack (1 bit), sorted from the top of the stack
+                id if (FieldType::MplsTopLabelStackSection..=FieldType::MplsLabelStackSection10).contains(id) => {
+                    if let FieldValue::Dyn(entry) = value {
+                        if entry.len() >= 3 {
+                            message.mpls_label.push((entry[0] as u32) << 12 | (entry[1] as u32) << 4 | (entry[2] as u32) >> 4);
+                        }
+                    }
+                }
+                FieldType::BgpNextHopIPv4Address | FieldType::BgpNextHopIPv6Address => message.bgp_next_hop = address(),
+                _ => {}
+            }
+        }
+
+        // the netflow v5 records and most IPFIX templates don't have the ethernet type, goflow2 derives it from the addresses
+        if message.etype == 0 {
+            message.etype = match message.src_addr.len() {
+                4 => ETYPE_IPV4,
+                16 => ETYPE_IPV6,
+                _ => 0,
+            };
+        }
+        message
+    }
+
+    /// Protobuf encoding, the fields in the order of their number and without the default values like the Go encoder
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Message::default();
+        let uint = |out: &mut Message, field: u32, value: u64| {
+            if value != 0 {
+                out.uint64(field, value);
+            }
+        };
+        let bytes = |out: &mut Message, field: u32, value: &[u8]| {
+            if !value.is_empty() {
+                out.bytes(field, value);
+            }
+        };
+
+        uint(&mut out, 1, self.flow_type as u64);
+        uint(&mut out, 3, self.sampling_rate);
+        bytes(&mut out, 6, &self.src_addr);
+        bytes(&mut out, 7, &self.dst_addr);
+        uint(&mut out, 9, self.bytes);
+        uint(&mut out, 10, self.packets);
+        bytes(&mut out, 11, &self.sampler_address);
+        bytes(&mut out, 12, &self.next_hop);
+        for (field, value) in [
+            (13, self.next_hop_as),
+            (14, self.src_as),
+            (15, self.dst_as),
+            (16, self.src_net),
+            (17, self.dst_net),
+            (18, self.in_if),
+            (19, self.out_if),
+            (20, self.proto),
+            (21, self.src_port),
+            (22, self.dst_port),
+            (23, self.ip_tos),
+            (24, self.forwarding_status),
+            (25, self.ip_ttl),
+            (26, self.tcp_flags),
+        ] {
+            uint(&mut out, field, value as u64);
+        }
+        uint(&mut out, 27, self.src_mac);
+        uint(&mut out, 28, self.dst_mac);
+        for (field, value) in [
+            (30, self.etype),
+            (31, self.icmp_type),
+            (32, self.icmp_code),
+            (33, self.src_vlan),
+            (34, self.dst_vlan),
+            (35, self.fragment_id),
+            (36, self.fragment_offset),
+            (37, self.ipv6_flow_label),
+            (38, self.ip_flags),
+            (70, self.observation_domain_id),
+            (71, self.observation_point_id),
+        ] {
+            uint(&mut out, field, value as u64);
+        }
+        if !self.mpls_label.is_empty() {
+            out.packed_uint64(81, &self.mpls_label.iter().map(|&label| label as u64).collect::<Vec<_>>());
+        }
+        bytes(&mut out, 100, &self.bgp_next_hop);
+        uint(&mut out, 110, self.time_received_ns);
+        uint(&mut out, 111, self.time_flow_start_ns);
+        uint(&mut out, 112, self.time_flow_end_ns);
+        out.into_bytes()
+    }
+
+    /// Encoding prefixed by its varint length, the framing of goflow2 -format=bin
+    pub fn encode_delimited(&self) -> Vec<u8> {
+        let message = self.encode();
+        let mut out = Vec::with_capacity(message.len() + 2);
+        protobuf::write_varint(&mut out, message.len() as u64);
+        out.extend_from_slice(&message);
+        out
+    }
+}
+
+/******************************** SINK ********************************/
+
+pub struct Goflow2Sink {
+    config: Goflow2SinkConfig,
+    /// Opened on the first batch, and again after a write error
+    out: Option<BufWriter<Box<dyn Write + Send>>>,
+    clock: Clock,
+}
+
+impl Goflow2Sink {
+    pub fn new(config: Goflow2SinkConfig) -> Result<Self, String> {
+        info!("Writing the flows as goflow2 FlowMessages to {:?}", config.output);
+        let mut sink = Goflow2Sink {
+            config,
+            out: None,
+            clock: Clock::default(),
+        };
+        // a wrong path is reported when the configuration is loaded, the TCP server may come up later
+        if let Output::File(_) = sink.config.output {
+            sink.open()?;
+        }
+        Ok(sink)
+    }
+
+    fn open(&mut self) -> Result<(), String> {
+        let out: Box<dyn Write + Send> = match &self.config.output {
+            Output::Stdout => Box::new(io::stdout()),
+            Output::File(path) => Box::new(open_file(path).map_err(|e| format!("Failed to open {} : {}", path.display(), e))?),
+            Output::Tcp(target) => {
+                let connect = || -> io::Result<TcpStream> {
+                    let stream = TcpStream::connect_timeout(target, CONNECT_TIMEOUT)?;
+                    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    Ok(stream)
+                };
+                Box::new(connect().map_err(|e| format!("Failed to connect to {} : {}", target, e))?)
+            }
+        };
+        self.out = Some(BufWriter::new(out));
+        Ok(())
+    }
+}
+
+fn open_file(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Sink for Goflow2Sink {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String> {
+        if self.out.is_none() {
+            self.open()?;
+        }
+        let received = self.clock.now_secs();
+        let mut buf = vec![];
+        for flow in &batch.flows {
+            buf.extend_from_slice(&FlowMessage::new(batch, flow.version(), &flow.fields(), received).encode_delimited());
+        }
+
+        // one write per batch, a message is never split between two connections
+        let result = self.out.as_mut().map_or(Ok(()), |out| out.write_all(&buf).and_then(|_| out.flush()));
+        if let Err(e) = result {
+            self.out = None;
+            return Err(format!("Failed to write the goflow2 messages to {:?} : {}", self.config.output, e));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match &mut self.out {
+            Some(out) => out.flush().map_err(|e| format!("Failed to flush {:?} : {}", self.config.output, e)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::ipfix;
+    use hex_literal::hex;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    fn netflow5_batch() -> FlowBatch {
+        FlowBatch {
+            tag: None,
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: Some(Arc::from("edge-1")),
+            flows: vec![Box::new(netflow5::DataSet {
+                src_addr: 0x0a00_0001,
+                dst_addr: 0x0a00_0002,
+                next_hop: 0x0a00_00fe,
+                input_int: 3,
+                output_int: 7,
+                packets: 3,
+                octets: 1500,
+                start_time: 1000,
+                end_time: 4500,
+                src_port: 54321,
+                dst_port: 443,
+                tcp_flag: 0x12,
+                protocol: 6,
+                src_as: 64500,
+                src_mask: 24,
+                dst_mask: 16,
+                system_init_time: Some(1_619_048_600_000),
+                ..Default::default()
+            })],
+        }
+    }
+
+    fn ipfix_batch() -> FlowBatch {
+        let mut fields = HashMap::new();
+        fields.insert(FieldType::SourceIPv6Address, FieldValue::U128(0x2001_0db8_0000_0000_0000_0000_0000_0001));
+        fields.insert(FieldType::DestinationIPv6Address, FieldValue::U128(0x2001_0db8_0000_0000_0000_0000_0000_0002));
+        fields.insert(FieldType::ProtocolIdentifier, FieldValue::U8(58));
+        fields.insert(FieldType::IcmpTypeCodeIPv6, FieldValue::U16(0x8000));
+        fields.insert(FieldType::SourceMacAddress, FieldValue::Dyn(vec![0x00, 0x11, 0x22, 0x33, 0x44, 0x55]));
+        fields.insert(FieldType::VlanId, FieldValue::U16(100));
+        fields.insert(FieldType::MplsTopLabelStackSection, FieldValue::Dyn(vec![0x00, 0x3e, 0x80]));
+        fields.insert(FieldType::MplsLabelStackSection2, FieldValue::Dyn(vec![0x00, 0x7d, 0x01]));
+        fields.insert(FieldType::SamplingInterval, FieldValue::U32(100));
+        fields.insert(FieldType::ObservationDomainId, FieldValue::U32(5));
+
+        FlowBatch {
+            tag: None,
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: None,
+            flows: vec![Box::new(ipfix::DataSet { fields })],
+        }
+    }
+
+    fn message(batch: &FlowBatch, received: u64) -> FlowMessage {
+        let flow = &batch.flows[0];
+        FlowMessage::new(batch, flow.version(), &flow.fields(), received)
+    }
+
+    #[test]
+    fn encode_netflow5() {
+        let message = message(&netflow5_batch(), 1_619_048_610);
+        assert_eq!(message.flow_type, NETFLOW_V5);
+        assert_eq!(message.etype, ETYPE_IPV4);
+        assert_eq!(message.time_flow_end_ns, 1_619_048_604_500_000_000);
+
+        assert_eq!(
+            message.encode(),
+            [
+                // type, src_addr, dst_addr, bytes, packets
+                &hex!("08 02 32 04 0a 00 00 01 3a 04 0a 00 00 02 48 dc 0b 50 03")[..],
+                // sampler_address, next_hop, src_as, src_net, dst_net, in_if, out_if
+                &hex!("5a 04 c0 00 02 01 62 04 0a 00 00 fe 70 f4 f7 03 80 01 18 88 01 10 90 01 03 98 01 07")[..],
+                // proto, src_port, dst_port, tcp_flags, etype
+                &hex!("a0 01 06 a8 01 b1 a8 03 b0 01 bb 03 d0 01 12 f0 01 80 10")[..],
+                // time_received_ns, time_flow_start_ns, time_flow_end_ns
+                &hex!("f0 06 80 a8 8f c9 9f 84 81 bc 16 f8 06 80 f4 ca 85 fe 83 81 bc 16 80 07 80 fa c1 8a 8b 84 81 bc 16")[..],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn encode_ipfix() {
+        let message = message(&ipfix_batch(), 1);
+        assert_eq!(message.mpls_label, [1000, 2000]);
+        assert_eq!((message.icmp_type, message.icmp_code), (128, 0));
+
+        let mut expected = hex!("08 04 18 64 32 10 20 01 0d b8 00 00 00 00 00 00 00 00 00 00 00 01 3a 10 20 01 0d b8 00 00 00 00 00 00 00 00 00 00 00 02").to_vec();
+        // sampler_address, proto, src_mac, etype, icmp_type, src_vlan
+        expected.extend_from_slice(&hex!("5a 04 c0 00 02 01 a0 01 3a d8 01 d5 88 cd 91 92 02 f0 01 dd 8d 02 f8 01 80 01 88 02 64"));
+        // observation_domain_id, mpls_label, time_received_ns
+        expected.extend_from_slice(&hex!("b0 04 05 8a 05 04 e8 07 d0 0f f0 06 80 94 eb dc 03"));
+        assert_eq!(message.encode(), expected);
+
+        let delimited = message.encode_delimited();
+        assert_eq!(delimited[0] as usize, expected.len());
+        assert_eq!(&delimited[1..], &expected[..]);
+    }
+
+    #[test]
+    fn write_to_file() {
+        let path = std::env::temp_dir().join(format!("ipfix-goflow2-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config: Goflow2SinkConfig = toml::from_str(&format!("output = {{ file = {:?} }}", path)).unwrap();
+
+        let mut sink = Goflow2Sink::new(config).unwrap();
+        sink.clock = Clock::manual(1);
+        sink.write(&netflow5_batch()).unwrap();
+        sink.write(&ipfix_batch()).unwrap();
+        drop(sink);
+
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(content, [message(&netflow5_batch(), 1).encode_delimited(), message(&ipfix_batch(), 1).encode_delimited()].concat());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stream_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Goflow2SinkConfig {
+            output: Output::Tcp(listener.local_addr().unwrap()),
+        };
+
+        let mut sink = Goflow2Sink::new(config).unwrap();
+        sink.clock = Clock::manual(1);
+        sink.write(&ipfix_batch()).unwrap();
+        drop(sink);
+
+        let mut received = vec![];
+        listener.accept().unwrap().0.read_to_end(&mut received).unwrap();
+        assert_eq!(received, message(&ipfix_batch(), 1).encode_delimited());
+    }
+}
@@ -0,0 +1,246 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time given to the server to store a batch and answer
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bodies of the answers are only read for the error messages and the bulk results
+const MAX_RESPONSE_SIZE: usize = 16 << 20;
+
+/******************************** HTTP CLIENT ********************************/
+
+/// Minimal HTTP/1.1 client for the sinks posting batches to a database, one connection per request and no TLS
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The client errors are caused by the request itself, sending it again can't succeed
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.status) && self.status != 408 && self.status != 429
+    }
+
+    /// Body of an error, shortened for the logs
+    pub fn error(&self) -> String {
+        let body = String::from_utf8_lossy(&self.body);
+        let body = body.trim();
+        match body.char_indices().nth(200) {
+            Some((i, _)) => format!("HTTP {} : {}...", self.status, &body[..i]),
+            None => format!("HTTP {} : {}", self.status, body),
+        }
+    }
+}
+
+/// POST the body to host:port, path includes the query string
+pub fn post(target: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> io::Result<Response> {
+    let addr = target
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No address for {}", target)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+
+    let mut request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n", path, target, body.len());
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    read_response(BufReader::new(stream))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    reader.by_ref().take(8192).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(invalid("Truncated HTTP response"));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+fn read_response<R: BufRead>(mut reader: R) -> io::Result<Response> {
+    // HTTP/1.1 200 OK
+    let status_line = read_line(&mut reader)?;
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid(&format!("Invalid HTTP status line '{}'", status_line)))?;
+
+    let (mut length, mut chunked) = (None, false);
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+            if name == "content-length" {
+                length = Some(value.parse::<usize>().map_err(|_| invalid("Invalid Content-Length"))?);
+            } else if name == "transfer-encoding" {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+
+    let mut body = vec![];
+    if chunked {
+        loop {
+            let line = read_line(&mut reader)?;
+            let size = usize::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16).map_err(|_| invalid("Invalid chunk size"))?;
+            if size == 0 {
+                break;
+            }
+            if body.len() + size > MAX_RESPONSE_SIZE {
+                return Err(invalid("HTTP response too large"));
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            read_line(&mut reader)?;
+        }
+    } else if let Some(length) = length {
+        if length > MAX_RESPONSE_SIZE {
+            return Err(invalid("HTTP response too large"));
+        }
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+    } else {
+        // the connection is closed after the body
+        reader.take(MAX_RESPONSE_SIZE as u64).read_to_end(&mut body)?;
+    }
+
+    Ok(Response { status, body })
+}
+
+/// Percent-encode a query string parameter
+pub fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Authorization header value of the basic authentication (RFC 7617)
+pub fn basic_auth(user: &str, password: &str) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let credentials = format!("{}:{}", user, password);
+    let mut encoded = String::from("Basic ");
+    for chunk in credentials.as_bytes().chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Test server answering each request with the next response of the list, the requests are returned by the thread
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    #[derive(Debug)]
+    pub struct Request {
+        /// POST /path?query HTTP/1.1
+        pub line: String,
+        pub headers: Vec<String>,
+        pub body: Vec<u8>,
+    }
+
+    pub fn serve(responses: Vec<(u16, &'static str)>) -> (String, JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let line = read_line(&mut reader).unwrap();
+                let mut headers = vec![];
+                loop {
+                    let header = read_line(&mut reader).unwrap();
+                    if header.is_empty() {
+                        break;
+                    }
+                    headers.push(header);
+                }
+                let length = headers.iter().find_map(|h| h.strip_prefix("Content-Length: ")).map_or(0, |l| l.parse().unwrap());
+                let mut content = vec![0; length];
+                reader.read_exact(&mut content).unwrap();
+
+                let mut stream = reader.into_inner();
+                write!(stream, "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).unwrap();
+                requests.push(Request { line, headers, body: content });
+            }
+            requests
+        });
+
+        (target, handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_responses() {
+        let response = read_response(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+        assert_eq!(response, Response { status: 200, body: b"ok".to_vec() });
+        assert!(response.is_success());
+
+        let response = read_response(&b"HTTP/1.1 500 Internal Server Error\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nCode\r\n3\r\n: 1\r\n0\r\n\r\n"[..]).unwrap();
+        assert_eq!(response.error(), "HTTP 500 : Code: 1");
+        assert!(!response.is_client_error());
+
+        let response = read_response(&b"HTTP/1.0 400 Bad Request\r\n\r\nwrong"[..]).unwrap();
+        assert_eq!(response.body, b"wrong");
+        assert!(response.is_client_error());
+
+        assert!(read_response(&b"HTTP/1.1 200 OK\r\nContent-Le"[..]).is_err());
+        assert!(read_response(&b"garbage\r\n\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn post_request() {
+        let (target, server) = mock::serve(vec![(200, "done")]);
+        let response = post(&target, "/?query=SELECT%201", &[("X-Token", "secret")], b"body").unwrap();
+        assert_eq!(response.body, b"done");
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].line, "POST /?query=SELECT%201 HTTP/1.1");
+        assert!(requests[0].headers.contains(&"X-Token: secret".to_string()));
+        assert_eq!(requests[0].body, b"body");
+        assert_eq!(url_encode("INSERT INTO t FORMAT RowBinary"), "INSERT%20INTO%20t%20FORMAT%20RowBinary");
+        assert_eq!(basic_auth("Aladdin", "open sesame"), "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+        assert_eq!(basic_auth("a", "b"), "Basic YTpi");
+    }
+}
@@ -0,0 +1,492 @@
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+use super::columns::{Column, Value};
+use super::http;
+use super::opensearch::transport_name;
+use super::Sink;
+use crate::flow::ipfix::{FieldType, FieldValue};
+use crate::flow::FlowBatch;
+use crate::metrics;
+use crate::threads::clock::Clock;
+
+/// Lines of one UDP datagram, to stay under the path MTU
+const MAX_DATAGRAM_SIZE: usize = 1400;
+
+/******************************** CONFIGURATION ********************************/
+
+/// Bytes, packets and flows aggregated per interval and written as InfluxDB line protocol
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxDbSinkConfig {
+    /// host:port of the HTTP API or of the UDP listener
+    pub target: String,
+    #[serde(default)]
+    pub transport: InfluxTransport,
+    /// Database of the v1 write API, InfluxDB 2 and 3 map it to a bucket
+    #[serde(default = "default_database")]
+    pub database: String,
+    /// API token of InfluxDB 2 and 3
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+    /// Tags of the points, each distinct set of values is a series
+    #[serde(default = "default_tags")]
+    pub tags: Vec<TagKey>,
+    #[serde(default = "default_fields")]
+    pub fields: Vec<FieldKey>,
+    /// Seconds aggregated in one point, the flows are placed by their end time
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Seconds waited after the end of an interval for the flows still in the caches of the exporters
+    #[serde(default = "default_delay")]
+    pub delay: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxTransport {
+    #[default]
+    Http,
+    Udp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TagKey {
+    Exporter,
+    ExporterName,
+    /// Tag of the listener
+    Tag,
+    /// Ingress interface of the flow for the direction in, egress interface for the direction out
+    Interface,
+    /// in or out
+    Direction,
+    /// Name of the IANA protocol, or its number
+    Protocol,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKey {
+    Bytes,
+    Packets,
+    Flows,
+}
+
+fn default_database() -> String {
+    "flows".to_string()
+}
+
+fn default_measurement() -> String {
+    "flows".to_string()
+}
+
+fn default_tags() -> Vec<TagKey> {
+    vec![TagKey::Exporter, TagKey::Interface, TagKey::Direction, TagKey::Protocol]
+}
+
+fn default_fields() -> Vec<FieldKey> {
+    vec![FieldKey::Bytes, FieldKey::Packets]
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_delay() -> u64 {
+    60
+}
+
+impl TagKey {
+    fn name(&self) -> &'static str {
+        match self {
+            TagKey::Exporter => "exporter",
+            TagKey::ExporterName => "exporter_name",
+            TagKey::Tag => "tag",
+            TagKey::Interface => "interface",
+            TagKey::Direction => "direction",
+            TagKey::Protocol => "protocol",
+        }
+    }
+}
+
+impl FieldKey {
+    fn name(&self) -> &'static str {
+        match self {
+            FieldKey::Bytes => "bytes",
+            FieldKey::Packets => "packets",
+            FieldKey::Flows => "flows",
+        }
+    }
+}
+
+/******************************** AGGREGATION ********************************/
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Totals {
+    bytes: u64,
+    packets: u64,
+    flows: u64,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.bytes = self.bytes.saturating_add(other.bytes);
+        self.packets = self.packets.saturating_add(other.packets);
+        self.flows = self.flows.saturating_add(other.flows);
+    }
+
+    fn get(&self, key: FieldKey) -> u64 {
+        match key {
+            FieldKey::Bytes => self.bytes,
+            FieldKey::Packets => self.packets,
+            FieldKey::Flows => self.flows,
+        }
+    }
+}
+
+/// Backslash before the characters with a meaning in the line protocol
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/******************************** SINK ********************************/
+
+pub struct InfluxDbSink {
+    config: InfluxDbSinkConfig,
+    /// Totals by start of interval and values of the tags, None for the tags the flow doesn't have
+    intervals: BTreeMap<u64, BTreeMap<Vec<Option<String>>, Totals>>,
+    /// The intervals starting before were written, their late flows are ignored
+    written_until: u64,
+    socket: Option<UdpSocket>,
+    clock: Clock,
+}
+
+impl InfluxDbSink {
+    pub fn new(config: InfluxDbSinkConfig) -> Result<Self, String> {
+        if config.interval == 0 {
+            return Err("The interval of the InfluxDB sink must be at least 1 second".to_string());
+        }
+        if config.fields.is_empty() {
+            return Err("The InfluxDB sink needs at least one field".to_string());
+        }
+        info!("Writing the flow totals of every {}s to InfluxDB at {} over {:?}", config.interval, config.target, config.transport);
+
+        Ok(InfluxDbSink {
+            config,
+            intervals: BTreeMap::new(),
+            written_until: 0,
+            socket: None,
+            clock: Clock::default(),
+        })
+    }
+
+    /// Add the flow to the totals of its interval, once for each side when the points are split by interface or direction
+    fn aggregate(&mut self, batch: &FlowBatch, fields: &[(FieldType, FieldValue)], now: u64) {
+        let get = |id: FieldType| fields.binary_search_by_key(&id, |&(t, _)| t).ok().and_then(|i| fields[i].1.as_u128());
+
+        let end = match Column::EndTime.value(batch, fields) {
+            Some(Value::Timestamp(millis)) => millis / 1000,
+            _ => now,
+        };
+        let start = end - end % self.config.interval;
+        if start < self.written_until {
+            metrics::INFLUXDB_LATE_FLOWS.inc();
+            return;
+        }
+
+        // the listener already multiplied the counters by the sampling interval
+        let totals = Totals {
+            bytes: get(FieldType::OctetDeltaCount).unwrap_or_default() as u64,
+            packets: get(FieldType::PacketDeltaCount).unwrap_or_default() as u64,
+            flows: 1,
+        };
+
+        // the flow leaves its egress interface with the same bytes it entered with
+        let by_side = self.config.tags.iter().any(|&tag| tag == TagKey::Interface || tag == TagKey::Direction);
+        let mut sides: Vec<(Option<&str>, Option<u128>)> = vec![];
+        if by_side {
+            for (direction, id) in [("in", FieldType::IngressInterface), ("out", FieldType::EgressInterface)] {
+                if let Some(interface) = get(id) {
+                    sides.push((Some(direction), Some(interface)));
+                }
+            }
+        }
+        if sides.is_empty() {
+            sides.push((None, None));
+        }
+
+        let protocol = get(FieldType::ProtocolIdentifier).map(|protocol| transport_name(protocol).map_or_else(|| protocol.to_string(), str::to_string));
+        for (direction, interface) in sides {
+            let key = self
+                .config
+                .tags
+                .iter()
+                .map(|tag| match tag {
+                    TagKey::Exporter => Some(batch.exporter.to_string()),
+                    TagKey::ExporterName => batch.exporter_name.as_deref().map(str::to_string),
+                    TagKey::Tag => batch.tag.as_deref().map(str::to_string),
+                    TagKey::Interface => interface.map(|interface| interface.to_string()),
+                    TagKey::Direction => direction.map(str::to_string),
+                    TagKey::Protocol => protocol.clone(),
+                })
+                .collect();
+            self.intervals.entry(start).or_default().entry(key).or_default().add(&totals);
+        }
+    }
+
+    /// Line protocol of the points of one interval, the timestamp is the start of the interval in nanoseconds
+    fn lines(&self, start: u64, points: &BTreeMap<Vec<Option<String>>, Totals>) -> Vec<String> {
+        let measurement = escape(&self.config.measurement, &[',', ' ']);
+        points
+            .iter()
+            .map(|(values, totals)| {
+                let mut line = measurement.clone();
+                for (tag, value) in self.config.tags.iter().zip(values) {
+                    // InfluxDB doesn't accept the empty tag values, the missing tags are left out
+                    if let Some(value) = value.as_deref().filter(|value| !value.is_empty()) {
+                        write!(line, ",{}={}", tag.name(), escape(value, &[',', '=', ' '])).unwrap();
+                    }
+                }
+                for (i, field) in self.config.fields.iter().enumerate() {
+                    write!(line, "{}{}={}i", if i == 0 { ' ' } else { ',' }, field.name(), totals.get(*field).min(i64::MAX as u64)).unwrap();
+                }
+                write!(line, " {}", start.saturating_mul(1_000_000_000)).unwrap();
+                line
+            })
+            .collect()
+    }
+
+    fn send(&mut self, lines: &[String]) -> Result<(), String> {
+        match self.config.transport {
+            InfluxTransport::Http => {
+                let mut headers = vec![("Content-Type", "text/plain; charset=utf-8")];
+                let authorization = self.config.token.as_ref().map(|token| format!("Token {}", token));
+                if let Some(authorization) = &authorization {
+                    headers.push(("Authorization", authorization.as_str()));
+                }
+
+                let path = format!("/write?db={}", http::url_encode(&self.config.database));
+                let body = lines.join("\n");
+                match http::post(&self.config.target, &path, &headers, body.as_bytes()) {
+                    Ok(response) if response.is_success() => Ok(()),
+                    Ok(response) => Err(format!("InfluxDB refused {} points : {}", lines.len(), response.error())),
+                    Err(e) => Err(format!("Failed to write {} points to InfluxDB : {}", lines.len(), e)),
+                }
+            }
+            InfluxTransport::Udp => self.send_datagrams(lines).map_err(|e| {
+                self.socket = None;
+                format!("Failed to send {} points to InfluxDB : {}", lines.len(), e)
+            }),
+        }
+    }
+
+    fn send_datagrams(&mut self, lines: &[String]) -> io::Result<()> {
+        if self.socket.is_none() {
+            let target = self
+                .config
+                .target
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No address for {}", self.config.target)))?;
+            let bind_addr: SocketAddr = match target {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = UdpSocket::bind(bind_addr)?;
+            socket.connect(target)?;
+            self.socket = Some(socket);
+        }
+        let socket = self.socket.as_ref().unwrap();
+
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+                socket.send(datagram.as_bytes())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(line);
+        }
+        if !datagram.is_empty() {
+            socket.send(datagram.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Write the intervals ending before until, their late flows are ignored afterwards
+    fn write_intervals(&mut self, until: u64) -> Result<(), String> {
+        let mut lines = vec![];
+        while let Some(entry) = self.intervals.first_entry() {
+            let start = *entry.key();
+            if start + self.config.interval > until {
+                break;
+            }
+            let points = entry.remove();
+            lines.extend(self.lines(start, &points));
+            self.written_until = self.written_until.max(start + self.config.interval);
+        }
+
+        if lines.is_empty() {
+            return Ok(());
+        }
+        self.send(&lines)
+    }
+}
+
+impl Sink for InfluxDbSink {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String> {
+        let now = self.clock.now_secs();
+        for flow in &batch.flows {
+            self.aggregate(batch, &flow.fields(), now);
+        }
+        self.write_intervals(now.saturating_sub(self.config.delay))
+    }
+
+    /// Also writes the intervals still open, on reload and shutdown
+    fn flush(&mut self) -> Result<(), String> {
+        self.write_intervals(u64::MAX)
+    }
+}
+
+impl Drop for InfluxDbSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{ipfix, netflow5};
+    use crate::sinks::fixture;
+    use crate::sinks::http::mock;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// Flows of the exporter 192.0.2.1 from interface 3 to interface 7, (end time in seconds after 2021-04-22T00:00:00Z, protocol, octets)
+    fn batch(flows: &[(u32, u8, u32)]) -> FlowBatch {
+        fixture::batch(flows.iter().map(|&(end, protocol, octets)| netflow5::DataSet {
+            protocol,
+            octets,
+            packets: 2,
+            input_int: 3,
+            output_int: 7,
+            start_time: 0,
+            end_time: end * 1000,
+            system_init_time: Some(1_619_049_600_000),
+            ..fixture::flow()
+        }))
+    }
+
+    fn config(target: &str) -> InfluxDbSinkConfig {
+        toml::from_str(&format!("target = \"{}\"\ntoken = \"secret\"", target)).unwrap()
+    }
+
+    #[test]
+    fn aggregate_per_interval() {
+        let (target, server) = mock::serve(vec![(204, ""), (204, "")]);
+        let mut sink = InfluxDbSink::new(config(&target)).unwrap();
+        // 2021-04-22T00:02:30Z
+        sink.clock = Clock::manual(1_619_049_750);
+
+        // two TCP flows and one UDP flow ending in the first minute, one TCP flow in the second minute
+        sink.write(&batch(&[(10, 6, 1000), (50, 6, 500), (59, 17, 100), (61, 6, 40)])).unwrap();
+        // the first minute was written, a late flow of that minute is ignored
+        let late = metrics::INFLUXDB_LATE_FLOWS.get();
+        sink.write(&batch(&[(30, 6, 1000)])).unwrap();
+        assert_eq!(metrics::INFLUXDB_LATE_FLOWS.get(), late + 1);
+        sink.flush().unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].line, "POST /write?db=flows HTTP/1.1");
+        assert!(requests[0].headers.contains(&"Authorization: Token secret".to_string()));
+        assert_eq!(
+            String::from_utf8(requests[0].body.clone()).unwrap(),
+            [
+                "flows,exporter=192.0.2.1,interface=3,direction=in,protocol=tcp bytes=1500i,packets=4i 1619049600000000000",
+                "flows,exporter=192.0.2.1,interface=3,direction=in,protocol=udp bytes=100i,packets=2i 1619049600000000000",
+                "flows,exporter=192.0.2.1,interface=7,direction=out,protocol=tcp bytes=1500i,packets=4i 1619049600000000000",
+                "flows,exporter=192.0.2.1,interface=7,direction=out,protocol=udp bytes=100i,packets=2i 1619049600000000000",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            String::from_utf8(requests[1].body.clone()).unwrap(),
+            [
+                "flows,exporter=192.0.2.1,interface=3,direction=in,protocol=tcp bytes=40i,packets=2i 1619049660000000000",
+                "flows,exporter=192.0.2.1,interface=7,direction=out,protocol=tcp bytes=40i,packets=2i 1619049660000000000",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn sampled_ipfix_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut config = config(&server.local_addr().unwrap().to_string());
+        config.transport = InfluxTransport::Udp;
+        config.measurement = "net flows".to_string();
+        config.tags = vec![TagKey::Tag, TagKey::ExporterName, TagKey::Protocol];
+        config.fields = vec![FieldKey::Bytes, FieldKey::Flows];
+
+        let mut fields = HashMap::new();
+        // counters corrected by the listener, not multiplied again
+        fields.insert(FieldType::OctetDeltaCount, FieldValue::U64(10000));
+        fields.insert(FieldType::ProtocolIdentifier, FieldValue::U8(112));
+        fields.insert(FieldType::SamplingPacketInterval, FieldValue::U32(1));
+        fields.insert(FieldType::SamplingPacketSpace, FieldValue::U32(99));
+        fields.insert(FieldType::FlowEndMilliseconds, FieldValue::U64(1_619_049_601_000));
+        let batch = FlowBatch {
+            tag: Some(Arc::from("core,edge")),
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: None,
+            flows: vec![Box::new(ipfix::DataSet { fields })],
+        };
+
+        let mut sink = InfluxDbSink::new(config).unwrap();
+        sink.write(&batch).unwrap();
+        sink.flush().unwrap();
+
+        let mut datagram = [0; 2048];
+        let size = server.recv(&mut datagram).unwrap();
+        assert_eq!(&datagram[..size], &b"net\\ flows,tag=core\\,edge,protocol=112 bytes=10000i,flows=1i 1619049600000000000"[..]);
+    }
+
+    #[test]
+    fn split_datagrams() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut config = config(&server.local_addr().unwrap().to_string());
+        config.transport = InfluxTransport::Udp;
+        let mut sink = InfluxDbSink::new(config).unwrap();
+
+        let lines: Vec<String> = (0..100).map(|i| format!("flows,exporter=192.0.2.{} bytes=1i 0", i)).collect();
+        sink.send(&lines).unwrap();
+
+        let mut received = vec![];
+        let mut datagram = [0; 2048];
+        while received.len() < lines.len() {
+            let size = server.recv(&mut datagram).unwrap();
+            assert!(size <= MAX_DATAGRAM_SIZE);
+            received.extend(std::str::from_utf8(&datagram[..size]).unwrap().split('\n').map(str::to_string));
+        }
+        assert_eq!(received, lines);
+    }
+}
@@ -0,0 +1,475 @@
+use core::convert::TryFrom;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::Sink;
+use crate::flow::ipfix::{self, DataSet, DataSetTemplate, FieldType, Header, SetHeader, TemplateField};
+use crate::flow::FlowBatch;
+use crate::threads::listener::Transport;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/******************************** CONFIGURATION ********************************/
+
+/// IPFIX mediator (RFC 6183): the flows are re-encoded with our own templates and sent to an upstream collector
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IpfixSinkConfig {
+    /// IP:port of the upstream collector
+    pub target: SocketAddr,
+    #[serde(default = "default_transport")]
+    pub transport: Transport,
+    /// Observation domain id of the messages we export
+    #[serde(default)]
+    pub domain_id: u32,
+    /// Seconds between two retransmissions of a template over UDP, 0 to send them in every message
+    #[serde(default = "default_template_refresh")]
+    pub template_refresh: u64,
+    /// Maximum size of a message, to stay under the path MTU over UDP
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+}
+
+fn default_transport() -> Transport {
+    Transport::Udp
+}
+
+fn default_template_refresh() -> u64 {
+    600
+}
+
+fn default_max_message_size() -> usize {
+    1400
+}
+
+/// Smallest message able to carry a template set and a data set with a few fields
+const MIN_MESSAGE_SIZE: usize = 256;
+
+/******************************** SINK ********************************/
+
+pub struct IpfixSink {
+    config: IpfixSinkConfig,
+    connection: Option<Connection>,
+    /// Template id assigned to each distinct list of fields and lengths
+    template_ids: HashMap<Vec<(FieldType, u16)>, u16>,
+    /// Templates indexed by their id minus ipfix::DataSet::MIN_SET_ID
+    templates: Vec<ExportedTemplate>,
+    /// Number of data records sent in the current transport session
+    seq_number: u32,
+}
+
+struct ExportedTemplate {
+    template: DataSetTemplate,
+    /// Last time the template has been sent in the current transport session
+    sent: Option<Instant>,
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl IpfixSink {
+    pub fn new(config: IpfixSinkConfig) -> Result<Self, String> {
+        if config.max_message_size < MIN_MESSAGE_SIZE || config.max_message_size > u16::MAX as usize {
+            return Err(format!("The maximum message size must be between {} and {} bytes", MIN_MESSAGE_SIZE, u16::MAX));
+        }
+
+        info!(
+            "Exporting the flows as IPFIX to {} over {:?} with the observation domain {}",
+            config.target, config.transport, config.domain_id
+        );
+
+        Ok(IpfixSink {
+            config,
+            connection: None,
+            template_ids: HashMap::new(),
+            templates: vec![],
+            seq_number: 0,
+        })
+    }
+
+    /// Open the transport session, every template must be sent again in a new session
+    fn connect(&mut self) -> io::Result<()> {
+        let connection = match self.config.transport {
+            Transport::Udp => {
+                let bind_addr: SocketAddr = match self.config.target {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                let socket = UdpSocket::bind(bind_addr)?;
+                socket.connect(self.config.target)?;
+                Connection::Udp(socket)
+            }
+            Transport::Tcp => {
+                let stream = TcpStream::connect_timeout(&self.config.target, CONNECT_TIMEOUT)?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                Connection::Tcp(stream)
+            }
+        };
+
+        for template in self.templates.iter_mut() {
+            template.sent = None;
+        }
+        self.seq_number = 0;
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    /// Id of the template matching the fields of a record, assigned on first use
+    fn template_id(&mut self, key: Vec<(FieldType, u16)>) -> Result<u16, String> {
+        if let Some(&id) = self.template_ids.get(&key) {
+            return Ok(id);
+        }
+
+        let id = u16::try_from(DataSet::MIN_SET_ID as usize + self.templates.len()).map_err(|_| "No template id left".to_string())?;
+        self.templates.push(ExportedTemplate {
+            template: DataSetTemplate::new(id, key.iter().map(|&(id, length)| TemplateField { id, length }).collect()),
+            sent: None,
+        });
+        self.template_ids.insert(key, id);
+        Ok(id)
+    }
+
+    /// Over UDP the templates are sent periodically, over TCP once per connection (RFC 7011 section 8)
+    fn template_due(&self, id: u16, now: Instant) -> bool {
+        match (self.templates[(id - DataSet::MIN_SET_ID) as usize].sent, self.config.transport) {
+            (None, _) => true,
+            (Some(_), Transport::Tcp) => false,
+            (Some(sent), Transport::Udp) => now.duration_since(sent).as_secs() >= self.config.template_refresh,
+        }
+    }
+
+    /// Encode the records grouped by template into messages of at most max_message_size bytes
+    fn encode(&mut self, groups: Vec<(u16, Vec<Vec<u8>>)>) -> Result<Vec<Vec<u8>>, String> {
+        let now = Instant::now();
+        let export_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0);
+        let max_size = self.config.max_message_size;
+
+        let mut messages = vec![];
+        let mut msg = self.start_message(export_time);
+
+        for (id, records) in groups {
+            let mut data_set = None;
+
+            for record in records {
+                let mut template_set = if data_set.is_none() && self.template_due(id, now) { Some(self.template_set(id)) } else { None };
+                let needed = template_set.as_ref().map_or(0, |set| set.len()) + if data_set.is_none() { SetHeader::SIZE } else { 0 } + record.len();
+
+                // a record bigger than a message is sent alone
+                if msg.len() + needed > max_size && msg.len() > Header::SIZE {
+                    if let Some(offset) = data_set.take() {
+                        close_set(&mut msg, offset)?;
+                    }
+                    messages.push(finish_message(msg)?);
+                    msg = self.start_message(export_time);
+
+                    if template_set.is_none() && self.template_due(id, now) {
+                        template_set = Some(self.template_set(id));
+                    }
+                }
+
+                if let Some(set) = template_set {
+                    msg.extend_from_slice(&set);
+                    self.templates[(id - DataSet::MIN_SET_ID) as usize].sent = Some(now);
+                }
+                if data_set.is_none() {
+                    data_set = Some(msg.len());
+                    SetHeader { id, length: 0 }.write(&mut msg);
+                }
+                msg.extend_from_slice(&record);
+                self.seq_number = self.seq_number.wrapping_add(1);
+            }
+
+            if let Some(offset) = data_set {
+                close_set(&mut msg, offset)?;
+            }
+        }
+
+        if msg.len() > Header::SIZE {
+            messages.push(finish_message(msg)?);
+        }
+
+        Ok(messages)
+    }
+
+    /// Template set carrying the template record of id
+    fn template_set(&self, id: u16) -> Vec<u8> {
+        let mut record = vec![];
+        self.templates[(id - DataSet::MIN_SET_ID) as usize].template.write(&mut record);
+
+        let mut set = vec![];
+        SetHeader {
+            id: DataSetTemplate::SET_ID,
+            length: (SetHeader::SIZE + record.len()) as u16,
+        }
+        .write(&mut set);
+        set.extend_from_slice(&record);
+        set
+    }
+
+    /// Header of a new message, its length is set when the message is finished
+    fn start_message(&self, export_time: u32) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(self.config.max_message_size);
+        Header {
+            version: ipfix::VERSION,
+            length: 0,
+            export_time,
+            seq_number: self.seq_number,
+            domain_id: self.config.domain_id,
+        }
+        .write(&mut msg);
+        msg
+    }
+
+    fn send(&mut self, messages: &[Vec<u8>]) -> io::Result<()> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "not connected")),
+        };
+
+        for msg in messages {
+            match connection {
+                Connection::Udp(socket) => socket.send(msg).map(|_| ())?,
+                Connection::Tcp(stream) => stream.write_all(msg)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Set the length of the set header written at offset
+fn close_set(msg: &mut [u8], offset: usize) -> Result<(), String> {
+    let length = u16::try_from(msg.len() - offset).map_err(|_| format!("Set of {} bytes too big", msg.len() - offset))?;
+    msg[offset + 2..offset + 4].copy_from_slice(&length.to_be_bytes());
+    Ok(())
+}
+
+fn finish_message(mut msg: Vec<u8>) -> Result<Vec<u8>, String> {
+    let length = u16::try_from(msg.len()).map_err(|_| format!("Message of {} bytes too big", msg.len()))?;
+    msg[2..4].copy_from_slice(&length.to_be_bytes());
+    Ok(msg)
+}
+
+impl Sink for IpfixSink {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String> {
+        // the records are grouped by template, in the order of the first record of each template
+        let mut groups: Vec<(u16, Vec<Vec<u8>>)> = vec![];
+
+        for flow in &batch.flows {
+            let fields = flow.fields();
+            let id = self.template_id(fields.iter().map(|(ftype, fvalue)| (*ftype, fvalue.length())).collect())?;
+
+            let mut record = vec![];
+            for (_, fvalue) in &fields {
+                fvalue.write(fvalue.length(), &mut record)?;
+            }
+
+            match groups.iter_mut().find(|(group_id, _)| *group_id == id) {
+                Some((_, records)) => records.push(record),
+                None => groups.push((id, vec![record])),
+            }
+        }
+
+        if groups.is_empty() {
+            return Ok(());
+        }
+
+        if self.connection.is_none() {
+            self.connect().map_err(|e| format!("Failed to connect to the IPFIX collector {} : {}", self.config.target, e))?;
+        }
+
+        let messages = self.encode(groups)?;
+        if let Err(e) = self.send(&messages) {
+            // a broken TCP session is opened again on the next batch, with all the templates
+            if self.config.transport == Transport::Tcp {
+                warn!("Connection to the IPFIX collector {} lost", self.config.target);
+                self.connection = None;
+            }
+            return Err(format!("Failed to send {} messages to the IPFIX collector {} : {}", messages.len(), self.config.target, e));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::ipfix::{FieldValue, VARIABLE_LENGTH};
+    use crate::flow::{netflow5, Flow};
+    use hex_literal::hex;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    const NETFLOW5_HEADER: [u8; netflow5::Header::SIZE] = hex!("00 05 00 10 00 00 04 b2 60 80 b8 9c 1a 47 ff 30 00 00 00 02 01 00 00 00");
+
+    const NETFLOW5_DATASET: [u8; netflow5::DataSet::SIZE] = hex!(
+        "70 0a 14 0a ac 1e be 0a ac c7 0f 01 00 00 00 00
+         00 00 03 1b 00 00 01 03 00 00 02 36 00 00 03 a8
+         00 28 00 50 00 00 06 00 c3 0d 35 bd 15 1a 00 00"
+    );
+
+    fn sink_config(target: SocketAddr, transport: Transport) -> IpfixSinkConfig {
+        IpfixSinkConfig {
+            target,
+            transport,
+            domain_id: 42,
+            template_refresh: 600,
+            max_message_size: 1400,
+        }
+    }
+
+    fn ipfix_flow(bytes: u64, name: &str) -> Box<dyn Flow> {
+        let mut fields = HashMap::new();
+        fields.insert(FieldType::OctetDeltaCount, FieldValue::U64(bytes));
+        fields.insert(FieldType::SourceIPv4Address, FieldValue::U32(0xc0000201));
+        fields.insert(FieldType::InterfaceName, FieldValue::Dyn(name.as_bytes().to_vec()));
+        Box::new(DataSet { fields })
+    }
+
+    fn batch(flows: Vec<Box<dyn Flow>>) -> FlowBatch {
+        FlowBatch {
+            tag: None,
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: None,
+            flows,
+        }
+    }
+
+    /// Decode a message with the templates received so far
+    fn decode(msg: &[u8], templates: &mut HashMap<u16, DataSetTemplate>) -> (Header, Vec<DataSet>) {
+        let header = Header::read(msg).unwrap();
+        assert_eq!(header.length as usize, msg.len());
+
+        let mut records = vec![];
+        let mut offset = Header::SIZE;
+        while offset < msg.len() {
+            let set = SetHeader::read(&msg[offset..]).unwrap();
+            let content = &msg[offset + SetHeader::SIZE..offset + set.length as usize];
+
+            let mut pos = 0;
+            while pos < content.len() {
+                if set.id == DataSetTemplate::SET_ID {
+                    let (template, size) = DataSetTemplate::read(&content[pos..]).unwrap();
+                    templates.insert(template.header.id, template);
+                    pos += size;
+                } else {
+                    let (record, size) = DataSet::read(&content[pos..], &templates[&set.id].plan).unwrap();
+                    records.push(record);
+                    pos += size;
+                }
+            }
+            offset += set.length as usize;
+        }
+
+        (header, records)
+    }
+
+    #[test]
+    fn export_ipfix_and_netflow5_records() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sink = IpfixSink::new(sink_config(collector.local_addr().unwrap(), Transport::Udp)).unwrap();
+
+        let mut v5 = netflow5::DataSet::read(&NETFLOW5_DATASET).unwrap();
+        v5.set_system_init_time(&netflow5::Header::read(&NETFLOW5_HEADER).unwrap());
+        let expected = [ipfix_flow(1000, "eth0").fields(), v5.fields(), ipfix_flow(2000, "xe-0/0/0.100").fields()];
+        sink.write(&batch(vec![ipfix_flow(1000, "eth0"), Box::new(v5), ipfix_flow(2000, "xe-0/0/0.100")])).unwrap();
+
+        let mut buf = [0; 2048];
+        let size = collector.recv(&mut buf).unwrap();
+        let mut templates = HashMap::new();
+        let (header, records) = decode(&buf[0..size], &mut templates);
+
+        assert_eq!(header.version, ipfix::VERSION);
+        assert_eq!(header.seq_number, 0);
+        assert_eq!(header.domain_id, 42);
+        assert_eq!(templates.len(), 2);
+        assert_eq!(
+            templates[&256].fields[2],
+            TemplateField {
+                id: FieldType::InterfaceName,
+                length: VARIABLE_LENGTH
+            }
+        );
+        // grouped by template, in the order of their first record
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].fields(), expected[0]);
+        assert_eq!(records[1].fields(), expected[2]);
+        assert_eq!(records[2].fields(), expected[1]);
+        assert_eq!(records[2].fields[&FieldType::FlowStartMilliseconds], FieldValue::U64(1619048604440 - 1202 + 566));
+
+        // the templates are only sent again after the refresh interval
+        sink.write(&batch(vec![ipfix_flow(3000, "eth1")])).unwrap();
+        let size = collector.recv(&mut buf).unwrap();
+        assert_eq!(SetHeader::read(&buf[Header::SIZE..size]).unwrap().id, 256);
+        let (header, records) = decode(&buf[0..size], &mut templates);
+        assert_eq!(header.seq_number, 3);
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn split_messages() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut config = sink_config(collector.local_addr().unwrap(), Transport::Udp);
+        config.max_message_size = MIN_MESSAGE_SIZE;
+        config.template_refresh = 0;
+        let mut sink = IpfixSink::new(config).unwrap();
+
+        sink.write(&batch((0..40).map(|i| ipfix_flow(i, "eth0")).collect())).unwrap();
+
+        let mut buf = [0; 2048];
+        let mut seq_number = 0;
+        let mut bytes = 0;
+        collector.set_nonblocking(true).unwrap();
+        while let Ok(size) = collector.recv(&mut buf) {
+            assert!(size <= MIN_MESSAGE_SIZE);
+            // with a refresh interval of 0 every message carries the template
+            let (header, records) = decode(&buf[0..size], &mut HashMap::new());
+            assert_eq!(header.seq_number, seq_number);
+            for record in &records {
+                assert_eq!(record.fields[&FieldType::OctetDeltaCount], FieldValue::U64(bytes));
+                bytes += 1;
+            }
+            seq_number += records.len() as u32;
+        }
+        assert_eq!(seq_number, 40);
+
+        assert!(IpfixSink::new(sink_config("127.0.0.1:4739".parse().unwrap(), Transport::Udp)).is_ok());
+        let mut config = sink_config("127.0.0.1:4739".parse().unwrap(), Transport::Udp);
+        config.max_message_size = 100;
+        assert!(IpfixSink::new(config).is_err());
+    }
+
+    #[test]
+    fn export_over_tcp() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = IpfixSink::new(sink_config(collector.local_addr().unwrap(), Transport::Tcp)).unwrap();
+
+        sink.write(&batch(vec![ipfix_flow(1000, "eth0")])).unwrap();
+        sink.write(&batch(vec![ipfix_flow(2000, "eth0")])).unwrap();
+
+        let (mut stream, _) = collector.accept().unwrap();
+        let mut templates = HashMap::new();
+        for (seq_number, with_template) in [(0, true), (1, false)] {
+            let mut header = [0; Header::SIZE];
+            stream.read_exact(&mut header).unwrap();
+            let mut msg = header.to_vec();
+            msg.resize(Header::read(&header).unwrap().length as usize, 0);
+            stream.read_exact(&mut msg[Header::SIZE..]).unwrap();
+
+            let count = templates.len();
+            let (header, records) = decode(&msg, &mut templates);
+            assert_eq!(header.seq_number, seq_number);
+            assert_eq!(records.len(), 1);
+            assert_eq!(templates.len() > count, with_template);
+        }
+    }
+}
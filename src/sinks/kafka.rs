@@ -0,0 +1,1065 @@
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use super::columns::{Column, Value};
+use super::goflow2::FlowMessage;
+use super::json;
+use super::protobuf;
+use super::{BatchConfig, BatchOptions, Batcher, Sink};
+use crate::flow::ipfix::{FieldType, FieldValue};
+use crate::flow::{Flow, FlowBatch};
+use crate::metrics;
+use crate::threads::clock::Clock;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bound of the answers of the brokers, the metadata of a big cluster stays far below
+const MAX_RESPONSE_SIZE: usize = 64 << 20;
+
+/******************************** CONFIGURATION ********************************/
+
+/// Records published to a Kafka topic, one per flow
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaSinkConfig {
+    /// host:port of the brokers asked for the leaders of the partitions
+    pub brokers: Vec<String>,
+    pub topic: String,
+    #[serde(default)]
+    pub format: RecordFormat,
+    /// The records with the same key are written to the same partition
+    #[serde(default)]
+    pub key: RecordKey,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default = "default_compression_level")]
+    pub compression_level: i32,
+    /// Acknowledgements required from the replicas: 0 (none), 1 (leader) or -1 (all in sync replicas)
+    #[serde(default = "default_acks")]
+    pub acks: i16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    /// Records of the produce requests, 1000 records or 5s by default, then 3 retries of the ones failing because a broker was unavailable or not the leader anymore
+    #[serde(flatten)]
+    pub batch: BatchConfig,
+    /// Milliseconds given to the brokers to answer, including the replication of the records
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordFormat {
+    /// One object with the IANA names of the fields
+    #[default]
+    Json,
+    /// The Flow message documented in sinks/protobuf.rs
+    Protobuf,
+    /// goflow2's FlowMessage prefixed by its length, like goflow2 -format=bin
+    Goflow2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKey {
+    #[default]
+    Exporter,
+    /// IPv4 or IPv6 source address of the flow, the exporter when the flow has none
+    SourceAddress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    /// Needs Kafka 2.1 or later
+    Zstd,
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_acks() -> i16 {
+    -1
+}
+
+fn default_client_id() -> String {
+    "ipfix".to_string()
+}
+
+fn default_timeout() -> u64 {
+    30_000
+}
+
+pub const BATCH_DEFAULTS: BatchOptions = BatchOptions {
+    size: 1000,
+    max_age: 5,
+    retries: 3,
+    backoff: 500,
+};
+
+/******************************** RECORDS ********************************/
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    /// Milliseconds since the UNIX epoch
+    timestamp: i64,
+}
+
+/// Value as a JSON number or string
+fn json_value(value: &Value, out: &mut String) {
+    match value {
+        Value::Unsigned(v) if *v <= u64::MAX as u128 => out.push_str(&v.to_string()),
+        Value::Signed(v) => out.push_str(&v.to_string()),
+        Value::Float(v) if v.is_finite() => out.push_str(&v.to_string()),
+        Value::Boolean(v) => out.push_str(&v.to_string()),
+        _ => json::write_string(out, &value.to_string()),
+    }
+}
+
+/// One line JSON object of a flow, the exporter and the absolute times followed by every field
+fn json_record(batch: &FlowBatch, fields: &[(FieldType, FieldValue)]) -> Vec<u8> {
+    let mut out = String::from("{");
+    let columns = [Column::Exporter, Column::ExporterName, Column::Tag, Column::StartTime, Column::EndTime];
+    let values = columns
+        .iter()
+        .filter_map(|column| Some((column.name(), column.value(batch, fields)?)))
+        .chain(fields.iter().map(|(id, value)| (Column::Field(*id).name(), Value::from_field(*id, value))));
+
+    for (i, (name, value)) in values.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json::write_string(&mut out, &name);
+        out.push(':');
+        json_value(&value, &mut out);
+    }
+    out.push('}');
+    out.into_bytes()
+}
+
+/// Hash of the keys used by the default partitioner of the Java client, the flows of a key land in the same partition with both
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h = 0x9747_b28c ^ data.len() as u32;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]).wrapping_mul(M);
+        k ^= k >> 24;
+        h = h.wrapping_mul(M) ^ k.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, b) in tail.iter().enumerate() {
+            h ^= (*b as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+fn partition(key: &[u8], partitions: usize) -> i32 {
+    ((murmur2(key) & 0x7fff_ffff) as usize % partitions) as i32
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Castagnoli CRC of the record batches
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+fn write_varlong(out: &mut Vec<u8>, value: i64) {
+    protobuf::write_varint(out, protobuf::zigzag(value));
+}
+
+/// Record batch of the v2 message format (magic 2), without producer id nor transaction
+fn record_batch(records: &[Record], compression: Compression, level: i32) -> io::Result<Vec<u8>> {
+    let base_timestamp = records.first().map_or(0, |r| r.timestamp);
+    let max_timestamp = records.iter().map(|r| r.timestamp).max().unwrap_or(0);
+
+    let mut encoded = vec![];
+    let mut record = vec![];
+    for (offset, r) in records.iter().enumerate() {
+        record.clear();
+        // attributes
+        record.push(0);
+        write_varlong(&mut record, r.timestamp - base_timestamp);
+        write_varlong(&mut record, offset as i64);
+        write_varlong(&mut record, r.key.len() as i64);
+        record.extend_from_slice(&r.key);
+        write_varlong(&mut record, r.value.len() as i64);
+        record.extend_from_slice(&r.value);
+        // no headers
+        write_varlong(&mut record, 0);
+
+        write_varlong(&mut encoded, record.len() as i64);
+        encoded.extend_from_slice(&record);
+    }
+
+    let (attributes, encoded) = match compression {
+        Compression::None => (0i16, encoded),
+        Compression::Zstd => (4, zstd::bulk::compress(&encoded, level)?),
+    };
+
+    // from attributes to the end, covered by the CRC
+    let mut body = vec![];
+    body.extend_from_slice(&attributes.to_be_bytes());
+    body.extend_from_slice(&(records.len() as i32 - 1).to_be_bytes());
+    body.extend_from_slice(&base_timestamp.to_be_bytes());
+    body.extend_from_slice(&max_timestamp.to_be_bytes());
+    // producer id, epoch and base sequence
+    body.extend_from_slice(&(-1i64).to_be_bytes());
+    body.extend_from_slice(&(-1i16).to_be_bytes());
+    body.extend_from_slice(&(-1i32).to_be_bytes());
+    body.extend_from_slice(&(records.len() as i32).to_be_bytes());
+    body.extend_from_slice(&encoded);
+
+    let mut batch = Vec::with_capacity(body.len() + 21);
+    // base offset, assigned by the broker
+    batch.extend_from_slice(&0i64.to_be_bytes());
+    // length of the batch after this field
+    batch.extend_from_slice(&(body.len() as i32 + 9).to_be_bytes());
+    // partition leader epoch
+    batch.extend_from_slice(&(-1i32).to_be_bytes());
+    // magic
+    batch.push(2);
+    batch.extend_from_slice(&crc32c(&body).to_be_bytes());
+    batch.extend_from_slice(&body);
+    Ok(batch)
+}
+
+/******************************** PROTOCOL ********************************/
+
+// Produce v7 and Metadata v4 are accepted from Kafka 2.1 to Kafka 4
+const PRODUCE: i16 = 0;
+const PRODUCE_VERSION: i16 = 7;
+const METADATA: i16 = 3;
+const METADATA_VERSION: i16 = 4;
+
+/// Error codes after which the records can be sent again, once the metadata is refreshed
+fn is_retriable(code: i16) -> bool {
+    matches!(code, 3 | 5 | 6 | 7 | 8 | 13 | 19 | 20 | 56)
+}
+
+fn error_name(code: i16) -> String {
+    match code {
+        -1 => "unknown_server_error".to_string(),
+        2 => "corrupt_message".to_string(),
+        3 => "unknown_topic_or_partition".to_string(),
+        5 => "leader_not_available".to_string(),
+        6 => "not_leader_or_follower".to_string(),
+        7 => "request_timed_out".to_string(),
+        10 => "message_too_large".to_string(),
+        17 => "invalid_topic".to_string(),
+        18 => "record_list_too_large".to_string(),
+        19 => "not_enough_replicas".to_string(),
+        29 => "topic_authorization_failed".to_string(),
+        76 => "unsupported_compression_type".to_string(),
+        87 => "invalid_record".to_string(),
+        code => format!("error_{}", code),
+    }
+}
+
+/// Big endian fields of the requests
+#[derive(Debug, Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn i16(&mut self, v: i16) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn i32(&mut self, v: i32) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn string(&mut self, v: &str) {
+        self.i16(v.len() as i16);
+        self.0.extend_from_slice(v.as_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.i32(v.len() as i32);
+        self.0.extend_from_slice(v);
+    }
+}
+
+/// Fields of the responses
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(|| "Truncated Kafka response".to_string())?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn i8(&mut self) -> Result<i8, String> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> Result<i16, String> {
+        let b = self.take(2)?;
+        Ok(i16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        let b = self.take(4)?;
+        Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        let b = self.take(8)?;
+        Ok(i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    /// Null strings are read as empty strings
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(String::new());
+        }
+        Ok(String::from_utf8_lossy(self.take(len as usize)?).to_string())
+    }
+
+    /// Length of an array, 0 for a null array
+    fn array(&mut self) -> Result<usize, String> {
+        let len = self.i32()?;
+        // each item takes at least one byte, a bigger length can only come from a corrupted response
+        if len > 0 && len as usize > self.data.len() - self.pos {
+            return Err("Invalid array length in Kafka response".to_string());
+        }
+        Ok(len.max(0) as usize)
+    }
+}
+
+/// Connection to one broker, the requests are sent one at a time
+struct Connection {
+    stream: BufReader<TcpStream>,
+    correlation_id: i32,
+}
+
+impl Connection {
+    fn open(address: &str, timeout: Duration) -> io::Result<Self> {
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No address for {}", address)))?;
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream: BufReader::new(stream),
+            correlation_id: 0,
+        })
+    }
+
+    /// Send a request with the v1 header, returns the body of the response or None when the request doesn't have one
+    fn request(&mut self, client_id: &str, api_key: i16, version: i16, body: &[u8], expect_response: bool) -> io::Result<Option<Vec<u8>>> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut request = Writer::default();
+        request.i32(0);
+        request.i16(api_key);
+        request.i16(version);
+        request.i32(self.correlation_id);
+        request.string(client_id);
+        request.0.extend_from_slice(body);
+        let size = (request.0.len() - 4) as i32;
+        request.0[0..4].copy_from_slice(&size.to_be_bytes());
+
+        let stream = self.stream.get_mut();
+        stream.write_all(&request.0)?;
+        stream.flush()?;
+        if !expect_response {
+            return Ok(None);
+        }
+
+        let mut size = [0; 4];
+        self.stream.read_exact(&mut size)?;
+        let size = i32::from_be_bytes(size);
+        if !(4..=MAX_RESPONSE_SIZE as i32).contains(&size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Kafka response size {}", size)));
+        }
+        let mut response = vec![0; size as usize];
+        self.stream.read_exact(&mut response)?;
+        if response[0..4] != self.correlation_id.to_be_bytes() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Kafka response to another request"));
+        }
+        response.drain(0..4);
+        Ok(Some(response))
+    }
+}
+
+/// Leaders of the partitions of the topic
+#[derive(Debug, Clone, PartialEq)]
+struct Metadata {
+    /// host:port of the brokers by node id
+    brokers: HashMap<i32, String>,
+    /// Leader of each partition, None while it is elected
+    leaders: Vec<Option<i32>>,
+}
+
+fn metadata_request(topic: &str) -> Vec<u8> {
+    let mut request = Writer::default();
+    request.i32(1);
+    request.string(topic);
+    // allow_auto_topic_creation, the brokers decide
+    request.0.push(1);
+    request.0
+}
+
+fn parse_metadata(response: &[u8], topic: &str) -> Result<Metadata, String> {
+    let mut reader = Reader::new(response);
+    // throttle_time_ms
+    reader.i32()?;
+
+    let mut brokers = HashMap::new();
+    for _ in 0..reader.array()? {
+        let node_id = reader.i32()?;
+        let host = reader.string()?;
+        let port = reader.i32()?;
+        // rack
+        reader.string()?;
+        let address = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+        brokers.insert(node_id, address);
+    }
+    // cluster_id and controller_id
+    reader.string()?;
+    reader.i32()?;
+
+    for _ in 0..reader.array()? {
+        let error_code = reader.i16()?;
+        let name = reader.string()?;
+        // is_internal
+        reader.i8()?;
+        let mut leaders = vec![];
+        for _ in 0..reader.array()? {
+            let partition_error = reader.i16()?;
+            let index = reader.i32()?;
+            let leader = reader.i32()?;
+            for _ in 0..2 {
+                // replica and in sync nodes
+                for _ in 0..reader.array()? {
+                    reader.i32()?;
+                }
+            }
+            if index < 0 {
+                return Err(format!("Invalid partition {} in Kafka metadata", index));
+            }
+            if leaders.len() <= index as usize {
+                leaders.resize(index as usize + 1, None);
+            }
+            leaders[index as usize] = Some(leader).filter(|&leader| leader >= 0 && partition_error == 0);
+        }
+
+        if name == topic {
+            if error_code != 0 {
+                return Err(format!("Kafka metadata of topic {} : {}", topic, error_name(error_code)));
+            }
+            if leaders.is_empty() {
+                return Err(format!("Kafka topic {} doesn't have any partition", topic));
+            }
+            return Ok(Metadata { brokers, leaders });
+        }
+    }
+    Err(format!("Kafka metadata without topic {}", topic))
+}
+
+/// Error code of each partition of a produce response
+fn parse_produce(response: &[u8]) -> Result<HashMap<i32, i16>, String> {
+    let mut reader = Reader::new(response);
+    let mut errors = HashMap::new();
+    for _ in 0..reader.array()? {
+        reader.string()?;
+        for _ in 0..reader.array()? {
+            let index = reader.i32()?;
+            let error_code = reader.i16()?;
+            // base_offset, log_append_time and log_start_offset
+            reader.i64()?;
+            reader.i64()?;
+            reader.i64()?;
+            errors.insert(index, error_code);
+        }
+    }
+    Ok(errors)
+}
+
+/******************************** SINK ********************************/
+
+pub struct KafkaSink {
+    config: KafkaSinkConfig,
+    /// Records of the next produce requests
+    records: Batcher<Record>,
+    metadata: Option<Metadata>,
+    /// Set when a broker wasn't the leader anymore or couldn't be reached
+    stale: bool,
+    /// Open connections by host:port
+    connections: HashMap<String, Connection>,
+    delivered: metrics::CounterHandle,
+    clock: Clock,
+}
+
+impl KafkaSink {
+    pub fn new(config: KafkaSinkConfig) -> Result<Self, String> {
+        if config.brokers.is_empty() {
+            return Err("The Kafka sink needs at least one broker".to_string());
+        }
+        if config.topic.is_empty() {
+            return Err("The Kafka sink needs a topic".to_string());
+        }
+        let options = config.batch.or(BATCH_DEFAULTS);
+        if options.size == 0 {
+            return Err("The produce requests of the Kafka sink need at least 1 record".to_string());
+        }
+        if !(-1..=1).contains(&config.acks) {
+            return Err(format!("Invalid acks {} for the Kafka sink, expected -1, 0 or 1", config.acks));
+        }
+        info!("Publishing the flows to the Kafka topic {} through {}", config.topic, config.brokers.join(", "));
+
+        Ok(KafkaSink {
+            delivered: metrics::KAFKA_DELIVERED_RECORDS.with(&[("topic", &config.topic)]),
+            config,
+            records: Batcher::new(options),
+            metadata: None,
+            stale: false,
+            connections: HashMap::new(),
+            clock: Clock::default(),
+        })
+    }
+
+    fn record(&self, batch: &FlowBatch, flow: &dyn Flow, now_millis: u64) -> Record {
+        let fields = &flow.fields();
+        let source = || {
+            [FieldType::SourceIPv4Address, FieldType::SourceIPv6Address]
+                .iter()
+                .find_map(|&id| match Column::Field(id).value(batch, fields) {
+                    Some(Value::Address(addr)) => Some(addr),
+                    _ => None,
+                })
+        };
+        let key: IpAddr = match self.config.key {
+            RecordKey::Exporter => batch.exporter,
+            RecordKey::SourceAddress => source().unwrap_or(batch.exporter),
+        };
+        let timestamp = match Column::EndTime.value(batch, fields) {
+            Some(Value::Timestamp(millis)) => millis,
+            _ => now_millis,
+        };
+
+        Record {
+            key: protobuf::address_bytes(&key),
+            value: match self.config.format {
+                RecordFormat::Json => json_record(batch, fields),
+                RecordFormat::Protobuf => protobuf::flow_message(batch, fields),
+                RecordFormat::Goflow2 => FlowMessage::new(batch, flow.version(), fields, now_millis / 1000).encode_delimited(),
+            },
+            timestamp: timestamp as i64,
+        }
+    }
+
+    fn connection(&mut self, address: &str) -> io::Result<&mut Connection> {
+        if !self.connections.contains_key(address) {
+            let connection = Connection::open(address, Duration::from_millis(self.config.timeout))?;
+            self.connections.insert(address.to_string(), connection);
+        }
+        Ok(self.connections.get_mut(address).unwrap())
+    }
+
+    /// Leaders of the partitions, asked to the known brokers then to the configured ones
+    fn refresh_metadata(&mut self) -> Result<(), String> {
+        let mut addresses: Vec<String> = self.metadata.iter().flat_map(|m| m.brokers.values().cloned()).collect();
+        addresses.extend(self.config.brokers.iter().cloned());
+        let request = metadata_request(&self.config.topic);
+
+        let mut last_error = String::new();
+        for address in addresses {
+            let client_id = self.config.client_id.clone();
+            let response = self
+                .connection(&address)
+                .and_then(|connection| connection.request(&client_id, METADATA, METADATA_VERSION, &request, true))
+                .map_err(|e| e.to_string())
+                .and_then(|response| parse_metadata(&response.unwrap_or_default(), &self.config.topic));
+            match response {
+                Ok(metadata) => {
+                    self.metadata = Some(metadata);
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.connections.remove(&address);
+                    last_error = format!("{} : {}", address, e);
+                }
+            }
+        }
+        Err(format!("Failed to get the Kafka metadata of topic {}, last error from {}", self.config.topic, last_error))
+    }
+
+    fn count_errors(&self, error: &str, records: usize) {
+        metrics::KAFKA_DELIVERY_ERRORS.with(&[("topic", &self.config.topic), ("error", error)]).add(records as u64);
+    }
+
+    /// Send one produce request per leader, returns the records that can be sent again
+    fn produce(&mut self, records: Vec<Record>) -> Vec<Record> {
+        if self.stale || self.metadata.is_none() {
+            match self.refresh_metadata() {
+                Ok(()) => self.stale = false,
+                Err(e) => {
+                    warn!("{}", e);
+                    return records;
+                }
+            }
+        }
+        let metadata = match &self.metadata {
+            Some(metadata) => metadata.clone(),
+            None => return records,
+        };
+
+        // records by leader and partition
+        let mut retry = vec![];
+        let mut leaders: BTreeMap<i32, BTreeMap<i32, Vec<Record>>> = BTreeMap::new();
+        for record in records {
+            let partition = partition(&record.key, metadata.leaders.len());
+            match metadata.leaders[partition as usize] {
+                Some(leader) => leaders.entry(leader).or_default().entry(partition).or_default().push(record),
+                None => retry.push(record),
+            }
+        }
+
+        for (leader, partitions) in leaders {
+            let address = match metadata.brokers.get(&leader) {
+                Some(address) => address.clone(),
+                None => {
+                    retry.extend(partitions.into_values().flatten());
+                    continue;
+                }
+            };
+
+            let mut batches = vec![];
+            for (partition, records) in partitions {
+                match record_batch(&records, self.config.compression, self.config.compression_level) {
+                    Ok(batch) => batches.push((partition, records, batch)),
+                    Err(e) => {
+                        error!("Failed to compress {} Kafka records : {}", records.len(), e);
+                        self.count_errors("compression", records.len());
+                    }
+                }
+            }
+
+            let mut request = Writer::default();
+            // no transactional id
+            request.i16(-1);
+            request.i16(self.config.acks);
+            request.i32(self.config.timeout.min(i32::MAX as u64) as i32);
+            request.i32(1);
+            request.string(&self.config.topic);
+            request.i32(batches.len() as i32);
+            for (partition, _, batch) in &batches {
+                request.i32(*partition);
+                request.bytes(batch);
+            }
+
+            let client_id = self.config.client_id.clone();
+            let expect_response = self.config.acks != 0;
+            let response = self
+                .connection(&address)
+                .and_then(|connection| connection.request(&client_id, PRODUCE, PRODUCE_VERSION, &request.0, expect_response))
+                .map_err(|e| e.to_string())
+                .and_then(|response| response.map_or_else(|| Ok(HashMap::new()), |response| parse_produce(&response)));
+
+            let errors = match response {
+                Ok(errors) => errors,
+                Err(e) => {
+                    warn!("Kafka produce request to {} failed : {}", address, e);
+                    self.connections.remove(&address);
+                    self.stale = true;
+                    retry.extend(batches.into_iter().flat_map(|(_, records, _)| records));
+                    continue;
+                }
+            };
+
+            for (partition, records, _) in batches {
+                // without acknowledgement the records are delivered once written
+                match errors.get(&partition).copied().unwrap_or(if expect_response { -1 } else { 0 }) {
+                    0 => self.delivered.add(records.len() as u64),
+                    code if is_retriable(code) => {
+                        self.stale = true;
+                        retry.extend(records);
+                    }
+                    code => {
+                        error!("Kafka rejected {} records of partition {} : {}", records.len(), partition, error_name(code));
+                        self.count_errors(&error_name(code), records.len());
+                    }
+                }
+            }
+        }
+        retry
+    }
+
+    /// Send the records, the ones that failed because of the brokers are retried with an exponential backoff
+    fn send(&mut self, records: Vec<Record>) -> Result<(), String> {
+        let options = self.records.options();
+        let unsent = super::send_with_retries(records, options, "Kafka records", |records| Ok(self.produce(records)))?;
+        if unsent.is_empty() {
+            Ok(())
+        } else {
+            self.count_errors("unavailable", unsent.len());
+            Err(format!("Kafka unavailable, {} records dropped", unsent.len()))
+        }
+    }
+}
+
+impl Sink for KafkaSink {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String> {
+        let now = self.clock.now_secs();
+        let records: Vec<_> = batch.flows.iter().map(|flow| self.record(batch, flow.as_ref(), now * 1000)).collect();
+
+        let ready = self.records.add(records, now);
+        super::send_all(ready, |records| self.send(records))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        let records = self.records.take();
+        self.send(records)
+    }
+
+    fn tick(&mut self) -> Result<(), String> {
+        if !self.records.expired(self.clock.now_secs()) {
+            return Ok(());
+        }
+        let records = self.records.take();
+        self.send(records)
+    }
+}
+
+impl Drop for KafkaSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::netflow5;
+    use crate::sinks::fixture;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    fn batch(sources: &[u32]) -> FlowBatch {
+        fixture::batch(sources.iter().map(|&src_addr| netflow5::DataSet { src_addr, ..fixture::flow() }))
+    }
+
+    fn config(broker: &str, topic: &str) -> KafkaSinkConfig {
+        let config = format!("brokers = [\"{}\"]\ntopic = \"{}\"\nbackoff = 10\ntimeout = 5000", broker, topic);
+        toml::from_str(&config).unwrap()
+    }
+
+    /// Key and value of the records of a batch, checking its CRC
+    fn decode_batch(batch: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut reader = Reader::new(batch);
+        assert_eq!(reader.i64().unwrap(), 0);
+        assert_eq!(reader.i32().unwrap() as usize, batch.len() - 12);
+        assert_eq!(reader.i32().unwrap(), -1);
+        assert_eq!(reader.i8().unwrap(), 2);
+        assert_eq!(reader.i32().unwrap() as u32, crc32c(&batch[21..]));
+        let attributes = reader.i16().unwrap();
+        reader.take(4 + 8 + 8 + 8 + 2 + 4).unwrap();
+        let count = reader.i32().unwrap() as usize;
+
+        let records = match attributes {
+            0 => batch[61..].to_vec(),
+            4 => zstd::decode_all(&batch[61..]).unwrap(),
+            _ => panic!("unexpected compression {}", attributes),
+        };
+        fn varint(records: &[u8], pos: &mut usize) -> usize {
+            let (mut value, mut shift) = (0u64, 0);
+            loop {
+                let b = records[*pos];
+                *pos += 1;
+                value |= ((b & 0x7f) as u64) << shift;
+                shift += 7;
+                if b < 0x80 {
+                    return ((value >> 1) as i64 ^ -((value & 1) as i64)) as usize;
+                }
+            }
+        }
+
+        let mut pos = 0;
+        let mut decoded = vec![];
+        for _ in 0..count {
+            let _length = varint(&records, &mut pos);
+            // attributes
+            pos += 1;
+            let (_timestamp_delta, _offset_delta) = (varint(&records, &mut pos), varint(&records, &mut pos));
+            let key_length = varint(&records, &mut pos);
+            let key = records[pos..pos + key_length].to_vec();
+            pos += key_length;
+            let value_length = varint(&records, &mut pos);
+            let value = records[pos..pos + value_length].to_vec();
+            pos += value_length;
+            assert_eq!(varint(&records, &mut pos), 0);
+            decoded.push((key, value));
+        }
+        assert_eq!(pos, records.len());
+        decoded
+    }
+
+    /// Partition and record batch of each produce request
+    type Produced = Vec<(i32, Vec<u8>)>;
+
+    /// Single broker with 3 partitions of the topic, answering each produce request with the next error code (0 when there is none left).
+    /// Returns the partitions and record batches of the produce requests and the number of metadata requests.
+    fn mock_broker(topic: &'static str, mut errors: Vec<i16>) -> (String, JoinHandle<(Produced, usize)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (mut batches, mut metadata_requests) = (vec![], 0);
+            errors.reverse();
+
+            loop {
+                let mut size = [0; 4];
+                if stream.read_exact(&mut size).is_err() {
+                    return (batches, metadata_requests);
+                }
+                let mut request = vec![0; i32::from_be_bytes(size) as usize];
+                stream.read_exact(&mut request).unwrap();
+
+                let mut reader = Reader::new(&request);
+                let (api_key, version, correlation_id) = (reader.i16().unwrap(), reader.i16().unwrap(), reader.i32().unwrap());
+                assert_eq!(reader.string().unwrap(), "ipfix");
+
+                let mut response = Writer::default();
+                response.i32(correlation_id);
+                match (api_key, version) {
+                    (METADATA, METADATA_VERSION) => {
+                        metadata_requests += 1;
+                        assert_eq!(reader.array().unwrap(), 1);
+                        assert_eq!(reader.string().unwrap(), topic);
+                        // throttle, one broker, cluster id, controller
+                        response.i32(0);
+                        response.i32(1);
+                        response.i32(7);
+                        response.string("127.0.0.1");
+                        response.i32(address.port() as i32);
+                        response.i16(-1);
+                        response.i16(-1);
+                        response.i32(7);
+                        response.i32(1);
+                        response.i16(0);
+                        response.string(topic);
+                        response.0.push(0);
+                        response.i32(3);
+                        for partition in 0..3 {
+                            response.i16(0);
+                            response.i32(partition);
+                            response.i32(7);
+                            response.i32(1);
+                            response.i32(7);
+                            response.i32(1);
+                            response.i32(7);
+                        }
+                    }
+                    (PRODUCE, PRODUCE_VERSION) => {
+                        assert_eq!(reader.i16().unwrap(), -1);
+                        assert_eq!(reader.i16().unwrap(), -1);
+                        assert_eq!(reader.i32().unwrap(), 5000);
+                        assert_eq!(reader.array().unwrap(), 1);
+                        assert_eq!(reader.string().unwrap(), topic);
+                        let error = errors.pop().unwrap_or(0);
+
+                        response.i32(1);
+                        response.string(topic);
+                        let partitions = reader.array().unwrap();
+                        response.i32(partitions as i32);
+                        for _ in 0..partitions {
+                            let partition = reader.i32().unwrap();
+                            let length = reader.i32().unwrap() as usize;
+                            batches.push((partition, reader.take(length).unwrap().to_vec()));
+                            response.i32(partition);
+                            response.i16(error);
+                            response.0.extend_from_slice(&[0; 24]);
+                        }
+                        // throttle
+                        response.i32(0);
+                    }
+                    request => panic!("unexpected request {:?}", request),
+                }
+
+                stream.write_all(&(response.0.len() as i32).to_be_bytes()).unwrap();
+                stream.write_all(&response.0).unwrap();
+            }
+        });
+
+        (address.to_string(), handle)
+    }
+
+    #[test]
+    fn checksums_and_partitions() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+
+        // values of the Java client
+        assert_eq!(murmur2(b"21") as i32, -973_932_308);
+        assert_eq!(murmur2(b"foobar") as i32, -790_332_482);
+        assert_eq!(murmur2(b"a-little-bit-long-string") as i32, -985_981_536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string") as i32, -1_486_304_829);
+        assert_eq!(murmur2(b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8") as i32, -58_897_971);
+        assert_eq!(murmur2(b"abc") as i32, 479_470_107);
+        assert!((0..100u8).all(|i| (0..3).contains(&partition(&[10, 0, 0, i], 3))));
+    }
+
+    #[test]
+    fn produce_json_records() {
+        let (broker, server) = mock_broker("flows-json", vec![]);
+        let mut config = config(&broker, "flows-json");
+        config.key = RecordKey::SourceAddress;
+        config.compression = Compression::Zstd;
+
+        let mut sink = KafkaSink::new(config).unwrap();
+        let sources: Vec<u32> = (1..=20).map(|i| 0x0a00_0000 + i).collect();
+        sink.write(&batch(&sources)).unwrap();
+        assert_eq!(sink.records.len(), 20);
+        sink.flush().unwrap();
+        drop(sink);
+
+        let (batches, metadata_requests) = server.join().unwrap();
+        assert_eq!(metadata_requests, 1);
+        let records: Vec<(i32, Vec<u8>, Vec<u8>)> = batches
+            .iter()
+            .flat_map(|(partition, batch)| decode_batch(batch).into_iter().map(move |(key, value)| (*partition, key, value)))
+            .collect();
+        assert_eq!(records.len(), 20);
+        for (partition, key, _) in &records {
+            assert_eq!(*partition, super::partition(key, 3));
+        }
+        // keyed by source address, spread over the partitions
+        assert!(batches.len() > 1);
+
+        let (_, key, value) = records.iter().find(|(_, key, _)| key == &[10, 0, 0, 7]).unwrap();
+        assert_eq!(key, &[10, 0, 0, 7]);
+        let json = json::Json::parse(std::str::from_utf8(value).unwrap()).unwrap();
+        assert_eq!(json.get("exporter").and_then(json::Json::as_str), Some("192.0.2.1"));
+        assert_eq!(json.get("exporter_name").and_then(json::Json::as_str), Some("edge-1"));
+        assert_eq!(json.get("end_time").and_then(json::Json::as_str), Some("2021-04-21T23:43:24.500Z"));
+        assert_eq!(json.get("sourceIPv4Address").and_then(json::Json::as_str), Some("10.0.0.7"));
+        assert_eq!(json.get("octetDeltaCount").and_then(json::Json::as_u64), Some(1500));
+    }
+
+    #[test]
+    fn retry_and_count_errors() {
+        // not leader, then message too large
+        let (broker, server) = mock_broker("flows-errors", vec![6, 10]);
+        let mut config = config(&broker, "flows-errors");
+        config.format = RecordFormat::Protobuf;
+        config.batch.batch_size = Some(2);
+
+        let mut sink = KafkaSink::new(config).unwrap();
+        // all the records of one exporter go to the same partition, with one produce request per batch
+        assert!(sink.write(&batch(&[1, 2])).is_ok());
+        assert!(sink.write(&batch(&[3, 4])).is_ok());
+        drop(sink);
+
+        let (batches, metadata_requests) = server.join().unwrap();
+        assert_eq!(metadata_requests, 2);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[0], batches[1]);
+        let records = decode_batch(&batches[2].1);
+        assert_eq!(records[0].0, [192, 0, 2, 1]);
+        assert_eq!(records[0].1, protobuf::flow_message(&batch(&[3]), &batch(&[3]).flows[0].fields()));
+
+        let metrics = metrics::render();
+        assert!(metrics.contains("ipfix_kafka_delivered_records_total{topic=\"flows-errors\"} 2"), "{}", metrics);
+        assert!(
+            metrics.contains("ipfix_kafka_delivery_errors_total{topic=\"flows-errors\",error=\"message_too_large\"} 2"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[test]
+    fn produce_the_batches_after_a_failed_one() {
+        let (broker, server) = mock_broker("flows-partial", vec![7]);
+        let mut config = config(&broker, "flows-partial");
+        config.batch.batch_size = Some(2);
+        config.batch.retries = Some(0);
+
+        let mut sink = KafkaSink::new(config).unwrap();
+        assert_eq!(sink.write(&batch(&[1, 2, 3, 4, 5])), Err("Kafka unavailable, 2 records dropped".to_string()));
+        // the fifth record waits for the next request
+        assert_eq!(sink.records.len(), 1);
+        drop(sink);
+
+        let (batches, _) = server.join().unwrap();
+        assert_eq!(batches.len(), 3);
+        assert_eq!(decode_batch(&batches[1].1).len(), 2);
+        let metrics = metrics::render();
+        assert!(metrics.contains("ipfix_kafka_delivered_records_total{topic=\"flows-partial\"} 3"), "{}", metrics);
+        assert!(metrics.contains("ipfix_kafka_delivery_errors_total{topic=\"flows-partial\",error=\"unavailable\"} 2"), "{}", metrics);
+    }
+
+    #[test]
+    fn goflow2_records() {
+        let mut config = config("127.0.0.1:9092", "flows-goflow2");
+        config.format = RecordFormat::Goflow2;
+        let sink = KafkaSink::new(config).unwrap();
+
+        let batch = batch(&[1]);
+        let record = sink.record(&batch, batch.flows[0].as_ref(), 1_619_048_610_000);
+        assert_eq!(record.key, [192, 0, 2, 1]);
+        assert_eq!(record.timestamp, 1_619_048_604_500);
+        assert_eq!(record.value, FlowMessage::new(&batch, 5, &batch.flows[0].fields(), 1_619_048_610).encode_delimited());
+    }
+
+    #[test]
+    fn drop_after_retries() {
+        let (broker, server) = mock_broker("flows-down", vec![7; 3]);
+        let mut config = config(&broker, "flows-down");
+        config.batch.retries = Some(2);
+
+        let mut sink = KafkaSink::new(config).unwrap();
+        sink.write(&batch(&[1])).unwrap();
+        assert_eq!(sink.flush(), Err("Kafka unavailable, 1 records dropped".to_string()));
+        drop(sink);
+
+        let (batches, _) = server.join().unwrap();
+        assert_eq!(batches.len(), 3);
+        assert!(metrics::render().contains("ipfix_kafka_delivery_errors_total{topic=\"flows-down\",error=\"unavailable\"} 1"));
+    }
+}
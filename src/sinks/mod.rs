@@ -0,0 +1,244 @@
+use log::warn;
+use serde::Deserialize;
+use std::thread;
+use std::time::Duration;
+
+use crate::flow::FlowBatch;
+
+pub mod clickhouse;
+pub mod columns;
+pub mod csv;
+pub mod goflow2;
+pub mod http;
+pub mod influxdb;
+pub mod ipfix;
+pub mod json;
+pub mod kafka;
+pub mod opensearch;
+pub mod parquet;
+pub mod protobuf;
+pub mod stdout;
+
+/// Destination of the decoded flows, owned by the exporter thread.
+/// A sink is dropped on shutdown or when the configuration is reloaded, the ones buffering records push them then.
+pub trait Sink: Send {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String>;
+
+    /// Push the buffered records to their destination
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Push the records buffered for longer than the age threshold of the sink, called every second even without traffic
+    fn tick(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// One [[sink]] entry of the configuration file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SinkConfig {
+    Stdout,
+    Ipfix(ipfix::IpfixSinkConfig),
+    Csv(csv::CsvSinkConfig),
+    Parquet(parquet::ParquetSinkConfig),
+    ClickHouse(clickhouse::ClickHouseSinkConfig),
+    #[serde(alias = "elasticsearch")]
+    OpenSearch(opensearch::OpenSearchSinkConfig),
+    Kafka(kafka::KafkaSinkConfig),
+    Goflow2(goflow2::Goflow2SinkConfig),
+    InfluxDb(influxdb::InfluxDbSinkConfig),
+}
+
+impl SinkConfig {
+    pub fn build(&self) -> Result<Box<dyn Sink>, String> {
+        Ok(match self {
+            SinkConfig::Stdout => Box::new(stdout::StdoutSink::default()),
+            SinkConfig::Ipfix(config) => Box::new(ipfix::IpfixSink::new(config.clone())?),
+            SinkConfig::Csv(config) => Box::new(csv::CsvSink::new(config.clone())?),
+            SinkConfig::Parquet(config) => Box::new(parquet::ParquetSink::new(config.clone())?),
+            SinkConfig::ClickHouse(config) => Box::new(clickhouse::ClickHouseSink::new(config.clone())?),
+            SinkConfig::OpenSearch(config) => Box::new(opensearch::OpenSearchSink::new(config.clone())?),
+            SinkConfig::Kafka(config) => Box::new(kafka::KafkaSink::new(config.clone())?),
+            SinkConfig::Goflow2(config) => Box::new(goflow2::Goflow2Sink::new(config.clone())?),
+            SinkConfig::InfluxDb(config) => Box::new(influxdb::InfluxDbSink::new(config.clone())?),
+        })
+    }
+}
+
+/******************************** BATCHES ********************************/
+
+/// Batches of the sinks sending their records to a server, flattened into their configuration.
+/// The options left out take the defaults of the sink.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct BatchConfig {
+    /// Records sent in one request
+    pub batch_size: Option<usize>,
+    /// Seconds after which an incomplete batch is sent
+    pub max_age: Option<u64>,
+    /// Attempts for the records that failed because the server was unavailable
+    pub retries: Option<u32>,
+    /// Milliseconds before the first retry, doubled for each next one
+    pub backoff: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchOptions {
+    pub size: usize,
+    pub max_age: u64,
+    pub retries: u32,
+    pub backoff: u64,
+}
+
+impl BatchConfig {
+    pub fn or(&self, defaults: BatchOptions) -> BatchOptions {
+        BatchOptions {
+            size: self.batch_size.unwrap_or(defaults.size),
+            max_age: self.max_age.unwrap_or(defaults.max_age),
+            retries: self.retries.unwrap_or(defaults.retries),
+            backoff: self.backoff.unwrap_or(defaults.backoff),
+        }
+    }
+}
+
+/// Records waiting for the next request of a sink
+pub struct Batcher<T> {
+    options: BatchOptions,
+    records: Vec<T>,
+    /// When the first record of the batch was added
+    started: u64,
+}
+
+impl<T> Batcher<T> {
+    pub fn new(options: BatchOptions) -> Self {
+        Batcher { options, records: vec![], started: 0 }
+    }
+
+    pub fn options(&self) -> BatchOptions {
+        self.options
+    }
+
+    /// Add the records, returns the batches to send: the full ones, then the incomplete one once it waited for max_age seconds
+    pub fn add<I: IntoIterator<Item = T>>(&mut self, records: I, now: u64) -> Vec<Vec<T>> {
+        let mut ready = vec![];
+        for record in records {
+            if self.records.is_empty() {
+                self.started = now;
+            }
+            self.records.push(record);
+            if self.records.len() >= self.options.size {
+                ready.push(self.take());
+            }
+        }
+        if self.expired(now) {
+            ready.push(self.take());
+        }
+        ready
+    }
+
+    /// The incomplete batch waited for max_age seconds
+    pub fn expired(&self, now: u64) -> bool {
+        !self.records.is_empty() && now >= self.started + self.options.max_age
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn take(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.records)
+    }
+}
+
+/// Send every batch, a failed one doesn't keep the next ones from being sent. The errors are joined.
+pub fn send_all<T, F: FnMut(Vec<T>) -> Result<(), String>>(batches: Vec<Vec<T>>, mut send: F) -> Result<(), String> {
+    let errors: Vec<String> = batches.into_iter().filter_map(|batch| send(batch).err()).collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+/// Send the records, the ones send gives back are sent again with an exponential backoff.
+/// Returns the records still failing after the retries, what describes them in the logs.
+pub fn send_with_retries<T, F: FnMut(Vec<T>) -> Result<Vec<T>, String>>(mut records: Vec<T>, options: BatchOptions, what: &str, mut send: F) -> Result<Vec<T>, String> {
+    let mut attempt = 0;
+    while !records.is_empty() {
+        records = send(records)?;
+        if records.is_empty() || attempt >= options.retries {
+            break;
+        }
+
+        let backoff = Duration::from_millis(options.backoff.saturating_mul(1 << attempt.min(16)));
+        warn!("Retrying {} {} in {:?}", records.len(), what, backoff);
+        thread::sleep(backoff);
+        attempt += 1;
+    }
+    Ok(records)
+}
+
+/// Flows shared by the tests of the sinks
+#[cfg(test)]
+pub mod fixture {
+    use crate::flow::{netflow5, Flow, FlowBatch};
+    use std::sync::Arc;
+
+    /// 10.0.0.1:54321 to 10.0.0.2:443 over TCP, 1500 bytes in 3 packets ending at 2021-04-21T23:43:24.500Z
+    pub fn flow() -> netflow5::DataSet {
+        netflow5::DataSet {
+            src_addr: 0x0a00_0001,
+            dst_addr: 0x0a00_0002,
+            src_port: 54321,
+            dst_port: 443,
+            protocol: 6,
+            octets: 1500,
+            packets: 3,
+            start_time: 1000,
+            end_time: 4500,
+            system_init_time: Some(1_619_048_600_000),
+            ..Default::default()
+        }
+    }
+
+    /// Flows of the exporter 192.0.2.1 named edge-1, tagged core
+    pub fn batch<I: IntoIterator<Item = netflow5::DataSet>>(flows: I) -> FlowBatch {
+        FlowBatch {
+            tag: Some(Arc::from("core")),
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: Some(Arc::from("edge-1")),
+            flows: flows.into_iter().map(|flow| Box::new(flow) as Box<dyn Flow>).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: BatchOptions = BatchOptions {
+        size: 3,
+        max_age: 10,
+        retries: 0,
+        backoff: 1,
+    };
+
+    #[test]
+    fn batch_by_size_and_age() {
+        let mut batcher = Batcher::new(OPTIONS);
+        assert_eq!(batcher.add(1..=4, 100), vec![vec![1, 2, 3]]);
+        assert!(!batcher.expired(109));
+        assert_eq!(batcher.add(vec![5], 109), Vec::<Vec<u32>>::new());
+
+        // no other record arrives, the incomplete batch expires
+        assert!(batcher.expired(110));
+        assert_eq!(batcher.take(), vec![4, 5]);
+        assert!(!batcher.expired(200));
+    }
+}
@@ -0,0 +1,487 @@
+use log::{error, info};
+use parquet::basic::{Compression, LogicalType, Repetition, TimeUnit, Type as PhysicalType, ZstdLevel};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::format::MilliSeconds;
+use parquet::schema::types::Type;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::columns::{self, Column, Value};
+use super::Sink;
+use crate::flow::ipfix::{DataType, FieldType, FieldValue};
+use crate::flow::FlowBatch;
+use crate::threads::clock::Clock;
+
+/******************************** CONFIGURATION ********************************/
+
+/// Parquet files partitioned by exporter/date/hour, with a column per field type seen in the flows of the file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParquetSinkConfig {
+    /// Root of the partitions, written as exporter=192.0.2.1/date=2021-04-21/hour=23 (hive partitioning)
+    pub directory: PathBuf,
+    /// Flows buffered for a partition before its file is written
+    #[serde(default = "default_max_rows")]
+    pub max_rows: usize,
+    /// Seconds after which the flows of a partition are written, even if there are less than max_rows
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+    /// zstd compression level, from 1 to 22
+    #[serde(default = "default_compression_level")]
+    pub compression_level: i32,
+}
+
+fn default_max_rows() -> usize {
+    100_000
+}
+
+fn default_max_age() -> u64 {
+    300
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+/******************************** SINK ********************************/
+
+/// The flows are partitioned by the hour of their end, or of their reception when they don't have an absolute end time.
+/// The buffers are checked when flows are written and on every tick of the exporter thread, and written when the sink stops.
+pub struct ParquetSink {
+    config: ParquetSinkConfig,
+    properties: Arc<WriterProperties>,
+    partitions: HashMap<(IpAddr, u64), Partition>,
+    clock: Clock,
+}
+
+struct Partition {
+    rows: Vec<Row>,
+    /// When the first flow was buffered
+    created: u64,
+}
+
+struct Row {
+    exporter_name: Option<Arc<str>>,
+    tag: Option<Arc<str>>,
+    fields: Vec<(FieldType, FieldValue)>,
+}
+
+impl ParquetSink {
+    pub fn new(config: ParquetSinkConfig) -> Result<Self, String> {
+        if config.max_rows == 0 {
+            return Err("The parquet sink must buffer at least 1 row".to_string());
+        }
+        let level = ZstdLevel::try_new(config.compression_level).map_err(|e| format!("Invalid zstd compression level {} : {}", config.compression_level, e))?;
+        let properties = WriterProperties::builder().set_compression(Compression::ZSTD(level)).build();
+        fs::create_dir_all(&config.directory).map_err(|e| format!("Failed to create the directory {} : {}", config.directory.display(), e))?;
+
+        Ok(ParquetSink {
+            config,
+            properties: Arc::new(properties),
+            partitions: HashMap::new(),
+            clock: Clock::default(),
+        })
+    }
+
+    /// Write the buffered flows of the partition to a new file
+    fn write_partition(&self, (exporter, hour): (IpAddr, u64), partition: Partition) -> Result<(), String> {
+        let stamp = columns::rfc3339_seconds(hour).to_string();
+        let directory = self
+            .config
+            .directory
+            .join(format!("exporter={}", exporter))
+            .join(format!("date={}", &stamp[0..10]))
+            .join(format!("hour={}", &stamp[11..13]));
+        fs::create_dir_all(&directory).map_err(|e| format!("Failed to create the directory {} : {}", directory.display(), e))?;
+
+        let created = columns::rfc3339_seconds(partition.created).to_string().replace(['-', ':'], "");
+        let mut path = directory.join(format!("flows-{}.parquet", created));
+        let mut i = 1;
+        while path.exists() {
+            path = directory.join(format!("flows-{}-{}.parquet", created, i));
+            i += 1;
+        }
+        // the readers only pick the completed files
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let file = File::create(&tmp_path).map_err(|e| format!("Failed to create {} : {}", tmp_path.display(), e))?;
+        write_file(file, exporter, &partition.rows, self.properties.clone()).map_err(|e| format!("Failed to write {} : {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, &path).map_err(|e| format!("Failed to rename {} : {}", tmp_path.display(), e))?;
+
+        info!("Parquet file {} completed with {} flows", path.display(), partition.rows.len());
+        Ok(())
+    }
+
+    /// Write the partitions selected by the filter, the flows of a partition that couldn't be written are dropped
+    fn write_partitions<F: Fn(&Partition) -> bool>(&mut self, filter: F) -> Result<(), String> {
+        let keys: Vec<_> = self.partitions.iter().filter(|(_, partition)| filter(partition)).map(|(key, _)| *key).collect();
+
+        let mut result = Ok(());
+        for key in keys {
+            let partition = self.partitions.remove(&key).unwrap();
+            if let Err(e) = self.write_partition(key, partition) {
+                error!("{}", e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+impl Sink for ParquetSink {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String> {
+        let now = self.clock.now_secs();
+
+        for flow in &batch.flows {
+            let fields = flow.fields();
+            let end = match Column::EndTime.value(batch, &fields) {
+                Some(Value::Timestamp(millis)) => millis / 1000,
+                _ => now,
+            };
+
+            let partition = self.partitions.entry((batch.exporter, end - end % 3600)).or_insert_with(|| Partition { rows: vec![], created: now });
+            partition.rows.push(Row {
+                exporter_name: batch.exporter_name.clone(),
+                tag: batch.tag.clone(),
+                fields,
+            });
+        }
+
+        let (max_rows, max_age) = (self.config.max_rows, self.config.max_age);
+        self.write_partitions(|partition| partition.rows.len() >= max_rows || now >= partition.created + max_age)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.write_partitions(|_| true)
+    }
+
+    fn tick(&mut self) -> Result<(), String> {
+        let (now, max_age) = (self.clock.now_secs(), self.config.max_age);
+        self.write_partitions(|partition| now >= partition.created + max_age)
+    }
+}
+
+/// Every partition is written, even the ones of the current hour
+impl Drop for ParquetSink {
+    fn drop(&mut self) {
+        let _ = self.write_partitions(|_| true);
+    }
+}
+
+/******************************** PARQUET FILE ********************************/
+
+/// Parquet representation of an IPFIX data type
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnType {
+    Unsigned,
+    Signed,
+    Double,
+    Boolean,
+    /// Milliseconds since the UNIX epoch, UTC
+    Timestamp,
+    /// UTF-8 text, the addresses are written in their text form
+    String,
+    Bytes,
+}
+
+impl ColumnType {
+    fn from_data_type(data_type: DataType) -> Self {
+        match data_type {
+            // the reduced size encodings of the exporters don't change the schema
+            DataType::Unsigned => ColumnType::Unsigned,
+            DataType::Signed => ColumnType::Signed,
+            DataType::Float64 => ColumnType::Double,
+            DataType::Boolean => ColumnType::Boolean,
+            DataType::DateTimeSeconds | DataType::DateTimeMilliseconds | DataType::DateTimeMicroseconds | DataType::DateTimeNanoseconds => ColumnType::Timestamp,
+            DataType::Ipv4Address | DataType::Ipv6Address | DataType::MacAddress | DataType::String => ColumnType::String,
+            DataType::OctetArray | DataType::List => ColumnType::Bytes,
+        }
+    }
+
+    fn schema(&self, name: &str, repetition: Repetition) -> parquet::errors::Result<Type> {
+        let (physical, logical) = match self {
+            ColumnType::Unsigned => (PhysicalType::INT64, Some(LogicalType::Integer { bit_width: 64, is_signed: false })),
+            ColumnType::Signed => (PhysicalType::INT64, None),
+            ColumnType::Double => (PhysicalType::DOUBLE, None),
+            ColumnType::Boolean => (PhysicalType::BOOLEAN, None),
+            ColumnType::Timestamp => (
+                PhysicalType::INT64,
+                Some(LogicalType::Timestamp {
+                    is_adjusted_to_u_t_c: true,
+                    unit: TimeUnit::MILLIS(MilliSeconds {}),
+                }),
+            ),
+            ColumnType::String => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+            ColumnType::Bytes => (PhysicalType::BYTE_ARRAY, None),
+        };
+        Type::primitive_type_builder(name, physical).with_repetition(repetition).with_logical_type(logical).build()
+    }
+
+    /// Write the values of the column, the values that don't fit its type are written as nulls
+    fn write(&self, writer: &mut SerializedColumnWriter, values: &[Option<Value>], required: bool) -> parquet::errors::Result<()> {
+        match self {
+            ColumnType::Unsigned => write_values::<Int64Type, _>(writer, values, required, |v| match v {
+                Value::Unsigned(v) if *v <= u64::MAX as u128 => Some(*v as u64 as i64),
+                _ => None,
+            }),
+            ColumnType::Signed => write_values::<Int64Type, _>(writer, values, required, |v| match v {
+                Value::Signed(v) => Some(*v),
+                _ => None,
+            }),
+            ColumnType::Double => write_values::<DoubleType, _>(writer, values, required, |v| match v {
+                Value::Float(v) => Some(*v),
+                _ => None,
+            }),
+            ColumnType::Boolean => write_values::<BoolType, _>(writer, values, required, |v| match v {
+                Value::Boolean(v) => Some(*v),
+                _ => None,
+            }),
+            ColumnType::Timestamp => write_values::<Int64Type, _>(writer, values, required, |v| match v {
+                Value::Timestamp(millis) => Some(*millis as i64),
+                _ => None,
+            }),
+            ColumnType::String => write_values::<ByteArrayType, _>(writer, values, required, |v| Some(ByteArray::from(v.to_string().into_bytes()))),
+            ColumnType::Bytes => write_values::<ByteArrayType, _>(writer, values, required, |v| match v {
+                Value::Bytes(bytes) => Some(ByteArray::from(bytes.clone())),
+                Value::Text(text) => Some(ByteArray::from(text.as_bytes().to_vec())),
+                _ => None,
+            }),
+        }
+    }
+}
+
+fn write_values<T, F>(writer: &mut SerializedColumnWriter, values: &[Option<Value>], required: bool, convert: F) -> parquet::errors::Result<()>
+where
+    T: parquet::data_type::DataType,
+    F: Fn(&Value) -> Option<T::T>,
+{
+    let mut levels = Vec::with_capacity(values.len());
+    let mut data = Vec::with_capacity(values.len());
+    for value in values {
+        match value.as_ref().and_then(&convert) {
+            Some(v) => {
+                data.push(v);
+                levels.push(1);
+            }
+            None => levels.push(0),
+        }
+    }
+
+    writer.typed::<T>().write_batch(&data, if required { None } else { Some(&levels) }, None)?;
+    Ok(())
+}
+
+/// One row group with the exporter, its name, the tag of the listener and the fields seen in the rows, ordered by field type
+fn write_file(file: File, exporter: IpAddr, rows: &[Row], properties: Arc<WriterProperties>) -> parquet::errors::Result<()> {
+    let field_types: BTreeSet<FieldType> = rows.iter().flat_map(|row| row.fields.iter().map(|&(id, _)| id)).collect();
+
+    let mut columns = vec![
+        (Column::Exporter, ColumnType::String, Repetition::REQUIRED),
+        (Column::ExporterName, ColumnType::String, Repetition::OPTIONAL),
+        (Column::Tag, ColumnType::String, Repetition::OPTIONAL),
+    ];
+    columns.extend(field_types.iter().map(|&id| (Column::Field(id), ColumnType::from_data_type(id.data_type()), Repetition::OPTIONAL)));
+
+    let fields = columns
+        .iter()
+        .map(|(column, column_type, repetition)| column_type.schema(&column.name(), *repetition).map(Arc::new))
+        .collect::<Result<_, _>>()?;
+    let schema = Type::group_type_builder("flow").with_fields(fields).build()?;
+
+    let mut writer = SerializedFileWriter::new(file, Arc::new(schema), properties)?;
+    let mut row_group = writer.next_row_group()?;
+
+    for (column, column_type, repetition) in &columns {
+        let values: Vec<Option<Value>> = rows
+            .iter()
+            .map(|row| match column {
+                Column::Field(id) => row.fields.binary_search_by_key(id, |&(t, _)| t).ok().map(|i| Value::from_field(*id, &row.fields[i].1)),
+                Column::ExporterName => row.exporter_name.as_deref().map(|name| Value::Text(name.to_string())),
+                Column::Tag => row.tag.as_deref().map(|tag| Value::Text(tag.to_string())),
+                _ => Some(Value::Address(exporter)),
+            })
+            .collect();
+
+        let mut column_writer = row_group.next_column()?.expect("one column writer per column of the schema");
+        column_type.write(&mut column_writer, &values, *repetition == Repetition::REQUIRED)?;
+        column_writer.close()?;
+    }
+
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::ipfix;
+    use crate::flow::netflow5;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    fn ipfix_flow(end: u64) -> ipfix::DataSet {
+        let mut fields = HashMap::new();
+        fields.insert(FieldType::SourceIPv6Address, FieldValue::U128(0x2001_0db8 << 96 | 1));
+        // reduced size encoding
+        fields.insert(FieldType::OctetDeltaCount, FieldValue::U32(1500));
+        fields.insert(FieldType::FlowEndMilliseconds, FieldValue::U64(end));
+        fields.insert(FieldType::InterfaceName, FieldValue::Dyn(b"eth0".to_vec()));
+        ipfix::DataSet { fields }
+    }
+
+    fn read_rows(path: &std::path::Path) -> Vec<String> {
+        let reader = SerializedFileReader::new(File::open(path).unwrap()).unwrap();
+        assert!(matches!(reader.metadata().row_group(0).column(0).compression(), Compression::ZSTD(_)));
+        reader.get_row_iter(None).unwrap().map(|row| row.unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn write_partitioned_files() {
+        let directory = std::env::temp_dir().join(format!("ipfix-parquet-{}", std::process::id()));
+        let config = ParquetSinkConfig {
+            directory: directory.clone(),
+            max_rows: 3,
+            max_age: 60,
+            compression_level: 3,
+        };
+        let mut sink = ParquetSink::new(config).unwrap();
+        sink.clock = Clock::manual(1_619_048_630);
+
+        let batch = FlowBatch {
+            tag: Some(Arc::from("core")),
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: None,
+            flows: vec![Box::new(ipfix_flow(1_619_048_604_500)), Box::new(ipfix_flow(1_619_044_000_000))],
+        };
+        sink.write(&batch).unwrap();
+
+        // the flows without an absolute end time are partitioned by their reception
+        let netflow = FlowBatch {
+            tag: None,
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: Some(Arc::from("edge-1")),
+            flows: vec![Box::new(netflow5::DataSet {
+                src_addr: 0x0a00_0001,
+                octets: 64,
+                ..Default::default()
+            })],
+        };
+        sink.write(&netflow).unwrap();
+        let partition = directory.join("exporter=192.0.2.1/date=2021-04-21/hour=23");
+        assert!(!partition.exists());
+
+        // the third flow of the partition fills it
+        sink.write(&netflow).unwrap();
+        let rows = read_rows(&partition.join("flows-20210421T234350Z.parquet"));
+        assert_eq!(rows.len(), 3);
+        // one column per field type seen in the partition
+        for column in &[
+            "exporter: \"192.0.2.1\"",
+            "tag: \"core\"",
+            "octetDeltaCount: 1500",
+            "sourceIPv4Address: null",
+            "sourceIPv6Address: \"2001:db8::1\"",
+            "interfaceName: \"eth0\"",
+            "flowEndMilliseconds: 2021-04-21 23:43:24 +00:00",
+        ] {
+            assert!(rows[0].contains(column), "{} not in {}", column, rows[0]);
+        }
+        for column in &[
+            "exporter_name: \"edge-1\"",
+            "tag: null",
+            "octetDeltaCount: 64",
+            "sourceIPv4Address: \"10.0.0.1\"",
+            "flowEndSysUpTime: 0",
+            "flowEndMilliseconds: null",
+        ] {
+            assert!(rows[2].contains(column), "{} not in {}", column, rows[2]);
+        }
+
+        // the other partition is written when its buffer is too old
+        sink.clock.advance(60);
+        sink.write(&FlowBatch { flows: vec![], ..netflow }).unwrap();
+        let rows = read_rows(&directory.join("exporter=192.0.2.1/date=2021-04-21/hour=22/flows-20210421T234350Z.parquet"));
+        assert_eq!(rows.len(), 1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn write_aged_partitions_without_traffic() {
+        let directory = std::env::temp_dir().join(format!("ipfix-parquet-tick-{}", std::process::id()));
+        let config = ParquetSinkConfig {
+            directory: directory.clone(),
+            max_rows: 3,
+            max_age: 60,
+            compression_level: 3,
+        };
+        let mut sink = ParquetSink::new(config).unwrap();
+        sink.clock = Clock::manual(1_619_048_630);
+
+        let batch = FlowBatch {
+            tag: None,
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: None,
+            flows: vec![Box::new(ipfix_flow(1_619_048_604_500))],
+        };
+        sink.write(&batch).unwrap();
+        let path = directory.join("exporter=192.0.2.1/date=2021-04-21/hour=23/flows-20210421T234350Z.parquet");
+
+        // the exporter went quiet, the ticks write the partition once it is too old
+        sink.clock.advance(59);
+        sink.tick().unwrap();
+        assert!(!path.exists());
+        sink.clock.advance(1);
+        sink.tick().unwrap();
+        assert_eq!(read_rows(&path).len(), 1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn write_end_time_past_year_9999() {
+        let directory = std::env::temp_dir().join(format!("ipfix-parquet-9999-{}", std::process::id()));
+        let config = ParquetSinkConfig {
+            directory: directory.clone(),
+            max_rows: 1,
+            max_age: 60,
+            compression_level: 3,
+        };
+        let mut sink = ParquetSink::new(config).unwrap();
+        sink.clock = Clock::manual(1_619_048_630);
+
+        let batch = FlowBatch {
+            tag: None,
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: None,
+            flows: vec![Box::new(ipfix_flow(u64::MAX))],
+        };
+        sink.write(&batch).unwrap();
+
+        // partitioned by its reception, the time is kept as null
+        let rows = read_rows(&directory.join("exporter=192.0.2.1/date=2021-04-21/hour=23/flows-20210421T234350Z.parquet"));
+        assert!(rows[0].contains("flowEndMilliseconds: null"), "{}", rows[0]);
+        assert_eq!(columns::rfc3339_seconds(u64::MAX).to_string(), "9999-12-31T23:59:59Z");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let config = ParquetSinkConfig {
+            directory: std::env::temp_dir(),
+            max_rows: 10,
+            max_age: 60,
+            compression_level: 40,
+        };
+        assert!(ParquetSink::new(config).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::net::IpAddr;
+
+use super::columns::{Column, Value};
+use crate::flow::ipfix::{FieldType, FieldValue};
+use crate::flow::FlowBatch;
+
+/******************************** ENCODING ********************************/
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+
+/// Append the base 128 encoding of the value, also used by the Kafka records
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Signed values with a small magnitude get a short varint
+pub fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Fields of a protobuf message, encoded in the order of the calls (https://protobuf.dev/programming-guides/encoding/)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message(Vec<u8>);
+
+impl Message {
+    fn key(&mut self, field: u32, wire_type: u64) {
+        write_varint(&mut self.0, (field as u64) << 3 | wire_type);
+    }
+
+    pub fn uint64(&mut self, field: u32, value: u64) {
+        self.key(field, VARINT);
+        write_varint(&mut self.0, value);
+    }
+
+    pub fn sint64(&mut self, field: u32, value: i64) {
+        self.uint64(field, zigzag(value));
+    }
+
+    pub fn bool(&mut self, field: u32, value: bool) {
+        self.uint64(field, value as u64);
+    }
+
+    pub fn double(&mut self, field: u32, value: f64) {
+        self.key(field, FIXED64);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, LENGTH_DELIMITED);
+        write_varint(&mut self.0, value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    pub fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    /// Repeated scalar field, in one packed record
+    pub fn packed_uint64(&mut self, field: u32, values: &[u64]) {
+        let mut packed = vec![];
+        for &value in values {
+            write_varint(&mut packed, value);
+        }
+        self.bytes(field, &packed);
+    }
+
+    pub fn message(&mut self, field: u32, value: &Message) {
+        self.bytes(field, &value.0);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Network byte order octets of the address, 4 or 16 of them
+pub fn address_bytes(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/******************************** FLOW MESSAGE ********************************/
+
+// Schema of the flows of the protobuf format, every information element of the record is kept:
+//
+// message Flow {
+//   bytes exporter = 1;              // 4 or 16 octets
+//   string exporter_name = 2;
+//   string tag = 3;
+//   uint64 start_time = 4;           // milliseconds since the UNIX epoch, when the flow has an absolute time
+//   uint64 end_time = 5;
+//   repeated Field fields = 6;
+// }
+//
+// message Field {
+//   uint32 id = 1;                   // IANA information element identifier
+//   oneof value {
+//     uint64 unsigned = 2;           // also the date-times, in milliseconds since the UNIX epoch
+//     sint64 signed = 3;
+//     double float = 4;
+//     bool boolean = 5;
+//     bytes octets = 6;              // addresses, MAC addresses, octet arrays and unsigned values above 64 bits
+//     string text = 7;
+//   }
+// }
+
+/// Flow record encoded with the schema above
+pub fn flow_message(batch: &FlowBatch, fields: &[(FieldType, FieldValue)]) -> Vec<u8> {
+    let mut flow = Message::default();
+    flow.bytes(1, &address_bytes(&batch.exporter));
+    if let Some(name) = &batch.exporter_name {
+        flow.string(2, name);
+    }
+    if let Some(tag) = &batch.tag {
+        flow.string(3, tag);
+    }
+    if let Some(Value::Timestamp(millis)) = Column::StartTime.value(batch, fields) {
+        flow.uint64(4, millis);
+    }
+    if let Some(Value::Timestamp(millis)) = Column::EndTime.value(batch, fields) {
+        flow.uint64(5, millis);
+    }
+
+    for (id, value) in fields {
+        let mut field = Message::default();
+        field.uint64(1, *id as u64);
+        match Value::from_field(*id, value) {
+            Value::Unsigned(v) if v <= u64::MAX as u128 => field.uint64(2, v as u64),
+            Value::Unsigned(v) => field.bytes(6, &v.to_be_bytes()),
+            Value::Signed(v) => field.sint64(3, v),
+            Value::Float(v) => field.double(4, v),
+            Value::Boolean(v) => field.bool(5, v),
+            Value::Timestamp(millis) => field.uint64(2, millis),
+            Value::Address(addr) => field.bytes(6, &address_bytes(&addr)),
+            Value::Mac(mac) => field.bytes(6, &mac),
+            Value::Bytes(bytes) => field.bytes(6, &bytes),
+            Value::Text(text) => field.string(7, &text),
+        }
+        flow.message(6, &field);
+    }
+    flow.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::ipfix;
+    use hex_literal::hex;
+    use std::collections::HashMap;
+
+    #[test]
+    fn encode_scalars() {
+        let mut buf = vec![];
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+        assert_eq!((zigzag(0), zigzag(-1), zigzag(1), zigzag(-2), zigzag(i64::MIN)), (0, 1, 2, 3, u64::MAX));
+
+        let mut message = Message::default();
+        message.uint64(1, 150);
+        message.sint64(2, -2);
+        message.string(3, "testing");
+        message.double(4, 1.0);
+        message.bool(5, true);
+        message.packed_uint64(6, &[3, 270]);
+        assert_eq!(message.as_bytes(), hex!("08 96 01 10 03 1a 07 74 65 73 74 69 6e 67 21 00 00 00 00 00 00 f0 3f 28 01 32 03 03 8e 02"));
+    }
+
+    #[test]
+    fn encode_flow() {
+        let batch = FlowBatch {
+            tag: Some("core".into()),
+            exporter: "192.0.2.1".parse().unwrap(),
+            exporter_name: None,
+            flows: vec![],
+        };
+        let mut data = ipfix::DataSet { fields: HashMap::new() };
+        data.fields.insert(FieldType::ProtocolIdentifier, FieldValue::U8(17));
+        data.fields.insert(FieldType::SourceIPv4Address, FieldValue::U32(0x0a00_0001));
+        data.fields.insert(FieldType::FlowEndMilliseconds, FieldValue::U64(1_619_048_604_500));
+        let fields = crate::flow::Flow::fields(&data);
+
+        assert_eq!(
+            flow_message(&batch, &fields),
+            [
+                &hex!("0a 04 c0 00 02 01 1a 04 63 6f 72 65")[..],
+                // end_time
+                &hex!("28 d4 c6 c4 b6 8f 2f")[..],
+                // protocolIdentifier = 17
+                &hex!("32 04 08 04 10 11")[..],
+                // sourceIPv4Address = 10.0.0.1
+                &hex!("32 08 08 08 32 04 0a 00 00 01")[..],
+                // flowEndMilliseconds
+                &hex!("32 0a 08 99 01 10 d4 c6 c4 b6 8f 2f")[..],
+            ]
+            .concat()
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use std::io::{self, BufWriter, Stdout, Write};
+
+use super::Sink;
+use crate::flow::FlowBatch;
+
+/// Print each flow on its own line, prefixed with its exporter, exporter name and listener tag
+pub struct StdoutSink {
+    out: BufWriter<Stdout>,
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        StdoutSink { out: BufWriter::new(io::stdout()) }
+    }
+}
+
+impl Sink for StdoutSink {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String> {
+        for flow in &batch.flows {
+            writeln!(
+                self.out,
+                "exporter: {}, name: {}, tag: {}, {}",
+                batch.exporter,
+                batch.exporter_name.as_deref().unwrap_or(""),
+                batch.tag.as_deref().unwrap_or(""),
+                flow
+            )
+            .map_err(|e| format!("Failed to write to stdout : {}", e))?;
+        }
+
+        // one flush per batch keeps the output readable when piped without a syscall per line
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.out.flush().map_err(|e| format!("Failed to flush stdout : {}", e))
+    }
+}
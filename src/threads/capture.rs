@@ -0,0 +1,164 @@
+use log::{error, info};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::listener::ListenerSpec;
+use crate::config::CaptureConfig;
+use crate::metrics::{self, CounterHandle};
+use crate::pcap;
+
+/******************************** DEBUG CAPTURE ********************************/
+
+/// Rotating pcap file of the datagrams received by a listener, wrapped in synthetic ethernet/IP/UDP headers.
+/// The messages of a TCP connection are written as datagrams too.
+#[derive(Debug)]
+pub struct DebugCapture {
+    config: CaptureConfig,
+    /// Destination of the synthetic packets
+    local_addr: SocketAddr,
+    /// Opened on the first captured datagram
+    file: Mutex<Option<CaptureFile>>,
+    captured: CounterHandle,
+}
+
+#[derive(Debug)]
+struct CaptureFile {
+    file: File,
+    size: u64,
+}
+
+impl DebugCapture {
+    pub fn new(config: CaptureConfig, spec: &ListenerSpec) -> Self {
+        info!("Capturing the datagrams of {} to {}", spec.name(), config.path.display());
+        DebugCapture {
+            config,
+            local_addr: spec.addr,
+            file: Mutex::new(None),
+            captured: metrics::CAPTURED_DATAGRAMS.with(&[("listener", spec.name().as_str())]),
+        }
+    }
+
+    /// Append the datagram if it matches the filters, failed tells if it couldn't be decoded
+    pub fn write(&self, buf: &[u8], from: SocketAddr, failed: bool) {
+        if !self.config.matches(from.ip(), failed) {
+            return;
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let record = match pcap::udp_record(timestamp, buf, from, self.local_addr) {
+            Some(record) => record,
+            None => return,
+        };
+
+        let mut file = self.file.lock().unwrap();
+        // the file is reopened on the next datagram after an error
+        match self.append(&mut file, &record) {
+            Ok(()) => self.captured.inc(),
+            Err(e) => {
+                *file = None;
+                error!("Failed to write the capture file {} : {}", self.config.path.display(), e);
+            }
+        }
+    }
+
+    fn append(&self, file: &mut Option<CaptureFile>, record: &[u8]) -> io::Result<()> {
+        let header_size = pcap::file_header().len() as u64;
+        let full = file.as_ref().is_some_and(|f| f.size > header_size && f.size + record.len() as u64 > self.config.max_size);
+
+        if file.is_none() || full {
+            *file = None;
+            // the capture of the previous run is kept as the first rotated file
+            self.rotate()?;
+            let mut new = OpenOptions::new().write(true).create(true).truncate(true).open(&self.config.path)?;
+            new.write_all(&pcap::file_header())?;
+            *file = Some(CaptureFile { file: new, size: header_size });
+        }
+
+        let current = file.as_mut().unwrap();
+        // one write per record, the file can be read while the collector is running
+        current.file.write_all(record)?;
+        current.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// Shift path to path.1, path.1 to path.2, ... dropping the oldest file
+    fn rotate(&self) -> io::Result<()> {
+        if self.config.max_files == 0 {
+            return match fs::remove_file(&self.config.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+
+        for i in (1..=self.config.max_files).rev() {
+            let from = if i == 1 { self.config.path.clone() } else { self.rotated_path(i - 1) };
+            match fs::rename(&from, self.rotated_path(i)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        path.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::Capture;
+    use std::env;
+    use std::process;
+
+    fn read_payloads(path: &PathBuf) -> Vec<Vec<u8>> {
+        let data = fs::read(path).unwrap();
+        let mut capture = Capture::new(&data[..]).unwrap();
+        let mut payloads = vec![];
+        while let Some(packet) = capture.next_packet().unwrap() {
+            let datagram = pcap::decode_udp(packet.link_type, &packet.data).unwrap();
+            assert_eq!(datagram.from, "192.0.2.1:54321".parse().unwrap());
+            assert_eq!(datagram.to, "0.0.0.0:4739".parse().unwrap());
+            payloads.push(datagram.payload.to_vec());
+        }
+        payloads
+    }
+
+    #[test]
+    fn capture_and_rotate() {
+        let dir = env::temp_dir().join(format!("ipfix-capture-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("debug.pcap");
+
+        let config = CaptureConfig {
+            path: path.clone(),
+            errors_only: true,
+            exporters: vec![],
+            // header and two records of a 10 bytes datagram
+            max_size: 24 + 2 * (16 + 14 + 20 + 8 + 10),
+            max_files: 1,
+        };
+        let capture = DebugCapture::new(config, &"0.0.0.0:4739".parse().unwrap());
+        let from = "192.0.2.1:54321".parse().unwrap();
+
+        for i in 0..5 {
+            capture.write(&[0, 10, 0, 0, 0, 0, 0, 0, 0, i], from, true);
+        }
+        // decoded datagrams are ignored
+        capture.write(&[0, 10, 0, 0, 0, 0, 0, 0, 0, 9], from, false);
+
+        assert_eq!(read_payloads(&path), vec![vec![0, 10, 0, 0, 0, 0, 0, 0, 0, 4]]);
+        assert_eq!(read_payloads(&dir.join("debug.pcap.1")).len(), 2);
+        // only one rotated file is kept
+        assert!(!dir.join("debug.pcap.2").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::flow::FlowBatch;
+use crate::metrics;
+
+/// What the listener does when the queue toward the exporter thread is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Wait for the exporter thread to free a slot, the datagrams pile up in the socket buffer
+    Block,
+    /// Drop the batch that was about to be queued
+    DropNewest,
+    /// Drop the oldest queued batch to make room for the new one
+    DropOldest,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            _ => Err(format!("Invalid overflow policy '{}', expected block, drop-newest or drop-oldest", s)),
+        }
+    }
+}
+
+/// Number of flow records carried by a message, for the drop accounting
+pub trait Records {
+    fn records(&self) -> usize;
+}
+
+impl Records for FlowBatch {
+    fn records(&self) -> usize {
+        self.flows.len()
+    }
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+/// Create a queue holding at most capacity messages, applying the policy when it is full
+pub fn bounded<T>(capacity: usize, policy: OverflowPolicy) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity: capacity.max(1),
+        policy,
+    });
+
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+/******************************** SENDER ********************************/
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Records> Sender<T> {
+    /// Queue the message, failing only if the receiver is gone. A message dropped by the overflow policy is not an error.
+    pub fn send(&self, msg: T) -> Result<(), T> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        while state.receiver_alive && state.queue.len() >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::Block => state = self.shared.not_full.wait(state).unwrap(),
+                OverflowPolicy::DropNewest => {
+                    count_drop(&msg);
+                    return Ok(());
+                }
+                OverflowPolicy::DropOldest => {
+                    if let Some(oldest) = state.queue.pop_front() {
+                        count_drop(&oldest);
+                    }
+                }
+            }
+        }
+
+        if !state.receiver_alive {
+            return Err(msg);
+        }
+
+        state.queue.push_back(msg);
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+fn count_drop<T: Records>(msg: &T) {
+    metrics::DROPPED_BATCHES.inc();
+    metrics::DROPPED_RECORDS.add(msg.records() as u64);
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+
+        if state.senders == 0 {
+            // wake up the receiver so it can see the channel is closed
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+/******************************** RECEIVER ********************************/
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecvTimeoutError {
+    /// No message arrived before the timeout
+    Timeout,
+    /// Every sender is gone and the queue is empty
+    Closed,
+}
+
+impl<T> Receiver<T> {
+    /// Wait for the next message, None once every sender is gone and the queue is empty
+    pub fn recv(&self) -> Option<T> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if let Some(msg) = state.queue.pop_front() {
+                self.shared.not_full.notify_one();
+                return Some(msg);
+            }
+
+            if state.senders == 0 {
+                return None;
+            }
+
+            state = self.shared.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Wait for the next message at most timeout
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if let Some(msg) = state.queue.pop_front() {
+                self.shared.not_full.notify_one();
+                return Ok(msg);
+            }
+
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Closed);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = self.shared.not_empty.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_alive = false;
+        self.shared.not_full.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    impl Records for u32 {
+        fn records(&self) -> usize {
+            1
+        }
+    }
+
+    fn drain(receiver: &Receiver<u32>) -> Vec<u32> {
+        let mut msgs = vec![];
+        while let Some(msg) = receiver.recv() {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    #[test]
+    fn block_when_full() {
+        let (sender, receiver) = bounded(1, OverflowPolicy::Block);
+
+        let t = thread::spawn(move || {
+            for i in 0..3 {
+                sender.send(i).unwrap();
+            }
+        });
+
+        assert_eq!(drain(&receiver), vec![0, 1, 2]);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn drop_newest_when_full() {
+        let (sender, receiver) = bounded(2, OverflowPolicy::DropNewest);
+
+        for i in 0..4 {
+            sender.send(i).unwrap();
+        }
+        drop(sender);
+
+        assert_eq!(drain(&receiver), vec![0, 1]);
+    }
+
+    #[test]
+    fn drop_oldest_when_full() {
+        let (sender, receiver) = bounded(2, OverflowPolicy::DropOldest);
+
+        for i in 0..4 {
+            sender.send(i).unwrap();
+        }
+        drop(sender);
+
+        assert_eq!(drain(&receiver), vec![2, 3]);
+    }
+
+    #[test]
+    fn send_without_receiver() {
+        let (sender, receiver) = bounded(1, OverflowPolicy::Block);
+        sender.send(0).unwrap();
+        drop(receiver);
+
+        // the queue is full but nobody will ever empty it
+        assert_eq!(sender.send(1), Err(1));
+    }
+
+    #[test]
+    fn recv_with_timeout() {
+        let (sender, receiver) = bounded(1, OverflowPolicy::Block);
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+
+        sender.send(1).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Ok(1));
+        drop(sender);
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Closed));
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!("drop-oldest".parse::<OverflowPolicy>(), Ok(OverflowPolicy::DropOldest));
+        assert!("drop".parse::<OverflowPolicy>().is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Time source of the listeners, the system clock unless a manual clock is given to control the template expiry in the tests
+#[derive(Debug, Clone, Default)]
+pub struct Clock(Option<Arc<AtomicU64>>);
+
+impl Clock {
+    /// Clock stopped at now (seconds since the UNIX epoch), only moving with advance
+    pub fn manual(now: u64) -> Self {
+        Clock(Some(Arc::new(AtomicU64::new(now))))
+    }
+
+    /// Seconds since the UNIX epoch
+    pub fn now_secs(&self) -> u64 {
+        match &self.0 {
+            Some(now) => now.load(Ordering::Relaxed),
+            None => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        }
+    }
+
+    /// Move a manual clock forward, shared by all its clones. The system clock can't be moved.
+    pub fn advance(&self, secs: u64) {
+        if let Some(now) = &self.0 {
+            now.fetch_add(secs, Ordering::Relaxed);
+        }
+    }
+
+    /// Move a manual clock to now, forward or backward. The system clock can't be moved.
+    pub fn set(&self, now: u64) {
+        if let Some(clock) = &self.0 {
+            clock.store(now, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advance_manual_clock() {
+        let clock = Clock::manual(1000);
+        clock.clone().advance(60);
+        assert_eq!(clock.now_secs(), 1060);
+        clock.set(500);
+        assert_eq!(clock.now_secs(), 500);
+
+        // the system clock ignores advance
+        let system = Clock::default();
+        let now = system.now_secs();
+        system.advance(1_000_000);
+        assert!(system.now_secs() < now + 1_000_000);
+    }
+}
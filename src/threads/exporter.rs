@@ -1,11 +1,77 @@
-use log::info;
-use std::sync::mpsc;
+use log::{error, info, trace};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::flow::Flow;
+use super::channel::{self, RecvTimeoutError};
+use crate::config::{Config, ConfigView, SharedConfig};
+use crate::flow::FlowBatch;
+use crate::sinks::Sink;
+
+/// Period of the age checks of the sinks, which also run when no flow arrives
+const TICK: Duration = Duration::from_secs(1);
+
+pub fn exporte(receiver: channel::Receiver<FlowBatch>, config: Arc<SharedConfig>) {
+    let mut config = ConfigView::new(config);
+    let mut sinks = build_sinks(config.get());
+    let mut last_tick = Instant::now();
 
-pub fn exporte(receiver: mpsc::Receiver<Vec<Box<dyn Flow>>>) {
     loop {
-        // TODO implémenter les différents exporters (json / stdout / ??)
-        let msg_list = receiver.recv().unwrap();
+        if last_tick.elapsed() >= TICK {
+            tick_sinks(&mut sinks);
+            last_tick = Instant::now();
+        }
+        let batch = match receiver.recv_timeout(TICK.saturating_sub(last_tick.elapsed())) {
+            Ok(batch) => batch,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+        };
+
+        // the sinks are rebuilt from scratch when the configuration is reloaded
+        if config.refresh() {
+            flush_sinks(&mut sinks);
+            sinks = build_sinks(config.get());
+            info!("{} sink(s) started after the configuration reload", sinks.len());
+        }
+
+        trace!("Received {} flows from {} (listener {:?})", batch.flows.len(), batch.exporter, batch.tag);
+
+        for sink in sinks.iter_mut() {
+            if let Err(e) = sink.write(&batch) {
+                error!("Failed to write flows to a sink: {}", e);
+            }
+        }
+    }
+
+    flush_sinks(&mut sinks);
+    info!("Queue drained, {} sink(s) flushed and closed", sinks.len());
+}
+
+fn build_sinks(config: &Config) -> Vec<Box<dyn Sink>> {
+    config
+        .sinks
+        .iter()
+        .filter_map(|sink| match sink.build() {
+            Ok(sink) => Some(sink),
+            Err(e) => {
+                error!("Failed to start the sink {:?}: {}", sink, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn tick_sinks(sinks: &mut [Box<dyn Sink>]) {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.tick() {
+            error!("Failed to write the aged flows of a sink: {}", e);
+        }
+    }
+}
+
+fn flush_sinks(sinks: &mut [Box<dyn Sink>]) {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.flush() {
+            error!("Failed to flush a sink: {}", e);
+        }
     }
 }
@@ -0,0 +1,152 @@
+use log::{info, trace};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+
+use crate::config::ForwardConfig;
+use crate::metrics::{self, CounterHandle};
+use crate::pcap;
+
+/******************************** FORWARDER ********************************/
+
+/// Copy of the received datagrams to the downstream collectors of a listener worker
+#[derive(Default)]
+pub struct Forwarder {
+    targets: Vec<Target>,
+}
+
+struct Target {
+    config: ForwardConfig,
+    socket: TargetSocket,
+    forwarded: CounterHandle,
+    errors: CounterHandle,
+}
+
+enum TargetSocket {
+    Udp(UdpSocket),
+    /// Raw socket writing its own IP header, to keep the address of the exporter as source
+    Spoofed(Socket),
+}
+
+impl Forwarder {
+    /// Open the sockets toward every target, spoofing needs CAP_NET_RAW
+    pub fn new(configs: &[ForwardConfig], listener: &str) -> Result<Self, String> {
+        let mut targets = vec![];
+
+        for config in configs {
+            let socket = if config.spoof {
+                TargetSocket::Spoofed(raw_socket(config.target).map_err(|e| format!("Failed to open the raw socket to forward to {} : {}", config.target, e))?)
+            } else {
+                let bind_addr: SocketAddr = match config.target {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                TargetSocket::Udp(UdpSocket::bind(bind_addr).map_err(|e| format!("Failed to open the socket to forward to {} : {}", config.target, e))?)
+            };
+
+            let target = config.target.to_string();
+            let labels = [("listener", listener), ("target", target.as_str())];
+            info!(
+                "Forwarding the datagrams of {} to {}{}",
+                listener,
+                config.target,
+                if config.spoof { " with the exporter address as source" } else { "" }
+            );
+
+            targets.push(Target {
+                config: config.clone(),
+                socket,
+                forwarded: metrics::FORWARDED_DATAGRAMS.with(&labels),
+                errors: metrics::FORWARD_ERRORS.with(&labels),
+            });
+        }
+
+        Ok(Forwarder { targets })
+    }
+
+    /// Send the datagram to every target whose filters match it, a failing target doesn't stop the others
+    pub fn forward(&self, buf: &[u8], from: SocketAddr) {
+        if self.targets.is_empty() || buf.len() < 2 {
+            return;
+        }
+        let version = u16::from_be_bytes([buf[0], buf[1]]);
+
+        for target in &self.targets {
+            if !target.config.matches(from.ip(), version) {
+                continue;
+            }
+
+            let sent = match &target.socket {
+                TargetSocket::Udp(socket) => socket.send_to(buf, target.config.target),
+                TargetSocket::Spoofed(socket) => send_spoofed(socket, buf, from, target.config.target),
+            };
+
+            match sent {
+                Ok(_) => target.forwarded.inc(),
+                Err(e) => {
+                    target.errors.inc();
+                    trace!("Failed to forward {} bytes from {} to {} : {}", buf.len(), from, target.config.target, e);
+                }
+            }
+        }
+    }
+}
+
+/******************************** SPOOFING ********************************/
+
+fn raw_socket(target: SocketAddr) -> io::Result<Socket> {
+    if !target.is_ipv4() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "spoofing is only available toward IPv4 targets"));
+    }
+
+    // IPPROTO_RAW implies IP_HDRINCL, the kernel sends the IP header we build
+    Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::from(255)))
+}
+
+/// The packet is sent whole, above the MTU of the route the kernel fails with EMSGSIZE instead of fragmenting it
+fn send_spoofed(socket: &Socket, buf: &[u8], from: SocketAddr, target: SocketAddr) -> io::Result<usize> {
+    let (from, target) = match (from, target) {
+        (SocketAddr::V4(from), SocketAddr::V4(target)) => (from, target),
+        (SocketAddr::V6(from), SocketAddr::V4(target)) => match from.ip().to_ipv4_mapped() {
+            Some(ip) => (SocketAddrV4::new(ip, from.port()), target),
+            None => return Err(io::Error::new(io::ErrorKind::Unsupported, "an IPv6 exporter can't be spoofed toward an IPv4 target")),
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::Unsupported, "spoofing is only available toward IPv4 targets")),
+    };
+
+    let packet = pcap::ipv4_udp_packet(buf, from, target).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "datagram too big for an IPv4 packet"))?;
+    socket.send_to(&packet, &SocketAddr::new(IpAddr::V4(*target.ip()), 0).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forward_config(target: SocketAddr) -> ForwardConfig {
+        ForwardConfig {
+            target,
+            exporters: vec![],
+            versions: None,
+            spoof: false,
+        }
+    }
+
+    #[test]
+    fn forward_datagrams() {
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        second.set_nonblocking(true).unwrap();
+
+        let mut filtered = forward_config(second.local_addr().unwrap());
+        filtered.versions = Some(vec![5]);
+        let forwarder = Forwarder::new(&[forward_config(first.local_addr().unwrap()), filtered], "test").unwrap();
+
+        forwarder.forward(&[0, 10, 1, 2], "192.0.2.1:4739".parse().unwrap());
+
+        let mut buf = [0; 16];
+        assert_eq!(first.recv(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[0..4], &[0, 10, 1, 2]);
+        // only netflow v5 is sent to the second target
+        assert!(second.recv(&mut buf).is_err());
+    }
+}
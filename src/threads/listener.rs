@@ -1,70 +1,521 @@
 use core::convert::TryInto;
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
+use serde::Deserialize;
+use socket2::Socket;
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
-use std::sync::mpsc;
+use std::io::{self, Read};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
 
-use crate::flow::{self, Flow, Template};
+use super::capture::DebugCapture;
+use super::channel;
+use super::clock::Clock;
+use super::forwarder::Forwarder;
+use super::receiver::{self, Datagram, Receiver};
+use super::shutdown::{self, Shutdown};
+use super::state::{Exporter, ExporterList, StateStore, TemplateEntry};
+use crate::config::{ConfigView, ForwardConfig, SharedConfig, TemplatesConfig};
+use crate::flow::{self, Flow, FlowBatch, Template};
+use crate::metrics::{self, CounterHandle};
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-struct Exporter {
-    addr: IpAddr,   // ip source of the exporter
-    domain_id: u32, // observation domain id unique to the exporter
+/// Denied sources counted under their own address by a worker, the next ones are counted together
+const MAX_DENIED_SOURCES: usize = 16;
+
+/******************************** LISTENER DEFINITION ********************************/
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    Tcp,
 }
 
-struct ExporterInfos {
-    pub sampling: u32,
-    template: HashMap<u16, Template>,
+/// One listener given on the command line as [udp|tcp]://IP:port[?versions=5,10&tag=name]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerSpec {
+    pub addr: SocketAddr,
+    pub transport: Transport,
+    /// Netflow/IPFIX versions accepted on this listener
+    pub versions: Vec<u16>,
+    /// Free-form tag attached to every flow received by this listener
+    pub tag: Option<Arc<str>>,
 }
 
-impl Default for ExporterInfos {
-    fn default() -> ExporterInfos {
-        ExporterInfos {
-            sampling: 1,
-            template: HashMap::new(),
+impl ListenerSpec {
+    /// Check the listener definition, versions defaults to every version supported by the transport
+    pub fn new(addr: SocketAddr, transport: Transport, versions: Option<Vec<u16>>, tag: Option<&str>) -> Result<Self, String> {
+        let versions = versions.unwrap_or_else(|| match transport {
+            Transport::Udp => vec![flow::netflow5::VERSION, flow::ipfix::VERSION],
+            // netflow v5 has no length in its header, the messages can't be delimited in a stream
+            Transport::Tcp => vec![flow::ipfix::VERSION],
+        });
+
+        if let Some(v) = versions.iter().find(|&&v| v != flow::netflow5::VERSION && v != flow::ipfix::VERSION) {
+            return Err(format!("Invalid version {} for the listener {}, expected 5 or 10", v, addr));
+        }
+
+        if transport == Transport::Tcp && versions != [flow::ipfix::VERSION] {
+            return Err(format!("Only IPFIX (version 10) can be received over TCP, invalid listener {}", addr));
+        }
+
+        Ok(ListenerSpec {
+            addr,
+            transport,
+            versions,
+            tag: tag.map(Arc::from),
+        })
+    }
+
+    /// Name used in the logs and as metric label
+    pub fn name(&self) -> String {
+        match &self.tag {
+            Some(tag) => tag.to_string(),
+            None => self.addr.to_string(),
+        }
+    }
+}
+
+impl FromStr for ListenerSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (transport, rest) = match s.split_once("://") {
+            Some(("udp", rest)) => (Transport::Udp, rest),
+            Some(("tcp", rest)) => (Transport::Tcp, rest),
+            Some((scheme, _)) => return Err(format!("Invalid transport '{}' for the listener {}, expected udp or tcp", scheme, s)),
+            None => (Transport::Udp, s),
+        };
+
+        let (addr, params) = rest.split_once('?').unwrap_or((rest, ""));
+        let addr = addr.parse().map_err(|e| format!("Invalid address '{}' for the listener {} : {}", addr, s, e))?;
+        let mut versions = None;
+        let mut tag = None;
+
+        for param in params.split('&').filter(|p| !p.is_empty()) {
+            match param.split_once('=') {
+                Some(("versions", list)) => {
+                    versions = Some(
+                        list.split(',')
+                            .map(|v| v.parse().map_err(|_| format!("Invalid version '{}' for the listener {}", v, s)))
+                            .collect::<Result<_, _>>()?,
+                    );
+                }
+                Some(("tag", value)) => tag = Some(value),
+                _ => return Err(format!("Invalid parameter '{}' for the listener {}, expected versions or tag", param, s)),
+            }
         }
+
+        ListenerSpec::new(addr, transport, versions, tag)
     }
 }
 
-type ExporterList = HashMap<Exporter, ExporterInfos>;
+/// Settings of one listener worker
+#[derive(Debug, Clone)]
+pub struct ListenerOptions {
+    pub spec: ListenerSpec,
+    /// Size in bytes of the kernel receive buffer of the socket
+    pub recv_buffer_size: Option<usize>,
+    /// Number of datagrams read per syscall
+    pub batch_size: usize,
+    /// Bind the socket with SO_REUSEPORT, to share the address with the other workers
+    pub reuse_port: bool,
+    /// Downstream collectors receiving a copy of the datagrams
+    pub forward: Vec<ForwardConfig>,
+    /// Rotating pcap file receiving a copy of the datagrams, shared by the workers of the listener
+    pub capture: Option<Arc<DebugCapture>>,
+    /// Time source of the template expiry and of the state snapshots
+    pub clock: Clock,
+}
 
-pub fn listen(addr: SocketAddr, sender: mpsc::Sender<Vec<Box<dyn Flow>>>) {
-    let socket = UdpSocket::bind(&addr).expect(&format!("Failed to bind UDP socket to {}", &addr));
-    info!("Listening for UDP packet on {}", &addr);
+/// Receive and decode the flows until the shutdown is triggered, failing only if the socket can't be bound
+pub fn listen(opts: ListenerOptions, sender: channel::Sender<FlowBatch>, config: Arc<SharedConfig>, shutdown: Shutdown, state: Option<Arc<StateStore>>) -> Result<(), String> {
+    Listener::bind(opts)?.run(sender, config, shutdown, state)
+}
 
-    let mut buf = [0; 1500];
-    let mut exporter_list: ExporterList = HashMap::new();
-    const MIN_BUF_LEN: usize = 2;
+/// Listener whose socket is bound, before its thread is started
+pub struct Listener {
+    opts: ListenerOptions,
+    socket: ListenerSocket,
+}
+
+enum ListenerSocket {
+    Udp(Socket),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /// Bind the socket of the listener, the system chooses the port of an address ending with :0
+    pub fn bind(opts: ListenerOptions) -> Result<Self, String> {
+        let addr = opts.spec.addr;
+
+        let socket = match opts.spec.transport {
+            Transport::Udp => {
+                let socket = receiver::bind(addr, opts.recv_buffer_size, opts.reuse_port).map_err(|e| format!("Failed to bind UDP socket to {} : {}", &addr, e))?;
+                // wake up regularly to check the shutdown flag
+                socket
+                    .set_read_timeout(Some(shutdown::POLL_INTERVAL))
+                    .map_err(|e| format!("Failed to set the read timeout of the UDP socket {} : {}", &addr, e))?;
+                ListenerSocket::Udp(socket)
+            }
+            Transport::Tcp => {
+                let listener = TcpListener::bind(addr).map_err(|e| format!("Failed to bind TCP socket to {} : {}", &addr, e))?;
+                listener.set_nonblocking(true).map_err(|e| format!("Failed to set the TCP socket {} non blocking : {}", &addr, e))?;
+                ListenerSocket::Tcp(listener)
+            }
+        };
 
-    loop {
+        Ok(Listener { opts, socket })
+    }
+
+    /// Address the socket is bound to, with the port chosen by the system
+    pub fn local_addr(&self) -> Result<SocketAddr, String> {
+        let addr = match &self.socket {
+            ListenerSocket::Udp(socket) => socket.local_addr().map(|addr| addr.as_socket()),
+            ListenerSocket::Tcp(listener) => listener.local_addr().map(Some),
+        };
+
+        match addr {
+            Ok(Some(addr)) => Ok(addr),
+            Ok(None) => Err(format!("The listener {} is not bound to an IP address", self.opts.spec.name())),
+            Err(e) => Err(format!("Failed to read the address of the listener {} : {}", self.opts.spec.name(), e)),
+        }
+    }
+
+    /// Receive and decode the flows until the shutdown is triggered
+    pub fn run(self, sender: channel::Sender<FlowBatch>, config: Arc<SharedConfig>, shutdown: Shutdown, state: Option<Arc<StateStore>>) -> Result<(), String> {
+        let addr = self.local_addr()?;
+
+        match self.socket {
+            ListenerSocket::Udp(socket) => listen_udp(self.opts, addr, socket, sender, config, shutdown, state),
+            // the templates of a TCP session die with it, the exporter sends them again on the next connection
+            ListenerSocket::Tcp(listener) => listen_tcp(self.opts, addr, listener, sender, config, shutdown),
+        }
+    }
+}
+
+fn listen_udp(
+    opts: ListenerOptions,
+    addr: SocketAddr,
+    socket: Socket,
+    sender: channel::Sender<FlowBatch>,
+    config: Arc<SharedConfig>,
+    shutdown: Shutdown,
+    state: Option<Arc<StateStore>>,
+) -> Result<(), String> {
+    info!("Listening for UDP packet on {} (listener {})", &addr, opts.spec.name());
+
+    let mut receiver = Receiver::new(socket, opts.batch_size);
+    // the templates are kept per worker, the kernel always sends the datagrams of a source to the same socket
+    let mut worker = Worker::new(opts.spec, sender, config);
+    worker.clock = opts.clock;
+    worker.capture = opts.capture;
+    worker.forwarder = Forwarder::new(&opts.forward, &worker.spec.name())?;
+    if let Some(state) = state {
+        worker.attach_state(state);
+    }
+
+    while !shutdown.is_triggered() {
         trace!("Waiting for data...");
-        let (rcv_bytes, from) = socket.recv_from(&mut buf).expect("Didn't received data");
-        trace!("Received {} bytes from {}", rcv_bytes, from);
+        match receiver.recv(|datagram| worker.handle_datagram(datagram)) {
+            Ok(_) => {}
+            Err(e) if shutdown::is_timeout(&e) => {}
+            // an error on a UDP socket (ICMP unreachable, ...) doesn't prevent the next datagrams from being received
+            Err(e) => error!("Failed to receive data on {} : {}", addr, e),
+        }
+        worker.publish_state(false);
+    }
+    worker.publish_state(true);
+
+    info!("UDP listener {} stopped", worker.spec.name());
+    Ok(())
+}
+
+fn listen_tcp(opts: ListenerOptions, addr: SocketAddr, listener: TcpListener, sender: channel::Sender<FlowBatch>, config: Arc<SharedConfig>, shutdown: Shutdown) -> Result<(), String> {
+    info!("Listening for TCP connection on {} (listener {})", &addr, opts.spec.name());
+
+    // the connections are closed on shutdown so their threads release their sender
+    let mut connections: Vec<(TcpStream, thread::JoinHandle<()>)> = vec![];
+
+    while let Some(stream) = shutdown::accept(&listener, &shutdown) {
+        connections.retain(|(_, t)| !t.is_finished());
+
+        let (stream, from) = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Connection failed : {}", e);
+                continue;
+            }
+        };
+        let stream_clone = match stream.try_clone() {
+            Ok(stream_clone) => stream_clone,
+            Err(e) => {
+                error!("Failed to handle the TCP connection of {} : {}", from, e);
+                continue;
+            }
+        };
 
-        if rcv_bytes < MIN_BUF_LEN {
+        // each connection is a transport session with its own templates
+        let mut worker = Worker::new(opts.spec.clone(), sender.clone(), config.clone());
+        worker.clock = opts.clock.clone();
+        worker.capture = opts.capture.clone();
+        worker.forwarder = match Forwarder::new(&opts.forward, &opts.spec.name()) {
+            Ok(forwarder) => forwarder,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+        match thread::Builder::new().name(format!("{}-tcp", opts.spec.name())).spawn(move || worker.handle_stream(stream)) {
+            Ok(t) => connections.push((stream_clone, t)),
+            Err(e) => error!("Failed to start the thread for a TCP connection on {} : {}", addr, e),
+        }
+    }
+
+    for (stream, t) in connections {
+        let _ = stream.shutdown(net::Shutdown::Both);
+        let _ = t.join();
+    }
+
+    info!("TCP listener {} stopped", opts.spec.name());
+    Ok(())
+}
+
+/******************************** WORKER ********************************/
+
+/// Decode the messages of a listener socket or TCP connection and forward the flows to the exporter thread
+pub(super) struct Worker {
+    pub(super) spec: ListenerSpec,
+    exporter_list: ExporterList,
+    sender: channel::Sender<FlowBatch>,
+    config: ConfigView,
+    /// Store receiving the snapshots of the templates, with the id of the worker and the time of its last snapshot
+    state: Option<(Arc<StateStore>, usize, u64)>,
+    forwarder: Forwarder,
+    capture: Option<Arc<DebugCapture>>,
+    pub(super) clock: Clock,
+    received: CounterHandle,
+    truncated: CounterHandle,
+    rejected: CounterHandle,
+    errors: CounterHandle,
+    decoded: CounterHandle,
+    /// Messages dropped by the access rules, by reason
+    denied: Vec<(&'static str, CounterHandle)>,
+    /// Messages dropped by the access rules, by source up to MAX_DENIED_SOURCES then under "other"
+    denied_sources: HashMap<IpAddr, CounterHandle>,
+    other_denied_sources: CounterHandle,
+}
+
+impl Worker {
+    pub(super) fn new(spec: ListenerSpec, sender: channel::Sender<FlowBatch>, config: Arc<SharedConfig>) -> Self {
+        let name = spec.name();
+        let labels = [("listener", name.as_str())];
+
+        Worker {
+            exporter_list: ExporterList::new(),
+            sender,
+            config: ConfigView::new(config),
+            state: None,
+            forwarder: Forwarder::default(),
+            capture: None,
+            clock: Clock::default(),
+            received: metrics::RECEIVED_DATAGRAMS.with(&labels),
+            truncated: metrics::TRUNCATED_DATAGRAMS.with(&labels),
+            rejected: metrics::REJECTED_VERSIONS.with(&labels),
+            errors: metrics::PARSING_ERRORS.with(&labels),
+            decoded: metrics::DECODED_FLOWS.with(&labels),
+            denied: ["acl", "domain", "max_exporters"]
+                .iter()
+                .map(|&reason| (reason, metrics::DENIED_MESSAGES.with(&[("listener", name.as_str()), ("reason", reason)])))
+                .collect(),
+            denied_sources: HashMap::new(),
+            other_denied_sources: metrics::DENIED_SOURCES.with(&[("listener", name.as_str()), ("source", "other")]),
+            spec,
+        }
+    }
+
+    /// Restore the templates saved by the previous run, and publish a snapshot of them regularly
+    fn attach_state(&mut self, store: Arc<StateStore>) {
+        let name = self.spec.name();
+        let config = self.config.get();
+        store.restore(&name, &mut self.exporter_list, config.exporters.max, config.templates.max_per_exporter);
+        let id = store.register(&name);
+        store.publish(id, &self.exporter_list);
+        self.state = Some((store, id, self.clock.now_secs()));
+    }
+
+    fn publish_state(&mut self, force: bool) {
+        if let Some((store, id, last_publish)) = &mut self.state {
+            let now = self.clock.now_secs();
+            if force || (store.interval > 0 && now >= *last_publish + store.interval) {
+                store.publish(*id, &self.exporter_list);
+                *last_publish = now;
+            }
+        }
+    }
+
+    pub(super) fn handle_datagram(&mut self, datagram: Datagram) {
+        self.received.inc();
+
+        if datagram.truncated {
+            self.truncated.inc();
+            warn!("Datagram from {} truncated to {} bytes, dropping it", datagram.from, datagram.data.len());
+            self.capture(datagram.data, datagram.from, true);
+            return;
+        }
+
+        self.handle_msg(datagram.data, datagram.from);
+    }
+
+    /// Read the IPFIX messages of a TCP connection, delimited with the length of their header
+    fn handle_stream(mut self, mut stream: TcpStream) {
+        let from = match stream.peer_addr() {
+            Ok(from) => from,
+            Err(e) => return error!("Failed to read the address of the TCP peer : {}", e),
+        };
+        info!("TCP connection from {} on {}", from, self.spec.name());
+
+        let mut buf = vec![0; u16::MAX as usize];
+        loop {
+            if let Err(e) = stream.read_exact(&mut buf[0..4]) {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    error!("Failed to read from the TCP connection of {} : {}", from, e);
+                }
+                break;
+            }
+
+            let length = u16::from_be_bytes(buf[2..4].try_into().unwrap()) as usize;
+            if length < flow::ipfix::Header::SIZE {
+                error!("Invalid IPFIX message length {} from {}, closing the connection", length, from);
+                break;
+            }
+
+            if let Err(e) = stream.read_exact(&mut buf[4..length]) {
+                error!("Failed to read from the TCP connection of {} : {}", from, e);
+                break;
+            }
+
+            self.received.inc();
+            self.handle_msg(&buf[0..length], from);
+        }
+
+        info!("TCP connection from {} closed", from);
+    }
+
+    fn handle_msg(&mut self, buf: &[u8], from: SocketAddr) {
+        const MIN_BUF_LEN: usize = 2;
+        trace!("Received {} bytes from {}", buf.len(), from);
+
+        self.config.refresh();
+        if !self.config.get().exporters.is_allowed(from.ip()) {
+            return self.deny(from, "acl");
+        }
+
+        // the downstream collectors get the datagram as it was received, even if it can't be decoded here
+        self.forwarder.forward(buf, from);
+
+        if buf.len() < MIN_BUF_LEN {
+            self.errors.inc();
+            self.capture(buf, from, true);
             error!("Data to small for a netflow packet from {}, expected at least {} bytes", from, MIN_BUF_LEN);
-            continue;
+            return;
         }
 
         // read the first 2 bytes to see what header we need to use
         let version = u16::from_be_bytes(buf[0..MIN_BUF_LEN].try_into().unwrap());
-        let msg_list = match version {
-            flow::netflow5::VERSION => parse_v5_msg(&buf[0..rcv_bytes]),
-            flow::ipfix::VERSION => parse_ipfix_msg(from.ip(), &buf[0..rcv_bytes], &mut exporter_list),
-            _ => {
-                error!("Invalid netflow version in packet from {}, read {}", from, version);
-                continue;
+        if !self.spec.versions.contains(&version) {
+            self.rejected.inc();
+            self.capture(buf, from, true);
+            error!("Invalid netflow version in packet from {} on {}, read {}", from, self.spec.name(), version);
+            return;
+        }
+
+        // the observation domain and the number of exporters are checked before any template is stored
+        let mut domain_id = None;
+        if version == flow::ipfix::VERSION && buf.len() >= flow::ipfix::Header::SIZE {
+            let exporters = &self.config.get().exporters;
+            let exporter = Exporter {
+                addr: from.ip(),
+                domain_id: u32::from_be_bytes(buf[12..16].try_into().unwrap()),
+            };
+            domain_id = Some(exporter.domain_id);
+
+            if !exporters.is_domain_allowed(exporter.domain_id) {
+                return self.deny(from, "domain");
+            }
+            if exporters.max > 0 && self.exporter_list.len() >= exporters.max && !self.exporter_list.contains_key(&exporter) {
+                return self.deny(from, "max_exporters");
             }
+        }
+
+        let msg_list = match version {
+            flow::netflow5::VERSION => parse_v5_msg(buf),
+            _ => parse_ipfix_msg(from.ip(), buf, &mut self.exporter_list, self.clock.now_secs(), &self.config.get().templates),
         };
 
-        /*match msg_list {
-            Ok(list) => {
-                if !list.is_empty() {
-                    sender.send(list).unwrap();
+        match msg_list {
+            Ok(flows) => {
+                self.capture(buf, from, false);
+                self.decoded.add(flows.len() as u64);
+                let (exporter, exporter_name) = self.identify(from.ip(), domain_id);
+
+                let batch = FlowBatch {
+                    tag: self.spec.tag.clone(),
+                    exporter,
+                    exporter_name,
+                    flows,
+                };
+                if !batch.flows.is_empty() && self.sender.send(batch).is_err() {
+                    error!("Exporter thread is gone, dropping the flows from {}", from);
                 }
             }
-            Err(e) => error!("Error while parsing netflow msg {} from {} : {}", version, from, e),
-        }*/
+            Err(e) => {
+                self.errors.inc();
+                self.capture(buf, from, true);
+                error!("Error while parsing netflow msg {} from {} : {}", version, from, e);
+            }
+        }
+    }
+
+    /// Address and name of the exporter attached to its flows, from its option data or the static mappings instead of the source address
+    fn identify(&self, addr: IpAddr, domain_id: Option<u32>) -> (IpAddr, Option<Arc<str>>) {
+        let exporters = &self.config.get().exporters;
+
+        let reported_addr = match domain_id {
+            Some(domain_id) if exporters.use_reported_address => self.exporter_list.get(&Exporter { addr, domain_id }).and_then(|infos| infos.reported_addr),
+            _ => None,
+        };
+
+        exporters.resolve(reported_addr.unwrap_or(addr), domain_id)
+    }
+
+    /// Copy the message to the debug capture, failed tells if it couldn't be decoded
+    fn capture(&self, buf: &[u8], from: SocketAddr, failed: bool) {
+        if let Some(capture) = &self.capture {
+            capture.write(buf, from, failed);
+        }
+    }
+
+    /// Drop a message refused by the exporter access rules, counted per reason and per source for the first MAX_DENIED_SOURCES sources
+    fn deny(&mut self, from: SocketAddr, reason: &str) {
+        if let Some((_, denied)) = self.denied.iter().find(|&&(r, _)| r == reason) {
+            denied.inc();
+        }
+
+        let source = from.ip();
+        match self.denied_sources.get(&source) {
+            Some(denied) => denied.inc(),
+            None if self.denied_sources.len() < MAX_DENIED_SOURCES => {
+                // logged once per source, the number of warnings is bounded like the series
+                warn!("Messages from {} on {} dropped ({})", source, self.spec.name(), reason);
+                let denied = metrics::DENIED_SOURCES.with(&[("listener", self.spec.name().as_str()), ("source", source.to_string().as_str())]);
+                denied.inc();
+                self.denied_sources.insert(source, denied);
+            }
+            None => self.other_denied_sources.inc(),
+        }
+        trace!("Message from {} on {} dropped ({})", from, self.spec.name(), reason);
     }
 }
 
@@ -88,6 +539,7 @@ fn parse_v5_msg(buf: &[u8]) -> Result<Vec<Box<dyn Flow>>, String> {
     while offset < buf_len {
         let mut pdu = DataSet::read(&buf[offset..])?;
         pdu.add_sampling(header.sampl_interval() as u32);
+        pdu.set_system_init_time(&header);
         pdu_list.push(Box::new(pdu));
 
         offset += DataSet::SIZE;
@@ -96,7 +548,9 @@ fn parse_v5_msg(buf: &[u8]) -> Result<Vec<Box<dyn Flow>>, String> {
     Ok(pdu_list)
 }
 
-fn parse_ipfix_msg(from: IpAddr, buf: &[u8], exporter_list: &mut ExporterList) -> Result<Vec<Box<dyn Flow>>, String> {
+/// Decode an IPFIX message received at now (seconds since the UNIX epoch).
+/// A template not refreshed during template_timeout seconds is discarded, 0 keeps the templates forever.
+fn parse_ipfix_msg(from: IpAddr, buf: &[u8], exporter_list: &mut ExporterList, now: u64, templates: &TemplatesConfig) -> Result<Vec<Box<dyn Flow>>, String> {
     use flow::ipfix::*;
     let buf_len = buf.len();
 
@@ -108,16 +562,19 @@ fn parse_ipfix_msg(from: IpAddr, buf: &[u8], exporter_list: &mut ExporterList) -
 
     let mut offset = Header::SIZE;
     let mut data_set_list: Vec<Box<dyn Flow>> = vec![];
-    let padding: usize = 4;
 
     while offset < buf_len {
         let set = SetHeader::read(&buf[offset..])?;
+        if (set.length as usize) < SetHeader::SIZE || offset + set.length as usize > buf_len {
+            return Err(format!("Invalid length {} of the set {} at offset {} of a message of {} bytes", set.length, set.id, offset, buf_len));
+        }
         offset += SetHeader::SIZE;
         let end_of_set = offset + set.content_size();
 
+        // a set may end with padding, always shorter than a record
         if set.id == DataSetTemplate::SET_ID {
-            while (offset + padding) < end_of_set {
-                let (template, size_read) = DataSetTemplate::read(&buf[offset..])?;
+            while offset + TemplateHeader::SIZE <= end_of_set {
+                let (template, size_read) = DataSetTemplate::read(&buf[offset..end_of_set])?;
                 let exporter_key = Exporter {
                     addr: from,
                     domain_id: header.domain_id,
@@ -126,11 +583,11 @@ fn parse_ipfix_msg(from: IpAddr, buf: &[u8], exporter_list: &mut ExporterList) -
                 info!("Template received from {:?}\n{}", exporter_key, template);
                 offset += size_read;
 
-                exporter_list.entry(exporter_key).or_default().template.insert(template.header.id, Template::IpfixDataSet(template));
+                store_template(exporter_list, exporter_key, Template::IpfixDataSet(template), now, templates.max_per_exporter);
             }
         } else if set.id == OptionDataSetTemplate::SET_ID {
-            while (offset + padding) < end_of_set {
-                let (option_template, size_read) = OptionDataSetTemplate::read(&buf[offset..])?;
+            while offset + OptionTemplateHeader::SIZE <= end_of_set {
+                let (option_template, size_read) = OptionDataSetTemplate::read(&buf[offset..end_of_set])?;
                 let exporter_key = Exporter {
                     addr: from,
                     domain_id: header.domain_id,
@@ -139,11 +596,7 @@ fn parse_ipfix_msg(from: IpAddr, buf: &[u8], exporter_list: &mut ExporterList) -
                 info!("Option template received from {:?}\n{}", exporter_key, option_template);
                 offset += size_read;
 
-                exporter_list
-                    .entry(exporter_key)
-                    .or_default()
-                    .template
-                    .insert(option_template.header.id, Template::IpfixOptionDataSet(option_template));
+                store_template(exporter_list, exporter_key, Template::IpfixOptionDataSet(option_template), now, templates.max_per_exporter);
             }
         } else if set.id >= DataSet::MIN_SET_ID {
             let exporter_key = Exporter {
@@ -152,21 +605,40 @@ fn parse_ipfix_msg(from: IpAddr, buf: &[u8], exporter_list: &mut ExporterList) -
             };
 
             if let Some(infos) = exporter_list.get_mut(&exporter_key) {
-                if let Some(template) = infos.template.get(&set.id) {
-                    match template {
+                if let Some(entry) = infos.template.get(&set.id) {
+                    if templates.timeout > 0 && now.saturating_sub(entry.received) > templates.timeout {
+                        warn!("Template {} of {:?} expired, it was received {}s ago", set.id, exporter_key, now.saturating_sub(entry.received));
+                        infos.template.remove(&set.id);
+                        offset = end_of_set;
+                        continue;
+                    }
+                }
+
+                if let Some(entry) = infos.template.get(&set.id) {
+                    match &entry.template {
                         Template::IpfixDataSet(t) => {
-                            while (offset + padding) < end_of_set {
-                                let mut msg = DataSet::read(&buf[offset..], &t.fields, t.length)?;
-                                msg.add_sampling(infos.sampling as u64);
+                            while end_of_set - offset >= t.plan.min_length.max(1) {
+                                let (mut msg, size_read) = DataSet::read(&buf[offset..end_of_set], &t.plan)?;
+                                if t.plan.has_counters {
+                                    // the sampling fields of the record take precedence over the options of the exporter
+                                    let sampling = msg.sampling().unwrap_or(infos.sampling as u64);
+                                    msg.add_sampling(sampling);
+                                }
+                                if t.plan.has_timestamps {
+                                    msg.normalize_timestamps(header.export_time, infos.system_init_time);
+                                }
                                 data_set_list.push(Box::new(msg));
-                                offset += t.length;
+                                offset += size_read;
+                                if size_read == 0 {
+                                    break;
+                                }
                             }
                         }
                         Template::IpfixOptionDataSet(t) => {
-                            while (offset + padding) < end_of_set {
-                                let msg = DataSet::read(&buf[offset..], &t.fields, t.length)?;
+                            while end_of_set - offset >= t.plan.min_length.max(1) {
+                                let (msg, size_read) = DataSet::read(&buf[offset..end_of_set], &t.plan)?;
                                 info!("Option data set received : {}", msg);
-                                offset += t.length;
+                                offset += size_read;
 
                                 // check if the sampling interval is set in this record
                                 if let Some(&FieldValue::U32(v)) = msg.fields.get(&FieldType::SamplingInterval) {
@@ -175,6 +647,25 @@ fn parse_ipfix_msg(from: IpAddr, buf: &[u8], exporter_list: &mut ExporterList) -
                                         info!("Setting the sampling for {:?} to {}", &exporter_key, infos.sampling);
                                     }
                                 }
+
+                                // keep the boot time of the exporter to convert the sysUpTime timestamps
+                                if let Some(&FieldValue::U64(v)) = msg.fields.get(&FieldType::SystemInitTimeMilliseconds) {
+                                    infos.system_init_time = Some(v);
+                                }
+
+                                // the address of the exporter as it knows it, the source address may be a NAT or a relay
+                                let reported_addr = match (msg.fields.get(&FieldType::ExporterIPv4Address), msg.fields.get(&FieldType::ExporterIPv6Address)) {
+                                    (Some(&FieldValue::U32(v)), _) if v != 0 => Some(IpAddr::from(Ipv4Addr::from(v))),
+                                    (_, Some(&FieldValue::U128(v))) if v != 0 => Some(IpAddr::from(Ipv6Addr::from(v))),
+                                    _ => None,
+                                };
+                                if reported_addr.is_some() && infos.reported_addr != reported_addr {
+                                    infos.reported_addr = reported_addr;
+                                    info!("Exporter {:?} reported its address {:?}", &exporter_key, reported_addr);
+                                }
+                                if size_read == 0 {
+                                    break;
+                                }
                             }
                         }
                     }
@@ -190,10 +681,29 @@ fn parse_ipfix_msg(from: IpAddr, buf: &[u8], exporter_list: &mut ExporterList) -
     Ok(data_set_list)
 }
 
+/// Keep the template, unless it is a new one and the exporter already has max_templates of them (0 for no limit)
+fn store_template(exporter_list: &mut ExporterList, exporter: Exporter, template: Template, now: u64, max_templates: usize) {
+    let infos = exporter_list.entry(exporter).or_default();
+
+    if max_templates > 0 && infos.template.len() >= max_templates && !infos.template.contains_key(&template.id()) {
+        metrics::REJECTED_TEMPLATES.inc();
+        warn!("Template {} of {:?} ignored, the exporter already has {} templates", template.id(), exporter, infos.template.len());
+        return;
+    }
+
+    infos.template.insert(template.id(), TemplateEntry { template, received: now });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::flow::ipfix::{DataSet, DataSetTemplate, DecodePlan, FieldType, FieldValue, Header, SetHeader, TemplateField, VARIABLE_LENGTH};
     use hex_literal::hex;
+    use num_traits::FromPrimitive;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+
+    const NO_LIMIT: TemplatesConfig = TemplatesConfig { timeout: 0, max_per_exporter: 0 };
 
     // TODO
     const NETFLOW5_MSG: [u8; 168] = hex!(
@@ -262,10 +772,10 @@ mod tests {
 
     #[test]
     fn read_ipfix_template() {
-        let mut exporter_list: ExporterList = HashMap::new();
+        let mut exporter_list = ExporterList::new();
         let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
-        let data_list = parse_ipfix_msg(from, &TEMPLATE_IPFIX_MSG, &mut exporter_list).unwrap();
+        let data_list = parse_ipfix_msg(from, &TEMPLATE_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
 
         assert_eq!(exporter_list.len(), 1); // template should be stored in the map
         assert_eq!(data_list.len(), 0);
@@ -273,10 +783,10 @@ mod tests {
 
     #[test]
     fn read_ipfix_option_template() {
-        let mut exporter_list: ExporterList = HashMap::new();
+        let mut exporter_list = ExporterList::new();
         let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
-        let data_list = parse_ipfix_msg(from, &OPTION_TEMPLATE_IPFIX_MSG, &mut exporter_list).unwrap();
+        let data_list = parse_ipfix_msg(from, &OPTION_TEMPLATE_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
 
         assert_eq!(exporter_list.len(), 1); // option template should be stored in the map
         assert_eq!(data_list.len(), 0);
@@ -284,24 +794,24 @@ mod tests {
 
     #[test]
     fn read_ipfix_dataset() {
-        let mut exporter_list: ExporterList = HashMap::new();
+        let mut exporter_list = ExporterList::new();
         let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
         // read and store the template for the dataset first
-        parse_ipfix_msg(from, &TEMPLATE_IPFIX_MSG, &mut exporter_list).unwrap();
+        parse_ipfix_msg(from, &TEMPLATE_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
         assert_eq!(exporter_list.len(), 1);
 
         // then read the data set with the template
-        let data_list = parse_ipfix_msg(from, &DATA_SET_IPFIX_MSG, &mut exporter_list).unwrap();
+        let data_list = parse_ipfix_msg(from, &DATA_SET_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
         assert_eq!(data_list.len(), 2);
     }
 
     #[test]
     fn read_ipfix_dataset_without_template() {
-        let mut exporter_list: ExporterList = HashMap::new();
+        let mut exporter_list = ExporterList::new();
         let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
-        let data_list = parse_ipfix_msg(from, &DATA_SET_IPFIX_MSG, &mut exporter_list).unwrap();
+        let data_list = parse_ipfix_msg(from, &DATA_SET_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
 
         // no template provied to read the dataset, so we expect 0 result
         assert_eq!(exporter_list.len(), 0);
@@ -310,14 +820,14 @@ mod tests {
 
     #[test]
     fn read_ipfix_dataset_with_template_from_difference_source() {
-        let mut exporter_list: ExporterList = HashMap::new();
+        let mut exporter_list = ExporterList::new();
         let from_template = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
-        parse_ipfix_msg(from_template, &TEMPLATE_IPFIX_MSG, &mut exporter_list).unwrap();
+        parse_ipfix_msg(from_template, &TEMPLATE_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
 
         // change the source exporter for the flow data
         let from_data = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 8));
-        let data_list = parse_ipfix_msg(from_data, &DATA_SET_IPFIX_MSG, &mut exporter_list).unwrap();
+        let data_list = parse_ipfix_msg(from_data, &DATA_SET_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
 
         // template should't match for the parsing
         assert_eq!(data_list.len(), 0);
@@ -325,15 +835,15 @@ mod tests {
 
     #[test]
     fn read_ipfix_option_dataset() {
-        let mut exporter_list: ExporterList = HashMap::new();
+        let mut exporter_list = ExporterList::new();
         let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
         // read and store the option template for the dataset first
-        parse_ipfix_msg(from, &OPTION_TEMPLATE_IPFIX_MSG, &mut exporter_list).unwrap();
+        parse_ipfix_msg(from, &OPTION_TEMPLATE_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
         assert_eq!(exporter_list.len(), 1);
 
         // then read the data set with the template
-        let data_list = parse_ipfix_msg(from, &OPTION_DATA_SET_IPFIX_MSG, &mut exporter_list).unwrap();
+        let data_list = parse_ipfix_msg(from, &OPTION_DATA_SET_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
 
         // no result expected because the function just print the data parsed
         // TODO capture the output of the function and check if it contains the parsed data ?
@@ -342,13 +852,332 @@ mod tests {
 
     #[test]
     fn read_ipfix_option_dataset_without_template() {
-        let mut exporter_list: ExporterList = HashMap::new();
+        let mut exporter_list = ExporterList::new();
         let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
-        let data_list = parse_ipfix_msg(from, &OPTION_DATA_SET_IPFIX_MSG, &mut exporter_list).unwrap();
+        let data_list = parse_ipfix_msg(from, &OPTION_DATA_SET_IPFIX_MSG, &mut exporter_list, 0, &NO_LIMIT).unwrap();
 
         // no change expected
         assert_eq!(exporter_list.len(), 0);
         assert_eq!(data_list.len(), 0);
     }
+
+    #[test]
+    fn parse_listener_spec() {
+        let spec: ListenerSpec = "0.0.0.0:2055?versions=5&tag=customer-a".parse().unwrap();
+        assert_eq!(spec.addr, "0.0.0.0:2055".parse().unwrap());
+        assert_eq!(spec.transport, Transport::Udp);
+        assert_eq!(spec.versions, vec![5]);
+        assert_eq!(spec.name(), "customer-a");
+
+        let spec: ListenerSpec = "tcp://[::1]:4739".parse().unwrap();
+        assert_eq!(spec.transport, Transport::Tcp);
+        assert_eq!(spec.versions, vec![10]);
+        assert_eq!(spec.tag, None);
+        assert_eq!(spec.name(), "[::1]:4739");
+
+        let spec: ListenerSpec = "udp://127.0.0.1:4739".parse().unwrap();
+        assert_eq!(spec.versions, vec![5, 10]);
+    }
+
+    #[test]
+    fn parse_invalid_listener_spec() {
+        assert!("sctp://127.0.0.1:4739".parse::<ListenerSpec>().is_err());
+        assert!("127.0.0.1".parse::<ListenerSpec>().is_err());
+        assert!("127.0.0.1:4739?versions=9".parse::<ListenerSpec>().is_err());
+        assert!("127.0.0.1:4739?port=1".parse::<ListenerSpec>().is_err());
+        assert!("tcp://127.0.0.1:4739?versions=5,10".parse::<ListenerSpec>().is_err());
+    }
+
+    #[test]
+    fn read_ipfix_template_with_empty_records() {
+        // template 256 with a zero-length octetDeltaCount, then a data set of 4 bytes
+        let msg = hex!(
+            "00 0a 00 24 60 6c 55 89 00 00 00 01 00 00 00 08
+             00 02 00 0c 01 00 00 01 00 01 00 00
+             01 00 00 08 00 00 00 00"
+        );
+        let mut exporter_list = ExporterList::new();
+        let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        assert!(parse_ipfix_msg(from, &msg, &mut exporter_list, 0, &NO_LIMIT).is_err());
+        assert!(exporter_list.values().all(|infos| infos.template.is_empty()));
+    }
+
+    #[test]
+    fn read_ipfix_template_past_its_set() {
+        // template set of 8 bytes, the field of its template is in the next 4 bytes of the message
+        let msg = hex!(
+            "00 0a 00 1c 60 6c 55 89 00 00 00 01 00 00 00 00
+             00 02 00 08 01 00 00 01 00 01 00 08"
+        );
+        let mut exporter_list = ExporterList::new();
+        let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        assert!(parse_ipfix_msg(from, &msg, &mut exporter_list, 0, &NO_LIMIT).is_err());
+        assert!(exporter_list.is_empty());
+    }
+
+    #[test]
+    fn read_ipfix_dataset_sampled_once() {
+        // option template 512 with the sampling interval, template 256 with octetDeltaCount and the sampling interval
+        let templates = hex!(
+            "00 0a 00 32 60 6c 55 89 00 00 00 01 00 00 00 00
+             00 03 00 12 02 00 00 02 00 01 00 95 00 04 00 22 00 04
+             00 02 00 10 01 00 00 02 00 01 00 08 00 22 00 04"
+        );
+        // both the options of the exporter and the record sample 1 packet out of 100
+        let data = hex!(
+            "00 0a 00 2c 60 6c 55 8a 00 00 00 02 00 00 00 00
+             02 00 00 0c 00 00 00 00 00 00 00 64
+             01 00 00 10 00 00 00 00 00 00 00 2a 00 00 00 64"
+        );
+        let mut exporter_list = ExporterList::new();
+        let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        parse_ipfix_msg(from, &templates, &mut exporter_list, 0, &NO_LIMIT).unwrap();
+        let data_list = parse_ipfix_msg(from, &data, &mut exporter_list, 0, &NO_LIMIT).unwrap();
+
+        assert_eq!(data_list.len(), 1);
+        let fields = data_list[0].fields();
+        assert!(fields.contains(&(FieldType::OctetDeltaCount, FieldValue::U64(4200))), "{:?}", fields);
+    }
+
+    #[test]
+    fn read_ipfix_dataset_with_expired_template() {
+        let mut exporter_list = ExporterList::new();
+        let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        let templates = TemplatesConfig { timeout: 60, max_per_exporter: 0 };
+
+        parse_ipfix_msg(from, &TEMPLATE_IPFIX_MSG, &mut exporter_list, 1000, &templates).unwrap();
+
+        // still valid
+        let data_list = parse_ipfix_msg(from, &DATA_SET_IPFIX_MSG, &mut exporter_list, 1060, &templates).unwrap();
+        assert_eq!(data_list.len(), 2);
+
+        // expired, the template is removed
+        let data_list = parse_ipfix_msg(from, &DATA_SET_IPFIX_MSG, &mut exporter_list, 1061, &templates).unwrap();
+        assert_eq!(data_list.len(), 0);
+        assert_eq!(exporter_list[&Exporter { addr: from, domain_id: 524288 }].template.len(), 0);
+    }
+
+    #[test]
+    fn limit_templates_per_exporter() {
+        let mut exporter_list = ExporterList::new();
+        let from = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let templates = TemplatesConfig { timeout: 0, max_per_exporter: 1 };
+
+        parse_ipfix_msg(from, &TEMPLATE_IPFIX_MSG, &mut exporter_list, 0, &templates).unwrap();
+        parse_ipfix_msg(from, &OPTION_TEMPLATE_IPFIX_MSG, &mut exporter_list, 0, &templates).unwrap();
+        // a known template can still be refreshed
+        parse_ipfix_msg(from, &TEMPLATE_IPFIX_MSG, &mut exporter_list, 10, &templates).unwrap();
+
+        let infos = &exporter_list[&Exporter { addr: from, domain_id: 524288 }];
+        assert_eq!(infos.template.len(), 1);
+        assert_eq!(infos.template[&256].received, 10);
+    }
+
+    #[test]
+    fn deny_exporters() {
+        let config = "[[listener]]\naddress = \"127.0.0.1:0\"\n[exporters]\ndeny = [\"192.0.2.0/24\"]\nmax = 1";
+        let config = SharedConfig::new(crate::config::Config::parse(config).unwrap());
+        let (sender, _receiver) = channel::bounded(16, channel::OverflowPolicy::DropNewest);
+        let mut worker = Worker::new("127.0.0.1:0".parse().unwrap(), sender, config.clone());
+
+        worker.handle_msg(&TEMPLATE_IPFIX_MSG, "192.0.2.1:1234".parse().unwrap());
+        assert!(worker.exporter_list.is_empty());
+
+        worker.handle_msg(&TEMPLATE_IPFIX_MSG, "127.0.0.1:1234".parse().unwrap());
+        worker.handle_msg(&TEMPLATE_IPFIX_MSG, "127.0.0.2:1234".parse().unwrap());
+        assert_eq!(worker.exporter_list.len(), 1);
+
+        let mut new = (*config.current()).clone();
+        new.exporters.domain_ids = vec![1];
+        config.reload(new);
+        worker.exporter_list.clear();
+        worker.handle_msg(&TEMPLATE_IPFIX_MSG, "127.0.0.1:1234".parse().unwrap());
+        assert!(worker.exporter_list.is_empty());
+
+        let metrics = metrics::render();
+        assert!(metrics.contains("ipfix_denied_messages_total{listener=\"127.0.0.1:0\",reason=\"acl\"} 1"), "{}", metrics);
+        assert!(metrics.contains("ipfix_denied_messages_total{listener=\"127.0.0.1:0\",reason=\"max_exporters\"} 1"), "{}", metrics);
+        assert!(metrics.contains("ipfix_denied_sources_total{listener=\"127.0.0.1:0\",source=\"127.0.0.1\"} 1"), "{}", metrics);
+        assert!(metrics.contains("ipfix_denied_sources_total{listener=\"127.0.0.1:0\",source=\"192.0.2.1\"} 1"), "{}", metrics);
+    }
+
+    #[test]
+    fn count_denied_sources_within_limit() {
+        let config = "[[listener]]\naddress = \"127.0.0.1:0\"\n[exporters]\ndeny = [\"198.51.100.0/24\"]";
+        let config = SharedConfig::new(crate::config::Config::parse(config).unwrap());
+        let (sender, _receiver) = channel::bounded(16, channel::OverflowPolicy::DropNewest);
+        let mut worker = Worker::new("127.0.0.2:0".parse().unwrap(), sender, config);
+
+        for i in 0..MAX_DENIED_SOURCES + 4 {
+            worker.handle_msg(&TEMPLATE_IPFIX_MSG, SocketAddr::from(([198, 51, 100, i as u8], 1234)));
+        }
+        worker.handle_msg(&TEMPLATE_IPFIX_MSG, "198.51.100.0:1234".parse().unwrap());
+
+        let metrics = metrics::render();
+        let series = metrics.lines().filter(|l| l.starts_with("ipfix_denied_sources_total{listener=\"127.0.0.2:0\"")).count();
+        assert_eq!(series, MAX_DENIED_SOURCES + 1, "{}", metrics);
+        assert!(metrics.contains("ipfix_denied_sources_total{listener=\"127.0.0.2:0\",source=\"198.51.100.0\"} 2"), "{}", metrics);
+        assert!(metrics.contains("ipfix_denied_sources_total{listener=\"127.0.0.2:0\",source=\"other\"} 4"), "{}", metrics);
+    }
+
+    #[test]
+    fn identify_exporters() {
+        let config = "[[listener]]\naddress = \"127.0.0.1:0\"\n[exporters]\nuse_reported_address = true\n[[exporters.mapping]]\nsource = \"178.132.16.32\"\nname = \"edge-1\"";
+        let config = SharedConfig::new(crate::config::Config::parse(config).unwrap());
+        let (sender, receiver) = channel::bounded(16, channel::OverflowPolicy::DropNewest);
+        let mut worker = Worker::new("127.0.0.1:0".parse().unwrap(), sender, config);
+        let from = "127.0.0.1:1234".parse().unwrap();
+
+        worker.handle_msg(&TEMPLATE_IPFIX_MSG, from);
+        worker.handle_msg(&DATA_SET_IPFIX_MSG, from);
+        let batch = receiver.recv().unwrap();
+        assert_eq!(batch.exporter, from.ip());
+        assert_eq!(batch.exporter_name, None);
+
+        // the option data carries the address of the exporter
+        worker.handle_msg(&OPTION_TEMPLATE_IPFIX_MSG, from);
+        worker.handle_msg(&OPTION_DATA_SET_IPFIX_MSG, from);
+        worker.handle_msg(&DATA_SET_IPFIX_MSG, from);
+        let batch = receiver.recv().unwrap();
+        assert_eq!(batch.exporter, IpAddr::V4(Ipv4Addr::new(178, 132, 16, 32)));
+        assert_eq!(batch.exporter_name.as_deref(), Some("edge-1"));
+    }
+
+    #[test]
+    fn stop_listeners_on_shutdown() {
+        let config = SharedConfig::new(crate::config::Config::parse("[[listener]]\naddress = \"127.0.0.1:0\"").unwrap());
+        let shutdown = Shutdown::new();
+
+        for spec in ["udp://127.0.0.1:0", "tcp://127.0.0.1:0"] {
+            let opts = ListenerOptions {
+                spec: spec.parse().unwrap(),
+                recv_buffer_size: None,
+                batch_size: 1,
+                reuse_port: false,
+                forward: vec![],
+                capture: None,
+                clock: Clock::default(),
+            };
+            let (sender, receiver) = channel::bounded(1, channel::OverflowPolicy::Block);
+            let (config, listener_shutdown) = (config.clone(), shutdown.clone());
+            let t = thread::spawn(move || listen(opts, sender, config, listener_shutdown, None));
+
+            shutdown.trigger();
+            assert_eq!(t.join().unwrap(), Ok(()));
+            // every sender is gone, the exporter thread would stop
+            assert!(receiver.recv().is_none());
+        }
+    }
+
+    /******************************** PROPERTY TESTS ********************************/
+
+    /// Template with distinct field ids and a few records. The timestamps are left out, the parser adds their normalized form.
+    fn template_and_records() -> impl Strategy<Value = (Vec<TemplateField>, Vec<DataSet>)> {
+        let field = (
+            (1u16..500)
+                .prop_filter_map("unassigned field id", FieldType::from_u16)
+                .prop_filter("normalized timestamp", |id| !id.is_flow_timestamp()),
+            prop_oneof![Just(1u16), Just(2), Just(4), Just(8), Just(16), 3u16..32, Just(VARIABLE_LENGTH)],
+        )
+            .prop_map(|(id, length)| TemplateField { id, length });
+
+        let fields = prop::collection::vec(field, 1..16).prop_map(|mut fields| {
+            let mut ids = HashSet::new();
+            fields.retain(|field| ids.insert(field.id));
+            fields
+        });
+
+        fields.prop_flat_map(|fields| {
+            let record = fields
+                .iter()
+                .map(|field| {
+                    let id = field.id;
+                    let value = match field.length {
+                        1 => any::<u8>().prop_map(FieldValue::U8).boxed(),
+                        2 => any::<u16>().prop_map(FieldValue::U16).boxed(),
+                        4 => any::<u32>().prop_map(FieldValue::U32).boxed(),
+                        8 => any::<u64>().prop_map(FieldValue::U64).boxed(),
+                        16 => any::<u128>().prop_map(FieldValue::U128).boxed(),
+                        VARIABLE_LENGTH => prop::collection::vec(any::<u8>(), 0..64).prop_map(FieldValue::Dyn).boxed(),
+                        length => prop::collection::vec(any::<u8>(), length as usize).prop_map(FieldValue::Dyn).boxed(),
+                    };
+                    value.prop_map(move |value| (id, value))
+                })
+                .collect::<Vec<_>>()
+                .prop_map(|values| DataSet { fields: values.into_iter().collect() });
+
+            (Just(fields), prop::collection::vec(record, 1..5))
+        })
+    }
+
+    /// Message with one template set, then one data set per template, each set followed by some padding
+    fn ipfix_msg(templates: &[(Vec<TemplateField>, Vec<DataSet>)], padding: usize) -> Vec<u8> {
+        let mut buf = vec![];
+        Header {
+            version: flow::ipfix::VERSION,
+            length: 0,
+            export_time: 1617712521,
+            seq_number: 0,
+            domain_id: 1,
+        }
+        .write(&mut buf);
+
+        let mut sets = vec![];
+        let mut template_set = vec![];
+        for (i, (fields, records)) in templates.iter().enumerate() {
+            let template = DataSetTemplate::new(256 + i as u16, fields.clone());
+            template.write(&mut template_set);
+
+            let mut data_set = vec![];
+            for record in records {
+                record.write(fields, &mut data_set).unwrap();
+            }
+            // the padding is shorter than the smallest record
+            data_set.resize(data_set.len() + padding % DecodePlan::compile(fields).min_length, 0);
+            sets.push((256 + i as u16, data_set));
+        }
+        template_set.resize(template_set.len() + padding % 4, 0);
+        sets.insert(0, (DataSetTemplate::SET_ID, template_set));
+
+        for (id, content) in sets {
+            SetHeader {
+                id,
+                length: (SetHeader::SIZE + content.len()) as u16,
+            }
+            .write(&mut buf);
+            buf.extend_from_slice(&content);
+        }
+
+        let length = buf.len() as u16;
+        buf[2..4].copy_from_slice(&length.to_be_bytes());
+        buf
+    }
+
+    proptest! {
+        #[test]
+        fn parse_generated_ipfix_msg(templates in prop::collection::vec(template_and_records(), 1..4), padding in 0usize..16) {
+            let msg = ipfix_msg(&templates, padding);
+            let mut exporter_list = ExporterList::new();
+            let flows = parse_ipfix_msg("192.0.2.1".parse().unwrap(), &msg, &mut exporter_list, 0, &NO_LIMIT).unwrap();
+
+            // the counters of the records with a sampling interval are corrected
+            let expected: Vec<_> = templates
+                .iter()
+                .flat_map(|(_, records)| {
+                    records.iter().map(|record| {
+                        let mut sampled = DataSet { fields: record.fields.clone() };
+                        sampled.add_sampling(record.sampling().unwrap_or(0));
+                        sampled.fields()
+                    })
+                })
+                .collect();
+            prop_assert_eq!(flows.iter().map(|flow| flow.fields()).collect::<Vec<_>>(), expected);
+            prop_assert_eq!(exporter_list.values().next().unwrap().template.len(), templates.len());
+        }
+    }
 }
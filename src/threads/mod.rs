@@ -1,3 +1,11 @@
+pub mod capture;
+pub mod channel;
+pub mod clock;
 pub mod exporter;
+pub mod forwarder;
 pub mod listener;
 pub mod prometheus;
+pub mod receiver;
+pub mod replay;
+pub mod shutdown;
+pub mod state;
@@ -2,35 +2,51 @@ use log::{error, info};
 use std::io::prelude::*;
 use std::net::{SocketAddr, TcpListener, TcpStream};
 
-pub fn listen(addr: SocketAddr) {
-    let listener = TcpListener::bind(&addr).unwrap();
+use super::shutdown::{self, Shutdown};
+use crate::metrics;
+
+pub fn listen(addr: SocketAddr, shutdown: Shutdown) -> Result<(), String> {
+    serve(bind(addr)?, shutdown)
+}
+
+/// Bind the socket of the exporter, the system chooses the port of an address ending with :0
+pub fn bind(addr: SocketAddr) -> Result<TcpListener, String> {
+    let listener = TcpListener::bind(addr).map_err(|e| format!("Failed to bind the prometheus exporter to {} : {}", &addr, e))?;
+    listener.set_nonblocking(true).map_err(|e| format!("Failed to set the TCP socket {} non blocking : {}", &addr, e))?;
+    Ok(listener)
+}
+
+/// Answer the metrics requests until the shutdown is triggered
+pub fn serve(listener: TcpListener, shutdown: Shutdown) -> Result<(), String> {
+    let addr = listener.local_addr().map_err(|e| format!("Failed to read the address of the prometheus exporter : {}", e))?;
     info!("Listening for TCP connection on {}", &addr);
 
-    for stream in listener.incoming() {
+    while let Some(stream) = shutdown::accept(&listener, &shutdown) {
         match stream {
-            Ok(stream) => handle_connection(stream),
+            Ok((stream, _)) => {
+                if let Err(e) = handle_connection(stream) {
+                    error!("Failed to answer the metrics request : {}", e);
+                }
+            }
             Err(e) => error!("Connection failed : {}", e),
         }
     }
+
+    Ok(())
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let contents = "
-    <!DOCTYPE html>
-<html lang=\"en\">
-  <head>
-    <meta charset=\"utf-8\">
-    <title>Hello!</title>
-  </head>
-  <body>
-    <h1>Hello!</h1>
-    <p>Hi from Rust</p>
-  </body>
-</html>
-    ";
-
-    let response = format!("{}{}", "HTTP/1.1 200 OK\r\n\r\n", contents);
-
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+fn handle_connection(mut stream: TcpStream) -> std::io::Result<()> {
+    // the request itself is not used, every path returns the metrics
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request)?;
+
+    let contents = metrics::render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        contents.len(),
+        contents
+    );
+
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
 }
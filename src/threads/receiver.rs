@@ -0,0 +1,254 @@
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+
+/// Biggest UDP payload we can receive, bigger than any IPv4/IPv6 datagram without jumbogram
+pub const BUF_SIZE: usize = 65535;
+
+pub struct Datagram<'a> {
+    pub data: &'a [u8],
+    pub from: SocketAddr,
+    /// The datagram didn't fit in the buffer and has been cut
+    pub truncated: bool,
+}
+
+/// Create the UDP socket, resizing its kernel receive buffer if requested.
+/// With reuse_port, several sockets can be bound to the same address and the kernel spreads the sources between them.
+pub fn bind(addr: SocketAddr, recv_buffer_size: Option<usize>, reuse_port: bool) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT is not available on this platform"));
+    }
+
+    if let Some(size) = recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+
+        // the kernel silently caps the value to net.core.rmem_max (and linux doubles it for its own bookkeeping)
+        let effective_size = socket.recv_buffer_size()?;
+        if effective_size < size {
+            warn!(
+                "Receive buffer of the socket {} capped by the kernel to {} bytes instead of {}, increase net.core.rmem_max to allow it",
+                addr, effective_size, size
+            );
+        } else {
+            info!("Receive buffer of the socket {} set to {} bytes", addr, effective_size);
+        }
+    }
+
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/******************************** SINGLE RECEIVER ********************************/
+
+/// Read the datagrams one by one with recvfrom
+pub struct SingleReceiver {
+    socket: Socket,
+    buf: Vec<u8>,
+}
+
+impl SingleReceiver {
+    pub fn new(socket: Socket) -> Self {
+        SingleReceiver { socket, buf: vec![0; BUF_SIZE] }
+    }
+
+    pub fn recv<F: FnMut(Datagram)>(&mut self, mut handler: F) -> io::Result<usize> {
+        // SAFETY: the buffer is already initialized, the kernel only writes bytes into it
+        let uninit_buf = unsafe { &mut *(self.buf.as_mut_slice() as *mut [u8] as *mut [std::mem::MaybeUninit<u8>]) };
+
+        // with MSG_TRUNC, linux returns the real size of the datagram even if it didn't fit in the buffer
+        #[cfg(target_os = "linux")]
+        let (datagram_size, from) = self.socket.recv_from_with_flags(uninit_buf, libc::MSG_TRUNC)?;
+        #[cfg(not(target_os = "linux"))]
+        let (datagram_size, from) = self.socket.recv_from(uninit_buf)?;
+
+        let from = from.as_socket().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Datagram received from a non IP address"))?;
+        let rcv_bytes = datagram_size.min(self.buf.len());
+
+        handler(Datagram {
+            data: &self.buf[0..rcv_bytes],
+            from,
+            truncated: datagram_size > rcv_bytes,
+        });
+
+        Ok(1)
+    }
+}
+
+/******************************** BATCH RECEIVER ********************************/
+
+/// Read up to batch_size datagrams per syscall with recvmmsg
+#[cfg(target_os = "linux")]
+pub struct BatchReceiver {
+    socket: Socket,
+    bufs: Vec<Vec<u8>>,
+    addrs: Vec<libc::sockaddr_storage>,
+    iovecs: Vec<libc::iovec>,
+    msgs: Vec<libc::mmsghdr>,
+}
+
+#[cfg(target_os = "linux")]
+impl BatchReceiver {
+    pub fn new(socket: Socket, batch_size: usize) -> Self {
+        BatchReceiver {
+            socket,
+            bufs: vec![vec![0; BUF_SIZE]; batch_size],
+            // SAFETY: sockaddr_storage, iovec and mmsghdr are plain C structs for which zero is a valid value
+            addrs: vec![unsafe { std::mem::zeroed() }; batch_size],
+            iovecs: vec![unsafe { std::mem::zeroed() }; batch_size],
+            msgs: vec![unsafe { std::mem::zeroed() }; batch_size],
+        }
+    }
+
+    pub fn recv<F: FnMut(Datagram)>(&mut self, mut handler: F) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+
+        // the pointers are set before each call, the vectors are never resized so their content don't move
+        for i in 0..self.msgs.len() {
+            self.iovecs[i] = libc::iovec {
+                iov_base: self.bufs[i].as_mut_ptr() as *mut libc::c_void,
+                iov_len: self.bufs[i].len(),
+            };
+            self.msgs[i].msg_hdr.msg_name = &mut self.addrs[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            self.msgs[i].msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            self.msgs[i].msg_hdr.msg_iov = &mut self.iovecs[i];
+            self.msgs[i].msg_hdr.msg_iovlen = 1;
+            self.msgs[i].msg_hdr.msg_flags = 0;
+            self.msgs[i].msg_len = 0;
+        }
+
+        // MSG_WAITFORONE: block until the first datagram, then only take what is already queued
+        // SAFETY: every mmsghdr points to a buffer and an address that live as long as self
+        let nb_msg = unsafe {
+            libc::recvmmsg(
+                self.socket.as_raw_fd(),
+                self.msgs.as_mut_ptr(),
+                self.msgs.len() as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if nb_msg < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for i in 0..nb_msg as usize {
+            let msg = &self.msgs[i];
+            // SAFETY: the kernel filled the address and its length
+            let from = unsafe { socket2::SockAddr::new(self.addrs[i], msg.msg_hdr.msg_namelen) };
+            let from = match from.as_socket() {
+                Some(from) => from,
+                None => continue,
+            };
+
+            handler(Datagram {
+                data: &self.bufs[i][0..msg.msg_len as usize],
+                from,
+                truncated: msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0,
+            });
+        }
+
+        Ok(nb_msg as usize)
+    }
+}
+
+/******************************** RECEIVER ********************************/
+
+pub enum Receiver {
+    Single(SingleReceiver),
+    #[cfg(target_os = "linux")]
+    Batch(BatchReceiver),
+}
+
+impl Receiver {
+    /// Use recvmmsg when more than one datagram can be read per call and the platform supports it
+    pub fn new(socket: Socket, batch_size: usize) -> Self {
+        #[cfg(target_os = "linux")]
+        if batch_size > 1 {
+            return Receiver::Batch(BatchReceiver::new(socket, batch_size));
+        }
+
+        if batch_size > 1 {
+            warn!("Batched reception is only available on linux, reading the datagrams one by one");
+        }
+
+        Receiver::Single(SingleReceiver::new(socket))
+    }
+
+    /// Wait for datagrams and call the handler for each of them, returning the number of datagrams received
+    pub fn recv<F: FnMut(Datagram)>(&mut self, handler: F) -> io::Result<usize> {
+        match self {
+            Receiver::Single(r) => r.recv(handler),
+            #[cfg(target_os = "linux")]
+            Receiver::Batch(r) => r.recv(handler),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    fn receive_all(receiver: &mut Receiver, expected: usize) -> Vec<(Vec<u8>, bool)> {
+        let mut received = vec![];
+
+        while received.len() < expected {
+            receiver.recv(|d| received.push((d.data.to_vec(), d.truncated))).unwrap();
+        }
+
+        received
+    }
+
+    #[test]
+    fn receive_datagrams_one_by_one() {
+        let socket = bind("127.0.0.1:0".parse().unwrap(), None, false).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+        let mut receiver = Receiver::new(socket, 1);
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(&[1, 2, 3], addr).unwrap();
+        sender.send_to(&vec![7; 9000], addr).unwrap();
+
+        let received = receive_all(&mut receiver, 2);
+
+        assert_eq!(received[0], (vec![1, 2, 3], false));
+        assert_eq!(received[1], (vec![7; 9000], false));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn receive_datagrams_by_batch() {
+        let socket = bind("127.0.0.1:0".parse().unwrap(), None, false).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+        let mut receiver = Receiver::new(socket, 8);
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for i in 0..20u8 {
+            sender.send_to(&[i; 10], addr).unwrap();
+        }
+
+        let received = receive_all(&mut receiver, 20);
+
+        for (i, (data, truncated)) in received.iter().enumerate() {
+            assert_eq!(data, &vec![i as u8; 10]);
+            assert!(!truncated);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn bind_several_sockets_with_reuse_port() {
+        let first = bind("127.0.0.1:0".parse().unwrap(), None, true).unwrap();
+        let addr = first.local_addr().unwrap().as_socket().unwrap();
+
+        assert!(bind(addr, None, true).is_ok());
+        assert!(bind(addr, None, false).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use log::{info, trace};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::channel;
+use super::clock::Clock;
+use super::listener::{ListenerSpec, Transport, Worker};
+use super::receiver::Datagram;
+use super::shutdown::{Shutdown, POLL_INTERVAL};
+use crate::config::SharedConfig;
+use crate::flow::FlowBatch;
+use crate::pcap::{self, Capture};
+
+/// Tag of the flows read from a capture file, and name of its metrics
+pub const REPLAY_TAG: &str = "pcap";
+
+/// Settings of the replay of a capture file
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// pcap or pcapng file
+    pub path: PathBuf,
+    /// UDP destination ports of the NetFlow/IPFIX traffic, the other packets are ignored
+    pub ports: Vec<u16>,
+    /// Wait between the datagrams as long as in the capture, instead of reading it as fast as possible
+    pub timing: bool,
+}
+
+/// Decode the NetFlow/IPFIX datagrams of a capture file as if a UDP listener had received them, until its end or the shutdown
+pub fn replay(opts: ReplayOptions, sender: channel::Sender<FlowBatch>, config: Arc<SharedConfig>, shutdown: Shutdown) -> Result<(), String> {
+    let file = File::open(&opts.path).map_err(|e| format!("Failed to open the capture file {} : {}", opts.path.display(), e))?;
+    info!("Replaying {} (ports {:?})", opts.path.display(), opts.ports);
+
+    let capture = Capture::new(BufReader::new(file)).map_err(|e| format!("{} : {}", opts.path.display(), e))?;
+    replay_capture(capture, &opts, sender, config, shutdown).map_err(|e| format!("{} : {}", opts.path.display(), e))
+}
+
+fn replay_capture<R: Read>(mut capture: Capture<R>, opts: &ReplayOptions, sender: channel::Sender<FlowBatch>, config: Arc<SharedConfig>, shutdown: Shutdown) -> Result<(), String> {
+    let spec = ListenerSpec::new(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), Transport::Udp, None, Some(REPLAY_TAG))?;
+    let mut worker = Worker::new(spec, sender, config);
+    // the templates expire with the time of the capture, not with the time of the replay
+    let clock = Clock::manual(0);
+    worker.clock = clock.clone();
+
+    // capture time of the first datagram and when it was replayed
+    let mut start: Option<(Duration, Instant)> = None;
+    let (mut packets, mut datagrams) = (0u64, 0u64);
+
+    while !shutdown.is_triggered() {
+        let packet = match capture.next_packet()? {
+            Some(packet) => packet,
+            None => break,
+        };
+        packets += 1;
+
+        let datagram = match pcap::decode_udp(packet.link_type, &packet.data) {
+            Some(datagram) if opts.ports.contains(&datagram.to.port()) => datagram,
+            _ => {
+                trace!("Packet {} ignored, not a UDP datagram on the replayed ports", packets);
+                continue;
+            }
+        };
+        datagrams += 1;
+
+        if opts.timing {
+            let (first, started) = *start.get_or_insert((packet.timestamp, Instant::now()));
+            wait_until(started + packet.timestamp.saturating_sub(first), &shutdown);
+        }
+
+        clock.set(packet.timestamp.as_secs());
+        worker.handle_datagram(Datagram {
+            data: datagram.payload,
+            from: datagram.from,
+            truncated: datagram.truncated,
+        });
+    }
+
+    info!("Replayed {} datagrams out of {} packets", datagrams, packets);
+    Ok(())
+}
+
+/// Sleep until the deadline, waking up to check the shutdown
+fn wait_until(deadline: Instant, shutdown: &Shutdown) {
+    loop {
+        let now = Instant::now();
+        if now >= deadline || shutdown.is_triggered() {
+            return;
+        }
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use hex_literal::hex;
+    use std::net::IpAddr;
+
+    /// Ethernet packet from 192.0.2.1:54321 to 198.51.100.7:2055, carrying a NetFlow v5 message with count zeroed records
+    fn netflow5_packet(timestamp: u32, port: u16, count: u8) -> Vec<u8> {
+        let records = vec![0; count as usize * 48];
+        let udp_length = 8 + 24 + records.len();
+        let ip_length = 20 + udp_length;
+
+        let mut packet = vec![];
+        packet.extend_from_slice(&timestamp.to_le_bytes());
+        packet.extend_from_slice(&[0; 4]);
+        packet.extend_from_slice(&(14 + ip_length as u32).to_le_bytes());
+        packet.extend_from_slice(&(14 + ip_length as u32).to_le_bytes());
+        packet.extend_from_slice(&hex!("00 11 22 33 44 55 66 77 88 99 aa bb 08 00"));
+        packet.extend_from_slice(&hex!("45 00"));
+        packet.extend_from_slice(&(ip_length as u16).to_be_bytes());
+        packet.extend_from_slice(&hex!("00 00 40 00 40 11 00 00 c0 00 02 01 c6 33 64 07 d4 31"));
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet.extend_from_slice(&(udp_length as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&[0, 5, 0, count, 0, 0, 0, 0]);
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&[0; 12]);
+        packet.extend_from_slice(&records);
+        packet
+    }
+
+    #[test]
+    fn replay_capture_file() {
+        let mut file = hex!(
+            "d4 c3 b2 a1 02 00 04 00 00 00 00 00 00 00 00 00
+             ff ff 00 00 01 00 00 00"
+        )
+        .to_vec();
+        file.extend(netflow5_packet(1_619_048_604, 2055, 2));
+        // another port
+        file.extend(netflow5_packet(1_619_048_605, 53, 1));
+        file.extend(netflow5_packet(1_619_048_606, 2055, 1));
+
+        let config = SharedConfig::new(Config::parse("[[listener]]\naddress = \"127.0.0.1:0\"").unwrap());
+        let (sender, receiver) = channel::bounded(16, channel::OverflowPolicy::Block);
+        let opts = ReplayOptions {
+            path: PathBuf::from("test.pcap"),
+            ports: vec![2055],
+            timing: false,
+        };
+        replay_capture(Capture::new(&file[..]).unwrap(), &opts, sender, config, Shutdown::new()).unwrap();
+
+        let mut flows = vec![];
+        while let Some(batch) = receiver.recv() {
+            // the exporter is the source of the captured datagram
+            assert_eq!(batch.exporter, IpAddr::from([192, 0, 2, 1]));
+            assert_eq!(batch.tag.as_deref(), Some(REPLAY_TAG));
+            flows.push(batch.flows.len());
+        }
+        assert_eq!(flows, vec![2, 1]);
+    }
+}
@@ -0,0 +1,82 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the blocking loops wake up to check if the collector is stopping
+pub const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Stop flag shared by the threads, raised on SIGINT/SIGTERM
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Guard triggering the shutdown if the thread holding it panics
+    pub fn on_panic(&self) -> PanicGuard {
+        PanicGuard(self.clone())
+    }
+}
+
+pub struct PanicGuard(Shutdown);
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.trigger();
+        }
+    }
+}
+
+/// The read timed out without data, the error returned by a socket with SO_RCVTIMEO
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Wait for the next connection on a non blocking listener, None once the shutdown is triggered
+pub fn accept(listener: &TcpListener, shutdown: &Shutdown) -> Option<io::Result<(TcpStream, SocketAddr)>> {
+    while !shutdown.is_triggered() {
+        match listener.accept() {
+            // the accepted socket may inherit the non blocking mode on some platforms
+            Ok((stream, addr)) => return Some(stream.set_nonblocking(false).map(|_| (stream, addr))),
+            Err(e) if is_timeout(&e) => thread::sleep(POLL_INTERVAL),
+            Err(e) => return Some(Err(e)),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_until_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let shutdown = Shutdown::new();
+
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(accept(&listener, &shutdown).unwrap().is_ok());
+
+        let trigger = shutdown.clone();
+        let t = thread::spawn(move || trigger.trigger());
+        assert!(accept(&listener, &shutdown).is_none());
+        t.join().unwrap();
+    }
+}
@@ -0,0 +1,428 @@
+use core::convert::TryInto;
+use log::{info, warn};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::flow::Template;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub struct Exporter {
+    pub addr: IpAddr,   // ip source of the exporter
+    pub domain_id: u32, // observation domain id unique to the exporter
+}
+
+pub struct ExporterInfos {
+    pub sampling: u32,
+    pub system_init_time: Option<u64>, // boot time of the exporter in ms since the UNIX epoch
+    pub reported_addr: Option<IpAddr>, // address sent by the exporter in its option data
+    pub template: HashMap<u16, TemplateEntry>,
+}
+
+pub struct TemplateEntry {
+    pub template: Template,
+    pub received: u64, // reception time of the template in seconds since the UNIX epoch
+}
+
+impl Default for ExporterInfos {
+    fn default() -> ExporterInfos {
+        ExporterInfos {
+            sampling: 1,
+            system_init_time: None,
+            reported_addr: None,
+            template: HashMap::new(),
+        }
+    }
+}
+
+pub type ExporterList = HashMap<Exporter, ExporterInfos>;
+
+/******************************** STATE FILE ********************************/
+
+// The state file is a header followed by one section per listener, every integer is big endian:
+//   listener:  name length (u16), name, length of the records (u32), then one record per exporter
+//   exporter:  address, domain id (u32), sampling (u32), system init time flag (u8) and value (u64),
+//              reported address, number of templates (u16)
+//   address:   family (u8, 4 or 6, 0 when there is no address) followed by 4 or 16 bytes
+//   template:  reception time (u64), set id (u16), length (u16) and the template record as received
+
+const MAGIC: &[u8; 4] = b"IPFX";
+const FORMAT_VERSION: u16 = 3;
+const HEADER_SIZE: usize = 6;
+
+/// Append the records of every exporter of the list
+pub fn encode(exporter_list: &ExporterList, buf: &mut Vec<u8>) {
+    for (exporter, infos) in exporter_list {
+        encode_addr(Some(exporter.addr), buf);
+        buf.extend_from_slice(&exporter.domain_id.to_be_bytes());
+        buf.extend_from_slice(&infos.sampling.to_be_bytes());
+        buf.push(infos.system_init_time.is_some() as u8);
+        buf.extend_from_slice(&infos.system_init_time.unwrap_or(0).to_be_bytes());
+        encode_addr(infos.reported_addr, buf);
+        buf.extend_from_slice(&(infos.template.len() as u16).to_be_bytes());
+
+        for entry in infos.template.values() {
+            let mut record = vec![];
+            entry.template.write(&mut record);
+
+            buf.extend_from_slice(&entry.received.to_be_bytes());
+            buf.extend_from_slice(&entry.template.set_id().to_be_bytes());
+            buf.extend_from_slice(&(record.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&record);
+        }
+    }
+}
+
+/// Read the exporter records into the list. When a template is already known, the most recently received one is kept.
+pub fn decode(mut buf: &[u8], exporter_list: &mut ExporterList) -> Result<(), String> {
+    while !buf.is_empty() {
+        let addr = decode_addr(&mut buf)?.ok_or_else(|| "Exporter without address in the state".to_string())?;
+        let domain_id = u32::from_be_bytes(take(&mut buf, 4)?.try_into().unwrap());
+        let sampling = u32::from_be_bytes(take(&mut buf, 4)?.try_into().unwrap());
+        let has_system_init_time = take(&mut buf, 1)?[0] != 0;
+        let system_init_time = u64::from_be_bytes(take(&mut buf, 8)?.try_into().unwrap());
+        let reported_addr = decode_addr(&mut buf)?;
+        let template_count = u16::from_be_bytes(take(&mut buf, 2)?.try_into().unwrap());
+
+        let infos = exporter_list.entry(Exporter { addr, domain_id }).or_default();
+        infos.sampling = sampling;
+        if has_system_init_time {
+            infos.system_init_time = Some(system_init_time);
+        }
+        if reported_addr.is_some() {
+            infos.reported_addr = reported_addr;
+        }
+
+        for _ in 0..template_count {
+            let received = u64::from_be_bytes(take(&mut buf, 8)?.try_into().unwrap());
+            let set_id = u16::from_be_bytes(take(&mut buf, 2)?.try_into().unwrap());
+            let length = u16::from_be_bytes(take(&mut buf, 2)?.try_into().unwrap()) as usize;
+            let (template, size_read) = Template::read(set_id, take(&mut buf, length)?)?;
+            if size_read != length {
+                return Err(format!("Template of {} bytes in the state but {} bytes read", length, size_read));
+            }
+
+            let id = template.id();
+            if infos.template.get(&id).is_none_or(|entry| entry.received <= received) {
+                infos.template.insert(id, TemplateEntry { template, received });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_addr(addr: Option<IpAddr>, buf: &mut Vec<u8>) {
+    match addr {
+        Some(IpAddr::V4(addr)) => {
+            buf.push(4);
+            buf.extend_from_slice(&addr.octets());
+        }
+        Some(IpAddr::V6(addr)) => {
+            buf.push(6);
+            buf.extend_from_slice(&addr.octets());
+        }
+        None => buf.push(0),
+    }
+}
+
+fn decode_addr(buf: &mut &[u8]) -> Result<Option<IpAddr>, String> {
+    Ok(match take(buf, 1)?[0] {
+        0 => None,
+        4 => Some(IpAddr::V4(Ipv4Addr::from(u32::from_be_bytes(take(buf, 4)?.try_into().unwrap())))),
+        6 => Some(IpAddr::V6(Ipv6Addr::from(u128::from_be_bytes(take(buf, 16)?.try_into().unwrap())))),
+        family => return Err(format!("Invalid address family {} in the state", family)),
+    })
+}
+
+fn take<'a>(buf: &mut &'a [u8], size: usize) -> Result<&'a [u8], String> {
+    if buf.len() < size {
+        return Err(format!("Truncated state, required {} bytes but {} left", size, buf.len()));
+    }
+
+    let (head, tail) = buf.split_at(size);
+    *buf = tail;
+    Ok(head)
+}
+
+/******************************** STATE STORE ********************************/
+
+/// Template state of the listener workers, restored at startup and written to the state file periodically and on shutdown.
+/// Each worker publishes a snapshot of its own exporter list, the snapshots of the workers of a listener are concatenated in its section.
+pub struct StateStore {
+    path: PathBuf,
+    /// Seconds between two snapshots of a worker, 0 to only write the state on shutdown
+    pub interval: u64,
+    /// Exporter records by listener name
+    restored: HashMap<String, Vec<u8>>,
+    /// Listener name and exporter records by worker id
+    snapshots: Mutex<HashMap<usize, (String, Vec<u8>)>>,
+    next_worker_id: AtomicUsize,
+}
+
+impl StateStore {
+    /// Read the state file, a missing or invalid file only means there is nothing to restore
+    pub fn load(path: &Path, interval: u64) -> Self {
+        let restored = match fs::read(path) {
+            Ok(content) => match check(&content) {
+                Ok((sections, exporters)) => {
+                    info!("{} exporters of {} listeners restored from the state file {}", exporters, sections.len(), path.display());
+                    sections
+                }
+                Err(e) => {
+                    warn!("Ignoring the state file {} : {}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No state file {} to restore", path.display());
+                HashMap::new()
+            }
+            Err(e) => {
+                warn!("Failed to read the state file {} : {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+
+        StateStore {
+            path: path.to_path_buf(),
+            interval,
+            restored,
+            snapshots: Mutex::new(HashMap::new()),
+            next_worker_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add the exporters restored for the listener to the list of a worker, within the maximum numbers of exporters and of templates per exporter (0 for no limit).
+    /// The exporters and the templates received most recently are kept first.
+    pub fn restore(&self, listener: &str, exporter_list: &mut ExporterList, max_exporters: usize, max_templates: usize) {
+        let mut restored = ExporterList::new();
+        if let Some(records) = self.restored.get(listener) {
+            // the content has been checked when the file was loaded
+            let _ = decode(records, &mut restored);
+        }
+
+        let mut exporters: Vec<_> = restored.into_iter().collect();
+        exporters.sort_by_key(|(_, infos)| Reverse(infos.template.values().map(|entry| entry.received).max()));
+        for (exporter, mut infos) in exporters {
+            if max_exporters > 0 && exporter_list.len() >= max_exporters && !exporter_list.contains_key(&exporter) {
+                warn!("{:?} not restored on {}, the listener already has {} exporters", exporter, listener, exporter_list.len());
+                continue;
+            }
+            if max_templates > 0 && infos.template.len() > max_templates {
+                let mut entries: Vec<_> = infos.template.drain().collect();
+                entries.sort_by_key(|(_, entry)| Reverse(entry.received));
+                entries.truncate(max_templates);
+                infos.template = entries.into_iter().collect();
+            }
+            exporter_list.insert(exporter, infos);
+        }
+    }
+
+    /// Unique id of a worker of the listener, to identify its snapshot
+    pub fn register(&self, listener: &str) -> usize {
+        let id = self.next_worker_id.fetch_add(1, Ordering::Relaxed);
+        self.snapshots.lock().unwrap().insert(id, (listener.to_string(), vec![]));
+        id
+    }
+
+    /// Replace the snapshot of the worker, identified by its id
+    pub fn publish(&self, worker_id: usize, exporter_list: &ExporterList) {
+        let mut buf = vec![];
+        encode(exporter_list, &mut buf);
+        if let Some((_, snapshot)) = self.snapshots.lock().unwrap().get_mut(&worker_id) {
+            *snapshot = buf;
+        }
+    }
+
+    /// Write the snapshots of every worker to the state file, through a temporary file so a crash never leaves it half-written
+    pub fn save(&self) -> Result<(), String> {
+        let mut sections: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        for (listener, snapshot) in self.snapshots.lock().unwrap().values() {
+            sections.entry(listener.clone()).or_default().extend_from_slice(snapshot);
+        }
+
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        for (listener, records) in sections {
+            buf.extend_from_slice(&(listener.len() as u16).to_be_bytes());
+            buf.extend_from_slice(listener.as_bytes());
+            buf.extend_from_slice(&(records.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&records);
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, &buf)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write the state file {} : {}", self.path.display(), e))
+    }
+}
+
+/// Validate the header and the records of the file, returning the exporter records by listener and the number of exporters
+fn check(content: &[u8]) -> Result<(HashMap<String, Vec<u8>>, usize), String> {
+    if content.len() < HEADER_SIZE || &content[0..4] != MAGIC {
+        return Err("not a state file".to_string());
+    }
+
+    let version = u16::from_be_bytes(content[4..6].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(format!("unsupported format version {}", version));
+    }
+
+    let mut buf = &content[HEADER_SIZE..];
+    let (mut sections, mut exporters) = (HashMap::new(), 0);
+    while !buf.is_empty() {
+        let length = u16::from_be_bytes(take(&mut buf, 2)?.try_into().unwrap()) as usize;
+        let listener = String::from_utf8(take(&mut buf, length)?.to_vec()).map_err(|_| "Invalid listener name in the state".to_string())?;
+        let length = u32::from_be_bytes(take(&mut buf, 4)?.try_into().unwrap()) as usize;
+        let records = take(&mut buf, length)?;
+
+        let mut exporter_list = ExporterList::new();
+        decode(records, &mut exporter_list)?;
+        exporters += exporter_list.len();
+        sections.insert(listener, records.to_vec());
+    }
+    Ok((sections, exporters))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::ipfix::DataSetTemplate;
+    use hex_literal::hex;
+
+    const TEMPLATE: [u8; 12] = hex!("01 00 00 02 00 08 00 04 00 0c 00 04");
+
+    fn exporter_list(received: u64) -> ExporterList {
+        let mut list = ExporterList::new();
+        let infos = list
+            .entry(Exporter {
+                addr: "2001:db8::1".parse().unwrap(),
+                domain_id: 7,
+            })
+            .or_default();
+        infos.sampling = 100;
+        infos.system_init_time = Some(1619048604000);
+        infos.reported_addr = Some("192.0.2.1".parse().unwrap());
+        infos.template.insert(
+            256,
+            TemplateEntry {
+                template: Template::IpfixDataSet(DataSetTemplate::read(&TEMPLATE).unwrap().0),
+                received,
+            },
+        );
+        list
+    }
+
+    #[test]
+    fn encode_and_decode_exporter_list() {
+        let mut buf = vec![];
+        encode(&exporter_list(1000), &mut buf);
+
+        let mut list = ExporterList::new();
+        decode(&buf, &mut list).unwrap();
+
+        let infos = &list[&Exporter {
+            addr: "2001:db8::1".parse().unwrap(),
+            domain_id: 7,
+        }];
+        assert_eq!(infos.sampling, 100);
+        assert_eq!(infos.system_init_time, Some(1619048604000));
+        assert_eq!(infos.reported_addr, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(infos.template[&256].received, 1000);
+        match &infos.template[&256].template {
+            Template::IpfixDataSet(t) => assert_eq!(t.plan.min_length, 8),
+            _ => panic!("Wrong template type"),
+        }
+
+        assert!(decode(&buf[0..buf.len() - 1], &mut list).is_err());
+    }
+
+    #[test]
+    fn keep_the_most_recent_template() {
+        let mut buf = vec![];
+        encode(&exporter_list(2000), &mut buf);
+        encode(&exporter_list(1000), &mut buf);
+
+        let mut list = ExporterList::new();
+        decode(&buf, &mut list).unwrap();
+
+        assert_eq!(list.len(), 1);
+        assert_eq!(list.values().next().unwrap().template[&256].received, 2000);
+    }
+
+    #[test]
+    fn save_and_load_state_file() {
+        let path = std::env::temp_dir().join(format!("ipfix-state-{}", std::process::id()));
+
+        let store = StateStore::load(&path, 0);
+        let (first, second) = (store.register("0.0.0.0:4739"), store.register("0.0.0.0:4739"));
+        store.publish(first, &exporter_list(1000));
+        store.publish(second, &ExporterList::new());
+        store.save().unwrap();
+
+        let mut list = ExporterList::new();
+        StateStore::load(&path, 0).restore("0.0.0.0:4739", &mut list, 0, 0);
+        assert_eq!(list.len(), 1);
+
+        fs::write(&path, b"garbage").unwrap();
+        let mut list = ExporterList::new();
+        StateStore::load(&path, 0).restore("0.0.0.0:4739", &mut list, 0, 0);
+        assert!(list.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restore_per_listener_within_limits() {
+        let path = std::env::temp_dir().join(format!("ipfix-state-listeners-{}", std::process::id()));
+
+        let mut other = exporter_list(3000);
+        let infos = other
+            .entry(Exporter {
+                addr: "192.0.2.2".parse().unwrap(),
+                domain_id: 0,
+            })
+            .or_default();
+        for (id, received) in [(256, 1000), (257, 2000)] {
+            let mut record = TEMPLATE;
+            record[0..2].copy_from_slice(&u16::to_be_bytes(id));
+            let template = Template::IpfixDataSet(DataSetTemplate::read(&record).unwrap().0);
+            infos.template.insert(id, TemplateEntry { template, received });
+        }
+
+        let store = StateStore::load(&path, 0);
+        let (udp, netflow) = (store.register("0.0.0.0:4739"), store.register("0.0.0.0:2055"));
+        store.publish(udp, &exporter_list(1000));
+        store.publish(netflow, &other);
+        store.save().unwrap();
+        let store = StateStore::load(&path, 0);
+
+        // the exporters of the other listener stay out
+        let mut list = ExporterList::new();
+        store.restore("0.0.0.0:4739", &mut list, 0, 0);
+        assert_eq!(list.len(), 1);
+        let mut list = ExporterList::new();
+        store.restore("127.0.0.1:4739", &mut list, 0, 0);
+        assert!(list.is_empty());
+
+        // the most recent exporter, and the most recent template of the other one
+        let mut list = ExporterList::new();
+        store.restore("0.0.0.0:2055", &mut list, 1, 1);
+        assert_eq!(list.keys().map(|e| e.domain_id).collect::<Vec<_>>(), vec![7]);
+        let mut list = ExporterList::new();
+        store.restore("0.0.0.0:2055", &mut list, 0, 1);
+        let infos = &list[&Exporter {
+            addr: "192.0.2.2".parse().unwrap(),
+            domain_id: 0,
+        }];
+        assert_eq!(infos.template.keys().collect::<Vec<_>>(), vec![&257]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
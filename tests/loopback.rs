@@ -0,0 +1,374 @@
+//! End-to-end tests of the collector: the listeners run on ephemeral localhost ports, the messages are sent over real sockets
+//! and the flows are captured at the end of the channel, where the exporter thread would write them to its sinks.
+
+use ipfix::config::{Config, SharedConfig};
+use ipfix::flow::ipfix::{DataSet, DataSetTemplate, FieldType, FieldValue, Header, OptionDataSetTemplate, SetHeader, TemplateField};
+use ipfix::flow::{self, netflow5, FlowBatch};
+use ipfix::sinks::Sink;
+use ipfix::threads::channel::{self, OverflowPolicy};
+use ipfix::threads::clock::Clock;
+use ipfix::threads::listener::Listener;
+use ipfix::threads::prometheus;
+use ipfix::threads::shutdown::Shutdown;
+use ipfix::threads::state::{Exporter, ExporterList, StateStore};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const DOMAIN_ID: u32 = 1;
+
+/******************************** HARNESS ********************************/
+
+/// Flow received by the capturing sink
+#[derive(Debug, Clone)]
+struct Record {
+    exporter: IpAddr,
+    tag: Option<String>,
+    fields: HashMap<FieldType, FieldValue>,
+}
+
+/// Sink keeping every flow written to it
+struct CaptureSink(Arc<Mutex<Vec<Record>>>);
+
+impl Sink for CaptureSink {
+    fn write(&mut self, batch: &FlowBatch) -> Result<(), String> {
+        let mut records = self.0.lock().unwrap();
+        for flow in &batch.flows {
+            records.push(Record {
+                exporter: batch.exporter,
+                tag: batch.tag.as_deref().map(String::from),
+                fields: flow.fields().into_iter().collect(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// One listener, the prometheus exporter and a capturing sink, stopped like the collector on SIGTERM
+struct Collector {
+    addr: SocketAddr,
+    metrics: SocketAddr,
+    shutdown: Shutdown,
+    state: Option<Arc<StateStore>>,
+    records: Arc<Mutex<Vec<Record>>>,
+    threads: Vec<JoinHandle<Result<(), String>>>,
+}
+
+impl Collector {
+    fn start(transport: &str, tag: &str, extra_config: &str, clock: Clock, state_path: Option<&Path>) -> Self {
+        let config = Config::parse(&format!("[[listener]]\naddress = \"127.0.0.1:0\"\ntransport = \"{}\"\ntag = \"{}\"\n{}", transport, tag, extra_config)).unwrap();
+        let mut opts = config.listener_options().remove(0);
+        opts.clock = clock;
+        let config = SharedConfig::new(config);
+        let shutdown = Shutdown::new();
+        let state = state_path.map(|path| Arc::new(StateStore::load(path, 0)));
+
+        let listener = Listener::bind(opts).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics_listener = prometheus::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let metrics = metrics_listener.local_addr().unwrap();
+
+        let (sender, receiver) = channel::bounded(64, OverflowPolicy::Block);
+        let records = Arc::new(Mutex::new(vec![]));
+        let mut sink = CaptureSink(records.clone());
+
+        let threads = vec![
+            thread::spawn({
+                let (shutdown, state) = (shutdown.clone(), state.clone());
+                move || listener.run(sender, config, shutdown, state)
+            }),
+            thread::spawn({
+                let shutdown = shutdown.clone();
+                move || prometheus::serve(metrics_listener, shutdown)
+            }),
+            thread::spawn(move || {
+                while let Some(batch) = receiver.recv() {
+                    sink.write(&batch)?;
+                }
+                Ok(())
+            }),
+        ];
+
+        Collector {
+            addr,
+            metrics,
+            shutdown,
+            state,
+            records,
+            threads,
+        }
+    }
+
+    /// Wait until count flows have been captured, returning all of them
+    fn wait_for(&self, count: usize) -> Vec<Record> {
+        let start = Instant::now();
+        loop {
+            let records = self.records.lock().unwrap().clone();
+            if records.len() >= count || start.elapsed() > TIMEOUT {
+                assert_eq!(records.len(), count, "{:?}", records);
+                return records;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Value of a metric of the listener, read from the prometheus exporter
+    fn metric(&self, name: &str, tag: &str) -> u64 {
+        let mut stream = TcpStream::connect(self.metrics).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let prefix = format!("{}{{listener=\"{}\"}} ", name, tag);
+        response.lines().find_map(|line| line.strip_prefix(&prefix)).map_or(0, |value| value.parse().unwrap())
+    }
+
+    /// Stop the threads and write the state file, as on SIGTERM
+    fn stop(self) {
+        self.shutdown.trigger();
+        for t in self.threads {
+            assert_eq!(t.join().unwrap(), Ok(()));
+        }
+        if let Some(state) = self.state {
+            state.save().unwrap();
+        }
+    }
+}
+
+fn exporter() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").unwrap()
+}
+
+fn state_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ipfix-{}-{}.state", name, std::process::id()))
+}
+
+/******************************** MESSAGES ********************************/
+
+fn data_template() -> DataSetTemplate {
+    let field = |id, length| TemplateField { id, length };
+    DataSetTemplate::new(
+        256,
+        vec![
+            field(FieldType::SourceIPv4Address, 4),
+            field(FieldType::DestinationIPv4Address, 4),
+            field(FieldType::OctetDeltaCount, 8),
+            field(FieldType::PacketDeltaCount, 8),
+        ],
+    )
+}
+
+fn option_template() -> OptionDataSetTemplate {
+    let field = |id, length| TemplateField { id, length };
+    OptionDataSetTemplate::new(257, 1, vec![field(FieldType::ObservationDomainId, 4), field(FieldType::SamplingInterval, 4)])
+}
+
+/// IPFIX message made of the sets (set id, content)
+fn ipfix_msg(sets: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut buf = vec![];
+    Header {
+        version: flow::ipfix::VERSION,
+        length: 0,
+        export_time: 1617712521,
+        seq_number: 0,
+        domain_id: DOMAIN_ID,
+    }
+    .write(&mut buf);
+
+    for (id, content) in sets {
+        SetHeader {
+            id: *id,
+            length: (SetHeader::SIZE + content.len()) as u16,
+        }
+        .write(&mut buf);
+        buf.extend_from_slice(content);
+    }
+
+    let length = buf.len() as u16;
+    buf[2..4].copy_from_slice(&length.to_be_bytes());
+    buf
+}
+
+fn template_set() -> (u16, Vec<u8>) {
+    let mut content = vec![];
+    data_template().write(&mut content);
+    (DataSetTemplate::SET_ID, content)
+}
+
+fn option_template_set() -> (u16, Vec<u8>) {
+    let mut content = vec![];
+    option_template().write(&mut content);
+    (OptionDataSetTemplate::SET_ID, content)
+}
+
+/// Data set with one record per source address, sending octets bytes in one packet
+fn data_set(sources: &[u32], octets: u64) -> (u16, Vec<u8>) {
+    let template = data_template();
+    let mut content = vec![];
+    for &source in sources {
+        let fields = vec![
+            (FieldType::SourceIPv4Address, FieldValue::U32(source)),
+            (FieldType::DestinationIPv4Address, FieldValue::U32(0xc6336401)),
+            (FieldType::OctetDeltaCount, FieldValue::U64(octets)),
+            (FieldType::PacketDeltaCount, FieldValue::U64(1)),
+        ];
+        DataSet { fields: fields.into_iter().collect() }.write(&template.fields, &mut content).unwrap();
+    }
+    (256, content)
+}
+
+fn sampling_set(sampling: u32) -> (u16, Vec<u8>) {
+    let mut content = vec![];
+    let fields = vec![(FieldType::ObservationDomainId, FieldValue::U32(DOMAIN_ID)), (FieldType::SamplingInterval, FieldValue::U32(sampling))];
+    DataSet { fields: fields.into_iter().collect() }.write(&option_template().fields, &mut content).unwrap();
+    (257, content)
+}
+
+fn netflow5_msg(sources: &[u32]) -> Vec<u8> {
+    let mut buf = vec![];
+    netflow5::Header {
+        version: netflow5::VERSION,
+        count: sources.len() as u16,
+        uptime: 10_000,
+        unix_secs: 1619048604,
+        unix_nsecs: 0,
+        seq_number: 0,
+        engine_type: 0,
+        engine_id: 0,
+        sampl: 0,
+    }
+    .write(&mut buf);
+
+    for &source in sources {
+        netflow5::DataSet {
+            src_addr: source,
+            dst_addr: 0xc6336401,
+            packets: 1,
+            octets: 1500,
+            start_time: 9_000,
+            end_time: 9_500,
+            protocol: 17,
+            ..Default::default()
+        }
+        .write(&mut buf);
+    }
+    buf
+}
+
+/******************************** TESTS ********************************/
+
+#[test]
+fn decode_datagrams() {
+    let collector = Collector::start("udp", "decode", "", Clock::default(), None);
+    let exporter = exporter();
+    let send = |msg: &[u8]| exporter.send_to(msg, collector.addr).unwrap();
+
+    send(&ipfix_msg(&[template_set(), data_set(&[0x0a000001, 0x0a000002], 1000)]));
+    // the counters of the next records are multiplied by the sampling interval sent in the option data
+    send(&ipfix_msg(&[option_template_set(), sampling_set(10)]));
+    send(&ipfix_msg(&[data_set(&[0x0a000003], 1000)]));
+    send(&netflow5_msg(&[0x0a000004, 0x0a000005]));
+    // unknown version, message shorter than its header says, data of an unknown template
+    send(&[0xff; 20]);
+    send(&ipfix_msg(&[data_set(&[0x0a000006], 1000)])[0..30]);
+    send(&ipfix_msg(&[(300, vec![0; 8])]));
+    // the records of a message sent after the garbage are decoded
+    send(&netflow5_msg(&[0x0a000007]));
+
+    let records = collector.wait_for(6);
+    let sources: Vec<_> = records.iter().map(|r| r.fields[&FieldType::SourceIPv4Address].clone()).collect();
+    assert_eq!(sources, [1, 2, 3, 4, 5, 7].map(|i| FieldValue::U32(0x0a000000 + i)));
+    assert!(records.iter().all(|r| r.exporter == IpAddr::from([127, 0, 0, 1]) && r.tag.as_deref() == Some("decode")));
+    assert_eq!(records[0].fields[&FieldType::OctetDeltaCount], FieldValue::U64(1000));
+    assert_eq!(records[2].fields[&FieldType::OctetDeltaCount], FieldValue::U64(10_000));
+    // the netflow v5 records are mapped to IPFIX fields, with absolute timestamps
+    assert_eq!(records[3].fields[&FieldType::ProtocolIdentifier], FieldValue::U8(17));
+    assert_eq!(records[3].fields[&FieldType::FlowStartMilliseconds], FieldValue::U64(1619048604000 - 10_000 + 9_000));
+
+    assert_eq!(collector.metric("ipfix_received_datagrams_total", "decode"), 8);
+    assert_eq!(collector.metric("ipfix_decoded_flows_total", "decode"), 6);
+    assert_eq!(collector.metric("ipfix_rejected_version_total", "decode"), 1);
+    assert_eq!(collector.metric("ipfix_parsing_errors_total", "decode"), 1);
+
+    collector.stop();
+}
+
+#[test]
+fn decode_tcp_stream() {
+    let collector = Collector::start("tcp", "stream", "", Clock::default(), None);
+    let mut stream = TcpStream::connect(collector.addr).unwrap();
+
+    // several messages in one write, the listener splits them with the length of their header
+    let mut buf = ipfix_msg(&[template_set()]);
+    buf.extend(ipfix_msg(&[data_set(&[0x0a000001], 1000)]));
+    buf.extend(ipfix_msg(&[data_set(&[0x0a000002, 0x0a000003], 1000)]));
+    stream.write_all(&buf).unwrap();
+
+    assert_eq!(collector.wait_for(3).len(), 3);
+    assert_eq!(collector.metric("ipfix_received_datagrams_total", "stream"), 3);
+
+    // the open connection doesn't prevent the shutdown
+    collector.stop();
+}
+
+#[test]
+fn restore_templates_after_restart() {
+    let path = state_path("restart");
+    let _ = fs::remove_file(&path);
+
+    let collector = Collector::start("udp", "restart", "", Clock::default(), Some(&path));
+    exporter().send_to(&ipfix_msg(&[template_set(), data_set(&[0x0a000001], 1000)]), collector.addr).unwrap();
+    collector.wait_for(1);
+    collector.stop();
+
+    // the template of the exporter has been written to the section of the listener on shutdown
+    let mut exporter_list = ExporterList::new();
+    StateStore::load(&path, 0).restore("restart", &mut exporter_list, 0, 0);
+    let key = Exporter {
+        addr: IpAddr::from([127, 0, 0, 1]),
+        domain_id: DOMAIN_ID,
+    };
+    assert_eq!(exporter_list[&key].template.keys().collect::<Vec<_>>(), [&256]);
+
+    // after the restart the data sets are decoded without waiting for the template
+    let collector = Collector::start("udp", "restart", "", Clock::default(), Some(&path));
+    exporter().send_to(&ipfix_msg(&[data_set(&[0x0a000002], 1000)]), collector.addr).unwrap();
+    assert_eq!(collector.wait_for(1)[0].fields[&FieldType::SourceIPv4Address], FieldValue::U32(0x0a000002));
+    collector.stop();
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn expire_templates() {
+    let clock = Clock::manual(1_700_000_000);
+    let collector = Collector::start("udp", "expiry", "[templates]\ntimeout = 60", clock.clone(), None);
+    let exporter = exporter();
+    let send = |msg: &[u8]| exporter.send_to(msg, collector.addr).unwrap();
+
+    send(&ipfix_msg(&[template_set(), data_set(&[0x0a000001], 1000)]));
+    collector.wait_for(1);
+
+    clock.advance(30);
+    send(&ipfix_msg(&[data_set(&[0x0a000002], 1000)]));
+    collector.wait_for(2);
+
+    // the data set is dropped with its expired template, the netflow v5 message shows it has been handled
+    clock.advance(61);
+    send(&ipfix_msg(&[data_set(&[0x0a000003], 1000)]));
+    send(&netflow5_msg(&[0x0a000004]));
+    let records = collector.wait_for(3);
+    assert_eq!(records[2].fields[&FieldType::SourceIPv4Address], FieldValue::U32(0x0a000004));
+
+    // decoded again once the exporter refreshes the template
+    send(&ipfix_msg(&[template_set(), data_set(&[0x0a000005], 1000)]));
+    collector.wait_for(4);
+
+    collector.stop();
+}